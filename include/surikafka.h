@@ -0,0 +1,35 @@
+#ifndef SURIKAFKA_H
+#define SURIKAFKA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ShipperHandle surikafka_t;
+
+/* Starts a shipper configured with surikafka command line flags (without the program name),
+ * e.g. {"--kafka", "broker:9092", "--topic", "eve"}. Returns NULL on error. */
+surikafka_t *surikafka_init(const char *const *argv, size_t argc);
+
+/* Queues a copy of an EVE record for delivery. Returns 0 on success, -1 on error. */
+int surikafka_send(surikafka_t *handle, const uint8_t *record, size_t len);
+
+/* Waits up to timeout_ms for queued records to be delivered. Returns 0 once all completed,
+ * 1 on timeout, -1 on error. */
+int surikafka_flush(surikafka_t *handle, uint64_t timeout_ms);
+
+/* Delivers queued records, stops the shipper, and frees the handle. Returns 0 on success,
+ * -1 if the pipeline failed. */
+int surikafka_close(surikafka_t *handle);
+
+/* Message of the last error on the calling thread, or NULL. */
+const char *surikafka_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,69 @@
+use std::{
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr
+    },
+    str::FromStr
+};
+
+/// Parses an address as it appears in EVE records, dropping any zone id and converting
+/// v4-mapped v6 addresses (`::ffff:10.0.0.1`) to plain v4.
+pub fn parse_ip(addr: &str) -> Option<IpAddr> {
+    let without_zone = addr.trim().split('%').next().unwrap_or("");
+    let trimmed = without_zone.trim_left_matches('[').trim_right_matches(']');
+
+    match IpAddr::from_str(trimmed).ok()? {
+        IpAddr::V6(v6) => Some(unmap(v6)),
+        v4 => Some(v4)
+    }
+}
+
+/// Canonical textual form of an address, so compressed and expanded forms of the same v6 address
+/// produce the same key. Unparseable values are passed through unchanged.
+pub fn normalize_ip(addr: &str) -> String {
+    parse_ip(addr)
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| addr.to_string())
+}
+
+fn unmap(addr: Ipv6Addr) -> IpAddr {
+    let segments = addr.segments();
+    if segments[0..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+        let octets = addr.octets();
+        IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else {
+        IpAddr::V6(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_v6_forms() {
+        assert_eq!(normalize_ip("2001:0db8:0000:0000:0000:0000:0000:0001"), "2001:db8::1");
+        assert_eq!(normalize_ip("2001:DB8::1"), "2001:db8::1");
+        assert_eq!(normalize_ip("10.0.0.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn unmaps_v4_mapped() {
+        assert_eq!(normalize_ip("::ffff:10.0.0.1"), "10.0.0.1");
+        assert_eq!(normalize_ip("0:0:0:0:0:ffff:a00:1"), "10.0.0.1");
+        assert!(parse_ip("::10.0.0.1").map(|ip| ip.is_ipv6()).unwrap_or(false));
+    }
+
+    #[test]
+    fn strips_zone_ids() {
+        assert_eq!(normalize_ip("fe80::1%eth0"), "fe80::1");
+        assert_eq!(normalize_ip("[fe80::0001%2]"), "fe80::1");
+    }
+
+    #[test]
+    fn passes_through_invalid() {
+        assert_eq!(normalize_ip("not an address"), "not an address");
+        assert_eq!(parse_ip(""), None);
+    }
+}
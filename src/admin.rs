@@ -0,0 +1,441 @@
+use super::{
+    errors::Error,
+    futures::{
+        Future,
+        Stream,
+        sync::oneshot
+    },
+    tokio::{
+        self,
+        io::{
+            AsyncRead,
+            AsyncWrite
+        },
+        net::{
+            TcpListener,
+            TcpStream
+        }
+    }
+};
+#[cfg(feature = "admin-tls")]
+use super::{
+    native_tls,
+    tokio_tls
+};
+use std::{
+    self,
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc
+};
+
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>
+}
+
+impl Request {
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|v| v.as_str())
+    }
+
+    /// Header lookup, with `name` in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: 200,
+            content_type: content_type,
+            body: body
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response {
+            status: status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.as_bytes().to_vec()
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            _ => "Internal Server Error"
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len()
+        ).into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+/// Decodes `%XX` escapes and `+` for space in a query string component. Invalid escapes are
+/// kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(b) = escaped {
+                    decoded.push(b);
+                    i += 3;
+                    continue
+                }
+                decoded.push(b'%');
+            }
+            b => decoded.push(b)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses the request line and headers of an HTTP/1.x request. Bodies are ignored; the admin
+/// endpoints only take query parameters.
+pub fn parse_request(bytes: &[u8]) -> Option<Request> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next()?.to_string();
+    let query = parts.next()
+        .map(|q| {
+            q.split('&')
+                .filter(|p| !p.is_empty())
+                .map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    (percent_decode(kv.next().unwrap_or("")), percent_decode(kv.next().unwrap_or("")))
+                })
+                .collect()
+        })
+        .unwrap_or_else(HashMap::new);
+
+    let headers = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| {
+            let mut kv = l.splitn(2, ':');
+            let name = kv.next()?.trim().to_ascii_lowercase();
+            let value = kv.next()?.trim().to_string();
+            Some( (name, value) )
+        })
+        .collect();
+
+    Some(Request {
+        method: method,
+        path: path,
+        query: query,
+        headers: headers
+    })
+}
+
+pub type Handler = Arc<Fn(&Request) -> Response + Send + Sync>;
+
+/// What a token may do. Read covers stats and status endpoints; control covers endpoints that
+/// change or load the shipper, such as profiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Control
+}
+
+impl std::str::FromStr for Permission {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Permission, Error> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "control" => Ok(Permission::Control),
+            _ => bail!("Invalid admin permission {}, expected read or control", s)
+        }
+    }
+}
+
+/// Parses a tokens file: one `<permission> <token>` per line, e.g. `read 3f9a...`. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn parse_tokens(contents: &str) -> Result<Vec<(Permission, String)>, Error> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut parts = l.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(permission), Some(token), None) => Ok( (permission.parse::<Permission>()?, token.to_string()) ),
+                _ => bail!("Invalid admin token line, expected <permission> <token>")
+            }
+        })
+        .collect()
+}
+
+/// Compares without returning early, so response times don't leak how much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Minimal HTTP server for operational endpoints. Handlers run on their own thread so slow
+/// handlers (profiling) don't block the runtime. Without tokens, every endpoint is open to anyone
+/// who can reach the server.
+#[derive(Clone, Default)]
+pub struct AdminServer {
+    routes: Vec<(String, Permission, Handler)>,
+    tokens: Vec<(String, Permission)>,
+    #[cfg(feature = "admin-tls")]
+    tls: Option<tokio_tls::TlsAcceptor>
+}
+
+impl AdminServer {
+    /// Serves `handler` on `path` to tokens with read permission.
+    pub fn route<F>(self, path: &str, handler: F) -> Self
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route_with(path, Permission::Read, handler)
+    }
+
+    pub fn route_with<F>(mut self, path: &str, permission: Permission, handler: F) -> Self
+        where F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.routes.push( (path.to_string(), permission, Arc::new(handler)) );
+        self
+    }
+
+    /// Requires requests to carry `Authorization: Bearer <token>` of a token with the route's
+    /// permission; control tokens may also use read routes.
+    pub fn with_token(mut self, token: &str, permission: Permission) -> Self {
+        self.tokens.push( (token.to_string(), permission) );
+        self
+    }
+
+    /// Serves over TLS with the identity of a PKCS #12 archive.
+    #[cfg(feature = "admin-tls")]
+    pub fn with_tls(mut self, pkcs12: &[u8], password: &str) -> Result<Self, Error> {
+        let identity = native_tls::Identity::from_pkcs12(pkcs12, password)
+            .map_err(|e| Error::from(format!("Invalid admin TLS identity: {}", e)))?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)
+            .map_err(|e| Error::from(format!("Failed to set up admin TLS: {}", e)))?;
+        self.tls = Some(tokio_tls::TlsAcceptor::from(acceptor));
+        Ok(self)
+    }
+
+    fn permission(&self, request: &Request) -> Option<Permission> {
+        if self.tokens.is_empty() {
+            return Some(Permission::Control)
+        }
+        let header = request.header("authorization")?;
+        if !header.starts_with("Bearer ") {
+            return None
+        }
+        let presented = header["Bearer ".len()..].trim().as_bytes();
+        self.tokens.iter()
+            .filter(|&&(ref token, _)| constant_time_eq(token.as_bytes(), presented))
+            .map(|&(_, permission)| permission)
+            .max()
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let granted = match self.permission(request) {
+            Some(granted) => granted,
+            None => return Response::text(401, "Unauthorized\n")
+        };
+        match self.routes.iter().find(|&&(ref path, _, _)| *path == request.path) {
+            Some(&(_, required, _)) if granted < required => Response::text(403, "Forbidden\n"),
+            Some(&(_, _, ref handler)) => handler(request),
+            None => Response::text(404, "Not found\n")
+        }
+    }
+
+    pub fn serve(self, addr: &SocketAddr) -> Result<Box<Future<Item=(), Error=()> + Send>, Error> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Admin server listening on {}", addr);
+        if self.tokens.is_empty() && !addr.ip().is_loopback() {
+            warn!("Admin server on {} has no tokens, anyone who can reach it can use every endpoint", addr);
+        }
+        let server = Arc::new(self);
+
+        let fut = listener.incoming()
+            .map_err(|e| error!("Admin server failed to accept: {:?}", e))
+            .for_each(move |socket| {
+                tokio::spawn(connection(server.clone(), socket));
+                Ok(())
+            });
+
+        Ok(Box::new(fut))
+    }
+}
+
+#[cfg(feature = "admin-tls")]
+fn connection(server: Arc<AdminServer>, socket: TcpStream) -> Box<Future<Item=(), Error=()> + Send> {
+    match server.tls.clone() {
+        Some(tls) => Box::new(tls.accept(socket)
+            .map_err(|e| error!("Admin server TLS handshake failed: {:?}", e))
+            .and_then(move |socket| respond(server, socket))),
+        None => respond(server, socket)
+    }
+}
+
+#[cfg(not(feature = "admin-tls"))]
+fn connection(server: Arc<AdminServer>, socket: TcpStream) -> Box<Future<Item=(), Error=()> + Send> {
+    respond(server, socket)
+}
+
+fn respond<S>(server: Arc<AdminServer>, socket: S) -> Box<Future<Item=(), Error=()> + Send>
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    let response = tokio::io::read(socket, vec![0; MAX_REQUEST_SIZE])
+        .map_err(|e| error!("Admin server failed to read request: {:?}", e))
+        .and_then(move |(socket, buf, len)| {
+            let (sender, receiver) = oneshot::channel();
+            let request = parse_request(&buf[..len]);
+            std::thread::spawn(move || {
+                let response = match request {
+                    Some(ref request) => server.handle(request),
+                    None => Response::text(400, "Bad request\n")
+                };
+                let _ = sender.send(response);
+            });
+            receiver
+                .map_err(|_| error!("Admin handler failed"))
+                .map(move |response| (socket, response))
+        })
+        .and_then(|(socket, response)| {
+            tokio::io::write_all(socket, response.to_bytes())
+                .map(|_| ())
+                .map_err(|e| error!("Admin server failed to write response: {:?}", e))
+        });
+    Box::new(response)
+}
+
+#[cfg(feature = "profiling")]
+pub mod profiling {
+    use super::*;
+    use super::super::cpuprofiler::PROFILER;
+
+    const DEFAULT_SECONDS: u64 = 30;
+    const MAX_SECONDS: u64 = 300;
+
+    /// Captures a CPU profile for `seconds` (default 30) and returns it in gperftools format,
+    /// readable with `pprof --svg surikafka profile.prof`.
+    pub fn profile(request: &Request) -> Response {
+        let seconds = request.query_param("seconds")
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_SECONDS)
+            .min(MAX_SECONDS);
+        let path = std::env::temp_dir().join(format!("surikafka-{}.prof", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+
+        let mut profiler = match PROFILER.lock() {
+            Ok(p) => p,
+            Err(_) => return Response::text(500, "Profiler unavailable\n")
+        };
+        if let Err(e) = profiler.start(path_str.as_str()) {
+            return Response::text(500, &format!("Failed to start profiler: {:?}\n", e))
+        }
+        std::thread::sleep(std::time::Duration::from_secs(seconds));
+        if let Err(e) = profiler.stop() {
+            return Response::text(500, &format!("Failed to stop profiler: {:?}\n", e))
+        }
+
+        match std::fs::read(&path) {
+            Ok(body) => {
+                let _ = std::fs::remove_file(&path);
+                Response::ok("application/octet-stream", body)
+            }
+            Err(e) => Response::text(500, &format!("Failed to read profile: {}\n", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        let request = parse_request(b"GET /debug/pprof/profile?seconds=5&x HTTP/1.1\r\nHost: sensor\r\nAuthorization: Bearer abc\r\n\r\n")
+            .expect("Failed to parse");
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/debug/pprof/profile");
+        assert_eq!(request.query_param("seconds"), Some("5"));
+        assert_eq!(request.query_param("x"), Some(""));
+        let request = parse_request(b"GET /events/recent?filter=event_type%3Dalert+and+src_ip%3D10.0.0.1 HTTP/1.1\r\n\r\n")
+            .expect("Failed to parse");
+        assert_eq!(request.query_param("filter"), Some("event_type=alert and src_ip=10.0.0.1"));
+        assert_eq!(request.header("authorization"), Some("Bearer abc"));
+        assert_eq!(parse_request(b""), None);
+    }
+
+    #[test]
+    fn routes_requests() {
+        let server = AdminServer::default()
+            .route("/ping", |_| Response::text(200, "pong"));
+
+        let ping = parse_request(b"GET /ping HTTP/1.1\r\n\r\n").expect("Failed to parse");
+        let missing = parse_request(b"GET /missing HTTP/1.1\r\n\r\n").expect("Failed to parse");
+
+        assert_eq!(server.handle(&ping).body, b"pong".to_vec());
+        assert_eq!(server.handle(&missing).status, 404);
+    }
+
+    #[test]
+    fn checks_token_permissions() {
+        let tokens = parse_tokens("# operators\nread r3ad\ncontrol c0ntrol\n").expect("Failed to parse");
+        let server = tokens.iter().fold(AdminServer::default(), |server, &(permission, ref token)| server.with_token(token, permission))
+            .route("/stats", |_| Response::text(200, "stats"))
+            .route_with("/profile", Permission::Control, |_| Response::text(200, "profile"));
+        let request = |path: &str, token: &str| {
+            parse_request(format!("GET {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", path, token).as_bytes())
+                .expect("Failed to parse")
+        };
+
+        assert_eq!(server.handle(&request("/stats", "r3ad")).status, 200);
+        assert_eq!(server.handle(&request("/profile", "r3ad")).status, 403);
+        assert_eq!(server.handle(&request("/profile", "c0ntrol")).status, 200);
+        assert_eq!(server.handle(&request("/stats", "wrong")).status, 401);
+        assert_eq!(server.handle(&parse_request(b"GET /stats HTTP/1.1\r\n\r\n").expect("Failed to parse")).status, 401);
+        assert!(parse_tokens("admin s3cret").is_err());
+    }
+
+    #[test]
+    fn serializes_responses() {
+        assert_eq!(
+            Response::text(200, "ok").to_bytes(),
+            b"HTTP/1.0 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_vec()
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use super::{
+    derive::Derivation,
+    errors::Error,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    self,
+    collections::HashMap,
+    io::BufRead,
+    path::Path
+};
+
+/// Weights of the anomaly types Suricata logs, used for anomalies without a more specific weight.
+const DEFAULT_WEIGHTS: &'static [(&'static str, f64)] = &[
+    ("decode", 0.5),
+    ("stream", 0.3),
+    ("applayer", 0.4)
+];
+
+/// Scores `anomaly` events by configurable weights and escalates those scoring at or above a
+/// threshold as synthetic alerts, so decoder and stream anomalies reach the SOC.
+pub struct AnomalyScorer {
+    weights: HashMap<String, f64>,
+    default_weight: f64,
+    threshold: f64
+}
+
+impl Default for AnomalyScorer {
+    fn default() -> AnomalyScorer {
+        AnomalyScorer {
+            weights: DEFAULT_WEIGHTS.iter().map(|&(name, weight)| (name.to_string(), weight)).collect(),
+            default_weight: 0.0,
+            threshold: 0.8
+        }
+    }
+}
+
+impl AnomalyScorer {
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Loads weights with one `name,weight` entry per line, where `name` is an anomaly type
+    /// (`decode`) or event (`decoder.ipv4.trunc_pkt`), or a dotted prefix of events (`decoder.ipv4`).
+    pub fn parse<R: BufRead>(&mut self, reader: R) -> Result<usize, Error> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            match (fields.get(0), fields.get(1).and_then(|w| w.parse::<f64>().ok())) {
+                (Some(name), Some(weight)) if !name.is_empty() => {
+                    self.weights.insert(name.to_string(), weight);
+                    count += 1;
+                }
+                _ => warn!("Ignoring invalid anomaly weight: {}", line)
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, Error> {
+        let file = std::fs::File::open(path)?;
+        self.parse(std::io::BufReader::new(file))
+    }
+
+    /// Weight of the longest matching event prefix, else of the anomaly type.
+    pub fn score(&self, event: &Value) -> Option<f64> {
+        let anomaly = event.get("anomaly")?;
+        if let Some(name) = anomaly.get("event").and_then(Value::as_str) {
+            let mut prefix = name;
+            loop {
+                if let Some(weight) = self.weights.get(prefix) {
+                    return Some(*weight)
+                }
+                match prefix.rfind('.') {
+                    Some(pos) => prefix = &prefix[..pos],
+                    None => break
+                }
+            }
+        }
+        let weight = anomaly.get("type").and_then(Value::as_str)
+            .and_then(|t| self.weights.get(t))
+            .cloned()
+            .unwrap_or(self.default_weight);
+        Some(weight)
+    }
+
+    /// Suricata alert severity for a score, 1 being the most severe.
+    pub fn severity(score: f64) -> u64 {
+        if score >= 0.9 {
+            1
+        } else if score >= 0.6 {
+            2
+        } else {
+            3
+        }
+    }
+
+    /// Synthetic alert for an anomaly scoring at or above the threshold.
+    pub fn escalate(&self, event: &Value) -> Option<Value> {
+        let score = self.score(event)?;
+        if score < self.threshold {
+            return None
+        }
+        let anomaly = event.get("anomaly")?;
+        let name = anomaly.get("event").and_then(Value::as_str)
+            .or_else(|| anomaly.get("type").and_then(Value::as_str))
+            .unwrap_or("unknown");
+        let mut alert = event.clone();
+        let object = alert.as_object_mut()?;
+        object.insert("event_type".to_string(), json!("alert"));
+        object.insert("alert".to_string(), json!({
+            "action": "allowed",
+            "signature_id": 0,
+            "signature": format!("SURIKAFKA anomaly {}", name),
+            "category": "Decoder anomaly",
+            "severity": AnomalyScorer::severity(score),
+            "score": score
+        }));
+        Some(alert)
+    }
+}
+
+impl Derivation for AnomalyScorer {
+    fn event_type(&self) -> &str {
+        "anomaly"
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        self.escalate(event).into_iter()
+            .map(|alert| serde_json::to_vec(&alert).expect("Alert is always serializable"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anomaly(kind: &str, name: &str) -> Value {
+        json!({
+            "timestamp": "2018-06-01T00:00:00.000000+0000",
+            "event_type": "anomaly",
+            "src_ip": "10.0.0.1",
+            "anomaly": {"type": kind, "event": name}
+        })
+    }
+
+    #[test]
+    fn scores_by_most_specific_weight() {
+        let mut scorer = AnomalyScorer::default();
+        scorer.parse("# weights\ndecoder.ipv4,0.7\ndecoder.ipv4.trunc_pkt,0.95\nbad line\n".as_bytes())
+            .expect("Failed to parse");
+
+        assert_eq!(scorer.score(&anomaly("decode", "decoder.ipv4.trunc_pkt")), Some(0.95));
+        assert_eq!(scorer.score(&anomaly("decode", "decoder.ipv4.opt_pad_required")), Some(0.7));
+        assert_eq!(scorer.score(&anomaly("decode", "decoder.ipv6.trunc_pkt")), Some(0.5));
+        assert_eq!(scorer.score(&anomaly("unknown", "x")), Some(0.0));
+        assert_eq!(scorer.score(&json!({"event_type": "anomaly"})), None);
+    }
+
+    #[test]
+    fn escalates_high_scores() {
+        let mut scorer = AnomalyScorer::default().with_threshold(0.6);
+        scorer.parse("decoder.ipv4.trunc_pkt,0.95\n".as_bytes()).expect("Failed to parse");
+
+        let records = scorer.derive(&anomaly("decode", "decoder.ipv4.trunc_pkt"));
+        let alert: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+
+        assert_eq!(alert["event_type"], "alert");
+        assert_eq!(alert["alert"]["severity"], 1);
+        assert_eq!(alert["alert"]["signature"], "SURIKAFKA anomaly decoder.ipv4.trunc_pkt");
+        assert_eq!(alert["src_ip"], "10.0.0.1");
+        assert!(scorer.derive(&anomaly("stream", "stream.pkt_invalid_ack")).is_empty());
+    }
+}
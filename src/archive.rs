@@ -0,0 +1,237 @@
+use super::{
+    chrono::Utc,
+    errors::Error,
+    eve,
+    parquet::{
+        column::writer::ColumnWriter,
+        data_type::ByteArray,
+        errors::ParquetError,
+        file::{
+            properties::WriterProperties,
+            writer::{
+                FileWriter,
+                RowGroupWriter,
+                SerializedFileWriter
+            }
+        },
+        schema::parser::parse_message_type
+    },
+    s3::S3Client,
+    serde_json::{
+        self,
+        Value
+    },
+    sink::Sink,
+    source::event_date
+};
+use std::{
+    self,
+    collections::HashMap,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Columns of archived files; `event` holds the record as logged, the others allow pruning
+/// without parsing it.
+const SCHEMA: &'static str = "
+message eve {
+    REQUIRED BYTE_ARRAY timestamp (UTF8);
+    REQUIRED BYTE_ARRAY event_type (UTF8);
+    REQUIRED BYTE_ARRAY src_ip (UTF8);
+    REQUIRED BYTE_ARRAY dest_ip (UTF8);
+    REQUIRED BYTE_ARRAY event (UTF8);
+}
+";
+
+const COLUMNS: &'static [&'static str] = &["timestamp", "event_type", "src_ip", "dest_ip"];
+
+fn parquet_err(e: ParquetError) -> Error {
+    Error::from(format!("Failed to write parquet: {}", e))
+}
+
+/// Encodes events as a Parquet file with a single row group.
+pub fn encode_parquet(events: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let schema = Rc::new(parse_message_type(SCHEMA).map_err(|e| Error::from(format!("Invalid schema: {}", e)))?);
+    let properties = Rc::new(WriterProperties::builder().build());
+    let path = std::env::temp_dir().join(format!("surikafka-{}-{}.parquet", std::process::id(), Utc::now().timestamp_nanos()));
+
+    let parsed: Vec<Value> = events.iter()
+        .map(|e| serde_json::from_slice(e).unwrap_or(Value::Null))
+        .collect();
+    let mut columns: Vec<Vec<ByteArray>> = COLUMNS.iter()
+        .map(|name| parsed.iter()
+            .map(|v| ByteArray::from(v.get(*name).and_then(Value::as_str).unwrap_or("").as_bytes().to_vec()))
+            .collect())
+        .collect();
+    columns.push(events.iter().map(|e| ByteArray::from(e.clone())).collect());
+
+    {
+        let file = std::fs::File::create(&path)?;
+        let mut writer = SerializedFileWriter::new(file, schema, properties).map_err(parquet_err)?;
+        let mut row_group = writer.next_row_group().map_err(parquet_err)?;
+        for values in columns.iter() {
+            let mut column = match row_group.next_column().map_err(parquet_err)? {
+                Some(c) => c,
+                None => bail!("Parquet schema has fewer columns than events")
+            };
+            if let ColumnWriter::ByteArrayColumnWriter(ref mut typed) = column {
+                typed.write_batch(values, None, None).map_err(parquet_err)?;
+            }
+            row_group.close_column(column).map_err(parquet_err)?;
+        }
+        writer.close_row_group(row_group).map_err(parquet_err)?;
+        writer.close().map_err(parquet_err)?;
+    }
+
+    let encoded = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    Ok(encoded?)
+}
+
+/// Partition of an event, `event_type=<type>/date=<YYYY-MM-DD>`.
+pub fn partition(msg: &[u8]) -> String {
+    let event: Value = serde_json::from_slice(msg).unwrap_or(Value::Null);
+    format!(
+        "event_type={}/date={}",
+        eve::event_type(msg).unwrap_or("unknown"),
+        event_date(&event).replace(".", "-")
+    )
+}
+
+struct Pending {
+    events: Vec<Vec<u8>>,
+    since: Instant
+}
+
+/// Batches events into Parquet files partitioned by event type and date, uploading each file
+/// once it holds `max_events` or has been open for `max_age`, for cheap long term retention.
+pub struct ArchiveSink {
+    client: S3Client,
+    prefix: String,
+    sensor_id: String,
+    max_events: usize,
+    max_age: Duration,
+    pending: HashMap<String, Pending>,
+    sequence: u64
+}
+
+impl ArchiveSink {
+    pub fn new(client: S3Client, prefix: &str, sensor_id: &str) -> ArchiveSink {
+        ArchiveSink {
+            client: client,
+            prefix: prefix.trim_matches('/').to_string(),
+            sensor_id: sensor_id.to_string(),
+            max_events: 100000,
+            max_age: Duration::from_secs(300),
+            pending: HashMap::new(),
+            sequence: 0
+        }
+    }
+
+    pub fn with_limits(mut self, max_events: usize, max_age: Duration) -> Self {
+        self.max_events = max_events;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn object_key(&self, partition: &str, sequence: u64) -> String {
+        let name = format!("{}-{}-{}.parquet", self.sensor_id, Utc::now().timestamp_millis(), sequence);
+        if self.prefix.is_empty() {
+            format!("{}/{}", partition, name)
+        } else {
+            format!("{}/{}/{}", self.prefix, partition, name)
+        }
+    }
+
+    fn upload(&mut self, partition: &str, events: &[Vec<u8>]) -> Result<(), Error> {
+        self.sequence += 1;
+        let key = self.object_key(partition, self.sequence);
+        let encoded = encode_parquet(events)?;
+        self.client.put(&key, &encoded)?;
+        info!("Archived {} events to {}", events.len(), key);
+        Ok( () )
+    }
+
+    /// Uploads the partitions that are full or old enough, or all of them if `all`.
+    fn flush(&mut self, all: bool) -> Result<(), Error> {
+        let now = Instant::now();
+        let (max_events, max_age) = (self.max_events, self.max_age);
+        let ready: Vec<String> = self.pending.iter()
+            .filter(|&(_, p)| all || p.events.len() >= max_events || now - p.since >= max_age)
+            .map(|(partition, _)| partition.clone())
+            .collect();
+        let mut first_error = None;
+        for partition in ready {
+            let pending = match self.pending.remove(&partition) {
+                Some(p) => p,
+                None => continue
+            };
+            if let Err(e) = self.upload(&partition, &pending.events) {
+                first_error = first_error.or(Some(e));
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok( () )
+        }
+    }
+}
+
+impl Sink for ArchiveSink {
+    fn name(&self) -> &str {
+        "archive"
+    }
+
+    fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+        let now = Instant::now();
+        for msg in batch {
+            self.pending.entry(partition(msg))
+                .or_insert_with(|| Pending { events: vec![], since: now })
+                .events.push(msg.clone());
+        }
+        self.flush(false)
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.flush(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::s3::Credentials;
+
+    #[test]
+    fn partitions_by_type_and_date() {
+        assert_eq!(
+            partition(br#"{"timestamp":"2018-06-01T00:00:00.000000+0000","event_type":"dns"}"#),
+            "event_type=dns/date=2018-06-01"
+        );
+    }
+
+    #[test]
+    fn encodes_parquet_files() {
+        let encoded = encode_parquet(&[br#"{"event_type":"alert","src_ip":"10.0.0.1"}"#.to_vec()])
+            .expect("Failed to encode");
+
+        assert_eq!(&encoded[..4], b"PAR1");
+        assert_eq!(&encoded[encoded.len() - 4..], b"PAR1");
+    }
+
+    #[test]
+    fn names_objects() {
+        let client = S3Client::new("minio:9000", "eve", Credentials {
+            access_key: "a".to_string(),
+            secret_key: "s".to_string(),
+            region: "us-east-1".to_string()
+        });
+        let sink = ArchiveSink::new(client, "/sensors/", "s1");
+
+        assert!(sink.object_key("event_type=dns/date=2018-06-01", 3).starts_with("sensors/event_type=dns/date=2018-06-01/s1-"));
+        assert!(sink.object_key("event_type=dns/date=2018-06-01", 3).ends_with("-3.parquet"));
+    }
+}
@@ -0,0 +1,278 @@
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    guard::StageGuard,
+    serde_json::{
+        self,
+        Value
+    },
+    writer::HeaderGenerator
+};
+use std::{
+    self,
+    collections::{
+        BTreeSet,
+        HashMap
+    },
+    io::BufRead,
+    path::Path
+};
+
+/// Metadata keys carrying ATT&CK ids in ET Open/Pro rules.
+const TECHNIQUE_KEYS: &'static [&'static str] = &["mitre_technique_id", "mitre_attack_technique"];
+const TACTIC_KEYS: &'static [&'static str] = &["mitre_tactic_id", "mitre_attack_tactic"];
+/// Talos rules reference techniques by url rather than metadata.
+const TECHNIQUE_URL: &'static str = "attack.mitre.org/techniques/";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Attack {
+    pub techniques: BTreeSet<String>,
+    pub tactics: BTreeSet<String>
+}
+
+impl Attack {
+    pub fn is_empty(&self) -> bool {
+        self.techniques.is_empty() && self.tactics.is_empty()
+    }
+
+    fn merge(&mut self, other: &Attack) {
+        self.techniques.extend(other.techniques.iter().cloned());
+        self.tactics.extend(other.tactics.iter().cloned());
+    }
+
+    fn add_metadata(&mut self, metadata: &Value) {
+        for key in TECHNIQUE_KEYS {
+            self.techniques.extend(strings(metadata.get(*key)));
+        }
+        for key in TACTIC_KEYS {
+            self.tactics.extend(strings(metadata.get(*key)));
+        }
+    }
+
+    fn add_references(&mut self, references: &Value) {
+        for reference in strings(Some(references)) {
+            if let Some(pos) = reference.find(TECHNIQUE_URL) {
+                let id: String = reference[pos + TECHNIQUE_URL.len()..]
+                    .split(|c| c == '/' || c == '?' || c == '#')
+                    .filter(|p| !p.is_empty())
+                    .collect::<Vec<&str>>()
+                    .join(".");
+                if !id.is_empty() {
+                    self.techniques.insert(id);
+                }
+            }
+        }
+    }
+}
+
+fn strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(&Value::String(ref s)) => vec![s.clone()],
+        Some(&Value::Array(ref values)) => values.iter()
+            .filter_map(Value::as_str)
+            .map(|s| s.to_string())
+            .collect(),
+        _ => vec![]
+    }
+}
+
+/// Adds `attack.technique` and `attack.tactic` to alerts, from rule metadata emitted by suricata
+/// (`alert.metadata`), metadata added by rule enrichment (`rule`), and an optional explicit
+/// mapping by signature id.
+#[derive(Default)]
+pub struct AttackTagger {
+    by_sid: HashMap<u64, Attack>
+}
+
+impl AttackTagger {
+    /// Loads a mapping with one `sid,technique[,tactic]` entry per line.
+    pub fn parse<R: BufRead>(&mut self, reader: R) -> Result<usize, Error> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            let sid = match fields[0].parse::<u64>() {
+                Ok(sid) => sid,
+                Err(_) => {
+                    warn!("Ignoring invalid ATT&CK mapping: {}", line);
+                    continue
+                }
+            };
+            let attack = self.by_sid.entry(sid).or_insert_with(Attack::default);
+            if let Some(technique) = fields.get(1).filter(|t| !t.is_empty()) {
+                attack.techniques.insert(technique.to_string());
+            }
+            if let Some(tactic) = fields.get(2).filter(|t| !t.is_empty()) {
+                attack.tactics.insert(tactic.to_string());
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, Error> {
+        let file = std::fs::File::open(path)?;
+        self.parse(std::io::BufReader::new(file))
+    }
+
+    pub fn attack(&self, event: &Value) -> Attack {
+        let mut attack = Attack::default();
+        if let Some(metadata) = event.pointer("/alert/metadata") {
+            attack.add_metadata(metadata);
+        }
+        if let Some(metadata) = event.pointer("/rule/metadata") {
+            attack.add_metadata(metadata);
+        }
+        if let Some(references) = event.pointer("/rule/references") {
+            attack.add_references(references);
+        }
+        if let Some(mapped) = event.pointer("/alert/signature_id").and_then(Value::as_u64).and_then(|sid| self.by_sid.get(&sid)) {
+            attack.merge(mapped);
+        }
+        attack
+    }
+
+    /// Returns the tagged event, or `None` if it is not an alert with known techniques.
+    pub fn tag(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        let mut event: Value = serde_json::from_slice(msg).ok()?;
+        if event.get("event_type").and_then(Value::as_str) != Some("alert") {
+            return None
+        }
+        let attack = self.attack(&event);
+        if attack.is_empty() {
+            return None
+        }
+        event.as_object_mut()?.insert("attack".to_string(), json!({
+            "technique": attack.techniques,
+            "tactic": attack.tactics
+        }));
+        serde_json::to_vec(&event).ok()
+    }
+}
+
+pub struct AttackTagStream<S> {
+    inner: S,
+    tagger: AttackTagger,
+    guard: StageGuard
+}
+
+impl<S> AttackTagStream<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, tagger: AttackTagger) -> AttackTagStream<S> {
+        AttackTagStream {
+            inner: inner,
+            tagger: tagger,
+            guard: StageGuard::new("attack")
+        }
+    }
+
+    pub fn with_guard(mut self, guard: StageGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+}
+
+impl<S> Stream for AttackTagStream<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    let tagger = &self.tagger;
+                    if let Some(msg) = self.guard.run(msg, |m| tagger.tag(m)) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Copies the `attack` fields added by `AttackTagger` into `attack.technique` and
+/// `attack.tactic` headers, comma separated.
+pub struct AttackHeaders;
+
+impl HeaderGenerator for AttackHeaders {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let event: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return vec![]
+        };
+        let mut headers = vec![];
+        for name in &["technique", "tactic"] {
+            let values = strings(event.get("attack").and_then(|a| a.get(*name)));
+            if !values.is_empty() {
+                headers.push( (format!("attack.{}", name), values.join(",").into_bytes()) );
+            }
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_from_suricata_metadata() {
+        let tagger = AttackTagger::default();
+
+        let alert = r#"{"event_type":"alert","alert":{"signature_id":1,"metadata":{"mitre_technique_id":["T1190"],"mitre_tactic_id":["TA0001"]}}}"#;
+        let tagged: Value = serde_json::from_slice(&tagger.tag(alert.as_bytes()).expect("Not tagged"))
+            .expect("Failed to parse");
+
+        assert_eq!(tagged["attack"]["technique"], json!(["T1190"]));
+        assert_eq!(tagged["attack"]["tactic"], json!(["TA0001"]));
+    }
+
+    #[test]
+    fn tags_from_talos_references() {
+        let tagger = AttackTagger::default();
+
+        let alert = r#"{"event_type":"alert","alert":{"signature_id":1},"rule":{"references":["url,attack.mitre.org/techniques/T1059/001/"]}}"#;
+        let tagged: Value = serde_json::from_slice(&tagger.tag(alert.as_bytes()).expect("Not tagged"))
+            .expect("Failed to parse");
+
+        assert_eq!(tagged["attack"]["technique"], json!(["T1059.001"]));
+    }
+
+    #[test]
+    fn tags_from_mapping() {
+        let mut tagger = AttackTagger::default();
+
+        assert_eq!(tagger.parse("# sid,technique,tactic\n2000001,T1046,TA0007\nbad\n".as_bytes()).expect("Failed to parse"), 1);
+
+        let alert = r#"{"event_type":"alert","alert":{"signature_id":2000001}}"#;
+        let tagged: Value = serde_json::from_slice(&tagger.tag(alert.as_bytes()).expect("Not tagged"))
+            .expect("Failed to parse");
+
+        assert_eq!(tagged["attack"]["technique"], json!(["T1046"]));
+        assert_eq!(tagger.tag(r#"{"event_type":"alert","alert":{"signature_id":3}}"#.as_bytes()), None);
+        assert_eq!(tagger.tag(r#"{"event_type":"flow"}"#.as_bytes()), None);
+    }
+
+    #[test]
+    fn generates_headers() {
+        let msg = r#"{"attack":{"technique":["T1046","T1190"],"tactic":["TA0007"]}}"#.to_string().into_bytes();
+
+        assert_eq!(AttackHeaders.generate(&msg), vec![
+            ("attack.technique".to_string(), b"T1046,T1190".to_vec()),
+            ("attack.tactic".to_string(), b"TA0007".to_vec())
+        ]);
+        assert!(AttackHeaders.generate(&b"{}".to_vec()).is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+use super::{
+    eve,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    metrics::QueueGauge,
+    runtime::Delay,
+    serde_json::{
+        self,
+        Value
+    },
+    writer::HeaderGenerator
+};
+use std::{
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Header with the number of events in a batch.
+pub const COUNT_HEADER: &'static str = "batch.count";
+/// Headers with the earliest and latest `timestamp` of the events in a batch.
+pub const FIRST_TIMESTAMP_HEADER: &'static str = "batch.first_timestamp";
+pub const LAST_TIMESTAMP_HEADER: &'static str = "batch.last_timestamp";
+
+/// Joins records into a JSON array without parsing them.
+pub fn join(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut array = Vec::with_capacity(records.iter().map(|r| r.len() + 1).sum::<usize>() + 1);
+    array.push(b'[');
+    for (i, record) in records.iter().enumerate() {
+        if i > 0 {
+            array.push(b',');
+        }
+        array.extend_from_slice(record);
+    }
+    array.push(b']');
+    array
+}
+
+struct Group {
+    records: Vec<Vec<u8>>,
+    started: Instant
+}
+
+/// Groups up to `max_records` events of one event type into a single JSON array record, for
+/// consumers behind HTTP bridges that take arrays, and to spend less per-record overhead on
+/// small events. A batch is sent once full or `max_wait` after its first event. Each batch
+/// starts with an event of its type, so routing by event type and event timestamps work on
+/// batches as on single events. Events the bypass accepts are passed on alone, at once.
+pub struct ArrayBatcher<S> {
+    inner: S,
+    max_records: usize,
+    max_wait: Duration,
+    bypass: Option<Box<Fn(&[u8]) -> bool + Send>>,
+    groups: HashMap<String, Group>,
+    /// Records ready to pass on, with the number of events each holds
+    ready: VecDeque<(Vec<u8>, usize)>,
+    pending_gauge: Option<QueueGauge>,
+    flush: Option<Delay>,
+    inner_done: bool
+}
+
+impl<S> ArrayBatcher<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, max_records: usize, max_wait: Duration) -> ArrayBatcher<S> {
+        ArrayBatcher {
+            inner: inner,
+            max_records: max_records.max(1),
+            max_wait: max_wait,
+            bypass: None,
+            groups: HashMap::new(),
+            ready: VecDeque::new(),
+            pending_gauge: None,
+            flush: None,
+            inner_done: false
+        }
+    }
+
+    /// Pass on the events `bypass` accepts without batching them, e.g. immediate records.
+    pub fn with_bypass<F>(mut self, bypass: F) -> Self
+        where F: Fn(&[u8]) -> bool + Send + 'static
+    {
+        self.bypass = Some(Box::new(bypass));
+        self
+    }
+
+    /// Tracks the number of events held, batched or not yet passed on.
+    pub fn with_pending_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.pending_gauge = Some(gauge);
+        self
+    }
+
+    fn add(&mut self, msg: Vec<u8>) {
+        if let Some(ref gauge) = self.pending_gauge {
+            gauge.add(1);
+        }
+        if self.bypass.as_ref().map(|b| b(&msg)).unwrap_or(false) {
+            self.ready.push_back( (msg, 1) );
+            return
+        }
+        let event_type = eve::event_type(&msg).unwrap_or("").to_string();
+        let full = {
+            let group = self.groups.entry(event_type.clone())
+                .or_insert_with(|| Group { records: vec![], started: Instant::now() });
+            group.records.push(msg);
+            group.records.len() >= self.max_records
+        };
+        if full {
+            if let Some(group) = self.groups.remove(&event_type) {
+                self.ready.push_back( (join(&group.records), group.records.len()) );
+            }
+        }
+    }
+
+    /// Moves batches that have waited `max_wait`, or all of them, to the ready queue.
+    fn flush_due(&mut self, all: bool) {
+        let now = Instant::now();
+        let max_wait = self.max_wait;
+        let due: Vec<String> = self.groups.iter()
+            .filter(|&(_, g)| all || now >= g.started + max_wait)
+            .map(|(t, _)| t.clone())
+            .collect();
+        for event_type in due {
+            if let Some(group) = self.groups.remove(&event_type) {
+                self.ready.push_back( (join(&group.records), group.records.len()) );
+            }
+        }
+    }
+
+    /// Polls the timer of the oldest batch, flushing whatever is due when it fires.
+    fn poll_flush(&mut self) {
+        loop {
+            let oldest = match self.groups.values().map(|g| g.started).min() {
+                Some(oldest) => oldest,
+                None => {
+                    self.flush = None;
+                    return
+                }
+            };
+            let mut flush = self.flush.take().unwrap_or_else(|| Delay::new(oldest + self.max_wait));
+            match flush.poll() {
+                Ok(Async::NotReady) => {
+                    self.flush = Some(flush);
+                    return
+                }
+                Ok(Async::Ready(())) => self.flush_due(false),
+                Err(e) => {
+                    error!("Batch timer failed: {:?}", e);
+                    self.flush_due(true);
+                }
+            }
+        }
+    }
+}
+
+impl<S> Stream for ArrayBatcher<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some( (batch, events) ) = self.ready.pop_front() {
+                if let Some(ref gauge) = self.pending_gauge {
+                    gauge.sub(events);
+                }
+                return Ok(Async::Ready(Some(batch)))
+            }
+            if self.inner_done {
+                return Ok(Async::Ready(None))
+            }
+            match self.inner.poll()? {
+                Async::Ready(Some(msg)) => self.add(msg),
+                Async::Ready(None) => {
+                    self.inner_done = true;
+                    self.flush = None;
+                    self.flush_due(true);
+                }
+                Async::NotReady => {
+                    self.poll_flush();
+                    if self.ready.is_empty() {
+                        return Ok(Async::NotReady)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Adds `batch.count`, `batch.first_timestamp`, and `batch.last_timestamp` headers to records
+/// that are JSON arrays, leaving other records as they are.
+pub struct BatchHeaders;
+
+impl HeaderGenerator for BatchHeaders {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        if !msg.starts_with(b"[") {
+            return vec![]
+        }
+        let events: Vec<Value> = match serde_json::from_slice(msg) {
+            Ok(events) => events,
+            Err(_) => return vec![]
+        };
+        let mut headers = vec![ (COUNT_HEADER.to_string(), events.len().to_string().into_bytes()) ];
+        let timestamps: Vec<&str> = events.iter().filter_map(|e| e.get("timestamp").and_then(|t| t.as_str())).collect();
+        if let (Some(first), Some(last)) = (timestamps.iter().min(), timestamps.iter().max()) {
+            headers.push( (FIRST_TIMESTAMP_HEADER.to_string(), first.as_bytes().to_vec()) );
+            headers.push( (LAST_TIMESTAMP_HEADER.to_string(), last.as_bytes().to_vec()) );
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::stream,
+        tokio
+    };
+
+    #[test]
+    fn batches_by_event_type() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let events = vec![
+            br#"{"timestamp":"2024-01-01T00:00:02","event_type":"flow"}"#.to_vec(),
+            br#"{"timestamp":"2024-01-01T00:00:01","event_type":"alert"}"#.to_vec(),
+            br#"{"timestamp":"2024-01-01T00:00:03","event_type":"flow"}"#.to_vec(),
+            br#"{"timestamp":"2024-01-01T00:00:04","event_type":"alert"}"#.to_vec(),
+            br#"{"timestamp":"2024-01-01T00:00:05","event_type":"alert"}"#.to_vec()
+        ];
+
+        let batcher = ArrayBatcher::new(stream::iter_ok::<_, ()>(events), 2, Duration::from_secs(60));
+        let batches = rt.block_on(batcher.collect()).expect("Stream failed");
+
+        assert_eq!(batches.len(), 3);
+        assert_eq!(eve::event_type(&batches[0]), Some("flow"));
+        assert_eq!(eve::event_type(&batches[1]), Some("alert"));
+        assert_eq!(batches[2], br#"[{"timestamp":"2024-01-01T00:00:05","event_type":"alert"}]"#.to_vec());
+
+        let headers = BatchHeaders.generate(&batches[1]);
+        assert_eq!(headers, vec![
+            (COUNT_HEADER.to_string(), b"2".to_vec()),
+            (FIRST_TIMESTAMP_HEADER.to_string(), b"2024-01-01T00:00:01".to_vec()),
+            (LAST_TIMESTAMP_HEADER.to_string(), b"2024-01-01T00:00:04".to_vec())
+        ]);
+        assert!(BatchHeaders.generate(&br#"{"event_type":"alert"}"#.to_vec()).is_empty());
+    }
+
+    #[test]
+    fn passes_bypassed_events_alone() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let events = vec![
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"alert","alert":{"severity":1}}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec()
+        ];
+
+        let pending = QueueGauge::new("batch.pending");
+        let batcher = ArrayBatcher::new(stream::iter_ok::<_, ()>(events), 10, Duration::from_secs(60))
+            .with_pending_gauge(pending.clone())
+            .with_bypass(|e| eve::event_type(e) == Some("alert"));
+        let batches = rt.block_on(batcher.collect()).expect("Stream failed");
+
+        assert_eq!(pending.depth(), 0);
+        assert_eq!(pending.high_watermark(), 2);
+
+        assert_eq!(batches, vec![
+            br#"{"event_type":"alert","alert":{"severity":1}}"#.to_vec(),
+            br#"[{"event_type":"flow"},{"event_type":"flow"}]"#.to_vec()
+        ]);
+    }
+}
@@ -0,0 +1,222 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    metrics::{
+        Counter,
+        Registry
+    },
+    futures::{
+        Stream,
+        sync::mpsc::{
+            self,
+            Sender
+        }
+    },
+    pipeline::{
+        Pipeline,
+        Settings
+    }
+};
+use std::{
+    self,
+    sync::{
+        Mutex,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    thread::JoinHandle,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const FLUSH_POLL_MS: u64 = 10;
+const DEFAULT_CAPACITY: usize = 10_000;
+const DEFAULT_SEND_TIMEOUT_MS: u64 = 1_000;
+
+/// Synchronous facade over `Pipeline` for small tools that don't want to deal with futures.
+pub struct SyncShipper {
+    settings: Settings,
+    capacity: usize,
+    send_timeout: Duration
+}
+
+impl SyncShipper {
+    pub fn new(settings: Settings) -> SyncShipper {
+        SyncShipper {
+            settings: settings,
+            capacity: DEFAULT_CAPACITY,
+            send_timeout: Duration::from_millis(DEFAULT_SEND_TIMEOUT_MS)
+        }
+    }
+
+    /// Records `ShipperHandle::send` queues for the pipeline before it has to wait.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Longest `ShipperHandle::send` waits for room in a full queue before failing.
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    /// Ships the configured file or socket, blocking until the source ends.
+    pub fn run(self) -> Result<(), Error> {
+        Pipeline::new(self.settings).run_blocking()
+    }
+
+    /// Starts a pipeline on a background thread that ships records passed to
+    /// `ShipperHandle::send`.
+    pub fn start(self) -> Result<ShipperHandle, Error> {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let source = receiver.map_err(|_| Error::from_kind(ErrorKind::ReceiverError));
+        let registry = Registry::default();
+        let pipeline = Pipeline::new(self.settings)
+            .with_source(source)
+            .with_registry(registry.clone());
+
+        let thread = std::thread::Builder::new()
+            .name("surikafka-shipper".to_string())
+            .spawn(move || pipeline.run_blocking())?;
+
+        Ok(ShipperHandle {
+            sender: Some(Mutex::new(sender)),
+            send_timeout: self.send_timeout,
+            thread: Some(thread),
+            sent: AtomicUsize::new(0),
+            delivered: registry.counter("writer.delivered"),
+            failed: registry.counter("writer.failed")
+        })
+    }
+}
+
+/// Handle to a pipeline started by `SyncShipper::start`. Dropping it closes the pipeline.
+pub struct ShipperHandle {
+    sender: Option<Mutex<Sender<Vec<u8>>>>,
+    send_timeout: Duration,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+    sent: AtomicUsize,
+    delivered: Counter,
+    failed: Counter
+}
+
+impl ShipperHandle {
+    /// Queues `record` for delivery, waiting up to the send timeout while the queue is full.
+    /// Fails if the pipeline has stopped or the queue stays full.
+    pub fn send<R: Into<Vec<u8>>>(&self, record: R) -> Result<(), Error> {
+        let sender = match self.sender {
+            Some(ref sender) => sender,
+            None => bail!("Pipeline closed")
+        };
+        let deadline = Instant::now() + self.send_timeout;
+        let mut record = record.into();
+        loop {
+            let res = sender.lock()
+                .map_err(|_| Error::from("Shipper lock poisoned".to_string()))?
+                .try_send(record);
+            match res {
+                Ok(()) => break,
+                Err(ref e) if e.is_disconnected() => bail!("Pipeline stopped"),
+                Err(e) => {
+                    if Instant::now() >= deadline {
+                        bail!("Shipper queue full");
+                    }
+                    record = e.into_inner();
+                    std::thread::sleep(Duration::from_millis(FLUSH_POLL_MS));
+                }
+            }
+        }
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        Ok( () )
+    }
+
+    /// Records sent but not yet delivered or failed.
+    pub fn pending(&self) -> usize {
+        let completed = self.delivered.value() + self.failed.value();
+        self.sent.load(Ordering::SeqCst).saturating_sub(completed)
+    }
+
+    /// Waits up to `timeout` for every record sent so far to be delivered or to fail, returning
+    /// whether they all completed.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.pending() > 0 {
+            if Instant::now() >= deadline {
+                return false
+            }
+            std::thread::sleep(Duration::from_millis(FLUSH_POLL_MS));
+        }
+        true
+    }
+
+    /// Records that failed delivery so far.
+    pub fn failed(&self) -> usize {
+        self.failed.value()
+    }
+
+    /// Stops accepting records, waits for queued records to be delivered, and returns the
+    /// pipeline's result.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.sender.take();
+        match self.thread.take() {
+            Some(thread) => thread.join()
+                .map_err(|_| Error::from("Pipeline thread panicked".to_string()))?,
+            None => Ok( () )
+        }
+    }
+}
+
+impl Drop for ShipperHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("Pipeline failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_fails_once_stopped() {
+        let (sender, receiver) = mpsc::channel(1);
+        let handle = ShipperHandle {
+            sender: Some(Mutex::new(sender)),
+            send_timeout: Duration::from_millis(20),
+            thread: None,
+            sent: AtomicUsize::new(0),
+            delivered: Counter::new("writer.delivered"),
+            failed: Counter::new("writer.failed")
+        };
+
+        assert!(handle.send("{}").is_ok());
+        assert_eq!(handle.pending(), 1);
+        assert!(!handle.flush(Duration::from_millis(20)));
+
+        handle.delivered.incr();
+
+        assert!(handle.flush(Duration::from_millis(20)));
+
+        // One slot of the buffer and one of the sender
+        assert!(handle.send("{}").is_ok());
+        let full = handle.send("{}").expect_err("Queued past capacity");
+        assert_eq!(full.to_string(), "Shipper queue full");
+
+        drop(receiver);
+
+        assert!(handle.send("{}").is_err());
+        assert!(handle.close().is_ok());
+    }
+}
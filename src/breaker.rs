@@ -0,0 +1,226 @@
+use super::journal::{
+    OpsJournal,
+    OpsKind
+};
+use std::{
+    self,
+    collections::VecDeque,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen
+}
+
+impl Default for BreakerState {
+    fn default() -> Self { BreakerState::Closed }
+}
+
+impl std::fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match *self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Number of most recent delivery results considered
+    pub window: usize,
+    /// Minimum results in the window before the breaker may open
+    pub min_samples: usize,
+    /// Fraction of failed deliveries in the window that opens the breaker
+    pub max_error_rate: f64,
+    /// Time to stop producing once the breaker opens
+    pub cooldown: Duration
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            window: 100,
+            min_samples: 20,
+            max_error_rate: 0.5,
+            cooldown: Duration::from_secs(30)
+        }
+    }
+}
+
+/// Stops produce attempts after too many delivery failures, so a broken cluster is not hammered
+/// with requests that will only time out. After the cooldown a single trial produce is allowed;
+/// success closes the breaker, failure opens it for another cooldown.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: BreakerState,
+    open_until: Option<Instant>,
+    results: VecDeque<bool>,
+    transitions: usize,
+    journal: Option<OpsJournal>
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            results: VecDeque::with_capacity(config.window),
+            config: config,
+            state: BreakerState::Closed,
+            open_until: None,
+            transitions: 0,
+            journal: None
+        }
+    }
+
+    /// Records state changes in the ops journal.
+    pub fn with_journal(mut self, journal: OpsJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn state(&self) -> BreakerState { self.state }
+    pub fn transitions(&self) -> usize { self.transitions }
+
+    fn transition(&mut self, state: BreakerState) {
+        if self.state != state {
+            warn!("Circuit breaker {} -> {}", self.state, state);
+            if let Some(ref journal) = self.journal {
+                journal.record(OpsKind::CircuitBreaker, json!({
+                    "from": self.state.to_string(),
+                    "to": state.to_string(),
+                    "error_rate": self.error_rate()
+                }));
+            }
+            self.state = state;
+            self.transitions += 1;
+        }
+    }
+
+    /// Returns the instant produces may resume if the breaker is currently open.
+    pub fn blocked_until(&mut self, now: Instant) -> Option<Instant> {
+        match (self.state, self.open_until) {
+            (BreakerState::Open, Some(until)) if now < until => Some(until),
+            (BreakerState::Open, _) => {
+                self.transition(BreakerState::HalfOpen);
+                None
+            }
+            _ => None
+        }
+    }
+
+    pub fn record(&mut self, success: bool, now: Instant) {
+        if self.state == BreakerState::HalfOpen {
+            if success {
+                self.results.clear();
+                self.transition(BreakerState::Closed);
+            } else {
+                self.open(now);
+            }
+            return;
+        }
+
+        if self.results.len() == self.config.window {
+            self.results.pop_front();
+        }
+        self.results.push_back(success);
+
+        if self.results.len() >= self.config.min_samples && self.error_rate() > self.config.max_error_rate {
+            self.open(now);
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.open_until = Some(now + self.config.cooldown);
+        self.results.clear();
+        self.transition(BreakerState::Open);
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.results.is_empty() {
+            0.0
+        } else {
+            let failures = self.results.iter().filter(|s| !**s).count();
+            failures as f64 / self.results.len() as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BreakerConfig {
+        BreakerConfig {
+            window: 10,
+            min_samples: 4,
+            max_error_rate: 0.5,
+            cooldown: Duration::from_secs(10)
+        }
+    }
+
+    #[test]
+    fn opens_after_error_rate() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(config());
+
+        breaker.record(false, now);
+        breaker.record(false, now);
+        breaker.record(false, now);
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        breaker.record(false, now);
+
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.blocked_until(now), Some(now + Duration::from_secs(10)));
+        assert_eq!(breaker.transitions(), 1);
+    }
+
+    #[test]
+    fn stays_closed_below_rate() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(config());
+
+        for i in 0..20 {
+            breaker.record(i % 3 != 0, now);
+        }
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.blocked_until(now), None);
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(11);
+        let mut breaker = CircuitBreaker::new(config());
+
+        for _ in 0..4 {
+            breaker.record(false, now);
+        }
+
+        assert_eq!(breaker.blocked_until(later), None);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        breaker.record(false, later);
+
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let after_retry = later + Duration::from_secs(11);
+
+        assert_eq!(breaker.blocked_until(after_retry), None);
+
+        breaker.record(true, after_retry);
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.transitions(), 5);
+    }
+}
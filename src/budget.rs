@@ -0,0 +1,91 @@
+use super::{
+    futures::{
+        Async,
+        Poll,
+        Stream,
+        task
+    },
+    metrics::Counter
+};
+
+/// Default number of events taken from a source before yielding to other tasks.
+pub const DEFAULT_POLL_BUDGET: usize = 1024;
+
+/// Yields to the executor after every `budget` items. A backlogged file is always ready, so
+/// without it the task draining it only returns once the writer's window is full, and with a
+/// wide window or filters dropping most events that can be long enough to starve other tasks on
+/// the same executor, such as the admin API and metrics server. Yielding wakes the task again
+/// straight away, so it only gives others their turn.
+pub struct Budgeted<S> {
+    inner: S,
+    budget: usize,
+    polled: usize,
+    yields: Option<Counter>
+}
+
+impl<S: Stream> Budgeted<S> {
+    pub fn new(inner: S, budget: usize) -> Budgeted<S> {
+        Budgeted {
+            inner: inner,
+            budget: budget.max(1),
+            polled: 0,
+            yields: None
+        }
+    }
+
+    /// Counts the times the budget ran out.
+    pub fn with_yield_counter(mut self, counter: Counter) -> Self {
+        self.yields = Some(counter);
+        self
+    }
+}
+
+impl<S: Stream> Stream for Budgeted<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if self.polled >= self.budget {
+            self.polled = 0;
+            if let Some(ref counter) = self.yields {
+                counter.incr();
+            }
+            task::current().notify();
+            return Ok(Async::NotReady)
+        }
+        match self.inner.poll()? {
+            Async::Ready(Some(item)) => {
+                self.polled += 1;
+                Ok(Async::Ready(Some(item)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                // The task is giving way anyway
+                self.polled = 0;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::stream,
+        tokio
+    };
+
+    #[test]
+    fn yields_between_budgets() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let yields = Counter::new("reader.yields");
+
+        let budgeted = Budgeted::new(stream::iter_ok::<_, ()>(0..7), 3)
+            .with_yield_counter(yields.clone());
+        let out = rt.block_on(budgeted.collect()).expect("Stream failed");
+
+        assert_eq!(out, (0..7).collect::<Vec<_>>());
+        assert_eq!(yields.value(), 2);
+    }
+}
@@ -0,0 +1,195 @@
+use super::{
+    chrono::Utc,
+    errors::{
+        Error,
+        ErrorKind
+    },
+    futures::Future,
+    rdkafka::{
+        ClientConfig,
+        ClientContext,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        },
+        producer::{
+            FutureProducer,
+            FutureRecord
+        }
+    },
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    self,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const POLL_TIMEOUT_MS: i32 = 500;
+
+/// How the startup self-test confirms a canary reached its topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelfTestMode {
+    /// A successful delivery report, which proves write access and a partition leader
+    Delivery,
+    /// Reading the canary back at its offset, which also proves read access
+    RoundTrip
+}
+
+impl std::str::FromStr for SelfTestMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SelfTestMode, Error> {
+        match s {
+            "delivery" => Ok(SelfTestMode::Delivery),
+            "round-trip" => Ok(SelfTestMode::RoundTrip),
+            _ => Err(Error::from_kind(ErrorKind::InvalidSelfTestMode(s.to_string())))
+        }
+    }
+}
+
+/// A `surikafka_canary` record for `topic`, told apart from earlier runs' canaries by `nonce`.
+/// Consumers of the event topics should skip this event type.
+pub fn canary(sensor_id: &str, topic: &str, nonce: &str) -> Vec<u8> {
+    let event = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "event_type": "surikafka_canary",
+        "sensor_id": sensor_id,
+        "topic": topic,
+        "nonce": nonce
+    });
+    serde_json::to_vec(&event).expect("Canary is always serializable")
+}
+
+/// Whether `payload` is the canary of this run.
+pub fn is_canary(payload: &[u8], nonce: &str) -> bool {
+    serde_json::from_slice::<Value>(payload).ok()
+        .and_then(|v| v.get("nonce").and_then(|n| n.as_str()).map(|n| n == nonce))
+        .unwrap_or(false)
+}
+
+/// Produces a canary to every topic the pipeline writes to before it starts reading, failing
+/// startup when one can't be delivered (or read back), so missing ACLs, topics, or partition
+/// leaders show up at boot rather than as delivery errors once events flow.
+pub struct SelfTest {
+    client: ClientConfig,
+    mode: SelfTestMode,
+    timeout: Duration,
+    sensor_id: String,
+    nonce: String
+}
+
+impl SelfTest {
+    /// `client` carries the connection settings shared with the producer.
+    pub fn new(client: &ClientConfig, sensor_id: &str) -> SelfTest {
+        SelfTest {
+            client: client.clone(),
+            mode: SelfTestMode::Delivery,
+            timeout: Duration::from_secs(10),
+            sensor_id: sensor_id.to_string(),
+            nonce: format!("{}-{}", std::process::id(), Utc::now().timestamp_nanos())
+        }
+    }
+
+    pub fn with_mode(mut self, mode: SelfTestMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How long each topic's delivery report, and read back, may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Checks every topic in turn, returning the first failure.
+    pub fn run<C>(&self, producer: &FutureProducer<C>, topics: &[String]) -> Result<(), Error>
+        where C: ClientContext + 'static
+    {
+        let consumer: Option<BaseConsumer> = match self.mode {
+            SelfTestMode::Delivery => None,
+            SelfTestMode::RoundTrip => Some(self.client.clone()
+                .set("group.id", "surikafka-selftest")
+                .set("enable.auto.commit", "false")
+                .create()
+                .map_err(|e| Error::from(format!("Failed to create self-test consumer: {:?}", e)))?)
+        };
+        for topic in topics {
+            let (partition, offset) = self.deliver(producer, topic)?;
+            if let Some(ref consumer) = consumer {
+                self.read_back(consumer, topic, partition, offset)?;
+            }
+            info!("Self-test of {} passed at partition {}, offset {}", topic, partition, offset);
+        }
+        Ok( () )
+    }
+
+    fn deliver<C>(&self, producer: &FutureProducer<C>, topic: &str) -> Result<(i32, i64), Error>
+        where C: ClientContext + 'static
+    {
+        let payload = canary(&self.sensor_id, topic, &self.nonce);
+        let timeout_ms = (self.timeout.as_secs() * 1000 + self.timeout.subsec_nanos() as u64 / 1000000) as i64;
+        let record: FutureRecord<str, Vec<u8>> = FutureRecord::to(topic)
+            .key(self.sensor_id.as_str())
+            .payload(&payload);
+        match producer.send(record, timeout_ms).wait() {
+            Ok(Ok(delivered)) => Ok(delivered),
+            Ok(Err( (e, _) )) => bail!("Self-test failed to produce to {}: {:?}", topic, e),
+            Err(_) => bail!("Self-test delivery to {} canceled", topic)
+        }
+    }
+
+    fn read_back(&self, consumer: &BaseConsumer, topic: &str, partition: i32, offset: i64) -> Result<(), Error> {
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(topic, partition, Offset::Offset(offset));
+        consumer.assign(&assignment)
+            .map_err(|e| Error::from(format!("Failed to assign partition: {:?}", e)))?;
+        let deadline = Instant::now() + self.timeout;
+        while Instant::now() < deadline {
+            match consumer.poll(POLL_TIMEOUT_MS) {
+                None => continue,
+                Some(Err(e)) => bail!("Self-test failed to consume {}/{}: {:?}", topic, partition, e),
+                Some(Ok(ref m)) if m.offset() == offset => {
+                    if is_canary(m.payload().unwrap_or(&[]), &self.nonce) {
+                        return Ok( () )
+                    }
+                    bail!("Self-test read back a different record at {}/{}/{}", topic, partition, offset)
+                }
+                Some(Ok(_)) => continue
+            }
+        }
+        bail!("Self-test timed out reading back {}/{}/{}", topic, partition, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!("delivery".parse::<SelfTestMode>().expect("Failed to parse"), SelfTestMode::Delivery);
+        assert_eq!("round-trip".parse::<SelfTestMode>().expect("Failed to parse"), SelfTestMode::RoundTrip);
+        assert!("consume".parse::<SelfTestMode>().is_err());
+    }
+
+    #[test]
+    fn recognizes_own_canary() {
+        let payload = canary("sensor-1", "eve-alerts", "42-1");
+        let event: Value = serde_json::from_slice(&payload).expect("Failed to parse");
+
+        assert_eq!(event["event_type"], "surikafka_canary");
+        assert_eq!(event["topic"], "eve-alerts");
+        assert!(is_canary(&payload, "42-1"));
+        assert!(!is_canary(&payload, "41-9"));
+        assert!(!is_canary(br#"{"event_type":"alert"}"#, "42-1"));
+    }
+}
@@ -0,0 +1,169 @@
+use super::futures::{
+    Async,
+    Future,
+    Poll,
+    Stream,
+    task::{
+        self,
+        Task
+    }
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    atomic::{
+        AtomicBool,
+        Ordering
+    }
+};
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    waiters: Mutex<Vec<Task>>
+}
+
+/// Shared flag a host application uses to stop a pipeline. Cancelling ends the source stream;
+/// records already read still pass through the stages and are delivered before the pipeline
+/// future resolves.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let waiters: Vec<Task> = self.inner.waiters.lock().expect("Cancellation lock poisoned").drain(..).collect();
+        for waiter in waiters {
+            waiter.notify();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Ready once cancelled, otherwise registers the current task to be notified on cancellation.
+    pub fn poll_cancelled(&self) -> Async<()> {
+        if self.is_cancelled() {
+            return Async::Ready(())
+        }
+        {
+            let mut waiters = self.inner.waiters.lock().expect("Cancellation lock poisoned");
+            if !waiters.iter().any(|t| t.will_notify_current()) {
+                waiters.push(task::current());
+            }
+        }
+        if self.is_cancelled() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    /// Future resolving when the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            token: self.clone()
+        }
+    }
+}
+
+pub struct Cancelled {
+    token: CancellationToken
+}
+
+impl Future for Cancelled {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.token.poll_cancelled())
+    }
+}
+
+/// Ends the inner stream once the token is cancelled.
+pub struct UntilCancelled<S> {
+    inner: S,
+    token: CancellationToken,
+    done: bool
+}
+
+impl<S: Stream> Stream for UntilCancelled<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None))
+        }
+        if let Async::Ready(()) = self.token.poll_cancelled() {
+            info!("Cancelled, ending stream");
+            self.done = true;
+            return Ok(Async::Ready(None))
+        }
+        self.inner.poll()
+    }
+}
+
+pub trait WithCancellation: Stream + Sized {
+    fn until_cancelled(self, token: CancellationToken) -> UntilCancelled<Self> {
+        UntilCancelled {
+            inner: self,
+            token: token,
+            done: false
+        }
+    }
+}
+
+impl<S: Stream> WithCancellation for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::sync::mpsc,
+        tokio
+    };
+    use std;
+
+    #[test]
+    fn ends_stream_on_cancel() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+
+        let (sender, receiver) = mpsc::unbounded::<u32>();
+        let token = CancellationToken::new();
+
+        sender.unbounded_send(1).expect("Failed to send");
+
+        let canceller = token.clone();
+        let stream = receiver
+            .until_cancelled(token.clone())
+            .inspect(move |_| canceller.cancel());
+
+        assert_eq!(rt.block_on(stream.collect()).expect("Failed to collect"), vec![1]);
+        assert!(token.is_cancelled());
+        drop(sender);
+    }
+
+    #[test]
+    fn notifies_waiting_futures() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let token = CancellationToken::new();
+
+        let canceller = token.clone();
+        let cancel = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            canceller.cancel();
+        });
+
+        assert_eq!(rt.block_on(token.cancelled()), Ok(()));
+
+        cancel.join().expect("Failed to cancel");
+    }
+}
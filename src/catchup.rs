@@ -0,0 +1,342 @@
+use super::{
+    cancel::CancellationToken,
+    checkpoint::CheckpointStore,
+    errors::Error,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    metrics::{
+        Counter,
+        Registry
+    },
+    runtime::Delay
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Where a file resumed with a backlog is read from: the backlog between the checkpoint and
+/// where the file ended at startup, and the live events tailed from there. A backlog stopped by
+/// cancellation is neither read further nor done with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reading {
+    Both,
+    LiveOnly,
+    Stopped
+}
+
+fn split_source(path: &str) -> String {
+    format!("{}.catch-up-split", path)
+}
+
+fn live_source(path: &str) -> String {
+    format!("{}.catch-up-live", path)
+}
+
+/// Where the backlog of a catch up ends and its live reader is, saved with the file's checkpoint
+/// so a restart part way through resumes both instead of reading the live events again.
+#[derive(Debug, Clone)]
+pub struct CatchUpPositions {
+    /// Where the file ended when the catch up started, zero once the backlog is done with
+    split: Arc<AtomicUsize>,
+    live: Arc<AtomicUsize>
+}
+
+impl CatchUpPositions {
+    /// The split and live positions of `path` saved by a catch up stopped part way, if any.
+    pub fn load<C: CheckpointStore + ?Sized>(store: &C, path: &str) -> Result<Option<(u64, u64)>, Error> {
+        match (store.load(&split_source(path))?, store.load(&live_source(path))?) {
+            (Some(split), Some(live)) if split > 0 => Ok(Some( (split, live) )),
+            _ => Ok(None)
+        }
+    }
+
+    pub fn save<C: CheckpointStore + ?Sized>(&self, store: &C, path: &str) -> Result<(), Error> {
+        store.save(&split_source(path), self.split.load(Ordering::SeqCst) as u64)?;
+        store.save(&live_source(path), self.live.load(Ordering::SeqCst) as u64)
+    }
+}
+
+/// Reads the backlog of a file resumed far behind its end alongside the live events tailed from
+/// where it ended at startup, so fresh alerts go out promptly rather than after the whole
+/// history. Live events always come first; backlog events are taken up to `rate` a second, and
+/// the backlog is abandoned after `max_duration`, if given. Events are counted in
+/// `reader.backlog_events` and `reader.live_events`.
+///
+/// The checkpointed position stays at the backlog's until it's drained, then follows the live
+/// reader's; until then, `positions` has where the backlog ends and where the live reader is, so
+/// a restart resumes both. If the live reader starts over on a rotated or truncated file first,
+/// the checkpoint follows it straight away, since the backlog belongs to a file that's gone.
+pub struct CatchUp<B, L> {
+    backlog: B,
+    live: L,
+    reading: Reading,
+    live_done: bool,
+    checkpointed: Arc<AtomicUsize>,
+    live_position: Arc<AtomicUsize>,
+    split: usize,
+    positions: CatchUpPositions,
+    cancellation: Option<CancellationToken>,
+    rate: Option<f64>,
+    tokens: f64,
+    refilled: Instant,
+    pacing: Option<Delay>,
+    deadline: Option<Instant>,
+    backlog_events: Counter,
+    live_events: Counter,
+    abandoned_bytes: Counter
+}
+
+impl<B, L> CatchUp<B, L>
+    where B: Stream<Item=Vec<u8>, Error=Error>,
+          L: Stream<Item=Vec<u8>, Error=Error>
+{
+    /// `checkpointed` is the position the backlog reader advances up to `split`, where the file
+    /// ended when the catch up started, and `live_position` the one the live reader advances from
+    /// `split` or where a previous run's live reader stopped.
+    pub fn new(backlog: B, live: L, checkpointed: Arc<AtomicUsize>, live_position: Arc<AtomicUsize>, split: usize, registry: &Registry) -> CatchUp<B, L> {
+        CatchUp {
+            backlog: backlog,
+            live: live,
+            reading: Reading::Both,
+            live_done: false,
+            checkpointed: checkpointed,
+            positions: CatchUpPositions {
+                split: Arc::new(AtomicUsize::new(split)),
+                live: live_position.clone()
+            },
+            live_position: live_position,
+            split: split,
+            cancellation: None,
+            rate: None,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            pacing: None,
+            deadline: None,
+            backlog_events: registry.counter("reader.backlog_events"),
+            live_events: registry.counter("reader.live_events"),
+            abandoned_bytes: registry.counter("reader.backlog_abandoned_bytes")
+        }
+    }
+
+    /// Take at most `rate` backlog events a second, in bursts of up to a second's worth.
+    pub fn with_rate(mut self, rate: f64) -> Self {
+        self.rate = Some(rate.max(0.001));
+        self.tokens = rate.max(1.0);
+        self
+    }
+
+    /// Abandon the rest of the backlog once it's been read for `max_duration`, counting its
+    /// bytes in `reader.backlog_abandoned_bytes`.
+    pub fn with_max_duration(mut self, max_duration: Duration) -> Self {
+        self.deadline = Some(Instant::now() + max_duration);
+        self
+    }
+
+    /// Readers end on `token` once they've passed on what they parsed; a backlog ended by it is
+    /// left to be resumed rather than done with.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Where the backlog ends and the live reader is, to save with the checkpoint.
+    pub fn positions(&self) -> CatchUpPositions {
+        self.positions.clone()
+    }
+
+    /// Moves the checkpoint to the live reader's position once the backlog is done with, or
+    /// once the live reader has started over.
+    fn sync_checkpoint(&self) {
+        let live = self.live_position.load(Ordering::SeqCst);
+        if self.reading == Reading::LiveOnly || live < self.split {
+            self.checkpointed.store(live, Ordering::SeqCst);
+            self.positions.split.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn finish_backlog(&mut self) {
+        self.reading = Reading::LiveOnly;
+        self.pacing = None;
+        self.sync_checkpoint();
+    }
+
+    /// Whether a backlog event may be taken now, scheduling a wake up for when one may if not.
+    fn poll_token(&mut self) -> bool {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return true
+        };
+        loop {
+            let now = Instant::now();
+            let elapsed = now - self.refilled;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                self.pacing = None;
+                return true
+            }
+            let wait = Duration::from_millis((((1.0 - self.tokens) / rate) * 1000.0).ceil() as u64);
+            let mut pacing = self.pacing.take().unwrap_or_else(|| Delay::new(now + wait));
+            match pacing.poll() {
+                Ok(Async::NotReady) => {
+                    self.pacing = Some(pacing);
+                    return false
+                }
+                Ok(Async::Ready(())) => continue,
+                Err(e) => {
+                    error!("Catch up timer failed: {:?}", e);
+                    return true
+                }
+            }
+        }
+    }
+}
+
+impl<B, L> Stream for CatchUp<B, L>
+    where B: Stream<Item=Vec<u8>, Error=Error>,
+          L: Stream<Item=Vec<u8>, Error=Error>
+{
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.live_done {
+            match self.live.poll()? {
+                Async::Ready(Some(msg)) => {
+                    self.live_events.incr();
+                    self.sync_checkpoint();
+                    return Ok(Async::Ready(Some(msg)))
+                }
+                Async::Ready(None) => self.live_done = true,
+                Async::NotReady => ()
+            }
+        }
+        if self.reading == Reading::Both {
+            if self.deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                let remaining = self.split.saturating_sub(self.checkpointed.load(Ordering::SeqCst));
+                warn!("Abandoning {} bytes of backlog not caught up on in time", remaining);
+                self.abandoned_bytes.add(remaining);
+                self.finish_backlog();
+            } else if self.poll_token() {
+                match self.backlog.poll()? {
+                    Async::Ready(Some(msg)) => {
+                        self.tokens -= 1.0;
+                        self.backlog_events.incr();
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                    Async::Ready(None) if self.cancellation.as_ref().map(|c| c.is_cancelled()).unwrap_or(false) => {
+                        info!("Stopped catching up on the backlog after {} events", self.backlog_events.value());
+                        self.reading = Reading::Stopped;
+                    }
+                    Async::Ready(None) => {
+                        info!("Caught up on the backlog after {} events", self.backlog_events.value());
+                        self.finish_backlog();
+                    }
+                    Async::NotReady => ()
+                }
+            }
+        }
+        if self.live_done && self.reading != Reading::Both {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        checkpoint::FileCheckpointStore,
+        futures::{
+            stream,
+            sync::mpsc
+        },
+        tokio
+    };
+    use std;
+
+    #[test]
+    fn puts_live_events_first_and_moves_checkpoint_once_caught_up() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let registry = Registry::default();
+        let checkpointed = Arc::new(AtomicUsize::new(100));
+        let live_position = Arc::new(AtomicUsize::new(1000));
+        let backlog = stream::iter_ok::<_, Error>(vec![b"old1".to_vec(), b"old2".to_vec()]);
+        let (sender, receiver) = mpsc::unbounded();
+        sender.unbounded_send(b"new1".to_vec()).expect("Failed to send");
+        drop(sender);
+        let live = receiver.map_err(|_| Error::from("Live reader failed"));
+
+        let catch_up = CatchUp::new(backlog, live, checkpointed.clone(), live_position.clone(), 1000, &registry);
+        let positions = catch_up.positions();
+        let events = rt.block_on(catch_up.collect()).expect("Stream failed");
+
+        assert_eq!(events, vec![b"new1".to_vec(), b"old1".to_vec(), b"old2".to_vec()]);
+        assert_eq!(checkpointed.load(Ordering::SeqCst), 1000);
+        assert_eq!(positions.split.load(Ordering::SeqCst), 0);
+        assert_eq!(registry.counter("reader.backlog_events").value(), 2);
+        assert_eq!(registry.counter("reader.live_events").value(), 1);
+    }
+
+    #[test]
+    fn abandons_backlog_after_max_duration() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let registry = Registry::default();
+        let checkpointed = Arc::new(AtomicUsize::new(100));
+        let live_position = Arc::new(AtomicUsize::new(1000));
+        let backlog = stream::iter_ok::<_, Error>(vec![b"old1".to_vec()]);
+        let live = stream::empty::<Vec<u8>, Error>();
+
+        let catch_up = CatchUp::new(backlog, live, checkpointed.clone(), live_position, 1000, &registry)
+            .with_max_duration(Duration::from_secs(0));
+        let events = rt.block_on(catch_up.collect()).expect("Stream failed");
+
+        assert!(events.is_empty());
+        assert_eq!(checkpointed.load(Ordering::SeqCst), 1000);
+        assert_eq!(registry.counter("reader.backlog_abandoned_bytes").value(), 900);
+    }
+
+    #[test]
+    fn keeps_backlog_and_live_positions_when_cancelled() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let registry = Registry::default();
+        let checkpointed = Arc::new(AtomicUsize::new(100));
+        let live_position = Arc::new(AtomicUsize::new(1200));
+        let token = CancellationToken::new();
+        token.cancel();
+        let backlog = stream::empty::<Vec<u8>, Error>();
+        let live = stream::empty::<Vec<u8>, Error>();
+
+        let catch_up = CatchUp::new(backlog, live, checkpointed.clone(), live_position, 1000, &registry)
+            .with_cancellation(token);
+        let positions = catch_up.positions();
+        let events = rt.block_on(catch_up.collect()).expect("Stream failed");
+
+        assert!(events.is_empty());
+        assert_eq!(checkpointed.load(Ordering::SeqCst), 100);
+
+        let dir = std::env::temp_dir().join(format!("surikafka-catchup-{}", std::process::id()));
+        let store = FileCheckpointStore::new(&dir);
+        assert_eq!(CatchUpPositions::load(&store, "eve.json").expect("Failed to load"), None);
+        positions.save(&store, "eve.json").expect("Failed to save");
+        assert_eq!(CatchUpPositions::load(&store, "eve.json").expect("Failed to load"), Some( (1000, 1200) ));
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
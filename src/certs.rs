@@ -0,0 +1,153 @@
+use super::{
+    derive::Derivation,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Certificate seen in a `tls` event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertObservation {
+    pub fingerprint: String,
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub serial: Option<String>,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+    pub ja3s: Option<String>
+}
+
+impl CertObservation {
+    /// Certificate metadata of a `tls` event; `None` for sessions without a certificate, such as
+    /// resumed sessions.
+    pub fn from_event(event: &Value) -> Option<CertObservation> {
+        let tls = event.get("tls")?;
+        let text = |name: &str| tls.get(name).and_then(Value::as_str).map(|s| s.to_string());
+        Some(CertObservation {
+            fingerprint: text("fingerprint")?,
+            subject: text("subject"),
+            issuer: text("issuerdn"),
+            serial: text("serial"),
+            not_before: text("notbefore"),
+            not_after: text("notafter"),
+            ja3s: tls.pointer("/ja3s/hash").and_then(Value::as_str).map(|s| s.to_string())
+        })
+    }
+
+    pub fn to_record(&self, timestamp: Option<&Value>) -> Vec<u8> {
+        let record = json!({
+            "timestamp": timestamp,
+            "fingerprint": self.fingerprint,
+            "subject": self.subject,
+            "issuer": self.issuer,
+            "serial": self.serial,
+            "notbefore": self.not_before,
+            "notafter": self.not_after,
+            "ja3s": self.ja3s
+        });
+        serde_json::to_vec(&record).expect("Record is always serializable")
+    }
+}
+
+/// Derives a certificate observation from `tls` events for a topic keyed by fingerprint,
+/// reporting each certificate at most once per window.
+pub struct CertObservations {
+    window: Duration,
+    seen: HashMap<String, Instant>,
+    last_prune: Option<Instant>
+}
+
+impl CertObservations {
+    pub fn new(window: Duration) -> CertObservations {
+        CertObservations {
+            window: window,
+            seen: HashMap::new(),
+            last_prune: None
+        }
+    }
+
+    /// Forgets certificates last reported a window ago, at most once per window.
+    fn prune(&mut self, now: Instant) {
+        match self.last_prune {
+            Some(last) if now < last + self.window => return,
+            _ => ()
+        }
+        let window = self.window;
+        self.seen.retain(|_, reported| now < *reported + window);
+        self.last_prune = Some(now);
+    }
+
+    pub fn derive_at(&mut self, event: &Value, now: Instant) -> Vec<Vec<u8>> {
+        self.prune(now);
+        let observation = match CertObservation::from_event(event) {
+            Some(o) => o,
+            None => return vec![]
+        };
+        if let Some(reported) = self.seen.get(&observation.fingerprint) {
+            if now < *reported + self.window {
+                return vec![]
+            }
+        }
+        self.seen.insert(observation.fingerprint.clone(), now);
+        vec![ observation.to_record(event.get("timestamp")) ]
+    }
+}
+
+impl Derivation for CertObservations {
+    fn event_type(&self) -> &str {
+        "tls"
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        self.derive_at(event, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls(fingerprint: &str) -> Value {
+        json!({
+            "event_type": "tls",
+            "timestamp": "2018-06-01T00:00:00.000000+0000",
+            "tls": {
+                "subject": "CN=example.com",
+                "issuerdn": "CN=Example CA",
+                "serial": "01:02:03",
+                "fingerprint": fingerprint,
+                "notbefore": "2018-01-01T00:00:00",
+                "notafter": "2019-01-01T00:00:00",
+                "ja3s": {"hash": "abc", "string": "771,49199,"}
+            }
+        })
+    }
+
+    #[test]
+    fn extracts_certificates() {
+        let observation = CertObservation::from_event(&tls("aa:bb")).expect("No certificate");
+
+        assert_eq!(observation.issuer, Some("CN=Example CA".to_string()));
+        assert_eq!(observation.ja3s, Some("abc".to_string()));
+        assert_eq!(CertObservation::from_event(&json!({"event_type": "tls", "tls": {"sni": "example.com"}})), None);
+    }
+
+    #[test]
+    fn deduplicates_over_window() {
+        let mut certs = CertObservations::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(certs.derive_at(&tls("aa:bb"), now).len(), 1);
+        assert!(certs.derive_at(&tls("aa:bb"), now + Duration::from_secs(30)).is_empty());
+        assert_eq!(certs.derive_at(&tls("cc:dd"), now + Duration::from_secs(30)).len(), 1);
+        assert_eq!(certs.derive_at(&tls("aa:bb"), now + Duration::from_secs(61)).len(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+use super::{
+    errors::Error,
+    persist
+};
+use std::{
+    self,
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf
+    },
+    sync::Arc
+};
+
+/// Persists the read offset of each source so a restart can resume where it left off.
+pub trait CheckpointStore {
+    fn load(&self, source: &str) -> Result<Option<u64>, Error>;
+    fn save(&self, source: &str, offset: u64) -> Result<(), Error>;
+}
+
+impl<C: CheckpointStore + ?Sized> CheckpointStore for Box<C> {
+    fn load(&self, source: &str) -> Result<Option<u64>, Error> {
+        (**self).load(source)
+    }
+
+    fn save(&self, source: &str, offset: u64) -> Result<(), Error> {
+        (**self).save(source, offset)
+    }
+}
+
+impl<C: CheckpointStore + ?Sized> CheckpointStore for Arc<C> {
+    fn load(&self, source: &str) -> Result<Option<u64>, Error> {
+        (**self).load(source)
+    }
+
+    fn save(&self, source: &str, offset: u64) -> Result<(), Error> {
+        (**self).save(source, offset)
+    }
+}
+
+pub const DEFAULT_INSTANCE: &'static str = "default";
+/// Backend of `FileCheckpointStore`, always registered.
+pub const FILE_BACKEND: &'static str = "file";
+
+/// What a checkpoint backend is opened with: where to keep checkpoints, e.g. a directory or an
+/// etcd or Consul URL, and the instance whose checkpoints to keep apart from other shippers'.
+#[derive(Debug, Clone)]
+pub struct BackendOptions {
+    pub location: String,
+    pub instance: String
+}
+
+pub type BackendFactory = Box<Fn(&BackendOptions) -> Result<Box<CheckpointStore + Send + Sync>, Error> + Send + Sync>;
+
+/// Checkpoint backends by name, selected with `--checkpoint-backend`. Embedders register their
+/// own, e.g. etcd, Consul, or a cloud key-value store, with `Pipeline::with_checkpoint_backend`.
+pub struct CheckpointBackends {
+    factories: HashMap<String, BackendFactory>
+}
+
+impl Default for CheckpointBackends {
+    fn default() -> CheckpointBackends {
+        let mut backends = CheckpointBackends { factories: HashMap::new() };
+        backends.register(FILE_BACKEND, |options: &BackendOptions| {
+            let store: Box<CheckpointStore + Send + Sync> = Box::new(FileCheckpointStore::new(&options.location).with_instance(&options.instance));
+            Ok(store)
+        });
+        backends
+    }
+}
+
+impl CheckpointBackends {
+    /// Registers `factory` as the backend `name`, replacing any registered before.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+        where F: Fn(&BackendOptions) -> Result<Box<CheckpointStore + Send + Sync>, Error> + Send + Sync + 'static
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.factories.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn open(&self, name: &str, options: &BackendOptions) -> Result<Box<CheckpointStore + Send + Sync>, Error> {
+        match self.factories.get(name) {
+            Some(factory) => factory(options),
+            None => bail!("Unknown checkpoint backend {}, expected one of {}", name, self.names().join(", "))
+        }
+    }
+}
+
+/// Escapes `name` into a file name, writing every byte outside `[A-Za-z0-9.-]` (and a leading
+/// dot) as `_` and two hex digits, so distinct names never share a checkpoint.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for (i, byte) in name.bytes().enumerate() {
+        let c = byte as char;
+        if c.is_ascii_alphanumeric() || c == '-' || (c == '.' && i > 0) {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// The lossy name checkpoints were stored under before `escape`, still read so upgrades resume.
+fn legacy_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Stores each source's offset as a small checksummed text file under `dir/<instance>`, so several
+/// shippers on one host reading the same file keep independent positions.
+pub struct FileCheckpointStore {
+    dir: PathBuf,
+    instance: String
+}
+
+impl FileCheckpointStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> FileCheckpointStore {
+        FileCheckpointStore {
+            dir: dir.as_ref().to_path_buf(),
+            instance: DEFAULT_INSTANCE.to_string()
+        }
+    }
+
+    pub fn with_instance(mut self, instance: &str) -> Self {
+        self.instance = instance.to_string();
+        self
+    }
+
+    fn instance_dir(&self) -> PathBuf {
+        self.dir.join(escape(&self.instance))
+    }
+
+    fn path(&self, source: &str) -> PathBuf {
+        self.instance_dir().join(format!("{}.offset", escape(source)))
+    }
+
+    fn legacy_path(&self, source: &str) -> PathBuf {
+        self.dir.join(legacy_name(&self.instance)).join(format!("{}.offset", legacy_name(source)))
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&self, source: &str) -> Result<Option<u64>, Error> {
+        let mut path = self.path(source);
+        if !path.exists() {
+            path = self.legacy_path(source);
+            if !path.exists() {
+                return Ok(None)
+            }
+        }
+        let contents = std::fs::read(path)?;
+        let offset = persist::unseal(&contents)
+            .and_then(|payload| std::str::from_utf8(payload).ok())
+            .and_then(|payload| payload.trim().parse::<u64>().ok());
+        match offset {
+            Some(offset) => Ok(Some(offset)),
+            None => {
+                warn!("Ignoring corrupt checkpoint for {}", source);
+                Ok(None)
+            }
+        }
+    }
+
+    fn save(&self, source: &str, offset: u64) -> Result<(), Error> {
+        std::fs::create_dir_all(self.instance_dir())?;
+        persist::write_atomic(self.path(source), &persist::seal(offset.to_string().as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("surikafka-checkpoint-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn round_trips_offsets() {
+        let dir = temp_dir("round-trip");
+        let store = FileCheckpointStore::new(&dir);
+
+        assert_eq!(store.load("/var/log/suricata/eve.json").expect("Failed to load"), None);
+
+        store.save("/var/log/suricata/eve.json", 1234).expect("Failed to save");
+
+        assert_eq!(store.load("/var/log/suricata/eve.json").expect("Failed to load"), Some(1234));
+        assert_eq!(store.load("/var/log/suricata/other.json").expect("Failed to load"), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn namespaces_instances() {
+        let dir = temp_dir("instances");
+        let alerts = FileCheckpointStore::new(&dir).with_instance("alerts");
+        let flows = FileCheckpointStore::new(&dir).with_instance("flows");
+
+        alerts.save("/var/log/suricata/eve.json", 10).expect("Failed to save");
+        flows.save("/var/log/suricata/eve.json", 20).expect("Failed to save");
+
+        assert_eq!(alerts.load("/var/log/suricata/eve.json").expect("Failed to load"), Some(10));
+        assert_eq!(flows.load("/var/log/suricata/eve.json").expect("Failed to load"), Some(20));
+        assert_eq!(FileCheckpointStore::new(&dir).load("/var/log/suricata/eve.json").expect("Failed to load"), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_similar_names_apart() {
+        let dir = temp_dir("similar");
+        let store = FileCheckpointStore::new(&dir);
+
+        store.save("/var/log/a_b", 1).expect("Failed to save");
+        store.save("/var/log/a/b", 2).expect("Failed to save");
+        store.save("..", 3).expect("Failed to save");
+
+        assert_eq!(store.load("/var/log/a_b").expect("Failed to load"), Some(1));
+        assert_eq!(store.load("/var/log/a/b").expect("Failed to load"), Some(2));
+        assert_eq!(store.load("..").expect("Failed to load"), Some(3));
+        assert_eq!(FileCheckpointStore::new(&dir).with_instance("a/b").load("/var/log/a_b").expect("Failed to load"), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_legacy_names() {
+        let dir = temp_dir("legacy");
+        let store = FileCheckpointStore::new(&dir);
+        let legacy = store.legacy_path("/var/log/eve.json");
+        std::fs::create_dir_all(legacy.parent().expect("No parent")).expect("Failed to create");
+        std::fs::write(&legacy, "17").expect("Failed to write legacy checkpoint");
+
+        assert_eq!(store.load("/var/log/eve.json").expect("Failed to load"), Some(17));
+
+        store.save("/var/log/eve.json", 18).expect("Failed to save");
+
+        assert_eq!(store.load("/var/log/eve.json").expect("Failed to load"), Some(18));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn opens_registered_backends() {
+        struct Fixed(u64);
+
+        impl CheckpointStore for Fixed {
+            fn load(&self, _source: &str) -> Result<Option<u64>, Error> {
+                Ok(Some(self.0))
+            }
+
+            fn save(&self, _source: &str, _offset: u64) -> Result<(), Error> {
+                Ok( () )
+            }
+        }
+
+        let dir = temp_dir("backends");
+        let mut backends = CheckpointBackends::default();
+        backends.register("fixed", |options: &BackendOptions| {
+            let store: Box<CheckpointStore + Send + Sync> = Box::new(Fixed(options.location.len() as u64));
+            Ok(store)
+        });
+        let options = BackendOptions { location: dir.to_string_lossy().into_owned(), instance: "alerts".to_string() };
+
+        assert_eq!(backends.names(), vec!["file".to_string(), "fixed".to_string()]);
+        assert_eq!(backends.open("fixed", &options).expect("Failed to open").load("eve.json").expect("Failed to load"), Some(options.location.len() as u64));
+        let file = backends.open(FILE_BACKEND, &options).expect("Failed to open");
+        file.save("eve.json", 7).expect("Failed to save");
+        assert_eq!(FileCheckpointStore::new(&dir).with_instance("alerts").load("eve.json").expect("Failed to load"), Some(7));
+        assert!(backends.open("etcd", &options).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ignores_corrupt_checkpoints() {
+        let dir = temp_dir("corrupt");
+        let store = FileCheckpointStore::new(&dir);
+
+        store.save("eve.json", 1234).expect("Failed to save");
+        let path = store.path("eve.json");
+        let contents = std::fs::read_to_string(&path).expect("Failed to read");
+        std::fs::write(&path, contents.replace("1234", "9234")).expect("Failed to corrupt");
+
+        assert_eq!(store.load("eve.json").expect("Failed to load"), None);
+
+        std::fs::write(&path, "42").expect("Failed to write legacy checkpoint");
+
+        assert_eq!(store.load("eve.json").expect("Failed to load"), Some(42));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
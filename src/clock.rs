@@ -0,0 +1,206 @@
+use super::{
+    chrono::Utc,
+    errors::Error,
+    futures::Future,
+    metrics::{
+        Counter,
+        Registry
+    },
+    rdkafka::{
+        ClientConfig,
+        ClientContext,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        },
+        message::Timestamp,
+        producer::{
+            FutureProducer,
+            FutureRecord
+        }
+    }
+};
+use std::{
+    self,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicIsize,
+            Ordering
+        }
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const POLL_TIMEOUT_MS: i32 = 500;
+const FETCH_TIMEOUT_SECS: u64 = 10;
+
+/// Sensor clock minus broker clock in milliseconds, taking the local time as the midpoint
+/// between sending a probe and receiving its delivery report.
+pub fn skew_ms(sent_ms: i64, delivered_ms: i64, broker_ms: i64) -> i64 {
+    sent_ms + (delivered_ms - sent_ms) / 2 - broker_ms
+}
+
+/// Outcome of reading back a probe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrokerTime {
+    /// Timestamp set by the broker on append
+    Append(i64),
+    /// The topic keeps producer timestamps, so broker time can't be observed
+    Producer,
+    Unavailable
+}
+
+impl From<Timestamp> for BrokerTime {
+    fn from(timestamp: Timestamp) -> BrokerTime {
+        match timestamp {
+            Timestamp::LogAppendTime(ms) => BrokerTime::Append(ms),
+            Timestamp::CreateTime(_) => BrokerTime::Producer,
+            Timestamp::NotAvailable => BrokerTime::Unavailable
+        }
+    }
+}
+
+fn read_timestamp(consumer: &BaseConsumer, topic: &str, partition: i32, offset: i64) -> Result<BrokerTime, Error> {
+    let mut assignment = TopicPartitionList::new();
+    assignment.add_partition_offset(topic, partition, Offset::Offset(offset));
+    consumer.assign(&assignment)
+        .map_err(|e| Error::from(format!("Failed to assign partition: {:?}", e)))?;
+    let deadline = Instant::now() + Duration::from_secs(FETCH_TIMEOUT_SECS);
+    while Instant::now() < deadline {
+        match consumer.poll(POLL_TIMEOUT_MS) {
+            None => continue,
+            Some(Err(e)) => bail!("Failed to consume probe: {:?}", e),
+            Some(Ok(ref m)) if m.offset() == offset => return Ok(BrokerTime::from(m.timestamp())),
+            Some(Ok(_)) => continue
+        }
+    }
+    bail!("Timed out reading back probe at {}/{}", partition, offset)
+}
+
+/// Periodically produces a probe to a topic using `message.timestamp.type=LogAppendTime`, reads
+/// back the timestamp the broker gave it, and warns when the sensor clock is off by more than
+/// `max_skew`, since skewed sensors silently break time based correlation downstream.
+pub struct ClockCheck {
+    skew: Arc<AtomicIsize>
+}
+
+impl ClockCheck {
+    /// `client` carries the connection settings shared with the producer.
+    pub fn spawn<C>(
+        client: &ClientConfig,
+        producer: FutureProducer<C>,
+        topic: &str,
+        sensor_id: &str,
+        interval: Duration,
+        max_skew: Duration,
+        registry: &Registry
+    ) -> Result<ClockCheck, Error>
+        where C: ClientContext + 'static
+    {
+        let consumer: BaseConsumer = client.clone()
+            .set("group.id", "surikafka-clock")
+            .set("enable.auto.commit", "false")
+            .create()
+            .map_err(|e| Error::from(format!("Failed to create clock consumer: {:?}", e)))?;
+        let skew = Arc::new(AtomicIsize::new(0));
+        let thread_skew = skew.clone();
+        let skewed = registry.counter("clock.skewed");
+        let topic = topic.to_string();
+        let probe = json!({"event_type": "surikafka_clock", "sensor_id": sensor_id}).to_string();
+        let max_skew_ms = (max_skew.as_secs() * 1000 + max_skew.subsec_nanos() as u64 / 1000000) as i64;
+
+        std::thread::spawn(move || {
+            loop {
+                match check(&producer, &consumer, &topic, &probe) {
+                    Ok(Some(ms)) => {
+                        thread_skew.store(ms as isize, Ordering::SeqCst);
+                        report(ms, max_skew_ms, &skewed);
+                    }
+                    Ok(None) => {
+                        error!("Topic {} doesn't use LogAppendTime timestamps, stopping clock checks", topic);
+                        return
+                    }
+                    Err(e) => warn!("Failed to check clock against brokers: {}", e)
+                }
+                if Arc::strong_count(&thread_skew) == 1 {
+                    return
+                }
+                std::thread::sleep(interval);
+            }
+        });
+
+        Ok(ClockCheck {
+            skew: skew
+        })
+    }
+
+    /// Last measured skew in milliseconds, positive when the sensor clock is ahead.
+    pub fn handle(&self) -> Arc<AtomicIsize> { self.skew.clone() }
+}
+
+/// Skew of one probe, `None` if the topic doesn't carry broker timestamps.
+fn check<C>(producer: &FutureProducer<C>, consumer: &BaseConsumer, topic: &str, probe: &str) -> Result<Option<i64>, Error>
+    where C: ClientContext + 'static
+{
+    let sent = Utc::now().timestamp_millis();
+    let record: FutureRecord<(), str> = FutureRecord::to(topic).payload(probe);
+    let (partition, offset) = match producer.send(record, 1000).wait() {
+        Ok(Ok(delivered)) => delivered,
+        Ok(Err( (e, _) )) => bail!("Failed to produce probe: {:?}", e),
+        Err(_) => bail!("Probe delivery canceled")
+    };
+    let delivered = Utc::now().timestamp_millis();
+    match read_timestamp(consumer, topic, partition, offset)? {
+        BrokerTime::Append(broker) => Ok(Some(skew_ms(sent, delivered, broker))),
+        BrokerTime::Producer => Ok(None),
+        BrokerTime::Unavailable => bail!("Probe has no timestamp")
+    }
+}
+
+fn report(skew_ms: i64, max_skew_ms: i64, skewed: &Counter) {
+    if skew_ms.abs() > max_skew_ms {
+        skewed.incr();
+        warn!(
+            "Sensor clock is {} ms {} broker time, more than the {} ms allowed",
+            skew_ms.abs(), if skew_ms > 0 { "ahead of" } else { "behind" }, max_skew_ms
+        );
+    } else {
+        debug!("Sensor clock skew is {} ms", skew_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_skew_from_midpoint() {
+        assert_eq!(skew_ms(1000, 1100, 1050), 0);
+        assert_eq!(skew_ms(6000, 6100, 1050), 5000);
+        assert_eq!(skew_ms(1000, 1000, 4000), -3000);
+    }
+
+    #[test]
+    fn needs_append_timestamps() {
+        assert_eq!(BrokerTime::from(Timestamp::LogAppendTime(5)), BrokerTime::Append(5));
+        assert_eq!(BrokerTime::from(Timestamp::CreateTime(5)), BrokerTime::Producer);
+        assert_eq!(BrokerTime::from(Timestamp::NotAvailable), BrokerTime::Unavailable);
+    }
+
+    #[test]
+    fn counts_skewed_checks() {
+        let skewed = Counter::new("clock.skewed");
+
+        report(-3000, 2000, &skewed);
+        report(1500, 2000, &skewed);
+
+        assert_eq!(skewed.value(), 1);
+    }
+}
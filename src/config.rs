@@ -0,0 +1,407 @@
+use super::{
+    errors::Error,
+    serde_json::{
+        self,
+        Map,
+        Value
+    },
+    serde_yaml,
+    toml
+};
+use std::{
+    self,
+    path::Path
+};
+
+/// Schema version of config files written for this release.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Upgrades a config document from version `from` to `from + 1`, returning a diagnostic for
+/// each change made.
+pub type MigrationFn = fn(&mut Map<String, Value>) -> Vec<String>;
+
+/// Setting renamed in `since`; `old` is rewritten to `new` when `new` isn't also set.
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    pub since: u64,
+    pub old: &'static str,
+    pub new: &'static str
+}
+
+/// Setting holding independent pipelines keyed by name, each overriding the top level settings.
+pub const PIPELINES: &'static str = "pipelines";
+
+/// Settings renamed since version 1.
+const DEPRECATIONS: &'static [Deprecation] = &[];
+
+/// Upgrades older config documents to the current schema.
+pub struct Migrator {
+    current: u64,
+    migrations: Vec<(u64, MigrationFn)>,
+    deprecations: Vec<Deprecation>
+}
+
+impl Default for Migrator {
+    fn default() -> Migrator {
+        Migrator {
+            current: CONFIG_VERSION,
+            migrations: vec![],
+            deprecations: DEPRECATIONS.to_vec()
+        }
+    }
+}
+
+impl Migrator {
+    pub fn with_migration(mut self, from: u64, migration: MigrationFn) -> Self {
+        self.migrations.push( (from, migration) );
+        self
+    }
+
+    pub fn with_current(mut self, version: u64) -> Self {
+        self.current = version;
+        self
+    }
+
+    pub fn with_deprecation(mut self, deprecation: Deprecation) -> Self {
+        self.deprecations.push(deprecation);
+        self
+    }
+
+    /// Migrates `doc` to the current version. Documents without a `version` are treated as
+    /// version 1; documents newer than this release are rejected rather than half understood.
+    pub fn migrate(&self, doc: Value) -> Result<(Map<String, Value>, Vec<String>), Error> {
+        let mut settings = match doc {
+            Value::Object(m) => m,
+            _ => bail!("Config must be a JSON object")
+        };
+        let mut diagnostics = vec![];
+
+        let mut version = match settings.remove("version") {
+            Some(Value::Number(ref n)) if n.as_u64().is_some() => n.as_u64().unwrap_or(1),
+            Some(v) => bail!("Invalid config version {}", v),
+            None => {
+                diagnostics.push(format!("Config has no version, assuming 1; add \"version\": {}", self.current));
+                1
+            }
+        };
+
+        if version > self.current {
+            bail!("Config version {} is newer than the supported version {}, upgrade surikafka", version, self.current);
+        }
+        if version < self.current {
+            diagnostics.push(format!("Config version {} is older than {}, migrating", version, self.current));
+        }
+
+        while version < self.current {
+            for &(from, migration) in self.migrations.iter().filter(|&&(from, _)| from == version) {
+                diagnostics.extend(migration(&mut settings).into_iter().map(|d| format!("v{} -> v{}: {}", from, from + 1, d)));
+            }
+            version += 1;
+        }
+
+        self.rename_deprecated(&mut settings, &mut diagnostics);
+        if let Some(&mut Value::Object(ref mut pipelines)) = settings.get_mut(PIPELINES) {
+            for (_, pipeline) in pipelines.iter_mut() {
+                if let Value::Object(ref mut pipeline) = *pipeline {
+                    self.rename_deprecated(pipeline, &mut diagnostics);
+                }
+            }
+        }
+
+        Ok( (settings, diagnostics) )
+    }
+
+    fn rename_deprecated(&self, settings: &mut Map<String, Value>, diagnostics: &mut Vec<String>) {
+        for deprecation in self.deprecations.iter() {
+            if let Some(value) = settings.remove(deprecation.old) {
+                if settings.contains_key(deprecation.new) {
+                    diagnostics.push(format!(
+                        "`{}` is deprecated since config version {} and ignored because `{}` is also set",
+                        deprecation.old, deprecation.since, deprecation.new
+                    ));
+                } else {
+                    diagnostics.push(format!(
+                        "`{}` is deprecated since config version {}, use `{}`",
+                        deprecation.old, deprecation.since, deprecation.new
+                    ));
+                    settings.insert(deprecation.new.to_string(), value);
+                }
+            }
+        }
+    }
+}
+
+/// Syntax of a config file, chosen by its extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml
+}
+
+impl Format {
+    /// `.toml` and `.yaml`/`.yml` files, anything else is read as JSON.
+    pub fn of<P: AsRef<Path>>(path: P) -> Format {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json
+        }
+    }
+
+    pub fn parse(&self, contents: &[u8]) -> Result<Value, Error> {
+        let parsed = match *self {
+            Format::Json => serde_json::from_slice(contents).map_err(|e| e.to_string()),
+            Format::Toml => std::str::from_utf8(contents).map_err(|e| e.to_string())
+                .and_then(|s| toml::from_str(s).map_err(|e| e.to_string())),
+            Format::Yaml => serde_yaml::from_slice(contents).map_err(|e| e.to_string())
+        };
+        parsed.map_err(Error::from)
+    }
+}
+
+/// Loaded config file, keyed by long command line flag name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub settings: Map<String, Value>,
+    pub diagnostics: Vec<String>
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let contents = std::fs::read(path.as_ref())?;
+        let doc = Format::of(path.as_ref()).parse(&contents)
+            .map_err(|e| Error::from(format!("Failed to parse config {}: {}", path.as_ref().display(), e)))?;
+        let (settings, diagnostics) = Migrator::default().migrate(doc)?;
+        Ok(Config {
+            settings: settings,
+            diagnostics: diagnostics
+        })
+    }
+
+    /// Config of each pipeline: the top level settings overridden by the pipeline's own, with
+    /// `instance-id` defaulting to the pipeline name so checkpoints stay separate. A config
+    /// without `pipelines` is a single pipeline named after its `instance-id`.
+    pub fn pipelines(&self) -> Result<Vec<(String, Config)>, Error> {
+        let mut shared = self.settings.clone();
+        let pipelines = match shared.remove(PIPELINES) {
+            None => {
+                let name = match shared.get("instance-id") {
+                    Some(&Value::String(ref id)) => id.clone(),
+                    _ => "default".to_string()
+                };
+                return Ok(vec![ (name, self.clone()) ])
+            }
+            Some(Value::Object(pipelines)) => pipelines,
+            Some(_) => bail!("`{}` must be an object of pipelines keyed by name", PIPELINES)
+        };
+        if pipelines.is_empty() {
+            bail!("`{}` has no pipelines", PIPELINES);
+        }
+
+        let mut configs = vec![];
+        for (name, overrides) in pipelines {
+            let overrides = match overrides {
+                Value::Object(o) => o,
+                _ => bail!("Pipeline {} must be an object of settings", name)
+            };
+            let mut settings = shared.clone();
+            settings.insert("instance-id".to_string(), Value::String(name.clone()));
+            settings.extend(overrides);
+            configs.push( (name, Config {
+                settings: settings,
+                diagnostics: vec![]
+            }) );
+        }
+        Ok(configs)
+    }
+
+    /// Converts settings to command line arguments, skipping any flag already given in `argv`
+    /// (long form, or short form via `shorts`) so the command line takes precedence.
+    pub fn to_args(&self, argv: &[String], shorts: &[(char, &str)]) -> Vec<String> {
+        let given = |flag: &str| {
+            let long = format!("--{}", flag);
+            let long_eq = format!("--{}=", flag);
+            let short = shorts.iter().find(|&&(_, l)| l == flag).map(|&(s, _)| format!("-{}", s));
+            argv.iter().any(|a| {
+                *a == long || a.starts_with(&long_eq) ||
+                    short.as_ref().map(|s| a.starts_with(s.as_str())).unwrap_or(false)
+            })
+        };
+
+        let mut args = vec![];
+        for (flag, value) in self.settings.iter() {
+            if given(flag) {
+                continue
+            }
+            let values = match *value {
+                Value::Null | Value::Bool(false) => continue,
+                Value::Bool(true) => {
+                    args.push(format!("--{}", flag));
+                    continue
+                }
+                Value::Array(ref a) => a.clone(),
+                ref v => vec![v.clone()]
+            };
+            for v in values {
+                args.push(format!("--{}", flag));
+                args.push(match v {
+                    Value::String(s) => s,
+                    v => v.to_string()
+                });
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rename_brokers(settings: &mut Map<String, Value>) -> Vec<String> {
+        match settings.remove("brokers") {
+            Some(v) => {
+                settings.insert("kafka".to_string(), v);
+                vec!["renamed `brokers` to `kafka`".to_string()]
+            }
+            None => vec![]
+        }
+    }
+
+    #[test]
+    fn accepts_current_version() {
+        let (settings, diagnostics) = Migrator::default()
+            .migrate(json!({"version": CONFIG_VERSION, "topic": "alerts"}))
+            .expect("Failed to migrate");
+
+        assert_eq!(settings.get("topic"), Some(&json!("alerts")));
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        let migrator = Migrator::default()
+            .with_current(2)
+            .with_migration(1, rename_brokers)
+            .with_deprecation(Deprecation { since: 2, old: "eve-path", new: "eve" });
+
+        let (settings, diagnostics) = migrator
+            .migrate(json!({"brokers": "kafka:9092", "eve-path": "/var/run/eve.sock"}))
+            .expect("Failed to migrate");
+
+        assert_eq!(settings.get("kafka"), Some(&json!("kafka:9092")));
+        assert_eq!(settings.get("eve"), Some(&json!("/var/run/eve.sock")));
+        assert_eq!(diagnostics, vec![
+            "Config has no version, assuming 1; add \"version\": 2".to_string(),
+            "Config version 1 is older than 2, migrating".to_string(),
+            "v1 -> v2: renamed `brokers` to `kafka`".to_string(),
+            "`eve-path` is deprecated since config version 2, use `eve`".to_string()
+        ]);
+    }
+
+    #[test]
+    fn parses_toml_and_yaml() {
+        let toml = br#"
+version = 1
+kafka = "kafka:9092"
+rules = ["a.rules"]
+
+[pipelines.alerts]
+eve = "/var/run/alerts.sock"
+"#;
+        let yaml = br#"
+version: 1
+kafka: kafka:9092
+rules: [a.rules]
+pipelines:
+  alerts:
+    eve: /var/run/alerts.sock
+"#;
+        let expected = json!({
+            "version": 1,
+            "kafka": "kafka:9092",
+            "rules": ["a.rules"],
+            "pipelines": {"alerts": {"eve": "/var/run/alerts.sock"}}
+        });
+
+        assert_eq!(Format::of("surikafka.toml"), Format::Toml);
+        assert_eq!(Format::of("surikafka.yml"), Format::Yaml);
+        assert_eq!(Format::of("surikafka.json"), Format::Json);
+        assert_eq!(Format::Toml.parse(toml).expect("Failed to parse TOML"), expected);
+        assert_eq!(Format::Yaml.parse(yaml).expect("Failed to parse YAML"), expected);
+        assert!(Format::Toml.parse(b"kafka = ").is_err());
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        assert!(Migrator::default().migrate(json!({"version": CONFIG_VERSION + 1})).is_err());
+        assert!(Migrator::default().migrate(json!({"version": "one"})).is_err());
+    }
+
+    #[test]
+    fn splits_pipelines() {
+        let (settings, _) = Migrator::default()
+            .migrate(json!({
+                "version": 1,
+                "kafka": "kafka:9092",
+                "pipelines": {
+                    "alerts": {"eve": "/var/run/alerts.sock", "topic": "alerts"},
+                    "flows": {"eve": "/var/run/flows.sock", "kafka": "flows:9092", "instance-id": "netflow"}
+                }
+            }))
+            .expect("Failed to migrate");
+        let config = Config { settings: settings, diagnostics: vec![] };
+
+        let pipelines = config.pipelines().expect("Failed to split pipelines");
+
+        assert_eq!(pipelines.len(), 2);
+        let (ref name, ref alerts) = pipelines[0];
+        assert_eq!(name, "alerts");
+        assert_eq!(alerts.settings.get("kafka"), Some(&json!("kafka:9092")));
+        assert_eq!(alerts.settings.get("topic"), Some(&json!("alerts")));
+        assert_eq!(alerts.settings.get("instance-id"), Some(&json!("alerts")));
+        assert!(alerts.settings.get(PIPELINES).is_none());
+
+        let (_, ref flows) = pipelines[1];
+        assert_eq!(flows.settings.get("kafka"), Some(&json!("flows:9092")));
+        assert_eq!(flows.settings.get("instance-id"), Some(&json!("netflow")));
+    }
+
+    #[test]
+    fn single_pipeline_without_pipelines() {
+        let config = Config { settings: Map::new(), diagnostics: vec![] };
+        let pipelines = config.pipelines().expect("Failed to split pipelines");
+
+        assert_eq!(pipelines, vec![ ("default".to_string(), config) ]);
+        assert!(Config { settings: json!({"pipelines": []}).as_object().cloned().unwrap_or_default(), diagnostics: vec![] }
+            .pipelines().is_err());
+    }
+
+    #[test]
+    fn converts_to_args() {
+        let (settings, _) = Migrator::default()
+            .migrate(json!({
+                "version": 1,
+                "kafka": "kafka:9092",
+                "topic": "alerts",
+                "rules": ["a.rules", "b.rules"],
+                "utf8-escape": true,
+                "attack-tags": false,
+                "max-drop-rate": 0.05
+            }))
+            .expect("Failed to migrate");
+        let config = Config { settings: settings, diagnostics: vec![] };
+
+        let argv = vec!["surikafka".to_string(), "-kbroker:9092".to_string()];
+        let args = config.to_args(&argv, &[('k', "kafka")]);
+
+        assert_eq!(args, vec![
+            "--max-drop-rate", "0.05",
+            "--rules", "a.rules",
+            "--rules", "b.rules",
+            "--topic", "alerts",
+            "--utf8-escape"
+        ].into_iter().map(String::from).collect::<Vec<String>>());
+    }
+}
@@ -0,0 +1,160 @@
+use super::{
+    derive::Derivation,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::collections::{
+    HashMap,
+    VecDeque
+};
+
+/// Bundles every alert with the events that preceded it on the same flow, e.g. its dns, http,
+/// and tls records, for a dedicated topic analysts can read without joining on `flow_id`. The
+/// last `depth` events of up to `max_flows` flows are kept, forgetting the oldest flows first.
+/// Every event with a `flow_id` is parsed to be buffered, so this costs more than derivations
+/// of a single event type.
+pub struct AlertContext {
+    depth: usize,
+    max_flows: usize,
+    flows: HashMap<u64, VecDeque<Value>>,
+    order: VecDeque<u64>
+}
+
+impl AlertContext {
+    pub fn new(depth: usize, max_flows: usize) -> AlertContext {
+        AlertContext {
+            depth: depth,
+            max_flows: max_flows,
+            flows: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    /// Flows with events buffered.
+    pub fn flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    fn remember(&mut self, flow_id: u64, event: &Value) {
+        if !self.flows.contains_key(&flow_id) {
+            while self.flows.len() >= self.max_flows {
+                match self.order.pop_front() {
+                    Some(oldest) => { self.flows.remove(&oldest); }
+                    None => break
+                }
+            }
+            self.order.push_back(flow_id);
+        }
+        let depth = self.depth;
+        let events = self.flows.entry(flow_id).or_insert_with(VecDeque::new);
+        if events.len() >= depth {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+    }
+}
+
+impl Derivation for AlertContext {
+    fn event_type(&self) -> &str {
+        "alert"
+    }
+
+    fn wants(&self, _event_type: &str) -> bool {
+        true
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        let flow_id = match event.get("flow_id").and_then(Value::as_u64) {
+            Some(id) => id,
+            None => return vec![]
+        };
+        if event.get("event_type").and_then(Value::as_str) != Some("alert") {
+            if self.depth > 0 && self.max_flows > 0 {
+                self.remember(flow_id, event);
+            }
+            return vec![]
+        }
+        let context: Vec<&Value> = self.flows.get(&flow_id)
+            .map(|events| events.iter().collect())
+            .unwrap_or_else(Vec::new);
+        let bundle = json!({
+            "timestamp": event.get("timestamp"),
+            "event_type": "alert_context",
+            "flow_id": flow_id,
+            "src_ip": event.get("src_ip"),
+            "dest_ip": event.get("dest_ip"),
+            "alert": event,
+            "context": context
+        });
+        vec![ serde_json::to_vec(&bundle).expect("Bundle is always serializable") ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::{
+            Async,
+            Stream
+        },
+        reader::EveReader
+    };
+    use std;
+
+    fn event(event_type: &str, flow_id: u64, seq: u64) -> Value {
+        json!({"event_type": event_type, "flow_id": flow_id, "src_ip": "10.0.0.1", "dest_ip": "10.0.0.2", "seq": seq})
+    }
+
+    #[test]
+    fn bundles_preceding_flow_events() {
+        let mut context = AlertContext::new(2, 10);
+        context.derive(&event("dns", 1, 1));
+        context.derive(&event("http", 1, 2));
+        context.derive(&event("tls", 1, 3));
+        context.derive(&event("dns", 2, 4));
+
+        let records = context.derive(&event("alert", 1, 5));
+        let bundle: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+
+        assert_eq!(bundle["event_type"], "alert_context");
+        assert_eq!(bundle["flow_id"], 1);
+        assert_eq!(bundle["alert"]["seq"], 5);
+        assert_eq!(bundle["context"].as_array().map(|c| c.iter().map(|e| e["seq"].clone()).collect::<Vec<_>>()), Some(vec![json!(2), json!(3)]));
+        assert_eq!(context.derive(&event("alert", 3, 6)).len(), 1);
+        assert!(context.derive(&json!({"event_type": "alert"})).is_empty());
+    }
+
+    #[test]
+    fn bundles_events_read_with_the_alert() {
+        let lines = [event("dns", 1, 1), event("http", 1, 2), event("alert", 1, 3)].iter()
+            .map(|e| format!("{}\n", e))
+            .collect::<String>();
+        let mut reader = EveReader::new(std::io::Cursor::new(lines.into_bytes()));
+        let mut context = AlertContext::new(5, 10);
+
+        let mut records = vec![];
+        while let Async::Ready(Some(msg)) = reader.poll().expect("Failed to read") {
+            let event: Value = serde_json::from_slice(&msg).expect("Failed to parse");
+            records.extend(context.derive(&event));
+        }
+
+        let bundle: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+        assert_eq!(bundle["context"].as_array().map(|c| c.iter().map(|e| e["seq"].clone()).collect::<Vec<_>>()), Some(vec![json!(1), json!(2)]));
+    }
+
+    #[test]
+    fn forgets_oldest_flows() {
+        let mut context = AlertContext::new(5, 2);
+        context.derive(&event("dns", 1, 1));
+        context.derive(&event("dns", 2, 2));
+        context.derive(&event("dns", 3, 3));
+
+        let bundle: Value = serde_json::from_slice(&context.derive(&event("alert", 1, 4))[0]).expect("Failed to parse");
+
+        assert_eq!(context.flows(), 2);
+        assert_eq!(bundle["context"], json!([]));
+    }
+}
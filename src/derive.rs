@@ -0,0 +1,245 @@
+use super::{
+    addr::normalize_ip,
+    eve,
+    flowbits::FlowState,
+    futures::{
+        Async,
+        Poll,
+        Stream,
+        sync::mpsc::UnboundedSender
+    },
+    key::KeyGenerator,
+    metrics::QueueGauge,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std;
+
+/// Builds compact records for a derived topic from the events of one `event_type`.
+pub trait Derivation {
+    fn event_type(&self) -> &str;
+
+    /// Whether events of `event_type` are handed to `derive`; derivations keeping state across
+    /// event types may want more than their own.
+    fn wants(&self, event_type: &str) -> bool {
+        self.event_type() == event_type
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>>;
+}
+
+/// Passes events through unchanged, sending the records derived from them to the topic task of
+/// each derivation. Events are only parsed when a derivation wants their `event_type`.
+pub struct DerivedStreams<S> {
+    inner: S,
+    derivations: Vec<(Box<Derivation + Send>, UnboundedSender<Vec<u8>>, Option<QueueGauge>)>
+}
+
+impl<S> DerivedStreams<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(stream: S) -> DerivedStreams<S> {
+        DerivedStreams {
+            inner: stream,
+            derivations: vec![]
+        }
+    }
+
+    /// Sends records derived by `derivation` to `sender`, counting them in `gauge`; the receiving
+    /// side is expected to subtract as it consumes them.
+    pub fn with_derivation<D>(mut self, derivation: D, sender: UnboundedSender<Vec<u8>>, gauge: Option<QueueGauge>) -> Self
+        where D: Derivation + Send + 'static
+    {
+        self.derivations.push( (Box::new(derivation), sender, gauge) );
+        self
+    }
+
+    fn inspect(&mut self, msg: &Vec<u8>) {
+        let event_type = match eve::event_type(msg) {
+            Some(t) => t,
+            None => return
+        };
+        if !self.derivations.iter().any(|&(ref d, _, _)| d.wants(event_type)) {
+            return
+        }
+        let event: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return
+        };
+        for &mut (ref mut derivation, ref sender, ref gauge) in self.derivations.iter_mut() {
+            if !derivation.wants(event_type) {
+                continue
+            }
+            for record in derivation.derive(&event) {
+                if sender.unbounded_send(record).is_err() {
+                    error!("Derived {} receiver closed, dropping record", event_type);
+                } else if let Some(ref gauge) = *gauge {
+                    gauge.add(1);
+                }
+            }
+        }
+    }
+}
+
+impl<S> Stream for DerivedStreams<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(msg) => {
+                self.inspect(msg.as_ref());
+                Ok(Async::Ready(Some(msg)))
+            }
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Keys a derived record by one of its top level string fields, so a compacted topic keeps the
+/// latest record per value.
+pub struct FieldKey {
+    field: &'static str,
+    address: bool
+}
+
+impl FieldKey {
+    pub fn new(field: &'static str) -> FieldKey {
+        FieldKey {
+            field: field,
+            address: false
+        }
+    }
+
+    /// Normalizes the field as an IP address, so every textual form of a host shares a key.
+    pub fn with_address(mut self) -> Self {
+        self.address = true;
+        self
+    }
+}
+
+impl KeyGenerator for FieldKey {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        let event: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return vec![]
+        };
+        match event.get(self.field).and_then(Value::as_str) {
+            Some(value) if self.address => normalize_ip(value).into_bytes(),
+            Some(value) => value.as_bytes().to_vec(),
+            None => vec![]
+        }
+    }
+}
+
+/// Summarizes alerts by destination host, for a compacted topic holding the latest alert on
+/// every asset.
+#[derive(Default)]
+pub struct LatestAlert;
+
+impl Derivation for LatestAlert {
+    fn event_type(&self) -> &str {
+        "alert"
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        let host = match event.get("dest_ip").and_then(Value::as_str) {
+            Some(h) => normalize_ip(h),
+            None => return vec![]
+        };
+        let alert = event.get("alert").cloned().unwrap_or(Value::Null);
+        let mut summary = json!({
+            "host": host,
+            "timestamp": event.get("timestamp"),
+            "src_ip": event.get("src_ip"),
+            "proto": event.get("proto"),
+            "signature_id": alert.get("signature_id"),
+            "signature": alert.get("signature"),
+            "category": alert.get("category"),
+            "severity": alert.get("severity"),
+            "action": alert.get("action")
+        });
+        let state = FlowState::from_event(event);
+        if !state.is_empty() {
+            summary["metadata"] = state.to_value();
+        }
+        vec![ serde_json::to_vec(&summary).expect("Summary is always serializable") ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream,
+        sync::mpsc
+    };
+
+    const ALERT: &'static str = r#"{"timestamp":"2018-06-01T00:00:00.000000+0000","event_type":"alert","src_ip":"10.0.0.1","dest_ip":"10.0.0.2","proto":"TCP","alert":{"signature_id":2000001,"signature":"ET TEST","category":"Misc","severity":2,"action":"allowed"},"payload":"AAAA"}"#;
+
+    #[test]
+    fn summarizes_alerts_by_host() {
+        let event: Value = serde_json::from_str(ALERT).expect("Failed to parse");
+
+        let records = LatestAlert.derive(&event);
+        let summary: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+
+        assert_eq!(summary["host"], "10.0.0.2");
+        assert_eq!(summary["signature_id"], 2000001);
+        assert_eq!(summary["severity"], 2);
+        assert!(summary.get("payload").is_none());
+        assert!(summary.get("metadata").is_none());
+        assert_eq!(FieldKey::new("host").generate(&records[0]), b"10.0.0.2".to_vec());
+        assert!(LatestAlert.derive(&json!({"event_type": "alert"})).is_empty());
+    }
+
+    #[test]
+    fn normalizes_address_keys() {
+        let mapped = LatestAlert.derive(&json!({"event_type": "alert", "dest_ip": "::ffff:10.0.0.1"}));
+        let plain = LatestAlert.derive(&json!({"event_type": "alert", "dest_ip": "10.0.0.1"}));
+
+        assert_eq!(FieldKey::new("host").generate(&mapped[0]), FieldKey::new("host").generate(&plain[0]));
+        assert_eq!(FieldKey::new("src_ip").with_address().generate(&br#"{"src_ip":"::ffff:10.0.0.1"}"#.to_vec()), b"10.0.0.1".to_vec());
+        assert_eq!(FieldKey::new("src_ip").with_address().generate(&br#"{"src_ip":"10.0.0.1"}"#.to_vec()), b"10.0.0.1".to_vec());
+        assert_eq!(FieldKey::new("src_ip").generate(&br#"{"src_ip":"::ffff:10.0.0.1"}"#.to_vec()), b"::ffff:10.0.0.1".to_vec());
+    }
+
+    #[test]
+    fn keeps_flowbits_of_alerts() {
+        let event = json!({"dest_ip": "10.0.0.2", "alert": {}, "metadata": {"flowbits": ["ET.http.binary"]}});
+
+        let summary: Value = serde_json::from_slice(&LatestAlert.derive(&event)[0]).expect("Failed to parse");
+
+        assert_eq!(summary["metadata"]["flowbits"], json!(["ET.http.binary"]));
+    }
+
+    #[test]
+    fn derives_alongside_raw_events() {
+        let (sender, receiver) = mpsc::unbounded();
+        let gauge = QueueGauge::new("derived.latest_alert");
+        let events = vec![
+            ALERT.to_string().into_bytes(),
+            r#"{"event_type":"flow","dest_ip":"10.0.0.2"}"#.to_string().into_bytes()
+        ];
+
+        let passed = DerivedStreams::new(stream::iter_ok::<_, ()>(events.clone()))
+            .with_derivation(LatestAlert, sender, Some(gauge.clone()))
+            .collect()
+            .wait()
+            .expect("Failed to derive");
+        let derived = receiver.collect().wait().expect("Failed to receive");
+
+        assert_eq!(passed, events);
+        assert_eq!(derived.len(), 1);
+        assert_eq!(gauge.depth(), 1);
+    }
+}
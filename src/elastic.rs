@@ -0,0 +1,130 @@
+use super::{
+    errors::Error,
+    eve,
+    http::{
+        parse_response,
+        parse_url
+    },
+    serde_json::{
+        self,
+        Value
+    },
+    sink::Sink,
+    source::event_date,
+    topics
+};
+use std::{
+    io::{
+        Read,
+        Write
+    },
+    net::TcpStream,
+    time::Duration
+};
+
+const IO_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_PORT: u16 = 9200;
+
+/// Index of an event, substituting `{event_type}` and `{date}` in `template`.
+pub fn index_name(template: &str, msg: &[u8], event: &Value) -> String {
+    let event_type = eve::event_type(msg).unwrap_or("unknown").to_lowercase();
+    topics::expand_template(template, &event_type).replace("{date}", &event_date(event))
+}
+
+/// Writes events with the bulk API, into daily indices by `event_type`, so small deployments can
+/// run without Kafka. Only plain HTTP is supported; front TLS clusters with a local proxy.
+pub struct ElasticSink {
+    addr: String,
+    path: String,
+    index_template: String
+}
+
+impl ElasticSink {
+    pub fn new(url: &str, index_template: &str) -> Result<ElasticSink, Error> {
+        let (addr, path) = parse_url(url, DEFAULT_PORT)?;
+        Ok(ElasticSink {
+            addr: addr,
+            path: path,
+            index_template: index_template.to_string()
+        })
+    }
+
+    /// Newline delimited bulk request body; events that aren't JSON are skipped.
+    pub fn bulk_body(&self, batch: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![];
+        for msg in batch {
+            let event: Value = match serde_json::from_slice(msg) {
+                Ok(v) => v,
+                Err(_) => {
+                    debug!("Skipping event that isn't JSON");
+                    continue
+                }
+            };
+            let action = json!({"index": {"_index": index_name(&self.index_template, msg, &event)}});
+            body.extend_from_slice(&serde_json::to_vec(&action).expect("Action is always serializable"));
+            body.push(b'\n');
+            body.extend_from_slice(msg);
+            body.push(b'\n');
+        }
+        body
+    }
+
+    fn post(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut stream = TcpStream::connect(self.addr.as_str())?;
+        stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+        let head = format!(
+            "POST {}/_bulk HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path, self.addr, body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl Sink for ElasticSink {
+    fn name(&self) -> &str {
+        "elastic"
+    }
+
+    fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+        let body = self.bulk_body(batch);
+        if body.is_empty() {
+            return Ok( () )
+        }
+        let response = self.post(&body)?;
+        let (status, body) = parse_response(&response)?;
+        if status != 200 {
+            bail!("Bulk request failed with status {}: {}", status, String::from_utf8_lossy(body));
+        }
+        // chunked responses still contain the `"errors":true` marker verbatim
+        if body.windows(13).any(|w| w == b"\"errors\":true") {
+            bail!("Bulk request had item failures: {}", String::from_utf8_lossy(&body[..body.len().min(1024)]));
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_indices_by_type_and_date() {
+        let msg = br#"{"timestamp":"2018-06-01T00:00:00.000000+0000","event_type":"DNS"}"#;
+        let event = json!({"timestamp": "2018-06-01T00:00:00.000000+0000", "event_type": "DNS"});
+
+        assert_eq!(index_name("surikafka-{event_type}-{date}", msg, &event), "surikafka-dns-2018.06.01");
+    }
+
+    #[test]
+    fn builds_bulk_bodies() {
+        let sink = ElasticSink::new("http://es:9200", "eve-{event_type}").expect("Failed to create sink");
+        let body = sink.bulk_body(&[br#"{"event_type":"alert"}"#.to_vec(), b"not json".to_vec()]);
+
+        assert_eq!(body, b"{\"index\":{\"_index\":\"eve-alert\"}}\n{\"event_type\":\"alert\"}\n".to_vec());
+    }
+}
@@ -0,0 +1,175 @@
+use super::{
+    addr::normalize_ip,
+    derive::Derivation,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Signature of meta-alerts raised when a source trips the rule of N.
+pub const META_SIGNATURE: &'static str = "SURIKAFKA host under active attack";
+
+#[derive(Default)]
+struct SourceActivity {
+    signatures: HashMap<u64, Instant>,
+    escalated: Option<Instant>
+}
+
+/// Counts distinct alert signatures per source address over a sliding window, and raises a
+/// meta-alert once a source reaches `threshold` of them, at most once per window per source.
+pub struct RuleOfN {
+    threshold: usize,
+    window: Duration,
+    sources: HashMap<String, SourceActivity>,
+    last_prune: Option<Instant>
+}
+
+impl RuleOfN {
+    pub fn new(threshold: usize, window: Duration) -> RuleOfN {
+        RuleOfN {
+            threshold: threshold.max(1),
+            window: window,
+            sources: HashMap::new(),
+            last_prune: None
+        }
+    }
+
+    /// Forgets signatures seen a window ago and sources left without any, at most once per
+    /// window.
+    fn prune(&mut self, now: Instant) {
+        match self.last_prune {
+            Some(last) if now < last + self.window => return,
+            _ => ()
+        }
+        let window = self.window;
+        for activity in self.sources.values_mut() {
+            activity.signatures.retain(|_, seen| now < *seen + window);
+        }
+        self.sources.retain(|_, activity| {
+            !activity.signatures.is_empty() || activity.escalated.map(|e| now < e + window).unwrap_or(false)
+        });
+        self.last_prune = Some(now);
+    }
+
+    pub fn derive_at(&mut self, event: &Value, now: Instant) -> Vec<Vec<u8>> {
+        self.prune(now);
+        let source = match event.get("src_ip").and_then(Value::as_str) {
+            Some(s) => normalize_ip(s),
+            None => return vec![]
+        };
+        let sid = match event.pointer("/alert/signature_id").and_then(Value::as_u64) {
+            Some(0) | None => return vec![],
+            Some(sid) => sid
+        };
+        let (window, threshold) = (self.window, self.threshold);
+        let activity = self.sources.entry(source.clone()).or_insert_with(SourceActivity::default);
+        activity.signatures.insert(sid, now);
+        activity.signatures.retain(|_, seen| now < *seen + window);
+        if activity.signatures.len() < threshold {
+            return vec![]
+        }
+        if let Some(escalated) = activity.escalated {
+            if now < escalated + window {
+                return vec![]
+            }
+        }
+        activity.escalated = Some(now);
+
+        let mut sids: Vec<u64> = activity.signatures.keys().cloned().collect();
+        sids.sort();
+        let meta = json!({
+            "timestamp": event.get("timestamp"),
+            "event_type": "alert",
+            "src_ip": source,
+            "alert": {
+                "action": "allowed",
+                "signature_id": 0,
+                "signature": META_SIGNATURE,
+                "category": "Meta alert",
+                "severity": 1
+            },
+            "meta_alert": {
+                "distinct_signatures": sids.len(),
+                "window_secs": window.as_secs(),
+                "signature_ids": sids
+            }
+        });
+        vec![ serde_json::to_vec(&meta).expect("Meta-alert is always serializable") ]
+    }
+}
+
+impl Derivation for RuleOfN {
+    fn event_type(&self) -> &str {
+        "alert"
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        self.derive_at(event, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(src_ip: &str, sid: u64) -> Value {
+        json!({"event_type": "alert", "src_ip": src_ip, "alert": {"signature_id": sid}})
+    }
+
+    #[test]
+    fn escalates_distinct_signatures() {
+        let mut rule = RuleOfN::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(rule.derive_at(&alert("10.0.0.1", 1), now).is_empty());
+        assert!(rule.derive_at(&alert("10.0.0.1", 1), now).is_empty());
+        assert!(rule.derive_at(&alert("10.0.0.2", 2), now).is_empty());
+        assert!(rule.derive_at(&alert("10.0.0.1", 2), now).is_empty());
+        let records = rule.derive_at(&alert("10.0.0.1", 3), now + Duration::from_secs(1));
+
+        let meta: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+        assert_eq!(meta["src_ip"], "10.0.0.1");
+        assert_eq!(meta["alert"]["signature"], META_SIGNATURE);
+        assert_eq!(meta["meta_alert"]["signature_ids"], json!([1, 2, 3]));
+    }
+
+    #[test]
+    fn counts_every_form_of_a_source_together() {
+        let mut rule = RuleOfN::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(rule.derive_at(&alert("::ffff:10.0.0.1", 1), now).is_empty());
+        let records = rule.derive_at(&alert("10.0.0.1", 2), now);
+
+        let meta: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+        assert_eq!(meta["src_ip"], "10.0.0.1");
+    }
+
+    #[test]
+    fn escalates_once_per_window() {
+        let mut rule = RuleOfN::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(rule.derive_at(&alert("10.0.0.1", 1), now).is_empty());
+        assert_eq!(rule.derive_at(&alert("10.0.0.1", 2), now).len(), 1);
+        assert!(rule.derive_at(&alert("10.0.0.1", 3), now + Duration::from_secs(30)).is_empty());
+        assert!(rule.derive_at(&alert("10.0.0.1", 4), now + Duration::from_secs(120)).is_empty());
+        assert_eq!(rule.derive_at(&alert("10.0.0.1", 5), now + Duration::from_secs(121)).len(), 1);
+    }
+
+    #[test]
+    fn ignores_synthetic_alerts() {
+        let mut rule = RuleOfN::new(1, Duration::from_secs(60));
+
+        assert!(rule.derive_at(&alert("10.0.0.1", 0), Instant::now()).is_empty());
+        assert!(rule.derive_at(&json!({"alert": {"signature_id": 1}}), Instant::now()).is_empty());
+    }
+}
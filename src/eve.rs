@@ -0,0 +1,203 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    key::KeyGenerator,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    self,
+    borrow::Cow,
+    str::FromStr
+};
+
+/// Field naming the capture interface label of an event, added by `iface::Labeled`.
+pub const IFACE_LABEL: &'static str = "iface_label";
+
+/// Fields of an EVE record that identify where traffic was captured, usable for keying and
+/// routing in multi-tenant and multi-vlan deployments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    Vlan,
+    Interface,
+    Tenant,
+    /// Label of the source an event was read from, see `iface::Labeled`
+    Label
+}
+
+impl Dimension {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Dimension::Vlan => "vlan",
+            Dimension::Interface => "in_iface",
+            Dimension::Tenant => "tenant_id",
+            Dimension::Label => IFACE_LABEL
+        }
+    }
+
+    /// Extracts the dimension from an EVE record. Suricata reports `vlan` as an array when
+    /// packets are QinQ tagged, which is joined with `.` from the outer tag inward.
+    pub fn extract(&self, event: &Value) -> Option<String> {
+        match event.get(self.name())? {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Array(tags) => {
+                let tags: Vec<String> = tags.iter()
+                    .filter_map(|t| t.as_u64())
+                    .map(|t| t.to_string())
+                    .collect();
+                if tags.is_empty() {
+                    None
+                } else {
+                    Some(tags.join("."))
+                }
+            }
+            _ => None
+        }
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Dimension, Error> {
+        match s.trim() {
+            "vlan" => Ok(Dimension::Vlan),
+            "in_iface" | "iface" | "interface" => Ok(Dimension::Interface),
+            "tenant_id" | "tenant" => Ok(Dimension::Tenant),
+            "iface_label" | "label" => Ok(Dimension::Label),
+            other => Err(Error::from_kind(ErrorKind::UnknownDimension(other.to_string())))
+        }
+    }
+}
+
+impl std::fmt::Display for Dimension {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub fn parse_dimensions(s: &str) -> Result<Vec<Dimension>, Error> {
+    s.split(',')
+        .filter(|d| !d.trim().is_empty())
+        .map(Dimension::from_str)
+        .collect()
+}
+
+fn string_field<'a>(msg: &'a [u8], needle: &[u8]) -> Option<&'a str> {
+    let start = msg.windows(needle.len()).position(|w| w == needle)? + needle.len();
+    let len = msg[start..].iter().position(|&b| b == b'"')?;
+    std::str::from_utf8(&msg[start..start + len]).ok()
+}
+
+/// `event_type` of a compact EVE record, found without parsing the record. Suricata writes it
+/// near the start of every record, before any nested object that could contain the same name.
+pub fn event_type(msg: &[u8]) -> Option<&str> {
+    string_field(msg, b"\"event_type\":\"")
+}
+
+/// `timestamp` of a compact EVE record, found without parsing the record. Like `event_type`,
+/// Suricata writes it first.
+pub fn timestamp(msg: &[u8]) -> Option<&str> {
+    string_field(msg, b"\"timestamp\":\"")
+}
+
+/// `iface_label` of a record. `iface::Labeled` writes it first, where it is found without
+/// parsing the record; stages that re-serialize records may move it, so other records are
+/// parsed for it.
+pub fn iface_label(msg: &[u8]) -> Option<Cow<str>> {
+    if msg.starts_with(b"{\"iface_label\":\"") {
+        if let Some(label) = string_field(msg, b"\"iface_label\":\"") {
+            return Some(Cow::Borrowed(label))
+        }
+    }
+    let event: Value = serde_json::from_slice(msg).ok()?;
+    event.get(IFACE_LABEL).and_then(Value::as_str).map(|label| Cow::Owned(label.to_string()))
+}
+
+/// Keys events by one or more capture dimensions, so all events from a tenant or vlan share a
+/// partition. Missing dimensions contribute an empty segment.
+pub struct DimensionGenerator {
+    dimensions: Vec<Dimension>
+}
+
+impl DimensionGenerator {
+    pub fn new(dimensions: Vec<Dimension>) -> DimensionGenerator {
+        DimensionGenerator {
+            dimensions: dimensions
+        }
+    }
+}
+
+impl KeyGenerator for DimensionGenerator {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        let event: Value = serde_json::from_slice(msg).unwrap_or(Value::Null);
+        let parts: Vec<String> = self.dimensions.iter()
+            .map(|d| d.extract(&event).unwrap_or_else(String::new))
+            .collect();
+        parts.join("|").into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_dimensions() {
+        let event: Value = serde_json::from_str(
+            r#"{"event_type":"alert","vlan":[100,200],"in_iface":"eth1","tenant_id":3}"#
+        ).expect("Failed to parse");
+
+        assert_eq!(Dimension::Vlan.extract(&event), Some("100.200".to_string()));
+        assert_eq!(Dimension::Interface.extract(&event), Some("eth1".to_string()));
+        assert_eq!(Dimension::Tenant.extract(&event), Some("3".to_string()));
+    }
+
+    #[test]
+    fn missing_dimensions() {
+        let event: Value = serde_json::from_str(r#"{"event_type":"alert","vlan":[]}"#)
+            .expect("Failed to parse");
+
+        assert_eq!(Dimension::Vlan.extract(&event), None);
+        assert_eq!(Dimension::Tenant.extract(&event), None);
+    }
+
+    #[test]
+    fn finds_event_type() {
+        assert_eq!(event_type(br#"{"timestamp":"x","event_type":"alert","alert":{}}"#), Some("alert"));
+        assert_eq!(event_type(br#"{"timestamp":"x"}"#), None);
+        assert_eq!(event_type(br#"{"event_type":"dns"#), None);
+        assert_eq!(timestamp(br#"{"timestamp":"x","event_type":"alert"}"#), Some("x"));
+        assert_eq!(iface_label(br#"{"iface_label":"tap","event_type":"alert"}"#), Some(Cow::Borrowed("tap")));
+        assert_eq!(iface_label(br#"{"event_type":"alert","iface_label":"tap"}"#), Some(Cow::Borrowed("tap")));
+        assert_eq!(iface_label(br#"{"event_type":"alert","http":{"iface_label":"tap"}}"#), None);
+        assert_eq!(iface_label(br#"{"event_type":"alert"}"#), None);
+    }
+
+    #[test]
+    fn parses_dimension_list() {
+        assert_eq!(
+            parse_dimensions("tenant, vlan").expect("Failed to parse"),
+            vec![Dimension::Tenant, Dimension::Vlan]
+        );
+        assert!(parse_dimensions("vlan,colour").is_err());
+    }
+
+    #[test]
+    fn dimension_generator() {
+        let generator = DimensionGenerator::new(vec![Dimension::Tenant, Dimension::Vlan]);
+
+        let key = generator.generate(&r#"{"tenant_id":1,"vlan":[10]}"#.to_string().into_bytes());
+        let untagged = generator.generate(&r#"{"tenant_id":1}"#.to_string().into_bytes());
+
+        assert_eq!(key, b"1|10".to_vec());
+        assert_eq!(untagged, b"1|".to_vec());
+    }
+}
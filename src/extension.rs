@@ -0,0 +1,591 @@
+//! Stage plugins loaded at runtime from a directory, rescanned on SIGHUP.
+//!
+//! A plugin is a shared object exporting
+//!
+//! ```c
+//! const struct StageDeclaration *surikafka_stages(size_t *count);
+//! ```
+//!
+//! returning the stages it declares. Each stage processes one record at a time, returning
+//! `STAGE_KEEP`, `STAGE_DROP`, or a negative error, and may hand back an output buffer: the
+//! rewritten record of a transform or the key of a keyer. WASM modules declare and run stages
+//! the same way, see `wasm`.
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    guard::StageGuard,
+    key::KeyGenerator,
+    metrics::Counter,
+    serde_json::Value,
+    sink::Sink
+};
+#[cfg(feature = "plugins")]
+use super::wasm;
+use std::{
+    self,
+    any::Any,
+    os::raw::{
+        c_char,
+        c_int
+    },
+    path::{
+        Path,
+        PathBuf
+    },
+    ptr,
+    slice,
+    sync::{
+        Arc,
+        RwLock
+    }
+};
+
+/// Version of the declaration layout; plugins built against another version are rejected.
+pub const ABI_VERSION: u32 = 1;
+pub const STAGE_KEEP: c_int = 0;
+pub const STAGE_DROP: c_int = 1;
+
+/// Buffer a stage hands back, released with its `free` once copied.
+#[repr(C)]
+pub struct StageOutput {
+    pub data: *mut u8,
+    pub len: usize,
+    pub free: Option<extern "C" fn(*mut u8, usize)>
+}
+
+pub type ProcessFn = extern "C" fn(*const u8, usize, *mut StageOutput) -> c_int;
+
+#[repr(C)]
+pub struct StageDeclaration {
+    pub abi_version: u32,
+    pub kind: u32,
+    pub name: *const c_char,
+    pub process: ProcessFn
+}
+
+pub type DeclareFn = extern "C" fn(*mut usize) -> *const StageDeclaration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageKind {
+    /// Keeps or drops records
+    Filter,
+    /// Rewrites or drops records
+    Transform,
+    /// Computes record keys, used with --key-plugin
+    Keyer,
+    /// Receives every record, alongside Kafka
+    Sink
+}
+
+impl StageKind {
+    pub fn from_abi(kind: u32) -> Option<StageKind> {
+        match kind {
+            0 => Some(StageKind::Filter),
+            1 => Some(StageKind::Transform),
+            2 => Some(StageKind::Keyer),
+            3 => Some(StageKind::Sink),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StageKind::Filter => "filter",
+            StageKind::Transform => "transform",
+            StageKind::Keyer => "keyer",
+            StageKind::Sink => "sink"
+        }
+    }
+}
+
+/// What a stage did with a record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Keep the record, with its output if the stage produced one
+    Keep(Option<Vec<u8>>),
+    Drop
+}
+
+/// Runs a stage's records: a function of a shared object, or an export of a WASM module.
+enum Process {
+    Native(ProcessFn),
+    #[cfg(feature = "plugins")]
+    Wasm(Arc<wasm::Module>, String)
+}
+
+/// One stage declared by a loaded plugin.
+pub struct Stage {
+    pub name: String,
+    pub kind: StageKind,
+    process: Process,
+    /// Keeps the plugin loaded while its stages are in use
+    _library: Arc<Any + Send + Sync>
+}
+
+fn call_native(process: ProcessFn, record: &[u8]) -> (c_int, Option<Vec<u8>>) {
+    let mut output = StageOutput {
+        data: ptr::null_mut(),
+        len: 0,
+        free: None
+    };
+    let rc = process(record.as_ptr(), record.len(), &mut output);
+    let data = if output.data.is_null() {
+        None
+    } else {
+        let data = unsafe { slice::from_raw_parts(output.data, output.len) }.to_vec();
+        if let Some(free) = output.free {
+            free(output.data, output.len);
+        }
+        Some(data)
+    };
+    (rc, data)
+}
+
+impl Stage {
+    pub fn call(&self, record: &[u8]) -> Result<Outcome, Error> {
+        let (rc, data) = match self.process {
+            Process::Native(process) => call_native(process, record),
+            #[cfg(feature = "plugins")]
+            Process::Wasm(ref module, ref export) => module.call(export, record)?
+        };
+        match rc {
+            STAGE_KEEP => Ok(Outcome::Keep(data)),
+            STAGE_DROP => Ok(Outcome::Drop),
+            _ => bail!("Plugin {} {} failed with {}", self.kind.name(), self.name, rc)
+        }
+    }
+}
+
+/// Load status of one file of the plugin directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginStatus {
+    pub path: PathBuf,
+    pub stages: Vec<(StageKind, String)>,
+    pub error: Option<String>
+}
+
+impl PluginStatus {
+    pub fn to_value(&self) -> Value {
+        let stages: Vec<Value> = self.stages.iter()
+            .map(|&(kind, ref name)| json!({"kind": kind.name(), "name": name}))
+            .collect();
+        json!({
+            "path": self.path.display().to_string(),
+            "loaded": self.error.is_none(),
+            "stages": stages,
+            "error": self.error
+        })
+    }
+}
+
+#[cfg(feature = "plugins")]
+fn load_library(path: &Path) -> Result<Vec<Stage>, Error> {
+    use super::libloading::{
+        Library,
+        Symbol
+    };
+    use std::ffi::CStr;
+
+    let library = Library::new(path)?;
+    let declarations = unsafe {
+        let declare: Symbol<DeclareFn> = library.get(b"surikafka_stages\0")?;
+        let mut count = 0;
+        let declarations = (*declare)(&mut count);
+        if declarations.is_null() {
+            bail!("Plugin declared no stages");
+        }
+        slice::from_raw_parts(declarations, count)
+    };
+    let library: Arc<Any + Send + Sync> = Arc::new(library);
+    declarations.iter().map(|declaration| {
+        if declaration.abi_version != ABI_VERSION {
+            bail!("Plugin built for ABI version {}, expected {}", declaration.abi_version, ABI_VERSION);
+        }
+        let kind = StageKind::from_abi(declaration.kind)
+            .ok_or_else(|| Error::from(format!("Unknown stage kind {}", declaration.kind)))?;
+        if declaration.name.is_null() {
+            bail!("Plugin declared a {} without a name", kind.name());
+        }
+        Ok(Stage {
+            name: unsafe { CStr::from_ptr(declaration.name) }.to_str()?.to_string(),
+            kind: kind,
+            process: Process::Native(declaration.process),
+            _library: library.clone()
+        })
+    }).collect()
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_library(_path: &Path) -> Result<Vec<Stage>, Error> {
+    bail!("Shared object plugins require building with the plugins feature")
+}
+
+#[cfg(feature = "plugins")]
+fn load_wasm(path: &Path) -> Result<Vec<Stage>, Error> {
+    let (module, declarations) = wasm::Module::load(path)?;
+    let module = Arc::new(module);
+    declarations.into_iter().map(|declaration| {
+        if declaration.abi_version != ABI_VERSION {
+            bail!("Plugin built for ABI version {}, expected {}", declaration.abi_version, ABI_VERSION);
+        }
+        let kind = StageKind::from_abi(declaration.kind)
+            .ok_or_else(|| Error::from(format!("Unknown stage kind {}", declaration.kind)))?;
+        Ok(Stage {
+            name: declaration.name,
+            kind: kind,
+            process: Process::Wasm(module.clone(), declaration.export),
+            _library: Arc::new(())
+        })
+    }).collect()
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_wasm(_path: &Path) -> Result<Vec<Stage>, Error> {
+    bail!("WASM plugins require building with the plugins feature")
+}
+
+fn load(path: &Path) -> Result<Vec<Stage>, Error> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("so") | Some("dylib") => load_library(path),
+        Some("wasm") => load_wasm(path),
+        _ => bail!("Not a plugin")
+    }
+}
+
+fn is_plugin(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("so") | Some("dylib") | Some("wasm") => true,
+        _ => false
+    }
+}
+
+#[derive(Default)]
+struct Loaded {
+    stages: Vec<Arc<Stage>>,
+    status: Vec<PluginStatus>
+}
+
+/// Stages of the plugins in a directory. Clones share the stages, so a rescan is seen by every
+/// pipeline stage using them; stages of removed plugins are unloaded once no longer in use.
+#[derive(Clone, Default)]
+pub struct PluginSet {
+    loaded: Arc<RwLock<Loaded>>
+}
+
+impl PluginSet {
+    /// Loads the plugins in `dir`, replacing those loaded before. Plugins that fail to load are
+    /// logged and reported in `status`; returns the number of stages loaded.
+    pub fn scan<P: AsRef<Path>>(&self, dir: P) -> Result<usize, Error> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir.as_ref())?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_plugin(path))
+            .collect();
+        paths.sort();
+
+        let mut loaded = Loaded::default();
+        for path in paths {
+            match load(&path) {
+                Ok(stages) => {
+                    info!("Loaded {} stages from plugin {}", stages.len(), path.display());
+                    loaded.status.push(PluginStatus {
+                        path: path,
+                        stages: stages.iter().map(|s| (s.kind, s.name.clone())).collect(),
+                        error: None
+                    });
+                    loaded.stages.extend(stages.into_iter().map(Arc::new));
+                }
+                Err(e) => {
+                    error!("Failed to load plugin {}: {}", path.display(), e);
+                    loaded.status.push(PluginStatus {
+                        path: path,
+                        stages: vec![],
+                        error: Some(e.to_string())
+                    });
+                }
+            }
+        }
+        let count = loaded.stages.len();
+        *self.loaded.write().expect("Plugin lock poisoned") = loaded;
+        Ok(count)
+    }
+
+    /// Stages of the given kinds, in directory order.
+    pub fn stages(&self, kinds: &[StageKind]) -> Vec<Arc<Stage>> {
+        let loaded = self.loaded.read().expect("Plugin lock poisoned");
+        loaded.stages.iter().filter(|s| kinds.contains(&s.kind)).cloned().collect()
+    }
+
+    pub fn stage(&self, kind: StageKind, name: &str) -> Option<Arc<Stage>> {
+        let loaded = self.loaded.read().expect("Plugin lock poisoned");
+        loaded.stages.iter().find(|s| s.kind == kind && s.name == name).cloned()
+    }
+
+    pub fn status(&self) -> Vec<PluginStatus> {
+        self.loaded.read().expect("Plugin lock poisoned").status.clone()
+    }
+}
+
+/// Runs the filter and transform stages of plugins over the events of a stream. Records a stage
+/// fails on are passed on unchanged; dropped records are counted in `plugins.dropped`.
+pub struct PluginStream<S> {
+    inner: S,
+    plugins: PluginSet,
+    guard: StageGuard,
+    dropped: Counter
+}
+
+impl<S> PluginStream<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, plugins: PluginSet) -> PluginStream<S> {
+        PluginStream {
+            inner: inner,
+            plugins: plugins,
+            guard: StageGuard::new("plugins"),
+            dropped: Counter::new("plugins.dropped")
+        }
+    }
+
+    pub fn with_guard(mut self, guard: StageGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    pub fn with_drop_counter(mut self, counter: Counter) -> Self {
+        self.dropped = counter;
+        self
+    }
+}
+
+impl<S> Stream for PluginStream<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    let stages = self.plugins.stages(&[StageKind::Filter, StageKind::Transform]);
+                    let mut dropped = false;
+                    let processed = self.guard.run(msg, |m| {
+                        let mut current: Option<Vec<u8>> = None;
+                        for stage in stages.iter() {
+                            let outcome = stage.call(current.as_ref().unwrap_or(m));
+                            match outcome {
+                                Ok(Outcome::Keep(Some(ref output))) if stage.kind == StageKind::Transform => {
+                                    current = Some(output.clone());
+                                }
+                                Ok(Outcome::Keep(_)) => (),
+                                Ok(Outcome::Drop) => {
+                                    dropped = true;
+                                    return None
+                                }
+                                Err(e) => warn!("{}", e)
+                            }
+                        }
+                        current
+                    });
+                    match processed {
+                        Some(_) if dropped => self.dropped.incr(),
+                        Some(msg) => return Ok(Async::Ready(Some(msg))),
+                        None => ()
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Keys records with the keyer stage `name`, if loaded; records are unkeyed otherwise.
+pub struct PluginKeyGenerator {
+    plugins: PluginSet,
+    name: String
+}
+
+impl PluginKeyGenerator {
+    pub fn new(plugins: PluginSet, name: &str) -> PluginKeyGenerator {
+        PluginKeyGenerator {
+            plugins: plugins,
+            name: name.to_string()
+        }
+    }
+}
+
+impl KeyGenerator for PluginKeyGenerator {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Vec<u8> {
+        let stage = match self.plugins.stage(StageKind::Keyer, &self.name) {
+            Some(stage) => stage,
+            None => return vec![]
+        };
+        match stage.call(msg) {
+            Ok(Outcome::Keep(Some(key))) => key,
+            Ok(_) => vec![],
+            Err(e) => {
+                warn!("{}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Writes records to the sink stage `name`. Writes fail while the stage isn't loaded.
+pub struct PluginSink {
+    plugins: PluginSet,
+    name: String
+}
+
+impl PluginSink {
+    pub fn new(plugins: PluginSet, name: &str) -> PluginSink {
+        PluginSink {
+            plugins: plugins,
+            name: name.to_string()
+        }
+    }
+}
+
+impl Sink for PluginSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+        let stage = self.plugins.stage(StageKind::Sink, &self.name)
+            .ok_or_else(|| Error::from(format!("Plugin sink {} is not loaded", self.name)))?;
+        for msg in batch {
+            stage.call(msg)?;
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream
+    };
+
+    extern "C" fn drop_stats(record: *const u8, len: usize, _output: *mut StageOutput) -> c_int {
+        let record = unsafe { slice::from_raw_parts(record, len) };
+        if record.windows(7).any(|w| w == b"\"stats\"") { STAGE_DROP } else { STAGE_KEEP }
+    }
+
+    extern "C" fn free_output(data: *mut u8, len: usize) {
+        unsafe { Vec::from_raw_parts(data, len, len) };
+    }
+
+    extern "C" fn upper_case(record: *const u8, len: usize, output: *mut StageOutput) -> c_int {
+        let mut upper = unsafe { slice::from_raw_parts(record, len) }.to_ascii_uppercase().into_boxed_slice();
+        unsafe {
+            (*output).data = upper.as_mut_ptr();
+            (*output).len = upper.len();
+            (*output).free = Some(free_output);
+        }
+        std::mem::forget(upper);
+        STAGE_KEEP
+    }
+
+    fn stage(name: &str, kind: StageKind, process: ProcessFn) -> Arc<Stage> {
+        Arc::new(Stage {
+            name: name.to_string(),
+            kind: kind,
+            process: Process::Native(process),
+            _library: Arc::new(())
+        })
+    }
+
+    #[test]
+    fn runs_plugin_stages() {
+        let plugins = PluginSet::default();
+        plugins.loaded.write().expect("Plugin lock poisoned").stages = vec![
+            stage("no_stats", StageKind::Filter, drop_stats),
+            stage("upper", StageKind::Transform, upper_case),
+            stage("upper_key", StageKind::Keyer, upper_case)
+        ];
+        let events = vec![br#"{"event_type":"stats"}"#.to_vec(), br#"{"event_type":"alert"}"#.to_vec()];
+        let dropped = Counter::new("plugins.dropped");
+
+        let sent: Vec<Vec<u8>> = PluginStream::new(stream::iter_ok::<_, ()>(events), plugins.clone())
+            .with_drop_counter(dropped.clone())
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(sent, vec![br#"{"EVENT_TYPE":"ALERT"}"#.to_vec()]);
+        assert_eq!(dropped.value(), 1);
+        assert_eq!(PluginKeyGenerator::new(plugins.clone(), "upper_key").generate(&b"k1".to_vec()), b"K1".to_vec());
+        assert_eq!(PluginKeyGenerator::new(plugins, "missing").generate(&b"k1".to_vec()), Vec::<u8>::new());
+    }
+
+    /// A module declaring `no_stats`, a filter dropping records starting with `s`, and `upper`, a
+    /// transform upper casing records into a buffer of its own. Its allocator bumps through its
+    /// one page of memory and it exports no `surikafka_free`.
+    #[cfg(feature = "plugins")]
+    const WASM_STAGES: &'static [u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0d, 0x02, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x03, 0x7f, 0x7f,
+        0x7f, 0x01, 0x7f, 0x03, 0x05, 0x04, 0x00, 0x00, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x06, 0x07, 0x01, 0x7f, 0x01,
+        0x41, 0x80, 0x08, 0x0b, 0x07, 0x42, 0x05, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x0f, 0x73, 0x75, 0x72,
+        0x69, 0x6b, 0x61, 0x66, 0x6b, 0x61, 0x5f, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x00, 0x00, 0x10, 0x73, 0x75, 0x72, 0x69, 0x6b,
+        0x61, 0x66, 0x6b, 0x61, 0x5f, 0x73, 0x74, 0x61, 0x67, 0x65, 0x73, 0x00, 0x01, 0x05, 0x75, 0x70, 0x70, 0x65, 0x72, 0x00,
+        0x02, 0x08, 0x6e, 0x6f, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x73, 0x00, 0x03, 0x0a, 0x90, 0x01, 0x04, 0x11, 0x01, 0x01, 0x7f,
+        0x23, 0x00, 0x21, 0x01, 0x23, 0x00, 0x20, 0x00, 0x6a, 0x24, 0x00, 0x20, 0x01, 0x0b, 0x0c, 0x00, 0x20, 0x00, 0x41, 0xfd,
+        0x00, 0x36, 0x02, 0x00, 0x41, 0x10, 0x0b, 0x5a, 0x01, 0x03, 0x7f, 0x20, 0x01, 0x10, 0x00, 0x21, 0x05, 0x02, 0x40, 0x03,
+        0x40, 0x20, 0x03, 0x20, 0x01, 0x4f, 0x0d, 0x01, 0x20, 0x00, 0x20, 0x03, 0x6a, 0x2d, 0x00, 0x00, 0x21, 0x04, 0x20, 0x05,
+        0x20, 0x03, 0x6a, 0x20, 0x04, 0x41, 0xe1, 0x00, 0x4f, 0x20, 0x04, 0x41, 0xfa, 0x00, 0x4d, 0x71, 0x04, 0x7f, 0x20, 0x04,
+        0x41, 0x20, 0x6b, 0x05, 0x20, 0x04, 0x0b, 0x3a, 0x00, 0x00, 0x20, 0x03, 0x41, 0x01, 0x6a, 0x21, 0x03, 0x0c, 0x00, 0x0b,
+        0x0b, 0x20, 0x02, 0x20, 0x05, 0x36, 0x02, 0x00, 0x20, 0x02, 0x20, 0x01, 0x36, 0x02, 0x04, 0x41, 0x00, 0x0b, 0x14, 0x00,
+        0x20, 0x01, 0x45, 0x04, 0x40, 0x41, 0x00, 0x0f, 0x0b, 0x20, 0x00, 0x2d, 0x00, 0x00, 0x41, 0xf3, 0x00, 0x46, 0x0b, 0x0b,
+        0x83, 0x01, 0x01, 0x00, 0x41, 0x10, 0x0b, 0x7d, 0x5b, 0x7b, 0x22, 0x61, 0x62, 0x69, 0x5f, 0x76, 0x65, 0x72, 0x73, 0x69,
+        0x6f, 0x6e, 0x22, 0x3a, 0x31, 0x2c, 0x22, 0x6b, 0x69, 0x6e, 0x64, 0x22, 0x3a, 0x30, 0x2c, 0x22, 0x6e, 0x61, 0x6d, 0x65,
+        0x22, 0x3a, 0x22, 0x6e, 0x6f, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x73, 0x22, 0x2c, 0x22, 0x65, 0x78, 0x70, 0x6f, 0x72, 0x74,
+        0x22, 0x3a, 0x22, 0x6e, 0x6f, 0x5f, 0x73, 0x74, 0x61, 0x74, 0x73, 0x22, 0x7d, 0x2c, 0x7b, 0x22, 0x61, 0x62, 0x69, 0x5f,
+        0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x22, 0x3a, 0x31, 0x2c, 0x22, 0x6b, 0x69, 0x6e, 0x64, 0x22, 0x3a, 0x31, 0x2c,
+        0x22, 0x6e, 0x61, 0x6d, 0x65, 0x22, 0x3a, 0x22, 0x75, 0x70, 0x70, 0x65, 0x72, 0x22, 0x2c, 0x22, 0x65, 0x78, 0x70, 0x6f,
+        0x72, 0x74, 0x22, 0x3a, 0x22, 0x75, 0x70, 0x70, 0x65, 0x72, 0x22, 0x7d, 0x5d
+    ];
+
+    #[cfg(feature = "plugins")]
+    #[test]
+    fn runs_wasm_stages() {
+        let dir = std::env::temp_dir().join(format!("surikafka-wasm-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create plugin dir");
+        std::fs::write(dir.join("stages.wasm"), WASM_STAGES).expect("Failed to write");
+        let plugins = PluginSet::default();
+
+        assert_eq!(plugins.scan(&dir).expect("Failed to scan"), 2);
+        assert_eq!(plugins.status()[0].stages, vec![ (StageKind::Filter, "no_stats".to_string()), (StageKind::Transform, "upper".to_string()) ]);
+
+        let events = vec![b"stats".to_vec(), br#"{"event_type":"alert"}"#.to_vec()];
+        let sent: Vec<Vec<u8>> = PluginStream::new(stream::iter_ok::<_, ()>(events), plugins.clone())
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(sent, vec![br#"{"EVENT_TYPE":"ALERT"}"#.to_vec()]);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn reports_load_failures() {
+        let dir = std::env::temp_dir().join(format!("surikafka-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create plugin dir");
+        std::fs::write(dir.join("broken.so"), b"not a library").expect("Failed to write");
+        std::fs::write(dir.join("filter.wasm"), b"\0asm").expect("Failed to write");
+        std::fs::write(dir.join("README"), b"plugins").expect("Failed to write");
+        let plugins = PluginSet::default();
+
+        assert_eq!(plugins.scan(&dir).expect("Failed to scan"), 0);
+
+        let status = plugins.status();
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|s| s.error.is_some()));
+        assert_eq!(status[1].to_value()["loaded"], false);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+}
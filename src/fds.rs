@@ -0,0 +1,212 @@
+use super::{
+    futures::Poll,
+    metrics::{
+        QueueGauge,
+        Registry
+    },
+    tokio::io::{
+        AsyncRead,
+        AsyncWrite
+    }
+};
+use std::{
+    self,
+    io::{
+        Read,
+        Write
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Kind of descriptor owned by the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdKind {
+    File,
+    Socket
+}
+
+impl FdKind {
+    pub fn gauge_name(&self) -> &'static str {
+        match *self {
+            FdKind::File => "fds.files",
+            FdKind::Socket => "fds.sockets"
+        }
+    }
+}
+
+/// Number of descriptors open in this process, counted from `/proc/self/fd`. `None` where
+/// procfs isn't available.
+pub fn process_open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd").ok().map(|entries| entries.count())
+}
+
+/// Counts the files and sockets the pipeline owns, as `fds.files` and `fds.sockets` gauges.
+#[derive(Clone)]
+pub struct FdAccounting {
+    files: QueueGauge,
+    sockets: QueueGauge
+}
+
+impl FdAccounting {
+    pub fn new(registry: &Registry) -> FdAccounting {
+        FdAccounting {
+            files: registry.queue(FdKind::File.gauge_name()),
+            sockets: registry.queue(FdKind::Socket.gauge_name())
+        }
+    }
+
+    fn gauge(&self, kind: FdKind) -> &QueueGauge {
+        match kind {
+            FdKind::File => &self.files,
+            FdKind::Socket => &self.sockets
+        }
+    }
+
+    /// Counts `inner` as open until the returned handle is dropped.
+    pub fn track<T>(&self, kind: FdKind, inner: T) -> Tracked<T> {
+        let gauge = self.gauge(kind).clone();
+        gauge.add(1);
+        Tracked {
+            inner: inner,
+            gauge: gauge
+        }
+    }
+
+    pub fn open(&self, kind: FdKind) -> usize {
+        self.gauge(kind).depth()
+    }
+
+    pub fn total(&self) -> usize {
+        self.files.depth() + self.sockets.depth()
+    }
+}
+
+/// Handle counted by `FdAccounting` while it is alive; closing happens when `inner` drops.
+pub struct Tracked<T> {
+    inner: T,
+    gauge: QueueGauge
+}
+
+impl<T> Tracked<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        self.gauge.sub(1);
+    }
+}
+
+impl<T: Read> Read for Tracked<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Tracked<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Tracked<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Tracked<T> {
+    fn shutdown(&mut self) -> Poll<(), std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Handles of rotated files, kept open for a grace period so records written just before the
+/// rotation can still be read, then closed.
+pub struct RetiredHandles<T> {
+    grace: Duration,
+    handles: Vec<(Instant, T)>
+}
+
+impl<T> RetiredHandles<T> {
+    pub fn new(grace: Duration) -> RetiredHandles<T> {
+        RetiredHandles {
+            grace: grace,
+            handles: vec![]
+        }
+    }
+
+    pub fn retire(&mut self, handle: T, now: Instant) {
+        self.handles.push( (now + self.grace, handle) );
+    }
+
+    /// Closes the handles whose grace period has expired by `now`, returning how many were closed.
+    pub fn close_expired(&mut self, now: Instant) -> usize {
+        let before = self.handles.len();
+        self.handles.retain(|&(deadline, _)| deadline > now);
+        before - self.handles.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_until_dropped() {
+        let registry = Registry::default();
+        let accounting = FdAccounting::new(&registry);
+
+        let file = accounting.track(FdKind::File, std::io::Cursor::new(vec![1u8]));
+        let socket = accounting.track(FdKind::Socket, std::io::Cursor::new(vec![2u8]));
+
+        assert_eq!(accounting.open(FdKind::File), 1);
+        assert_eq!(accounting.total(), 2);
+
+        drop(file);
+        drop(socket);
+
+        assert_eq!(accounting.total(), 0);
+        assert_eq!(registry.queue("fds.files").high_watermark(), 1);
+    }
+
+    #[test]
+    fn reads_through_tracked_handles() {
+        let accounting = FdAccounting::new(&Registry::default());
+        let mut tracked = accounting.track(FdKind::File, std::io::Cursor::new(b"event".to_vec()));
+
+        let mut out = String::new();
+        tracked.read_to_string(&mut out).expect("Failed to read");
+
+        assert_eq!(out, "event");
+    }
+
+    #[test]
+    fn closes_retired_handles_after_grace() {
+        let accounting = FdAccounting::new(&Registry::default());
+        let mut retired = RetiredHandles::new(Duration::from_secs(30));
+        let now = Instant::now();
+
+        retired.retire(accounting.track(FdKind::File, ()), now);
+        retired.retire(accounting.track(FdKind::File, ()), now + Duration::from_secs(20));
+
+        assert_eq!(retired.close_expired(now + Duration::from_secs(40)), 1);
+        assert_eq!(accounting.open(FdKind::File), 1);
+        assert_eq!(retired.close_expired(now + Duration::from_secs(60)), 1);
+        assert!(retired.is_empty());
+        assert_eq!(accounting.open(FdKind::File), 0);
+    }
+}
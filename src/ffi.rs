@@ -0,0 +1,159 @@
+//! C ABI for handing records to surikafka in-process, e.g. from a Suricata output plugin. See
+//! `include/surikafka.h`.
+use super::{
+    blocking::{
+        ShipperHandle,
+        SyncShipper
+    },
+    errors::Error,
+    guard::guarded,
+    pipeline::Settings
+};
+use std::{
+    self,
+    cell::RefCell,
+    ffi::{
+        CStr,
+        CString
+    },
+    os::raw::{
+        c_char,
+        c_int
+    },
+    panic,
+    ptr,
+    slice,
+    time::Duration
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(e: &Error) {
+    let message = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn result_code(res: Result<(), Error>) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(&e);
+            -1
+        }
+    }
+}
+
+unsafe fn flags(argv: *const *const c_char, argc: usize) -> Result<Vec<String>, Error> {
+    if argv.is_null() {
+        return Ok(vec![])
+    }
+    slice::from_raw_parts(argv, argc).iter()
+        .map(|&arg| {
+            if arg.is_null() {
+                bail!("Null flag")
+            }
+            Ok(CStr::from_ptr(arg).to_str()?.to_string())
+        })
+        .collect()
+}
+
+/// Starts a shipper configured with command line style `argv` (without the program name).
+/// Returns null on error, see `surikafka_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn surikafka_init(argv: *const *const c_char, argc: usize) -> *mut ShipperHandle {
+    let handle = guarded(|| {
+        flags(argv, argc)
+            .and_then(|f| Settings::from_flags(&f))
+            .and_then(|settings| SyncShipper::new(settings).start())
+    });
+    match handle {
+        Ok(h) => Box::into_raw(Box::new(h)),
+        Err(e) => {
+            set_last_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Queues a copy of `record` for delivery. Returns 0 on success, -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn surikafka_send(handle: *mut ShipperHandle, record: *const u8, len: usize) -> c_int {
+    if handle.is_null() || (record.is_null() && len > 0) {
+        return result_code(Err(Error::from("Null argument".to_string())))
+    }
+    let record = if len == 0 { &[][..] } else { slice::from_raw_parts(record, len) };
+    result_code(guarded(|| (*handle).send(record)))
+}
+
+/// Waits up to `timeout_ms` for queued records to be delivered. Returns 0 once all completed,
+/// 1 on timeout, -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn surikafka_flush(handle: *mut ShipperHandle, timeout_ms: u64) -> c_int {
+    if handle.is_null() {
+        return result_code(Err(Error::from("Null argument".to_string())))
+    }
+    match guarded(|| Ok((*handle).flush(Duration::from_millis(timeout_ms)))) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            set_last_error(&e);
+            -1
+        }
+    }
+}
+
+/// Delivers queued records, stops the shipper, and frees `handle`. Returns 0 on success, -1 if
+/// the pipeline failed.
+#[no_mangle]
+pub unsafe extern "C" fn surikafka_close(handle: *mut ShipperHandle) -> c_int {
+    if handle.is_null() {
+        return 0
+    }
+    result_code(guarded(|| Box::from_raw(handle).close()))
+}
+
+/// Message of the last error on this thread, or null. Valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn surikafka_last_error() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|last| {
+            last.borrow().as_ref().map(|m| m.as_ptr()).unwrap_or(ptr::null())
+        })
+    }).unwrap_or(ptr::null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_invalid_flags() {
+        let flag = CString::new("--no-such-flag").expect("Failed to build flag");
+        let argv = [flag.as_ptr()];
+
+        let handle = unsafe { surikafka_init(argv.as_ptr(), argv.len()) };
+
+        assert!(handle.is_null());
+        let error = unsafe { CStr::from_ptr(surikafka_last_error()) };
+        assert!(error.to_string_lossy().contains("Invalid settings"));
+    }
+
+    #[test]
+    fn rejects_null_handles() {
+        assert_eq!(unsafe { surikafka_send(ptr::null_mut(), ptr::null(), 0) }, -1);
+        assert_eq!(unsafe { surikafka_flush(ptr::null_mut(), 0) }, -1);
+        assert_eq!(unsafe { surikafka_close(ptr::null_mut()) }, 0);
+    }
+
+    #[test]
+    fn turns_panics_into_errors() {
+        let res: Result<(), Error> = guarded(|| panic!("boom"));
+
+        assert_eq!(res.expect_err("Panic passed through").to_string(), "Panicked: boom");
+        assert_eq!(result_code(guarded(|| panic!("{}", 1))), -1);
+        let error = unsafe { CStr::from_ptr(surikafka_last_error()) };
+        assert_eq!(error.to_string_lossy(), "Panicked: 1");
+    }
+}
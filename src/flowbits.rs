@@ -0,0 +1,138 @@
+use super::{
+    serde_json::{
+        self,
+        Value
+    },
+    writer::HeaderGenerator
+};
+use std::collections::{
+    BTreeMap,
+    HashSet
+};
+
+/// Flowbits and flowints set on the flow of an event by the rules that matched it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlowState {
+    pub flowbits: Vec<String>,
+    pub flowints: BTreeMap<String, i64>
+}
+
+impl FlowState {
+    /// Reads `metadata.flowbits` (a list) and `metadata.flowints` (an object) as logged by
+    /// Suricata 5 and later, falling back to `vars.flowbits` (an object of booleans) and
+    /// `vars.flowints` from Suricata 4.
+    pub fn from_event(event: &Value) -> FlowState {
+        let mut state = FlowState::default();
+        for section in &["metadata", "vars"] {
+            let section = match event.get(*section) {
+                Some(s) => s,
+                None => continue
+            };
+            match section.get("flowbits") {
+                Some(&Value::Array(ref bits)) => {
+                    state.flowbits.extend(bits.iter().filter_map(Value::as_str).map(|s| s.to_string()));
+                }
+                Some(&Value::Object(ref bits)) => {
+                    state.flowbits.extend(bits.iter().filter(|&(_, v)| v.as_bool().unwrap_or(false)).map(|(k, _)| k.clone()));
+                }
+                _ => {}
+            }
+            if let Some(&Value::Object(ref ints)) = section.get("flowints") {
+                state.flowints.extend(ints.iter().filter_map(|(k, v)| v.as_i64().map(|i| (k.clone(), i))));
+            }
+        }
+        state.flowbits.sort();
+        state.flowbits.dedup();
+        state
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flowbits.is_empty() && self.flowints.is_empty()
+    }
+
+    pub fn to_value(&self) -> Value {
+        json!({
+            "flowbits": self.flowbits,
+            "flowints": self.flowints
+        })
+    }
+}
+
+/// Adds a `flowbits` header listing the set flowbits, and a `flowint.<name>` header per flowint,
+/// so correlation keyed on flowbits can filter records without parsing payloads.
+#[derive(Debug, Clone, Default)]
+pub struct FlowbitsHeaders {
+    names: Option<HashSet<String>>
+}
+
+impl FlowbitsHeaders {
+    /// Only index these flowbits and flowints, leaving the rest in the payload.
+    pub fn with_names(mut self, names: &str) -> Self {
+        let names: HashSet<String> = names.split(',')
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .collect();
+        self.names = if names.is_empty() { None } else { Some(names) };
+        self
+    }
+
+    fn indexed(&self, name: &str) -> bool {
+        self.names.as_ref().map(|n| n.contains(name)).unwrap_or(true)
+    }
+}
+
+impl HeaderGenerator for FlowbitsHeaders {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let event: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return vec![]
+        };
+        let state = FlowState::from_event(&event);
+        let mut headers = vec![];
+        let bits: Vec<&str> = state.flowbits.iter()
+            .filter(|b| self.indexed(b))
+            .map(|b| b.as_str())
+            .collect();
+        if !bits.is_empty() {
+            headers.push( ("flowbits".to_string(), bits.join(",").into_bytes()) );
+        }
+        for (name, value) in state.flowints.iter().filter(|&(n, _)| self.indexed(n)) {
+            headers.push( (format!("flowint.{}", name), value.to_string().into_bytes()) );
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_metadata_and_vars() {
+        let current = json!({"metadata": {"flowbits": ["ET.http.binary", "is_proto_irc"], "flowints": {"http.anomaly.count": 2}}});
+        let legacy = json!({"vars": {"flowbits": {"ET.http.binary": true, "unset": false}}});
+
+        let state = FlowState::from_event(&current);
+
+        assert_eq!(state.flowbits, vec!["ET.http.binary".to_string(), "is_proto_irc".to_string()]);
+        assert_eq!(state.flowints.get("http.anomaly.count"), Some(&2));
+        assert_eq!(FlowState::from_event(&legacy).flowbits, vec!["ET.http.binary".to_string()]);
+        assert!(FlowState::from_event(&json!({"event_type": "flow"})).is_empty());
+    }
+
+    #[test]
+    fn indexes_flowbits_into_headers() {
+        let msg = br#"{"metadata":{"flowbits":["b","a"],"flowints":{"count":3,"other":1}}}"#.to_vec();
+
+        assert_eq!(FlowbitsHeaders::default().generate(&msg), vec![
+            ("flowbits".to_string(), b"a,b".to_vec()),
+            ("flowint.count".to_string(), b"3".to_vec()),
+            ("flowint.other".to_string(), b"1".to_vec())
+        ]);
+        assert_eq!(FlowbitsHeaders::default().with_names("a, count").generate(&msg), vec![
+            ("flowbits".to_string(), b"a".to_vec()),
+            ("flowint.count".to_string(), b"3".to_vec())
+        ]);
+        assert!(FlowbitsHeaders::default().generate(&b"{}".to_vec()).is_empty());
+    }
+}
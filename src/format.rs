@@ -0,0 +1,247 @@
+use super::{
+    chrono::{
+        DateTime,
+        TimeZone,
+        Utc
+    },
+    errors::{
+        Error,
+        ErrorKind
+    },
+    futures::{
+        Poll,
+        Stream
+    },
+    serde_json::{
+        self,
+        Map,
+        Value
+    }
+};
+use std::{
+    self,
+    path::Path
+};
+
+/// Timestamp format of EVE events, which the filters, `--start-position time:`, and event
+/// latency metrics read.
+const EVE_TIMESTAMP: &'static str = "%Y-%m-%dT%H:%M:%S%.6f%z";
+
+/// Format of the NDJSON events of a source, normalized to carry the `event_type`, `timestamp`,
+/// and, for network logs, `src_ip`, `src_port`, `dest_ip`, `dest_port`, and `proto` fields that
+/// filtering, keying, and routing read from EVE events. Other fields are kept as they are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFormat {
+    /// Suricata EVE, passed through
+    Eve,
+    /// Zeek JSON logs, typed `zeek.<log>` by their `_path` or the file's name, e.g. `zeek.conn`
+    Zeek,
+    /// osquery results, typed `osquery.<query name>`
+    Osquery,
+    /// Any other NDJSON, typed by the file's name unless events have an `event_type` of their own
+    Ndjson
+}
+
+impl std::str::FromStr for SourceFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SourceFormat, Error> {
+        match s {
+            "eve" => Ok(SourceFormat::Eve),
+            "zeek" => Ok(SourceFormat::Zeek),
+            "osquery" => Ok(SourceFormat::Osquery),
+            "ndjson" | "json" => Ok(SourceFormat::Ndjson),
+            _ => Err(Error::from_kind(ErrorKind::InvalidSourceFormat(s.to_string())))
+        }
+    }
+}
+
+/// Parses `path=format`, as given with `--source-format`.
+pub fn parse_source_format(s: &str) -> Result<(String, SourceFormat), Error> {
+    let mut parts = s.rsplitn(2, '=');
+    match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+        (Some(format), Some(path)) if !path.is_empty() => Ok( (path.to_string(), format.parse()?) ),
+        _ => bail!("Invalid source format {}, expected path=format", s)
+    }
+}
+
+fn eve_timestamp(time: DateTime<Utc>) -> String {
+    time.format(EVE_TIMESTAMP).to_string()
+}
+
+/// Seconds since the epoch, as Zeek's `ts` and osquery's `unixTime` are by default, or an
+/// RFC 3339 timestamp.
+fn parse_time(value: &Value) -> Option<DateTime<Utc>> {
+    let seconds = match *value {
+        Value::Number(ref n) => n.as_f64(),
+        Value::String(ref s) => match s.parse::<f64>() {
+            Ok(seconds) => Some(seconds),
+            Err(_) => return DateTime::parse_from_rfc3339(s).ok().map(|t| t.with_timezone(&Utc))
+        },
+        _ => None
+    }?;
+    let millis = seconds * 1000.0;
+    // Casting a float out of the range of i64 isn't defined, so such times are left unset too
+    if !millis.is_finite() || millis.abs() >= std::i64::MAX as f64 {
+        return None
+    }
+    Utc.timestamp_millis_opt(millis as i64).single()
+}
+
+/// Normalizes the events of one source, see `SourceFormat`.
+#[derive(Debug, Clone)]
+pub struct Normalizer {
+    format: SourceFormat,
+    /// Type of the source's events by its file name, e.g. `conn` for conn.log
+    log_type: Option<String>
+}
+
+impl Normalizer {
+    pub fn new(format: SourceFormat, path: &str) -> Normalizer {
+        let log_type = Path::new(path).file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split('.').next())
+            .filter(|n| !n.is_empty())
+            .map(str::to_string);
+        Normalizer {
+            format: format,
+            log_type: log_type
+        }
+    }
+
+    /// `msg` as an EVE-like event; events that aren't JSON objects are passed on as they are.
+    pub fn normalize(&self, msg: Vec<u8>) -> Vec<u8> {
+        if self.format == SourceFormat::Eve {
+            return msg
+        }
+        let mut event: Map<String, Value> = match serde_json::from_slice(&msg) {
+            Ok(Value::Object(event)) => event,
+            _ => return msg
+        };
+        match self.format {
+            SourceFormat::Eve => (),
+            SourceFormat::Zeek => self.zeek(&mut event),
+            SourceFormat::Osquery => osquery(&mut event),
+            SourceFormat::Ndjson => {
+                if !event.contains_key("event_type") {
+                    let event_type = self.log_type.clone().unwrap_or_else(|| "ndjson".to_string());
+                    event.insert("event_type".to_string(), Value::String(event_type));
+                }
+            }
+        }
+        serde_json::to_vec(&event).unwrap_or(msg)
+    }
+
+    fn zeek(&self, event: &mut Map<String, Value>) {
+        let log = event.get("_path").and_then(Value::as_str).map(str::to_string)
+            .or_else(|| self.log_type.clone())
+            .unwrap_or_else(|| "log".to_string());
+        event.insert("event_type".to_string(), Value::String(format!("zeek.{}", log)));
+        if let Some(time) = event.get("ts").and_then(parse_time) {
+            event.insert("timestamp".to_string(), Value::String(eve_timestamp(time)));
+        }
+        let endpoints = [("id.orig_h", "src_ip"), ("id.orig_p", "src_port"), ("id.resp_h", "dest_ip"), ("id.resp_p", "dest_port")];
+        for &(zeek, eve) in endpoints.iter() {
+            if let Some(value) = event.get(zeek).cloned() {
+                event.insert(eve.to_string(), value);
+            }
+        }
+        if let Some(proto) = event.get("proto").and_then(Value::as_str).map(str::to_uppercase) {
+            event.insert("proto".to_string(), Value::String(proto));
+        }
+    }
+}
+
+fn osquery(event: &mut Map<String, Value>) {
+    // Pack queries are named pack/<pack>/<query>
+    let name = event.get("name").and_then(Value::as_str).map(|n| n.replace('/', ".")).unwrap_or_else(|| "result".to_string());
+    event.insert("event_type".to_string(), Value::String(format!("osquery.{}", name)));
+    let time = event.get("unixTime").and_then(parse_time)
+        .or_else(|| event.get("calendarTime").and_then(Value::as_str)
+            .and_then(|t| Utc.datetime_from_str(t, "%a %b %e %H:%M:%S %Y UTC").ok()));
+    if let Some(time) = time {
+        event.insert("timestamp".to_string(), Value::String(eve_timestamp(time)));
+    }
+    if let Some(host) = event.get("hostIdentifier").cloned() {
+        event.insert("host".to_string(), host);
+    }
+}
+
+/// Normalizes every event of a non-EVE source, so it's shipped with the same filtering, keying,
+/// and routing as EVE events.
+pub struct Normalized<S> {
+    inner: S,
+    normalizer: Normalizer
+}
+
+impl<S> Normalized<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, normalizer: Normalizer) -> Normalized<S> {
+        Normalized {
+            inner: inner,
+            normalizer: normalizer
+        }
+    }
+}
+
+impl<S> Stream for Normalized<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let msg = try_ready!(self.inner.poll());
+        Ok(msg.map(|msg| self.normalizer.normalize(msg)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        eve,
+        source
+    };
+
+    fn normalized(format: SourceFormat, path: &str, event: &str) -> Value {
+        let msg = Normalizer::new(format, path).normalize(event.as_bytes().to_vec());
+        serde_json::from_slice(&msg).expect("Failed to parse")
+    }
+
+    #[test]
+    fn normalizes_zeek_logs() {
+        let event = normalized(
+            SourceFormat::Zeek, "/var/log/zeek/conn.log",
+            r#"{"ts":1514764800.5,"uid":"CHhAvVGS1DHFjwGM9","id.orig_h":"10.0.0.1","id.orig_p":51000,"id.resp_h":"10.0.0.2","id.resp_p":80,"proto":"tcp"}"#
+        );
+
+        assert_eq!(event["event_type"], "zeek.conn");
+        assert_eq!(event["timestamp"], "2018-01-01T00:00:00.500000+0000");
+        assert_eq!(event["src_ip"], "10.0.0.1");
+        assert_eq!(event["dest_port"], 80);
+        assert_eq!(event["proto"], "TCP");
+        assert_eq!(event["uid"], "CHhAvVGS1DHFjwGM9");
+        assert!(normalized(SourceFormat::Zeek, "conn.log", r#"{"ts":1e300}"#).get("timestamp").is_none());
+        assert!(normalized(SourceFormat::Zeek, "conn.log", r#"{"ts":"9e15"}"#).get("timestamp").is_none());
+        let msg = serde_json::to_vec(&event).expect("Failed to serialize");
+        assert!(source::parse_timestamp(eve::timestamp(&msg).expect("No timestamp")).is_some());
+    }
+
+    #[test]
+    fn normalizes_osquery_and_other_ndjson() {
+        let event = normalized(
+            SourceFormat::Osquery, "osqueryd.results.log",
+            r#"{"name":"pack/incident/listening_ports","hostIdentifier":"web-1","unixTime":1514764800,"columns":{"port":"22"}}"#
+        );
+        assert_eq!(event["event_type"], "osquery.pack.incident.listening_ports");
+        assert_eq!(event["timestamp"], "2018-01-01T00:00:00.000000+0000");
+        assert_eq!(event["host"], "web-1");
+
+        assert_eq!(normalized(SourceFormat::Ndjson, "/var/log/audit.json", r#"{"user":"root"}"#)["event_type"], "audit");
+        assert_eq!(Normalizer::new(SourceFormat::Eve, "eve.json").normalize(b"{\"a\":1}".to_vec()), b"{\"a\":1}".to_vec());
+        assert_eq!(parse_source_format("/logs/a=b/conn.log=zeek").expect("Failed to parse"), ("/logs/a=b/conn.log".to_string(), SourceFormat::Zeek));
+        assert!(parse_source_format("conn.log=bro").is_err());
+    }
+}
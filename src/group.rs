@@ -0,0 +1,110 @@
+use super::{
+    cancel::CancellationToken,
+    errors::Error,
+    futures::{
+        Future,
+        future
+    },
+    pipeline::Pipeline,
+    print_error,
+    tokio::runtime::Runtime
+};
+use std::panic::AssertUnwindSafe;
+
+/// Several independent pipelines sharing one runtime, e.g. different sources shipping to
+/// different clusters on a consolidated sensor. A pipeline that fails or panics is logged and
+/// doesn't stop the others; the group fails once all pipelines have finished.
+pub struct PipelineGroup {
+    pipelines: Vec<(String, Pipeline)>,
+    cancellation: CancellationToken
+}
+
+impl Default for PipelineGroup {
+    fn default() -> PipelineGroup {
+        PipelineGroup {
+            pipelines: vec![],
+            cancellation: CancellationToken::new()
+        }
+    }
+}
+
+impl PipelineGroup {
+    pub fn new() -> PipelineGroup {
+        PipelineGroup::default()
+    }
+
+    /// Adds `pipeline`, named `name` in logs. Its cancellation token is replaced by the group's.
+    pub fn with_pipeline(mut self, name: &str, pipeline: Pipeline) -> Self {
+        self.pipelines.push( (name.to_string(), pipeline) );
+        self
+    }
+
+    /// Token that stops every pipeline in the group.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Future running every pipeline to completion, resolving to the names of those that failed.
+    pub fn into_future(self) -> Box<Future<Item=Vec<String>, Error=Error> + Send> {
+        let cancellation = self.cancellation;
+        let pipelines = self.pipelines.into_iter().map(move |(name, pipeline)| {
+            info!("Starting pipeline {}", name);
+            AssertUnwindSafe(pipeline.with_cancellation(cancellation.clone()).into_future())
+                .catch_unwind()
+                .then(move |res| {
+                    let failed = match res {
+                        Ok(Ok(())) => {
+                            info!("Pipeline {} finished", name);
+                            None
+                        }
+                        Ok(Err(e)) => {
+                            error!("Pipeline {} failed", name);
+                            print_error(&e);
+                            Some(name)
+                        }
+                        Err(_) => {
+                            error!("Pipeline {} panicked", name);
+                            Some(name)
+                        }
+                    };
+                    Ok::<_, Error>(failed)
+                })
+        }).collect::<Vec<_>>();
+
+        Box::new(future::join_all(pipelines).map(|failed| failed.into_iter().filter_map(|f| f).collect()))
+    }
+
+    /// Runs every pipeline to completion on a runtime owned by the group.
+    pub fn run_blocking(self) -> Result<(), Error> {
+        let total = self.pipelines.len();
+        let mut rt = Runtime::new()?;
+        let res = rt.block_on(self.into_future());
+        let _ = rt.shutdown_now().wait();
+        let failed = res?;
+        if !failed.is_empty() {
+            bail!("{} of {} pipelines failed: {}", failed.len(), total, failed.join(", "));
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::pipeline::Settings;
+
+    #[test]
+    fn reports_failed_pipelines() {
+        let failing = Settings {
+            sensor_partitions: Some(2),
+            ..Settings::default()
+        };
+        let res = PipelineGroup::new()
+            .with_pipeline("alerts", Pipeline::new(failing.clone()))
+            .with_pipeline("flows", Pipeline::new(failing))
+            .run_blocking();
+
+        let message = format!("{}", res.expect_err("Pipelines should fail"));
+        assert_eq!(message, "2 of 2 pipelines failed: alerts, flows");
+    }
+}
@@ -0,0 +1,162 @@
+use super::{
+    backtrace::Backtrace,
+    errors::Error,
+    futures::sync::mpsc::UnboundedSender,
+    metrics::{
+        Counter,
+        QueueGauge
+    },
+    serde_json
+};
+use std::{
+    self,
+    any::Any,
+    panic::{
+        self,
+        AssertUnwindSafe
+    }
+};
+
+/// Logs panics with a backtrace through the logger rather than only to stderr, including panics
+/// later contained by a `StageGuard`.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        error!("{}\n{:?}", info, Backtrace::new());
+    }));
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs `f`, turning a panic into an error, for calls that mustn't unwind into C.
+pub fn guarded<T, F: FnOnce() -> Result<T, Error>>(f: F) -> Result<T, Error> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => bail!("Panicked: {}", panic_message(&payload))
+    }
+}
+
+/// Runs a pipeline stage on one record at a time, so a panic drops (and optionally quarantines)
+/// only the record that caused it.
+pub struct StageGuard {
+    stage: String,
+    panics: Counter,
+    quarantine: Option<UnboundedSender<Vec<u8>>>,
+    quarantine_gauge: Option<QueueGauge>
+}
+
+impl StageGuard {
+    pub fn new(stage: &str) -> StageGuard {
+        StageGuard {
+            stage: stage.to_string(),
+            panics: Counter::new(&format!("{}.panics", stage)),
+            quarantine: None,
+            quarantine_gauge: None
+        }
+    }
+
+    pub fn with_panic_counter(mut self, counter: Counter) -> Self {
+        self.panics = counter;
+        self
+    }
+
+    /// Sends a `surikafka_quarantine` event carrying the offending record to `quarantine`.
+    pub fn with_quarantine(mut self, quarantine: UnboundedSender<Vec<u8>>) -> Self {
+        self.quarantine = Some(quarantine);
+        self
+    }
+
+    /// Counts quarantined records sent; the receiving side is expected to subtract as it consumes them.
+    pub fn with_queue_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.quarantine_gauge = Some(gauge);
+        self
+    }
+
+    /// Applies `f` to `msg`, passing `msg` through unchanged if `f` returns `None`. Returns `None`
+    /// if `f` panicked.
+    pub fn run<F>(&self, msg: Vec<u8>, f: F) -> Option<Vec<u8>>
+        where F: FnOnce(&Vec<u8>) -> Option<Vec<u8>>
+    {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&msg)));
+        match result {
+            Ok(transformed) => Some(transformed.unwrap_or(msg)),
+            Err(payload) => {
+                let reason = panic_message(&payload);
+                self.panics.incr();
+                error!("Stage {} panicked, quarantining record: {}", self.stage, reason);
+                self.quarantine_record(&msg, &reason);
+                None
+            }
+        }
+    }
+
+    fn quarantine_record(&self, msg: &Vec<u8>, reason: &str) {
+        let quarantine = match self.quarantine {
+            Some(ref q) => q,
+            None => return
+        };
+        let event = json!({
+            "event_type": "surikafka_quarantine",
+            "stage": self.stage,
+            "panic": reason,
+            "record": String::from_utf8_lossy(msg)
+        });
+        let bytes = serde_json::to_vec(&event).unwrap_or_default();
+        if quarantine.unbounded_send(bytes).is_err() {
+            error!("Quarantine receiver closed, dropping record");
+        } else if let Some(ref gauge) = self.quarantine_gauge {
+            gauge.add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::{
+            Stream,
+            sync::mpsc
+        },
+        serde_json::Value
+    };
+
+    #[test]
+    fn passes_records_through() {
+        let guard = StageGuard::new("test");
+
+        assert_eq!(guard.run(b"a".to_vec(), |m| Some(m.iter().cloned().chain(b"b".iter().cloned()).collect())), Some(b"ab".to_vec()));
+        assert_eq!(guard.run(b"a".to_vec(), |_| None), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn contains_panics() {
+        let (sender, receiver) = mpsc::unbounded();
+        let counter = Counter::new("test.panics");
+        let gauge = QueueGauge::new("quarantine");
+        let guard = StageGuard::new("test")
+            .with_panic_counter(counter.clone())
+            .with_quarantine(sender)
+            .with_queue_gauge(gauge.clone());
+
+        assert_eq!(guard.run(b"{\"bad\":true}".to_vec(), |_| panic!("bad record")), None);
+        assert_eq!(counter.value(), 1);
+        assert_eq!(gauge.depth(), 1);
+
+        drop(guard);
+        let quarantined: Vec<Vec<u8>> = receiver.wait().map(|r| r.expect("Failed to receive")).collect();
+        assert_eq!(quarantined.len(), 1);
+        let event: Value = serde_json::from_slice(&quarantined[0]).expect("Failed to parse");
+        assert_eq!(event["event_type"], "surikafka_quarantine");
+        assert_eq!(event["stage"], "test");
+        assert_eq!(event["panic"], "bad record");
+        assert_eq!(event["record"], "{\"bad\":true}");
+    }
+}
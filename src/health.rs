@@ -0,0 +1,297 @@
+use super::{
+    futures::{
+        Async,
+        Poll,
+        Stream,
+        sync::mpsc::UnboundedSender
+    },
+    metrics::QueueGauge,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std;
+
+/// Counters in a suricata `stats` record that indicate lost packets, paired with the counter
+/// they should be compared against.
+const DROP_COUNTERS: &'static [(&'static str, &'static str)] = &[
+    ("/stats/capture/kernel_drops", "/stats/capture/kernel_packets"),
+    ("/stats/capture/kernel_ifdrops", "/stats/capture/kernel_packets"),
+    ("/stats/decoder/pkts_dropped", "/stats/decoder/pkts")
+];
+
+#[derive(Debug, Clone)]
+pub struct DropThresholds {
+    pub max_drop_rate: f64,
+    pub min_packets: u64
+}
+
+impl Default for DropThresholds {
+    fn default() -> Self {
+        DropThresholds {
+            max_drop_rate: 0.01,
+            min_packets: 1000
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropAlarm {
+    pub counter: String,
+    pub dropped: u64,
+    pub packets: u64,
+    pub rate: f64
+}
+
+impl DropAlarm {
+    pub fn to_event(&self, thresholds: &DropThresholds, timestamp: Option<&Value>) -> Vec<u8> {
+        let event = json!({
+            "event_type": "surikafka_alarm",
+            "timestamp": timestamp.cloned().unwrap_or(Value::Null),
+            "alarm": {
+                "kind": "capture_loss",
+                "counter": self.counter,
+                "dropped": self.dropped,
+                "packets": self.packets,
+                "rate": self.rate,
+                "threshold": thresholds.max_drop_rate
+            }
+        });
+        serde_json::to_vec(&event).expect("Alarm is always serializable")
+    }
+}
+
+/// Tracks drop counters across consecutive `stats` records. Suricata reports counters as
+/// totals since startup, so rates are computed from the delta between two records.
+#[derive(Default)]
+pub struct DropTracker {
+    thresholds: DropThresholds,
+    last: std::collections::HashMap<&'static str, u64>
+}
+
+impl DropTracker {
+    pub fn new(thresholds: DropThresholds) -> DropTracker {
+        DropTracker {
+            thresholds: thresholds,
+            last: std::collections::HashMap::new()
+        }
+    }
+
+    pub fn thresholds(&self) -> &DropThresholds { &self.thresholds }
+
+    fn delta(&mut self, pointer: &'static str, value: u64) -> Option<u64> {
+        let previous = self.last.insert(pointer, value);
+        match previous {
+            // counters reset when suricata restarts
+            Some(p) if p <= value => Some(value - p),
+            _ => None
+        }
+    }
+
+    pub fn observe(&mut self, stats: &Value) -> Vec<DropAlarm> {
+        let mut alarms = vec![];
+        let mut packet_deltas = std::collections::HashMap::new();
+
+        for &(drops, packets) in DROP_COUNTERS {
+            if !packet_deltas.contains_key(packets) {
+                let delta = stats.pointer(packets)
+                    .and_then(Value::as_u64)
+                    .and_then(|v| self.delta(packets, v));
+                packet_deltas.insert(packets, delta);
+            }
+            let dropped = stats.pointer(drops)
+                .and_then(Value::as_u64)
+                .and_then(|v| self.delta(drops, v));
+
+            if let (Some(dropped), Some(&Some(received))) = (dropped, packet_deltas.get(packets)) {
+                let total = received + dropped;
+                if total < self.thresholds.min_packets || total == 0 {
+                    continue;
+                }
+                let rate = dropped as f64 / total as f64;
+                if rate > self.thresholds.max_drop_rate {
+                    alarms.push(DropAlarm {
+                        counter: drops.trim_left_matches("/stats/").replace("/", "."),
+                        dropped: dropped,
+                        packets: received,
+                        rate: rate
+                    });
+                }
+            }
+        }
+
+        alarms
+    }
+}
+
+/// Passes events through unchanged, inspecting `stats` records and sending synthesized alarm
+/// events to `alarms` when drop rates exceed the configured thresholds.
+pub struct DropMonitor<S> {
+    inner: S,
+    tracker: DropTracker,
+    alarms: UnboundedSender<Vec<u8>>,
+    alarms_gauge: Option<QueueGauge>
+}
+
+impl<S> DropMonitor<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(stream: S, thresholds: DropThresholds, alarms: UnboundedSender<Vec<u8>>) -> DropMonitor<S> {
+        DropMonitor {
+            inner: stream,
+            tracker: DropTracker::new(thresholds),
+            alarms: alarms,
+            alarms_gauge: None
+        }
+    }
+
+    /// Counts alarms sent; the receiving side is expected to subtract as it consumes them.
+    pub fn with_queue_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.alarms_gauge = Some(gauge);
+        self
+    }
+
+    fn inspect(&mut self, msg: &Vec<u8>) {
+        let value: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return
+        };
+        if value.get("event_type").and_then(Value::as_str) != Some("stats") {
+            return;
+        }
+        for alarm in self.tracker.observe(&value) {
+            warn!("Capture loss on {}: {:.4} of packets dropped", alarm.counter, alarm.rate);
+            let event = alarm.to_event(self.tracker.thresholds(), value.get("timestamp"));
+            if self.alarms.unbounded_send(event).is_err() {
+                error!("Alarm receiver closed, dropping alarm");
+            } else if let Some(ref gauge) = self.alarms_gauge {
+                gauge.add(1);
+            }
+        }
+    }
+}
+
+impl<S> Stream for DropMonitor<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(msg) => {
+                self.inspect(msg.as_ref());
+                Ok(Async::Ready(Some(msg)))
+            }
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+pub trait WithDropMonitor<S>
+    where S: Stream + Sized,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    fn monitor_drops(self, thresholds: DropThresholds, alarms: UnboundedSender<Vec<u8>>) -> DropMonitor<S>;
+}
+
+impl<S> WithDropMonitor<S> for S
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    fn monitor_drops(self, thresholds: DropThresholds, alarms: UnboundedSender<Vec<u8>>) -> DropMonitor<S> {
+        DropMonitor::new(self, thresholds, alarms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        env_logger,
+        errors::Error,
+        futures::{
+            self,
+            Future,
+            sync::mpsc
+        }
+    };
+
+    fn stats(packets: u64, drops: u64) -> Vec<u8> {
+        format!(
+            r#"{{"timestamp":"2018-06-01T00:00:00.000000+0000","event_type":"stats","stats":{{"capture":{{"kernel_packets":{},"kernel_drops":{}}}}}}}"#,
+            packets,
+            drops
+        ).into_bytes()
+    }
+
+    #[test]
+    fn first_record_has_no_rate() {
+        let mut tracker = DropTracker::new(DropThresholds::default());
+
+        let value: Value = serde_json::from_slice(&stats(100_000, 50_000)).expect("Failed to parse");
+
+        assert!(tracker.observe(&value).is_empty());
+    }
+
+    #[test]
+    fn alarms_when_rate_exceeded() {
+        let mut tracker = DropTracker::new(DropThresholds::default());
+
+        let first: Value = serde_json::from_slice(&stats(100_000, 0)).expect("Failed to parse");
+        let second: Value = serde_json::from_slice(&stats(109_000, 1_000)).expect("Failed to parse");
+
+        assert!(tracker.observe(&first).is_empty());
+
+        let alarms = tracker.observe(&second);
+
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].counter, "capture.kernel_drops");
+        assert_eq!(alarms[0].dropped, 1_000);
+        assert_eq!(alarms[0].rate, 0.1);
+    }
+
+    #[test]
+    fn ignores_counter_reset() {
+        let mut tracker = DropTracker::new(DropThresholds::default());
+
+        let first: Value = serde_json::from_slice(&stats(100_000, 10_000)).expect("Failed to parse");
+        let second: Value = serde_json::from_slice(&stats(5_000, 2_000)).expect("Failed to parse");
+
+        assert!(tracker.observe(&first).is_empty());
+        assert!(tracker.observe(&second).is_empty());
+    }
+
+    #[test]
+    fn monitor_passes_events_and_sends_alarms() {
+        let _ = env_logger::try_init();
+
+        let (sender, receiver) = mpsc::unbounded();
+
+        let events = vec![
+            stats(100_000, 0),
+            r#"{"event_type":"alert"}"#.to_string().into_bytes(),
+            stats(110_000, 5_000)
+        ];
+
+        let passed = futures::stream::iter_ok::<Vec<Vec<u8>>, Error>(events.clone())
+            .monitor_drops(DropThresholds::default(), sender)
+            .collect()
+            .wait()
+            .expect("Failed to collect");
+
+        assert_eq!(passed, events);
+
+        let alarms = receiver.collect().wait().expect("Failed to receive alarms");
+
+        assert_eq!(alarms.len(), 1);
+
+        let alarm: Value = serde_json::from_slice(&alarms[0]).expect("Failed to parse alarm");
+
+        assert_eq!(alarm["event_type"], "surikafka_alarm");
+        assert_eq!(alarm["alarm"]["counter"], "capture.kernel_drops");
+    }
+}
@@ -0,0 +1,195 @@
+use super::{
+    chrono::{
+        TimeZone,
+        Utc
+    },
+    writer::HeaderGenerator
+};
+use std::{
+    cmp::Ordering,
+    fmt,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Instant,
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+/// Header with the ingest time of a record as RFC 3339, from the clock's physical time.
+pub const INGEST_TIME_HEADER: &'static str = "ingest_time";
+/// Header with the full hybrid timestamp of a record, zero padded so it sorts bytewise.
+pub const INGEST_HLC_HEADER: &'static str = "ingest_hlc";
+/// Checkpoint store source the clock's lease, or its last physical time after a clean
+/// shutdown, is saved under.
+pub const CHECKPOINT_SOURCE: &'static str = "hlc";
+
+/// A hybrid logical timestamp: milliseconds since the epoch, and a counter ordering timestamps
+/// within the same millisecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HlcTimestamp {
+    pub physical_ms: u64,
+    pub logical: u32
+}
+
+impl PartialOrd for HlcTimestamp {
+    fn partial_cmp(&self, other: &HlcTimestamp) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HlcTimestamp {
+    fn cmp(&self, other: &HlcTimestamp) -> Ordering {
+        (self.physical_ms, self.logical).cmp(&(other.physical_ms, other.logical))
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016}.{:010}", self.physical_ms, self.logical)
+    }
+}
+
+fn wall_ms() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1000000
+}
+
+struct ClockState {
+    last: HlcTimestamp,
+    ticked_at: Instant
+}
+
+/// Ingest clock that never runs backwards across NTP step corrections or restarts. Each tick
+/// takes the later of the wall clock and the last tick advanced by the monotonic time since, so
+/// a clock stepped back keeps advancing at the real rate instead of stalling on the counter, and
+/// a clock stepped forward is followed at once. Ticks within one millisecond are ordered by the
+/// counter.
+#[derive(Clone)]
+pub struct HybridClock {
+    state: Arc<Mutex<ClockState>>
+}
+
+impl HybridClock {
+    /// A clock resuming after `saved`, the physical time of a previous run's last tick, so
+    /// timestamps stay ordered across restarts even if the wall clock was stepped back meanwhile.
+    pub fn new(saved: Option<u64>) -> HybridClock {
+        HybridClock::anchored(saved, Instant::now())
+    }
+
+    fn anchored(saved: Option<u64>, now: Instant) -> HybridClock {
+        HybridClock {
+            state: Arc::new(Mutex::new(ClockState {
+                last: HlcTimestamp {
+                    physical_ms: saved.map(|s| s + 1).unwrap_or(0),
+                    logical: 0
+                },
+                ticked_at: now
+            }))
+        }
+    }
+
+    pub fn tick(&self) -> HlcTimestamp {
+        self.tick_at(wall_ms(), Instant::now())
+    }
+
+    fn tick_at(&self, wall_ms: u64, now: Instant) -> HlcTimestamp {
+        let mut state = self.state.lock().expect("Clock lock poisoned");
+        let elapsed = if now > state.ticked_at { now - state.ticked_at } else { Default::default() };
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+        let physical_ms = wall_ms.max(state.last.physical_ms + elapsed_ms);
+        let next = if physical_ms > state.last.physical_ms {
+            HlcTimestamp { physical_ms: physical_ms, logical: 0 }
+        } else {
+            HlcTimestamp { physical_ms: state.last.physical_ms, logical: state.last.logical + 1 }
+        };
+        // Only advance the monotonic anchor with the physical time, so sub-millisecond ticks
+        // still add up
+        if next.physical_ms > state.last.physical_ms {
+            state.ticked_at = now;
+        }
+        state.last = next;
+        next
+    }
+
+    /// Physical time of the last tick, to save in the checkpoint store.
+    pub fn last_physical_ms(&self) -> u64 {
+        self.state.lock().expect("Clock lock poisoned").last.physical_ms
+    }
+
+    /// Physical time `ahead_ms` past both the last tick and the wall clock, to save in the
+    /// checkpoint store before ticking that far, so a clock resuming after a crash starts past
+    /// every tick of this one.
+    pub fn lease(&self, ahead_ms: u64) -> u64 {
+        self.last_physical_ms().max(wall_ms()) + ahead_ms
+    }
+}
+
+/// Stamps records with `ingest_time` and `ingest_hlc` headers from a hybrid clock, so consumers
+/// can order records of a sensor even across clock corrections.
+#[derive(Clone)]
+pub struct HlcHeaders {
+    clock: HybridClock
+}
+
+impl HlcHeaders {
+    pub fn new(clock: HybridClock) -> HlcHeaders {
+        HlcHeaders {
+            clock: clock
+        }
+    }
+}
+
+impl HeaderGenerator for HlcHeaders {
+    fn generate(&self, _msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let timestamp = self.clock.tick();
+        let time = Utc.timestamp_millis(timestamp.physical_ms as i64).to_rfc3339();
+        vec![
+            (INGEST_TIME_HEADER.to_string(), time.into_bytes()),
+            (INGEST_HLC_HEADER.to_string(), timestamp.to_string().into_bytes())
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn stays_ordered_across_clock_steps() {
+        let start = Instant::now();
+        let clock = HybridClock::anchored(Some(5_000), start);
+
+        let first = clock.tick_at(1_000, start);
+        assert_eq!(first, HlcTimestamp { physical_ms: 5_001, logical: 1 });
+
+        let same_ms = clock.tick_at(1_000, start);
+        assert_eq!(same_ms, HlcTimestamp { physical_ms: 5_001, logical: 2 });
+
+        let stepped_back = clock.tick_at(2_000, start + Duration::from_millis(10));
+        assert_eq!(stepped_back, HlcTimestamp { physical_ms: 5_011, logical: 0 });
+
+        let stepped_forward = clock.tick_at(60_000, start + Duration::from_millis(20));
+        assert_eq!(stepped_forward, HlcTimestamp { physical_ms: 60_000, logical: 0 });
+        assert_eq!(clock.last_physical_ms(), 60_000);
+
+        assert!(first < same_ms && same_ms < stepped_back && stepped_back < stepped_forward);
+        assert_eq!(same_ms.to_string(), "0000000000005001.0000000002");
+    }
+
+    #[test]
+    fn resumes_past_its_lease() {
+        let clock = HybridClock::new(None);
+        let last = clock.tick();
+        let lease = clock.lease(60_000);
+        assert!(lease >= last.physical_ms + 60_000);
+
+        let resumed = HybridClock::new(Some(lease));
+        assert!(resumed.tick().physical_ms > lease);
+        assert!(clock.tick() < resumed.tick());
+    }
+}
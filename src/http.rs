@@ -0,0 +1,55 @@
+//! Plain HTTP/1.1 helpers of the schema registry client and the Elasticsearch sink.
+use super::errors::Error;
+use std;
+
+/// `host:port` and path prefix of an `http://` url, using `default_port` if it has none.
+pub fn parse_url(url: &str, default_port: u16) -> Result<(String, String), Error> {
+    if !url.starts_with("http://") {
+        bail!("Invalid url {}, only http:// is supported", url);
+    }
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], rest[pos..].trim_right_matches('/')),
+        None => (rest, "")
+    };
+    if authority.is_empty() {
+        bail!("Invalid url {}, no host", url);
+    }
+    let authority = if authority.contains(':') { authority.to_string() } else { format!("{}:{}", authority, default_port) };
+    Ok( (authority, path.to_string()) )
+}
+
+/// Splits a raw HTTP response into its status code and body.
+pub fn parse_response(response: &[u8]) -> Result<(u16, &[u8]), Error> {
+    let split = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => bail!("Incomplete HTTP response")
+    };
+    let head = std::str::from_utf8(&response[..split])?;
+    let status = head.split_whitespace().nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| Error::from(format!("Invalid HTTP status line: {}", head.lines().next().unwrap_or(""))))?;
+    Ok( (status, &response[split + 4..]) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(parse_url("http://es:9201/", 9200).expect("Failed to parse"), ("es:9201".to_string(), "".to_string()));
+        assert_eq!(parse_url("http://es/proxy", 9200).expect("Failed to parse"), ("es:9200".to_string(), "/proxy".to_string()));
+        assert!(parse_url("https://es:9200", 9200).is_err());
+    }
+
+    #[test]
+    fn parses_responses() {
+        let (status, body) = parse_response(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\r\n{\"errors\":false}")
+            .expect("Failed to parse");
+
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{\"errors\":false}");
+        assert!(parse_response(b"HTTP/1.1 200").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use super::{
+    errors::Error,
+    eve::{
+        self,
+        IFACE_LABEL
+    },
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    serde_json,
+    writer::HeaderGenerator
+};
+
+/// Parses `label=path`, as given with `--interface-file`.
+pub fn parse_source(s: &str) -> Result<(String, String), Error> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+        (Some(label), Some(path)) if !label.is_empty() && !path.is_empty() => Ok( (label.to_string(), path.to_string()) ),
+        _ => bail!("Invalid interface file {}, expected label=path", s)
+    }
+}
+
+/// Labels every event of a source with the capture interface it came from, for sensors where
+/// Suricata writes one EVE file per interface, e.g. `monitoring` and `tap`. The label is written
+/// as the first field, `iface_label`, so it is usually found without parsing the record; it is
+/// available to `--key-by iface_label`, `{iface}` in topic templates, and `InterfaceHeaders`.
+pub struct Labeled<S> {
+    inner: S,
+    field: Vec<u8>
+}
+
+impl<S> Labeled<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, label: &str) -> Labeled<S> {
+        let field = format!("\"{}\":{}", IFACE_LABEL, serde_json::to_string(label).expect("Strings are always serializable"));
+        Labeled {
+            inner: inner,
+            field: field.into_bytes()
+        }
+    }
+
+    pub fn label(&self, msg: Vec<u8>) -> Vec<u8> {
+        let rest = match msg.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) if msg[start] == b'{' => &msg[start + 1..],
+            _ => return msg
+        };
+        let empty = rest.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'}');
+        let mut labeled = Vec::with_capacity(msg.len() + self.field.len() + 2);
+        labeled.push(b'{');
+        labeled.extend_from_slice(&self.field);
+        if !empty {
+            labeled.push(b',');
+        }
+        labeled.extend_from_slice(rest);
+        labeled
+    }
+}
+
+impl<S> Stream for Labeled<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(msg) => Ok(Async::Ready(Some(self.label(msg)))),
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Adds an `iface` header with the interface label of labeled records.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceHeaders;
+
+impl HeaderGenerator for InterfaceHeaders {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        match eve::iface_label(msg) {
+            Some(label) => vec![ ("iface".to_string(), label.as_bytes().to_vec()) ],
+            None => vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        topics::TopicRouter,
+        transform::{
+            Redactor,
+            TransformStream
+        }
+    };
+    use futures::{
+        Future,
+        stream
+    };
+
+    #[test]
+    fn labels_events() {
+        let events = vec![
+            br#"{"event_type":"alert"}"#.to_vec(),
+            br#" { }"#.to_vec(),
+            b"not json".to_vec()
+        ];
+
+        let labeled: Vec<Vec<u8>> = Labeled::new(stream::iter_ok::<_, ()>(events), "tap")
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(labeled, vec![
+            br#"{"iface_label":"tap","event_type":"alert"}"#.to_vec(),
+            br#"{"iface_label":"tap" }"#.to_vec(),
+            b"not json".to_vec()
+        ]);
+        assert_eq!(InterfaceHeaders.generate(&labeled[0]), vec![ ("iface".to_string(), b"tap".to_vec()) ]);
+        assert_eq!(eve::Dimension::Label.extract(&serde_json::from_slice(&labeled[1]).expect("Failed to parse")), Some("tap".to_string()));
+    }
+
+    #[test]
+    fn keeps_label_through_transforms() {
+        let events = vec![ br#"{"timestamp":"x","event_type":"alert","http":{"http_request_body":"secret"}}"#.to_vec() ];
+        let labeled = Labeled::new(stream::iter_ok::<_, ()>(events), "tap");
+        let redactor = Redactor::parse("http.http_request_body").expect("Failed to parse");
+
+        let transformed: Vec<Vec<u8>> = TransformStream::new(labeled, redactor).collect().wait().expect("Stream failed");
+        let mut router = TopicRouter::new("eve-{iface}", "eve-{iface}-{event_type}");
+
+        assert_eq!(InterfaceHeaders.generate(&transformed[0]), vec![ ("iface".to_string(), b"tap".to_vec()) ]);
+        assert_eq!(router.route(&transformed[0]), "eve-tap-alert");
+    }
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(parse_source("tap=/var/log/suricata/tap/eve.json").expect("Failed to parse"),
+                   ("tap".to_string(), "/var/log/suricata/tap/eve.json".to_string()));
+        assert!(parse_source("/var/log/suricata/eve.json").is_err());
+        assert!(parse_source("=eve.json").is_err());
+    }
+}
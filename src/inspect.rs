@@ -0,0 +1,315 @@
+use super::{
+    errors::Error,
+    serde_json::{
+        self,
+        Value
+    },
+    writer::key_text
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    fmt
+};
+
+/// How a clause compares a field with its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Equals,
+    NotEquals,
+    Contains
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Clause {
+    field: String,
+    op: Op,
+    value: String
+}
+
+/// Selects the events `inspect` prints, as clauses joined by ` and `, each `field=value`,
+/// `field!=value`, or `field~substring`. Fields are dotted paths into the event, e.g.
+/// `alert.signature_id=2100498`, or `topic` and `key` for where the event would be sent, so
+/// routing rules can be checked directly: `event_type=dns and topic!=eve-dns`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    clauses: Vec<Clause>
+}
+
+impl Filter {
+    /// A filter matching every event.
+    pub fn all() -> Filter {
+        Filter::default()
+    }
+
+    pub fn parse(s: &str) -> Result<Filter, Error> {
+        let mut clauses = vec![];
+        for clause in s.split(" and ").map(str::trim).filter(|c| !c.is_empty()) {
+            let (field, op, value) = if let Some(i) = clause.find("!=") {
+                (&clause[..i], Op::NotEquals, &clause[i + 2..])
+            } else if let Some(i) = clause.find('=') {
+                (&clause[..i], Op::Equals, &clause[i + 1..])
+            } else if let Some(i) = clause.find('~') {
+                (&clause[..i], Op::Contains, &clause[i + 1..])
+            } else {
+                bail!("Invalid filter clause {}, expected field=value, field!=value, or field~substring", clause)
+            };
+            if field.trim().is_empty() {
+                bail!("Invalid filter clause {}, missing the field", clause);
+            }
+            clauses.push(Clause {
+                field: field.trim().to_string(),
+                op: op,
+                value: value.trim().to_string()
+            });
+        }
+        Ok(Filter {
+            clauses: clauses
+        })
+    }
+
+    pub fn matches(&self, inspection: &Inspection) -> bool {
+        self.matches_record(&inspection.event, &inspection.topic, &inspection.key)
+    }
+
+    /// Whether an event matches as it's sent to `topic` with `key`.
+    pub fn matches_record(&self, event: &[u8], topic: &str, key: &[u8]) -> bool {
+        self.matches_with(event, Some( (topic, key) ))
+    }
+
+    /// Whether a raw event matches, before it has a topic or key; `topic` and `key` clauses
+    /// only match with `!=`.
+    pub fn matches_event(&self, event: &[u8]) -> bool {
+        self.matches_with(event, None)
+    }
+
+    fn matches_with(&self, event: &[u8], route: Option<(&str, &[u8])>) -> bool {
+        if self.clauses.is_empty() {
+            return true
+        }
+        let event: Option<Value> = serde_json::from_slice(event).ok();
+        self.clauses.iter().all(|clause| {
+            let actual = match clause.field.as_str() {
+                "topic" => route.map(|(topic, _)| topic.to_string()),
+                "key" => route.map(|(_, key)| key_text(key)),
+                field => event.as_ref()
+                    .and_then(|e| e.pointer(&format!("/{}", field.replace('.', "/"))))
+                    .and_then(field_text)
+            };
+            match (clause.op, actual) {
+                (Op::Equals, Some(actual)) => actual == clause.value,
+                (Op::NotEquals, actual) => actual.map(|a| a != clause.value).unwrap_or(true),
+                (Op::Contains, Some(actual)) => actual.contains(&clause.value),
+                (_, None) => false
+            }
+        })
+    }
+}
+
+fn field_text(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Number(ref n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None
+    }
+}
+
+/// What the writer would send an event as, computed by its key, route, and header stages
+/// without producing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspection {
+    pub topic: String,
+    pub partition: Option<i32>,
+    pub key: Vec<u8>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub event: Vec<u8>
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.partition {
+            Some(partition) => writeln!(f, "topic: {} partition: {}", self.topic, partition)?,
+            None => writeln!(f, "topic: {}", self.topic)?
+        }
+        writeln!(f, "key: {}", key_text(&self.key))?;
+        for &(ref name, ref value) in self.headers.iter() {
+            writeln!(f, "header {}: {}", name, String::from_utf8_lossy(value))?;
+        }
+        let pretty = serde_json::from_slice::<Value>(&self.event).ok()
+            .and_then(|event| serde_json::to_string_pretty(&event).ok());
+        match pretty {
+            Some(pretty) => write!(f, "{}", pretty),
+            None => write!(f, "{}", String::from_utf8_lossy(&self.event))
+        }
+    }
+}
+
+/// Prints an inspection to stdout, followed by a blank line.
+pub fn print(inspection: &Inspection) {
+    use std::io::Write;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let _ = writeln!(out, "{}\n", inspection);
+}
+
+/// Identity of an event across configurations: its type, timestamp, and flow, which transforms
+/// leave alone, or the whole event if it has none of them.
+fn identity(event: &[u8]) -> String {
+    let fields: Vec<String> = match serde_json::from_slice::<Value>(event) {
+        Ok(event) => ["event_type", "timestamp", "flow_id"].iter()
+            .filter_map(|f| event.get(*f).and_then(field_text))
+            .collect(),
+        Err(_) => vec![]
+    };
+    if fields.is_empty() {
+        String::from_utf8_lossy(event).into_owned()
+    } else {
+        fields.join(" ")
+    }
+}
+
+/// How two configurations treat the same sample of events: which events only one of them lets
+/// through, and which they route or key differently.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigDiff {
+    /// Events the new configuration filters out
+    pub dropped: Vec<Inspection>,
+    /// Events only the new configuration lets through
+    pub added: Vec<Inspection>,
+    /// Events sent to another topic or partition, or with another key, as (old, new)
+    pub changed: Vec<(Inspection, Inspection)>,
+    pub unchanged: usize
+}
+
+impl ConfigDiff {
+    /// Compares the inspections of a sample under the old and new configuration, pairing events
+    /// by identity in the order they were sent.
+    pub fn new(old: Vec<Inspection>, new: Vec<Inspection>) -> ConfigDiff {
+        let mut unmatched: HashMap<String, VecDeque<Inspection>> = HashMap::new();
+        for inspection in new {
+            unmatched.entry(identity(&inspection.event)).or_insert_with(VecDeque::new).push_back(inspection);
+        }
+        let mut diff = ConfigDiff::default();
+        for old in old {
+            let new = unmatched.get_mut(&identity(&old.event)).and_then(|n| n.pop_front());
+            match new {
+                Some(new) => {
+                    if old.topic == new.topic && old.partition == new.partition && old.key == new.key {
+                        diff.unchanged += 1;
+                    } else {
+                        diff.changed.push( (old, new) );
+                    }
+                }
+                None => diff.dropped.push(old)
+            }
+        }
+        diff.added = unmatched.into_iter().flat_map(|(_, n)| n).collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dropped.is_empty() && self.added.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f, "{} events unchanged, {} changed, {} filtered out by the new config, {} let through only by it",
+            self.unchanged, self.changed.len(), self.dropped.len(), self.added.len()
+        )?;
+        for &(ref old, ref new) in self.changed.iter() {
+            write!(f, "changed {}:", identity(&old.event))?;
+            if old.topic != new.topic {
+                write!(f, " topic {} -> {}", old.topic, new.topic)?;
+            }
+            if old.partition != new.partition {
+                write!(f, " partition {:?} -> {:?}", old.partition, new.partition)?;
+            }
+            if old.key != new.key {
+                write!(f, " key {} -> {}", key_text(&old.key), key_text(&new.key))?;
+            }
+            writeln!(f)?;
+        }
+        for dropped in self.dropped.iter() {
+            writeln!(f, "filtered out {}: was sent to {}", identity(&dropped.event), dropped.topic)?;
+        }
+        for added in self.added.iter() {
+            writeln!(f, "let through {}: now sent to {}", identity(&added.event), added.topic)?;
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspection(event: &str, topic: &str) -> Inspection {
+        Inspection {
+            topic: topic.to_string(),
+            partition: None,
+            key: b"10.0.0.1".to_vec(),
+            headers: vec![ ("sensor".to_string(), b"s1".to_vec()) ],
+            event: event.as_bytes().to_vec()
+        }
+    }
+
+    #[test]
+    fn filters_on_fields_and_route() {
+        let alert = inspection(r#"{"event_type":"alert","alert":{"signature_id":2100498,"signature":"GPL ATTACK_RESPONSE"}}"#, "eve-alerts");
+        let dns = inspection(r#"{"event_type":"dns"}"#, "eve-alerts");
+
+        let filter = Filter::parse("event_type=alert and alert.signature_id=2100498 and alert.signature~ATTACK").expect("Failed to parse");
+        assert!(filter.matches(&alert));
+        assert!(!filter.matches(&dns));
+
+        let misrouted = Filter::parse("event_type=dns and topic!=eve-dns").expect("Failed to parse");
+        assert!(misrouted.matches(&dns));
+        assert!(!misrouted.matches(&alert));
+
+        assert!(Filter::parse("key=10.0.0.1").expect("Failed to parse").matches(&dns));
+        assert!(Filter::all().matches(&dns));
+        assert!(Filter::parse("event_type").is_err());
+        assert!(filter.matches_event(&alert.event));
+        assert!(!Filter::parse("topic=eve-alerts").expect("Failed to parse").matches_event(&alert.event));
+    }
+
+    #[test]
+    fn prints_route_and_event() {
+        let printed = inspection(r#"{"event_type":"dns"}"#, "eve-dns").to_string();
+
+        assert_eq!(printed, "topic: eve-dns\nkey: 10.0.0.1\nheader sensor: s1\n{\n  \"event_type\": \"dns\"\n}");
+    }
+
+    #[test]
+    fn diffs_routing_filtering_and_keys() {
+        let alert = r#"{"event_type":"alert","timestamp":"2024-01-01T00:00:00","flow_id":1}"#;
+        let dns = r#"{"event_type":"dns","timestamp":"2024-01-01T00:00:01","flow_id":2}"#;
+        let flow = r#"{"event_type":"flow","timestamp":"2024-01-01T00:00:02","flow_id":3}"#;
+        let stats = r#"{"event_type":"stats","timestamp":"2024-01-01T00:00:03"}"#;
+
+        let old = vec![inspection(alert, "eve-alerts"), inspection(dns, "eve-alerts"), inspection(flow, "eve-alerts")];
+        let mut rekeyed = inspection(alert, "eve-alerts");
+        rekeyed.key = b"1".to_vec();
+        let new = vec![rekeyed, inspection(dns, "eve-dns"), inspection(stats, "eve-alerts")];
+
+        let diff = ConfigDiff::new(old, new);
+
+        assert_eq!(diff.unchanged, 0);
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(diff.dropped.len(), 1);
+        assert_eq!(diff.added.len(), 1);
+        let report = diff.to_string();
+        assert!(report.contains("changed alert 2024-01-01T00:00:00 1: key 10.0.0.1 -> 1\n"));
+        assert!(report.contains("changed dns 2024-01-01T00:00:01 2: topic eve-alerts -> eve-dns\n"));
+        assert!(report.contains("filtered out flow 2024-01-01T00:00:02 3: was sent to eve-alerts\n"));
+        assert!(report.contains("let through stats 2024-01-01T00:00:03: now sent to eve-alerts\n"));
+        assert!(ConfigDiff::new(vec![inspection(dns, "eve-dns")], vec![inspection(dns, "eve-dns")]).is_empty());
+    }
+}
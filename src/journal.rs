@@ -0,0 +1,102 @@
+use super::{
+    chrono::Utc,
+    futures::sync::mpsc::UnboundedSender,
+    metrics::QueueGauge,
+    serde_json::{
+        self,
+        Value
+    }
+};
+
+/// Operational events recorded in the journal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpsKind {
+    /// A config override from the config topic was applied
+    ConfigReload,
+    /// Stage plugins were rescanned
+    PluginReload,
+    /// The writer's circuit breaker changed state
+    CircuitBreaker,
+    /// The spool emptied after backing events up
+    SpoolDrained
+}
+
+impl OpsKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OpsKind::ConfigReload => "config_reload",
+            OpsKind::PluginReload => "plugin_reload",
+            OpsKind::CircuitBreaker => "circuit_breaker",
+            OpsKind::SpoolDrained => "spool_drained"
+        }
+    }
+}
+
+/// Records surikafka's own operational events as `surikafka_ops` records, sent to a channel that
+/// is produced to the ops topic, e.g. `surikafka.ops`, so fleet operators get an audit trail of
+/// shipper behavior next to the events. Cheap to clone; every clone sends to the same channel.
+#[derive(Clone)]
+pub struct OpsJournal {
+    sender: UnboundedSender<Vec<u8>>,
+    gauge: QueueGauge,
+    sensor_id: String
+}
+
+impl OpsJournal {
+    /// Journals to `sender`, counting records sent in `gauge`; the receiving side is expected to
+    /// subtract as it consumes them.
+    pub fn new(sender: UnboundedSender<Vec<u8>>, gauge: QueueGauge, sensor_id: &str) -> OpsJournal {
+        OpsJournal {
+            sender: sender,
+            gauge: gauge,
+            sensor_id: sensor_id.to_string()
+        }
+    }
+
+    pub fn to_event(&self, kind: OpsKind, details: Value) -> Vec<u8> {
+        let event = json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "event_type": "surikafka_ops",
+            "sensor_id": self.sensor_id,
+            "kind": kind.name(),
+            "details": details
+        });
+        serde_json::to_vec(&event).expect("Ops event is always serializable")
+    }
+
+    pub fn record(&self, kind: OpsKind, details: Value) {
+        if self.sender.unbounded_send(self.to_event(kind, details)).is_err() {
+            error!("Ops journal receiver closed, dropping {} event", kind.name());
+        } else {
+            self.gauge.add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        Stream,
+        sync::mpsc
+    };
+
+    #[test]
+    fn records_ops_events() {
+        let (sender, receiver) = mpsc::unbounded();
+        let gauge = QueueGauge::new("ops.channel");
+        let journal = OpsJournal::new(sender, gauge.clone(), "s1");
+
+        journal.record(OpsKind::SpoolDrained, json!({"records": 3}));
+        drop(journal);
+
+        let records = receiver.collect().wait().expect("Failed to receive");
+        let event: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+        assert_eq!(gauge.depth(), 1);
+        assert_eq!(event["event_type"], "surikafka_ops");
+        assert_eq!(event["sensor_id"], "s1");
+        assert_eq!(event["kind"], "spool_drained");
+        assert_eq!(event["details"]["records"], 3);
+    }
+}
@@ -1,32 +1,122 @@
 use errors::Error;
 use serde_json::{
+    self,
     Deserializer,
     Value
 };
+use std;
+
+/// How invalid UTF-8 inside an event is handled. Suricata can emit raw bytes in printable
+/// payload fields, which would otherwise make the whole event unparseable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Utf8Mode {
+    /// Replace each invalid sequence with U+FFFD
+    Lossy,
+    /// Replace each invalid byte with a literal `\xNN`, preserving the original bytes
+    Escape
+}
+
+impl Default for Utf8Mode {
+    fn default() -> Self { Utf8Mode::Lossy }
+}
+
+pub fn repair_utf8(bytes: &[u8], mode: Utf8Mode) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                out.extend_from_slice(valid.as_bytes());
+                return out
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(after.len());
+                out.extend_from_slice(valid);
+                match mode {
+                    Utf8Mode::Lossy => out.extend_from_slice("\u{FFFD}".as_bytes()),
+                    Utf8Mode::Escape => {
+                        for b in &after[..invalid_len] {
+                            out.extend_from_slice(format!("\\\\x{:02x}", b).as_bytes());
+                        }
+                    }
+                }
+                rest = &after[invalid_len..];
+            }
+        }
+    }
+}
+
+fn trim_start(buffer: &[u8]) -> &[u8] {
+    let start = buffer.iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buffer.len());
+    &buffer[start..]
+}
 
 pub struct JsonParser;
 
 impl JsonParser {
     pub fn parse<'a>(buffer: &'a [u8]) -> Result<(&'a [u8], Vec<Vec<u8>>), Error> {
-        let deserializer = Deserializer::from_slice(buffer);
-        let mut stream_deserializer = deserializer.into_iter::<Value>();
+        JsonParser::parse_with(buffer, Utf8Mode::default())
+    }
+
+    pub fn parse_with<'a>(buffer: &'a [u8], mode: Utf8Mode) -> Result<(&'a [u8], Vec<Vec<u8>>), Error> {
+        JsonParser::parse_lines(buffer, mode, false)
+    }
+
+    /// Like `parse_with`, but fails on unparseable events rather than skipping them.
+    pub fn parse_strict<'a>(buffer: &'a [u8], mode: Utf8Mode) -> Result<(&'a [u8], Vec<Vec<u8>>), Error> {
+        JsonParser::parse_lines(buffer, mode, true)
+    }
+
+    fn parse_lines<'a>(buffer: &'a [u8], mode: Utf8Mode, strict: bool) -> Result<(&'a [u8], Vec<Vec<u8>>), Error> {
+        let mut buffer = buffer;
         let mut values = vec![];
-        let mut last_good_offset = 0;
 
+        // Parses from the start of `buffer` until a bad line, then carries on after it
         loop {
-            match stream_deserializer.next() {
-                Some(Ok(_)) => {
-                    let slice = buffer[last_good_offset..stream_deserializer.byte_offset()].to_vec();
-                    values.push(slice);
-                    last_good_offset = stream_deserializer.byte_offset();
-                }
-                Some(Err(_)) => {
-                    return Ok( (&buffer[last_good_offset..buffer.len()], values) );
-                }
-                None => {
-                    return Ok( (&buffer[last_good_offset..buffer.len()], values) );
+            let deserializer = Deserializer::from_slice(buffer);
+            let mut stream_deserializer = deserializer.into_iter::<Value>();
+            let mut last_good_offset = 0;
+
+            let after_bad_line = loop {
+                match stream_deserializer.next() {
+                    Some(Ok(_)) => {
+                        let slice = &buffer[last_good_offset..stream_deserializer.byte_offset()];
+                        values.push(trim_start(slice).to_vec());
+                        last_good_offset = stream_deserializer.byte_offset();
+                    }
+                    Some(Err(ref e)) if !e.is_eof() => {
+                        let rest = trim_start(&buffer[last_good_offset..]);
+                        let line_end = match rest.iter().position(|b| *b == b'\n') {
+                            Some(pos) => pos,
+                            // wait for the rest of the line before deciding what to do with it
+                            None => return Ok( (rest, values) )
+                        };
+                        let line = &rest[..line_end];
+                        if std::str::from_utf8(line).is_err() {
+                            let repaired = repair_utf8(line, mode);
+                            if serde_json::from_slice::<Value>(&repaired).is_ok() {
+                                values.push(repaired);
+                            } else if strict {
+                                bail!("Unparseable event after UTF-8 repair");
+                            } else {
+                                warn!("Skipping unparseable event after UTF-8 repair");
+                            }
+                        } else if strict {
+                            bail!("Unparseable event: {}", e);
+                        } else {
+                            warn!("Skipping unparseable event: {}", e);
+                        }
+                        break &rest[line_end + 1..];
+                    }
+                    Some(Err(_)) | None => {
+                        return Ok( (&buffer[last_good_offset..buffer.len()], values) );
+                    }
                 }
-            }
+            };
+            buffer = after_bad_line;
         }
     }
 }
@@ -84,4 +174,91 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn repairs_invalid_utf8() {
+        let _ = env_logger::try_init();
+
+        let mut test = b"{\"payload_printable\":\"ab".to_vec();
+        test.push(0xff);
+        test.extend_from_slice(b"c\"}\n{\"next\":1}\n");
+
+        let (rem, v) = JsonParser::parse(&test).expect("Failed to parse");
+
+        assert_eq!(rem, b"\n");
+
+        assert_eq!(v, vec![
+            "{\"payload_printable\":\"ab\u{FFFD}c\"}".to_bytes().to_vec(),
+            r#"{"next":1}"#.to_bytes().to_vec()
+        ]);
+    }
+
+    #[test]
+    fn escapes_invalid_utf8() {
+        let _ = env_logger::try_init();
+
+        let mut test = b"{\"payload_printable\":\"ab".to_vec();
+        test.extend_from_slice(&[0xc3, 0x28]);
+        test.extend_from_slice(b"\"}\n");
+
+        let (_, v) = JsonParser::parse_with(&test, Utf8Mode::Escape).expect("Failed to parse");
+
+        let value: Value = serde_json::from_slice(&v[0]).expect("Failed to parse repaired");
+
+        assert_eq!(value["payload_printable"], "ab\\xc3(");
+    }
+
+    #[test]
+    fn fails_on_garbage_lines_when_strict() {
+        assert!(JsonParser::parse_strict("not json\n{\"key\":1}\n".as_ref(), Utf8Mode::Lossy).is_err());
+
+        let (rem, values) = JsonParser::parse_strict("{\"key\":1}\n{\"key\"".as_ref(), Utf8Mode::Lossy).expect("Failed to parse");
+        assert_eq!(values, vec![b"{\"key\":1}".to_vec()]);
+        assert_eq!(rem, b"{\"key\"");
+    }
+
+    #[test]
+    fn skips_garbage_lines() {
+        let _ = env_logger::try_init();
+
+        let test = "not json\n{\"key\":1}\ngarbage".as_ref();
+
+        let (rem, v) = JsonParser::parse(test).expect("Failed to parse");
+
+        assert_eq!(rem, b"garbage");
+
+        assert_eq!(v, vec![r#"{"key":1}"#.to_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn strips_separating_newlines() {
+        let _ = env_logger::try_init();
+
+        let test = "{\"key1\":1}\n{\"key2\":2}\n".as_ref();
+
+        let (rem, v) = JsonParser::parse(test).expect("Failed to parse");
+
+        assert_eq!(rem, b"\n");
+
+        assert_eq!(v, vec![
+            r#"{"key1":1}"#.to_bytes().to_vec(),
+            r#"{"key2":2}"#.to_bytes().to_vec()
+        ]);
+    }
+
+    #[test]
+    fn skips_many_garbage_lines() {
+        let _ = env_logger::try_init();
+
+        let mut test = vec![];
+        for _ in 0..100_000 {
+            test.extend_from_slice(b"garbage\n");
+        }
+        test.extend_from_slice(b"{\"after\":\"garbage\"}");
+
+        let (rem, v) = JsonParser::parse(&test).expect("Failed to parse");
+
+        assert!(rem.is_empty());
+        assert_eq!(v, vec![br#"{"after":"garbage"}"#.to_vec()]);
+    }
+
 }
\ No newline at end of file
@@ -1,4 +1,28 @@
-use super::rdkafka::message::ToBytes;
+use super::{
+    addr::normalize_ip,
+    errors::{
+        Error,
+        ErrorKind
+    },
+    hmac::{
+        Hmac,
+        Mac
+    },
+    rdkafka::message::ToBytes,
+    serde_json::{
+        self,
+        Value
+    },
+    sha2::Sha256
+};
+use std::str::FromStr;
+
+/// FNV-1a, used where a hash must be stable across builds and rust releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
 
 pub trait KeyGenerator {
     type Item: ToBytes + ?Sized;
@@ -6,6 +30,14 @@ pub trait KeyGenerator {
     fn generate(&self, msg: &Vec<u8>) -> Self::Item;
 }
 
+impl<K: KeyGenerator + ?Sized> KeyGenerator for Box<K> {
+    type Item = K::Item;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        (**self).generate(msg)
+    }
+}
+
 pub struct BytesGenerator;
 
 impl KeyGenerator for BytesGenerator {
@@ -16,12 +48,189 @@ impl KeyGenerator for BytesGenerator {
     }
 }
 
+/// Keys events by Suricata's `flow_id`, so every event of a flow lands on the same partition.
+/// Events without a flow, such as stats, get an empty key.
+pub struct FlowIdKeyGenerator;
+
+impl KeyGenerator for FlowIdKeyGenerator {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        serde_json::from_slice::<Value>(msg).ok()
+            .and_then(|v| v.get("flow_id").and_then(Value::as_u64))
+            .map(|id| id.to_string().into_bytes())
+            .unwrap_or_else(Vec::new)
+    }
+}
+
+/// Keys events by a hash of their five tuple, with the endpoints ordered so both directions of
+/// a conversation share a key. Unlike `flow_id`, the key is the same across sensors and Suricata
+/// restarts. Addresses are normalized first, so sensors writing v6 differently agree on the key.
+pub struct FiveTupleKeyGenerator;
+
+impl KeyGenerator for FiveTupleKeyGenerator {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        let event: Value = match serde_json::from_slice(msg) {
+            Ok(v) => v,
+            Err(_) => return vec![]
+        };
+        let text = |name: &str| match event.get(name) {
+            Some(&Value::String(ref s)) => s.clone(),
+            Some(&Value::Number(ref n)) => n.to_string(),
+            _ => String::new()
+        };
+        let mut endpoints = [
+            (normalize_ip(&text("src_ip")), text("src_port")),
+            (normalize_ip(&text("dest_ip")), text("dest_port"))
+        ];
+        endpoints.sort();
+        let tuple = format!(
+            "{}|{}|{}|{}|{}",
+            endpoints[0].0, endpoints[0].1, endpoints[1].0, endpoints[1].1, text("proto")
+        );
+        format!("{:016x}", fnv1a(tuple.as_bytes())).into_bytes()
+    }
+}
+
+/// Keys events by the sensor that logged them, the `host` field Suricata adds when
+/// `sensor-name` is set, falling back to `sensor_id`. Preserves per-sensor ordering.
+pub struct SensorKeyGenerator {
+    sensor_id: String
+}
+
+impl SensorKeyGenerator {
+    pub fn new(sensor_id: &str) -> SensorKeyGenerator {
+        SensorKeyGenerator {
+            sensor_id: sensor_id.to_string()
+        }
+    }
+}
+
+impl KeyGenerator for SensorKeyGenerator {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        serde_json::from_slice::<Value>(msg).ok()
+            .and_then(|v| v.get("host").and_then(Value::as_str).map(|s| s.as_bytes().to_vec()))
+            .unwrap_or_else(|| self.sensor_id.as_bytes().to_vec())
+    }
+}
+
+/// Which part of an event keys it on the event topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyStrategy {
+    /// The whole record, or the dimensions given with --key-by
+    Payload,
+    FlowId,
+    FiveTuple,
+    Sensor
+}
+
+impl FromStr for KeyStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<KeyStrategy, Error> {
+        match s {
+            "payload" => Ok(KeyStrategy::Payload),
+            "flow-id" | "flow_id" => Ok(KeyStrategy::FlowId),
+            "five-tuple" | "5-tuple" => Ok(KeyStrategy::FiveTuple),
+            "sensor" => Ok(KeyStrategy::Sensor),
+            _ => Err(Error::from_kind(ErrorKind::InvalidKeyStrategy(s.to_string())))
+        }
+    }
+}
+
+/// Replaces the key produced by `inner` with its HMAC-SHA256 under a per-deployment secret.
+/// Equal keys still map to equal partitions, but the addressing information in flow keys is not
+/// readable by anyone with access to the topic.
+pub struct SaltedGenerator<K: KeyGenerator> {
+    inner: K,
+    secret: Vec<u8>
+}
+
+impl<K: KeyGenerator> SaltedGenerator<K> {
+    pub fn new(inner: K, secret: Vec<u8>) -> SaltedGenerator<K> {
+        SaltedGenerator {
+            inner: inner,
+            secret: secret
+        }
+    }
+}
+
+impl<K: KeyGenerator> KeyGenerator for SaltedGenerator<K>
+    where K::Item: Sized
+{
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        let key = self.inner.generate(msg);
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts keys of any length");
+        mac.input(key.to_bytes());
+        mac.result().code().to_vec()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
     #[test]
     fn string_generator() {
         assert_eq!(BytesGenerator.generate(&"test".to_string().into_bytes()), "test".to_string().into_bytes());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn flow_generators() {
+        let request = br#"{"flow_id":1234,"src_ip":"10.0.0.1","src_port":51000,"dest_ip":"10.0.0.2","dest_port":80,"proto":"TCP"}"#.to_vec();
+        let response = br#"{"flow_id":1234,"src_ip":"10.0.0.2","src_port":80,"dest_ip":"10.0.0.1","dest_port":51000,"proto":"TCP"}"#.to_vec();
+        let other = br#"{"flow_id":99,"src_ip":"10.0.0.3","src_port":51000,"dest_ip":"10.0.0.2","dest_port":80,"proto":"TCP"}"#.to_vec();
+
+        assert_eq!(FlowIdKeyGenerator.generate(&request), b"1234".to_vec());
+        assert!(FlowIdKeyGenerator.generate(&br#"{"event_type":"stats"}"#.to_vec()).is_empty());
+        assert_eq!(FiveTupleKeyGenerator.generate(&request), FiveTupleKeyGenerator.generate(&response));
+        assert_ne!(FiveTupleKeyGenerator.generate(&request), FiveTupleKeyGenerator.generate(&other));
+    }
+
+    #[test]
+    fn five_tuple_normalizes_addresses() {
+        let compressed = br#"{"src_ip":"2001:db8::1","src_port":51000,"dest_ip":"10.0.0.2","dest_port":80,"proto":"TCP"}"#.to_vec();
+        let expanded = br#"{"src_ip":"2001:0DB8:0000:0000:0000:0000:0000:0001","src_port":51000,"dest_ip":"::ffff:10.0.0.2","dest_port":80,"proto":"TCP"}"#.to_vec();
+        let zoned = br#"{"src_ip":"10.0.0.2","src_port":80,"dest_ip":"2001:db8::1%eth0","dest_port":51000,"proto":"TCP"}"#.to_vec();
+
+        assert_eq!(FiveTupleKeyGenerator.generate(&compressed), FiveTupleKeyGenerator.generate(&expanded));
+        assert_eq!(FiveTupleKeyGenerator.generate(&compressed), FiveTupleKeyGenerator.generate(&zoned));
+    }
+
+    #[test]
+    fn sensor_generator() {
+        let generator = SensorKeyGenerator::new("fallback");
+
+        assert_eq!(generator.generate(&br#"{"host":"sensor-1"}"#.to_vec()), b"sensor-1".to_vec());
+        assert_eq!(generator.generate(&br#"{"event_type":"flow"}"#.to_vec()), b"fallback".to_vec());
+        assert_eq!("five-tuple".parse::<KeyStrategy>().expect("Failed to parse"), KeyStrategy::FiveTuple);
+        assert!("random".parse::<KeyStrategy>().is_err());
+    }
+
+    #[test]
+    fn salted_generator() {
+        let msg = "10.0.0.1:1234 -> 10.0.0.2:80".to_string().into_bytes();
+
+        let salted = SaltedGenerator::new(BytesGenerator, b"secret".to_vec());
+        let other = SaltedGenerator::new(BytesGenerator, b"other secret".to_vec());
+
+        let key = salted.generate(&msg);
+
+        assert_eq!(key.len(), 32);
+        assert_eq!(key, salted.generate(&msg));
+        assert_ne!(key, msg);
+        assert_ne!(key, other.generate(&msg));
+    }
+}
@@ -0,0 +1,401 @@
+use super::{
+    errors::Error,
+    eve,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    metrics::{
+        Counter,
+        Gauge,
+        Registry
+    },
+    rdkafka::{
+        ClientConfig,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        }
+    },
+    runtime::Delay
+};
+use std::{
+    self,
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const METADATA_TIMEOUT_MS: i32 = 5000;
+
+/// Sum of `high watermark - committed offset` over all partitions. Partitions the group has never
+/// committed count as fully lagging.
+pub fn total_lag(high_watermarks: &[(i32, i64)], committed: &HashMap<i32, i64>) -> u64 {
+    high_watermarks.iter()
+        .map(|&(partition, high)| {
+            let committed = committed.get(&partition).cloned().unwrap_or(0);
+            (high - committed).max(0) as u64
+        })
+        .sum()
+}
+
+fn query_lag(consumer: &BaseConsumer, topic: &str) -> Result<u64, Error> {
+    let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT_MS)
+        .map_err(|e| Error::from(format!("Failed to fetch metadata: {:?}", e)))?;
+    let partitions: Vec<i32> = metadata.topics().iter()
+        .filter(|t| t.name() == topic)
+        .flat_map(|t| t.partitions().iter().map(|p| p.id()).collect::<Vec<i32>>())
+        .collect();
+
+    let mut high_watermarks = vec![];
+    let mut assignment = TopicPartitionList::new();
+    for partition in partitions {
+        let (_, high) = consumer.fetch_watermarks(topic, partition, METADATA_TIMEOUT_MS)
+            .map_err(|e| Error::from(format!("Failed to fetch watermarks: {:?}", e)))?;
+        high_watermarks.push( (partition, high) );
+        assignment.add_partition(topic, partition);
+    }
+
+    consumer.assign(&assignment)
+        .map_err(|e| Error::from(format!("Failed to assign partitions: {:?}", e)))?;
+    let committed: HashMap<i32, i64> = consumer.committed(METADATA_TIMEOUT_MS)
+        .map_err(|e| Error::from(format!("Failed to fetch committed offsets: {:?}", e)))?
+        .elements()
+        .iter()
+        .filter_map(|e| match e.offset() {
+            Offset::Offset(o) => Some( (e.partition(), o) ),
+            _ => None
+        })
+        .collect();
+
+    Ok(total_lag(&high_watermarks, &committed))
+}
+
+/// Periodically measures how far a downstream consumer group is behind on a topic, from a
+/// background thread since the metadata calls block.
+pub struct LagMonitor {
+    lag: Arc<AtomicUsize>
+}
+
+impl LagMonitor {
+    /// `client` carries the connection settings shared with the producer.
+    pub fn spawn(client: &ClientConfig, group: &str, topic: &str, interval: Duration) -> Result<LagMonitor, Error> {
+        let consumer: BaseConsumer = client.clone()
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .create()
+            .map_err(|e| Error::from(format!("Failed to create lag consumer: {:?}", e)))?;
+        let lag = Arc::new(AtomicUsize::new(0));
+        let thread_lag = lag.clone();
+        let topic = topic.to_string();
+        let group = group.to_string();
+
+        std::thread::spawn(move || {
+            loop {
+                match query_lag(&consumer, &topic) {
+                    Ok(l) => {
+                        debug!("Consumer group {} lag on {} is {}", group, topic, l);
+                        thread_lag.store(l as usize, Ordering::SeqCst);
+                    }
+                    Err(e) => warn!("Failed to measure lag of {}: {}", group, e)
+                }
+                if Arc::strong_count(&thread_lag) == 1 {
+                    return
+                }
+                std::thread::sleep(interval);
+            }
+        });
+
+        Ok(LagMonitor {
+            lag: lag
+        })
+    }
+
+    pub fn handle(&self) -> Arc<AtomicUsize> { self.lag.clone() }
+}
+
+/// Holds back the inner stream while the measured downstream lag is above `max_lag`, so a
+/// backfill doesn't bury live traffic for consumers sharing the topic.
+pub struct Paced<S> {
+    inner: S,
+    lag: Arc<AtomicUsize>,
+    max_lag: usize,
+    recheck: Duration,
+    delay: Option<Delay>
+}
+
+impl<S: Stream> Paced<S> {
+    pub fn new(inner: S, lag: Arc<AtomicUsize>, max_lag: usize, recheck: Duration) -> Paced<S> {
+        Paced {
+            inner: inner,
+            lag: lag,
+            max_lag: max_lag,
+            recheck: recheck,
+            delay: None
+        }
+    }
+}
+
+impl<S: Stream> Stream for Paced<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(mut delay) = self.delay.take() {
+                match delay.poll() {
+                    Ok(Async::NotReady) => {
+                        self.delay = Some(delay);
+                        return Ok(Async::NotReady)
+                    }
+                    Ok(Async::Ready(())) => {}
+                    Err(e) => error!("Pacing timer failed: {:?}", e)
+                }
+            }
+            if self.lag.load(Ordering::SeqCst) <= self.max_lag {
+                return self.inner.poll()
+            }
+            debug!("Downstream lag above {}, pausing", self.max_lag);
+            self.delay = Some(Delay::new(Instant::now() + self.recheck));
+        }
+    }
+}
+
+/// How `LagSampler` adjusts sampling of bulk event types. While the lag is above `tighten_above`
+/// the rates are halved every `interval`, down to `min_rate`; once it falls below `loosen_below`
+/// they are doubled every `interval` back up to keeping everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingPolicy {
+    pub event_types: Vec<String>,
+    pub tighten_above: usize,
+    pub loosen_below: usize,
+    pub min_rate: f64,
+    pub interval: Duration
+}
+
+impl SamplingPolicy {
+    /// Samples the comma separated `event_types`, loosening once the lag is below half of
+    /// `tighten_above`.
+    pub fn new(event_types: &str, tighten_above: usize, min_rate: f64, interval: Duration) -> Result<SamplingPolicy, Error> {
+        if min_rate <= 0.0 || min_rate > 1.0 {
+            bail!("Minimum sample rate {} is not above 0 and at most 1", min_rate);
+        }
+        let event_types: Vec<String> = event_types.split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if event_types.is_empty() {
+            bail!("No event types to sample");
+        }
+        Ok(SamplingPolicy {
+            event_types: event_types,
+            tighten_above: tighten_above,
+            loosen_below: tighten_above / 2,
+            min_rate: min_rate,
+            interval: interval
+        })
+    }
+
+    /// Rate to apply after `rate` at `lag`.
+    pub fn adjust(&self, rate: f64, lag: usize) -> f64 {
+        if lag > self.tighten_above {
+            (rate / 2.0).max(self.min_rate)
+        } else if lag < self.loosen_below {
+            (rate * 2.0).min(1.0)
+        } else {
+            rate
+        }
+    }
+}
+
+/// Samples bulk event types by the downstream lag measured by a `LagMonitor`, so consumers that
+/// fall behind catch up on alerts rather than flows. Unlike `Paced`, nothing is held back.
+/// Adjustments are logged and counted in `sampling.adjustments`, the current rate is exported
+/// in permille as `sampling.rate_permille`, and sampled out events are counted in
+/// `sampling.<event_type>`.
+pub struct LagSampler<S> {
+    inner: S,
+    lag: Arc<AtomicUsize>,
+    policy: SamplingPolicy,
+    rate: f64,
+    adjusted: Instant,
+    credit: HashMap<String, f64>,
+    registry: Registry,
+    sampled: HashMap<String, Counter>,
+    adjustments: Counter,
+    rate_gauge: Gauge
+}
+
+impl<S> LagSampler<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, lag: Arc<AtomicUsize>, policy: SamplingPolicy, registry: &Registry) -> LagSampler<S> {
+        let rate_gauge = registry.gauge("sampling.rate_permille");
+        rate_gauge.set(1000);
+        LagSampler {
+            inner: inner,
+            lag: lag,
+            policy: policy,
+            rate: 1.0,
+            adjusted: Instant::now(),
+            credit: HashMap::new(),
+            registry: registry.clone(),
+            sampled: HashMap::new(),
+            adjustments: registry.counter("sampling.adjustments"),
+            rate_gauge: rate_gauge
+        }
+    }
+
+    pub fn rate(&self) -> f64 { self.rate }
+
+    fn maybe_adjust(&mut self) {
+        if self.adjusted.elapsed() < self.policy.interval {
+            return
+        }
+        self.adjusted = Instant::now();
+        let lag = self.lag.load(Ordering::SeqCst);
+        let rate = self.policy.adjust(self.rate, lag);
+        if rate == self.rate {
+            return
+        }
+        if rate < self.rate {
+            warn!("Downstream lag of {}, sampling {} at {}", lag, self.policy.event_types.join(","), rate);
+        } else {
+            info!("Downstream lag down to {}, sampling {} at {}", lag, self.policy.event_types.join(","), rate);
+        }
+        self.rate = rate;
+        self.credit.clear();
+        self.adjustments.incr();
+        self.rate_gauge.set((rate * 1000.0) as usize);
+    }
+
+    fn keep(&mut self, msg: &Vec<u8>) -> bool {
+        self.maybe_adjust();
+        if self.rate >= 1.0 {
+            return true
+        }
+        let event_type = match eve::event_type(msg) {
+            Some(t) if self.policy.event_types.iter().any(|s| s == t) => t,
+            _ => return true
+        };
+        let credit = self.credit.entry(event_type.to_string()).or_insert(0.0);
+        *credit += self.rate;
+        if *credit >= 1.0 {
+            *credit -= 1.0;
+            return true
+        }
+        let registry = &self.registry;
+        self.sampled.entry(event_type.to_string())
+            .or_insert_with(|| registry.counter(&format!("sampling.{}", event_type)))
+            .incr();
+        false
+    }
+}
+
+impl<S> Stream for LagSampler<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if self.keep(&msg) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures,
+        tokio
+    };
+
+    #[test]
+    fn sums_lag() {
+        let mut committed = HashMap::new();
+        committed.insert(0, 90);
+        committed.insert(1, 200);
+
+        assert_eq!(total_lag(&[(0, 100), (1, 150), (2, 5)], &committed), 15);
+    }
+
+    #[test]
+    fn passes_through_below_max() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+
+        let lag = Arc::new(AtomicUsize::new(10));
+        let paced = Paced::new(futures::stream::iter_ok::<_, ()>(vec![1, 2, 3]), lag, 100, Duration::from_millis(10));
+
+        assert_eq!(rt.block_on(paced.collect()).expect("Failed to collect"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn resumes_when_lag_drops() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+
+        let lag = Arc::new(AtomicUsize::new(1000));
+        let paced = Paced::new(futures::stream::iter_ok::<_, ()>(vec![1]), lag.clone(), 100, Duration::from_millis(10));
+
+        let drop_lag = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            lag.store(0, Ordering::SeqCst);
+        });
+
+        assert_eq!(rt.block_on(paced.collect()).expect("Failed to collect"), vec![1]);
+
+        drop_lag.join().expect("Failed to drop lag");
+    }
+
+    #[test]
+    fn samples_bulk_types_by_lag() {
+        let registry = Registry::default();
+        let policy = SamplingPolicy::new("flow, dns", 100, 0.25, Duration::from_secs(0)).expect("Failed to parse");
+
+        assert_eq!(policy.adjust(1.0, 150), 0.5);
+        assert_eq!(policy.adjust(0.25, 150), 0.25);
+        assert_eq!(policy.adjust(0.5, 75), 0.5);
+        assert_eq!(policy.adjust(0.5, 10), 1.0);
+        assert!(SamplingPolicy::new("flow", 100, 0.0, Duration::from_secs(0)).is_err());
+
+        let lag = Arc::new(AtomicUsize::new(1000));
+        let events = vec![
+            br#"{"event_type":"alert"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec()
+        ];
+        let sampler = LagSampler::new(futures::stream::iter_ok::<_, ()>(events), lag, policy, &registry);
+
+        let kept = sampler.collect().wait().expect("Stream failed");
+
+        // Halved to 0.5 and 0.25 on the first two events, then held at the minimum
+        assert_eq!(kept, vec![br#"{"event_type":"alert"}"#.to_vec(), br#"{"event_type":"flow"}"#.to_vec()]);
+        assert_eq!(registry.counter("sampling.flow").value(), 3);
+        assert_eq!(registry.counter("sampling.adjustments").value(), 2);
+        assert_eq!(registry.gauge("sampling.rate_permille").value(), 250);
+    }
+}
@@ -0,0 +1,228 @@
+#![recursion_limit="128"]
+#![feature(try_from, test)]
+#![allow(dead_code)]
+#![cfg_attr(feature = "python", feature(specialization))]
+#[cfg(feature = "async-std-runtime")] extern crate async_std;
+extern crate backtrace;
+extern crate bytes;
+extern crate chrono;
+#[cfg(feature = "profiling")] extern crate cpuprofiler;
+extern crate flate2;
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate futures;
+#[cfg(feature = "async-std-runtime")] extern crate futures03;
+extern crate hdrhistogram;
+extern crate hmac;
+#[cfg(feature = "plugins")] extern crate libloading;
+#[cfg(feature = "python")] #[macro_use] extern crate pyo3;
+#[macro_use(debug, info, error, log, trace, warn)] extern crate log;
+#[cfg(feature = "admin-tls")] extern crate native_tls;
+//#[macro_use] extern crate nom;
+#[cfg(feature = "archive")] extern crate parquet;
+extern crate serde;
+#[macro_use] extern crate serde_json;
+extern crate serde_yaml;
+extern crate rdkafka;
+extern crate sha2;
+#[macro_use] extern crate structopt;
+extern crate tokio;
+extern crate tokio_signal;
+#[cfg(feature = "admin-tls")] extern crate tokio_tls;
+extern crate tokio_uds;
+extern crate toml;
+#[cfg(feature = "plugins")] extern crate wasmi;
+#[cfg(feature = "zstd-dict")] extern crate zstd;
+
+pub mod errors {
+    use std;
+    use super::{
+        futures,
+        //nom
+    };
+
+    // Create the Error, ErrorKind, ResultExt, and Result types
+    error_chain! {
+        foreign_links {
+            Canceled(futures::Canceled) #[doc = "Future cancelled"];
+            Io(std::io::Error) #[doc = "Error during IO"];
+            Ffi(std::ffi::NulError) #[doc = "Error during FFI conversion"];
+            FromUtf8(std::string::FromUtf8Error) #[doc = "Error during UTF8 conversion"];
+            TimeError(std::time::SystemTimeError) #[doc = "Error during duration calculation"];
+            Utf8(std::str::Utf8Error) #[doc = "Error during UTF8 conversion"];
+        }
+//        links {
+//            ErrorName(error_lib::errors::Error, error_lib::errors::ErrorKind);
+//        }
+        errors {
+            ReceiverError {
+                display("Receiver encountered an error")
+            }
+            NomIncomplete(needed: String) {
+                display("Not enough data to parse, needed {}", needed)
+            }
+            NomError(message: String) {
+                display("Error parsing: {}", message)
+            }
+            UnknownDimension(name: String) {
+                display("Unknown event dimension: {}", name)
+            }
+            InvalidKeyPlacement(placement: String) {
+                display("Invalid key placement: {}, expected record, header, or field", placement)
+            }
+            InvalidKeyStrategy(strategy: String) {
+                display("Invalid key strategy: {}, expected payload, flow-id, five-tuple, or sensor", strategy)
+            }
+            InvalidStartPosition(position: String) {
+                display("Invalid start position: {}, expected start, end, resume-checkpoint, or time:-<n><s|m|h|d>", position)
+            }
+            InvalidEventTypePolicy(policy: String) {
+                display("Invalid unknown event type policy: {}, expected catch-all or create", policy)
+            }
+            InvalidEnvelopeMode(mode: String) {
+                display("Invalid envelope mode: {}, expected none, structured, or binary", mode)
+            }
+            InvalidRedisMode(mode: String) {
+                display("Invalid Redis mode: {}, expected stream or publish", mode)
+            }
+            InvalidInjectionTarget(target: String) {
+                display("Invalid static field target: {}, expected field, header, or both", target)
+            }
+            InvalidSelfTestMode(mode: String) {
+                display("Invalid self-test mode: {}, expected delivery or round-trip", mode)
+            }
+            InvalidAcks(acks: String) {
+                display("Invalid acks: {}, expected 0, 1, or all", acks)
+            }
+            InvalidSourceFormat(format: String) {
+                display("Invalid source format: {}, expected eve, zeek, osquery, or ndjson", format)
+            }
+        }
+    }
+
+//    impl<I, E> From<nom::Err<I, E>> for Error {
+//        fn from(err: nom::Err<I, E>) -> Error {
+//            match err {
+//                nom::Err::Incomplete(super::nom::Needed::Unknown) => {
+//                    Error::from_kind(ErrorKind::NomIncomplete("Unknown".to_string()))
+//                }
+//                nom::Err::Incomplete(super::nom::Needed::Size(sz)) => {
+//                    Error::from_kind(ErrorKind::NomIncomplete(format!("{}", sz)))
+//                }
+//                nom::Err::Error(super::nom::simple_errors::Context::Code(_, k)) => {
+//                    Error::from_kind(ErrorKind::NomError(k.description().to_string()))
+//                }
+//                nom::Err::Failure(super::nom::simple_errors::Context::Code(_, k)) => {
+//                    Error::from_kind(ErrorKind::NomError(k.description().to_string()))
+//                }
+//            }
+//        }
+//    }
+}
+
+pub mod addr;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod anomaly;
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "enrichment")]
+pub mod attack;
+pub mod batch;
+pub mod blocking;
+pub mod breaker;
+pub mod budget;
+pub mod cancel;
+pub mod canary;
+pub mod catchup;
+pub mod certs;
+pub mod checkpoint;
+pub mod clock;
+pub mod config;
+pub mod context;
+pub mod derive;
+#[cfg(feature = "sinks")]
+pub mod elastic;
+pub mod escalate;
+pub mod eve;
+pub mod extension;
+pub mod fds;
+pub mod ffi;
+pub mod flowbits;
+pub mod format;
+pub mod group;
+pub mod guard;
+pub mod health;
+pub mod hlc;
+pub mod http;
+pub mod iface;
+pub mod inspect;
+pub mod journal;
+pub mod json;
+pub mod key;
+pub mod lag;
+pub mod lineage;
+pub mod metrics;
+#[cfg(feature = "testkit")]
+pub mod ordering;
+pub mod partition;
+pub mod pdns;
+pub mod persist;
+pub mod pipeline;
+pub mod presets;
+#[cfg(feature = "suricata-plugin")]
+pub mod plugin;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod reader;
+pub mod recent;
+#[cfg(feature = "sinks")]
+pub mod redis;
+pub mod registry;
+pub mod remote;
+pub mod replay;
+pub mod routes;
+#[cfg(feature = "enrichment")]
+pub mod rules;
+pub mod runtime;
+#[cfg(feature = "archive")]
+pub mod s3;
+pub mod shed;
+pub mod shutdown;
+#[cfg(feature = "testkit")]
+pub mod simulate;
+pub mod sink;
+pub mod source;
+pub mod spool;
+pub mod stats;
+pub mod storm;
+pub mod suppress;
+pub mod tenant;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+pub mod throttle;
+pub mod topics;
+pub mod totals;
+pub mod trace;
+pub mod transform;
+pub mod truncate;
+pub mod verify;
+pub mod warm;
+#[cfg(feature = "plugins")]
+pub mod wasm;
+pub mod writer;
+
+use errors::Error;
+
+pub fn print_error(err: &Error) {
+    error!("error: {}", err);
+
+    for e in err.iter().skip(1) {
+        error!("caused by: {}", e);
+    }
+
+// The backtrace is not always generated. Try to run this example
+// with `RUST_BACKTRACE=1`.
+    if let Some(backtrace) = err.backtrace() {
+        error!("backtrace: {:?}", backtrace)
+    }
+}
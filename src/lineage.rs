@@ -0,0 +1,128 @@
+use super::{
+    errors::Error,
+    key::fnv1a,
+    serde_json::{
+        self,
+        Value
+    },
+    writer::HeaderGenerator
+};
+
+/// Header carrying the chain of producers a record passed through.
+pub const LINEAGE_HEADER: &'static str = "lineage";
+
+/// Hash of the configuration of the stages that can change a record, together with the shipper
+/// version, so audits can tell which records were enriched or rewritten the same way.
+pub fn stage_hash(stages: &[String]) -> String {
+    let mut description = format!("surikafka/{}", env!("CARGO_PKG_VERSION"));
+    for stage in stages {
+        description.push('\n');
+        description.push_str(stage);
+    }
+    format!("{:016x}", fnv1a(description.as_bytes()))
+}
+
+/// One producer in the lineage of a record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub sensor_id: String,
+    pub instance_id: String,
+    pub stages: String
+}
+
+impl Hop {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "sensor_id": self.sensor_id,
+            "instance_id": self.instance_id,
+            "stages": self.stages
+        })
+    }
+
+    pub fn from_value(value: &Value) -> Option<Hop> {
+        let field = |name: &str| value.get(name).and_then(Value::as_str).map(|s| s.to_string());
+        Some(Hop {
+            sensor_id: field("sensor_id")?,
+            instance_id: field("instance_id")?,
+            stages: field("stages")?
+        })
+    }
+}
+
+/// Parses a lineage header, a JSON array of hops with the original producer first.
+pub fn parse(header: &[u8]) -> Result<Vec<Hop>, Error> {
+    let hops: Vec<Value> = serde_json::from_slice(header)
+        .map_err(|e| Error::from(format!("Lineage header is not a JSON array: {}", e)))?;
+    hops.iter()
+        .map(|h| Hop::from_value(h).ok_or_else(|| Error::from(format!("Invalid lineage hop {}", h))))
+        .collect()
+}
+
+/// Appends `hop` to the lineage `header` of a consumed record, for tools that re-produce records
+/// downstream. A missing or unreadable header starts a new chain.
+pub fn extend(header: Option<&[u8]>, hop: &Hop) -> Vec<u8> {
+    let mut hops = match header.map(parse) {
+        Some(Ok(hops)) => hops,
+        Some(Err(e)) => {
+            warn!("Starting a new lineage: {}", e);
+            vec![]
+        }
+        None => vec![]
+    };
+    hops.push(hop.clone());
+    let hops: Vec<Value> = hops.iter().map(Hop::to_value).collect();
+    serde_json::to_vec(&hops).expect("Lineage is always serializable")
+}
+
+/// Stamps every record with a lineage header naming this shipper as its first hop.
+#[derive(Debug, Clone)]
+pub struct LineageHeaders {
+    value: Vec<u8>
+}
+
+impl LineageHeaders {
+    pub fn new(hop: &Hop) -> LineageHeaders {
+        LineageHeaders {
+            value: extend(None, hop)
+        }
+    }
+}
+
+impl HeaderGenerator for LineageHeaders {
+    fn generate(&self, _msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        vec![ (LINEAGE_HEADER.to_string(), self.value.clone()) ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hop(sensor_id: &str) -> Hop {
+        Hop {
+            sensor_id: sensor_id.to_string(),
+            instance_id: "default".to_string(),
+            stages: stage_hash(&["truncate=http.url=2048".to_string()])
+        }
+    }
+
+    #[test]
+    fn hashes_stage_configuration() {
+        let truncated = stage_hash(&["truncate=http.url=2048".to_string()]);
+
+        assert_eq!(truncated.len(), 16);
+        assert_eq!(truncated, stage_hash(&["truncate=http.url=2048".to_string()]));
+        assert!(truncated != stage_hash(&[]));
+    }
+
+    #[test]
+    fn extends_chains() {
+        let first = LineageHeaders::new(&hop("s1")).generate(&b"{}".to_vec());
+        let chain = extend(Some(&first[0].1), &hop("enricher"));
+
+        assert_eq!(first[0].0, LINEAGE_HEADER);
+        assert_eq!(parse(&chain).expect("Failed to parse"), vec![hop("s1"), hop("enricher")]);
+        assert_eq!(parse(&extend(Some(b"garbage"), &hop("s2"))).expect("Failed to parse"), vec![hop("s2")]);
+        assert!(parse(br#"[{"sensor_id":"s1"}]"#).is_err());
+    }
+}
@@ -1,156 +1,141 @@
-#![recursion_limit="128"]
-#![feature(try_from, test)]
-#![allow(dead_code)]
-extern crate bytes;
 extern crate env_logger;
-#[macro_use] extern crate error_chain;
-#[macro_use] extern crate futures;
-#[macro_use(debug, info, error, log, trace, warn)] extern crate log;
-//#[macro_use] extern crate nom;
-extern crate serde;
-extern crate serde_json;
-extern crate rdkafka;
-#[macro_use] extern crate structopt;
-extern crate tokio;
-extern crate tokio_uds;
+#[macro_use(info, warn)] extern crate log;
+extern crate structopt;
+extern crate surikafka;
 
-pub mod errors {
-    use std;
-    use super::{
-        futures,
-        //nom
-    };
+use structopt::StructOpt;
+use surikafka::{
+    config,
+    errors::Error,
+    group::PipelineGroup,
+    guard,
+    inspect::ConfigDiff,
+    pipeline::{
+        self,
+        Pipeline,
+        Settings
+    },
+    print_error
+};
 
-    // Create the Error, ErrorKind, ResultExt, and Result types
-    error_chain! {
-        foreign_links {
-            Canceled(futures::Canceled) #[doc = "Future cancelled"];
-            Io(std::io::Error) #[doc = "Error during IO"];
-            Ffi(std::ffi::NulError) #[doc = "Error during FFI conversion"];
-            FromUtf8(std::string::FromUtf8Error) #[doc = "Error during UTF8 conversion"];
-            TimeError(std::time::SystemTimeError) #[doc = "Error during duration calculation"];
-            Utf8(std::str::Utf8Error) #[doc = "Error during UTF8 conversion"];
-        }
-//        links {
-//            ErrorName(error_lib::errors::Error, error_lib::errors::ErrorKind);
-//        }
-        errors {
-            ReceiverError {
-                display("Receiver encountered an error")
-            }
-            NomIncomplete(needed: String) {
-                display("Not enough data to parse, needed {}", needed)
-            }
-            NomError(message: String) {
-                display("Error parsing: {}", message)
-            }
-        }
-    }
+/// Short forms of flags, so a config setting isn't applied when given as a short flag.
+const SHORT_FLAGS: &'static [(char, &'static str)] = &[('e', "eve"), ('k', "kafka"), ('t', "topic")];
 
-//    impl<I, E> From<nom::Err<I, E>> for Error {
-//        fn from(err: nom::Err<I, E>) -> Error {
-//            match err {
-//                nom::Err::Incomplete(super::nom::Needed::Unknown) => {
-//                    Error::from_kind(ErrorKind::NomIncomplete("Unknown".to_string()))
-//                }
-//                nom::Err::Incomplete(super::nom::Needed::Size(sz)) => {
-//                    Error::from_kind(ErrorKind::NomIncomplete(format!("{}", sz)))
-//                }
-//                nom::Err::Error(super::nom::simple_errors::Context::Code(_, k)) => {
-//                    Error::from_kind(ErrorKind::NomError(k.description().to_string()))
-//                }
-//                nom::Err::Failure(super::nom::simple_errors::Context::Code(_, k)) => {
-//                    Error::from_kind(ErrorKind::NomError(k.description().to_string()))
-//                }
-//            }
-//        }
-//    }
+/// The command line, with a leading `inspect` command turned into `--inspect`.
+fn command_line() -> Vec<String> {
+    let mut argv: Vec<String> = std::env::args().collect();
+    if argv.get(1).map(|a| a == "inspect").unwrap_or(false) {
+        argv[1] = "--inspect".to_string();
+    }
+    argv
 }
 
-mod json;
-mod key;
-mod reader;
-mod stats;
-mod writer;
-
-#[derive(Debug, StructOpt, Clone)]
-pub struct CommandLineArguments {
-    #[structopt(long = "eve", short = "e", default_value="/tmp/suricata.alerts")]
-    eve_socket_path: String,
-    #[structopt(long = "kafka", short = "k", default_value="kafka:9092")]
-    kafka_servers: String,
-    #[structopt(long = "topic", short = "t", default_value="eve-alerts")]
-    topic: String
+fn config_path(argv: &[String]) -> Option<String> {
+    argv.iter().enumerate().filter_map(|(i, a)| {
+        if a == "--config" {
+            argv.get(i + 1).cloned()
+        } else if a.starts_with("--config=") {
+            Some(a["--config=".len()..].to_string())
+        } else {
+            None
+        }
+    }).last()
 }
 
-use errors::Error;
-use futures::{
-    Future,
-    Stream
-};
-use structopt::StructOpt;
-use writer::WithProduce;
-
-fn print_error(err: &Error) {
-    error!("error: {}", err);
-
-    for e in err.iter().skip(1) {
-        error!("caused by: {}", e);
-    }
-
-// The backtrace is not always generated. Try to run this example
-// with `RUST_BACKTRACE=1`.
-    if let Some(backtrace) = err.backtrace() {
-        error!("backtrace: {:?}", backtrace)
+/// Parses the command line, filling in flags not given there from the `--config` file, once
+/// for each pipeline the config file defines.
+fn load_pipelines(argv: Vec<String>) -> Result<Vec<(String, Settings)>, Error> {
+    match config_path(&argv) {
+        Some(path) => config_pipelines(&path, &argv),
+        None => {
+            let settings = Settings::from_iter(argv);
+            Ok(vec![ (settings.instance_id.clone(), settings) ])
+        }
     }
 }
 
-fn run_main(args: CommandLineArguments) -> Result<(), Error> {
-    let mut rt = tokio::runtime::Runtime::new().map_err(Error::from)?;
-
-    let producer: rdkafka::producer::FutureProducer = rdkafka::ClientConfig::new()
-        .set("bootstrap.servers", args.kafka_servers.as_str())
-        .set("produce.offset.report", "true")
-        .set("message.timeout.ms", "5000")
-        .create()
-        .expect("Producer creation error");
-
-    let uds_path = std::path::PathBuf::from(args.eve_socket_path);
-
-    if uds_path.exists() {
-        std::fs::remove_file(uds_path.clone()).map_err(Error::from)?
+/// Settings of each pipeline of the config file at `path`, with the flags of `argv` taking
+/// precedence.
+fn config_pipelines(path: &str, argv: &[String]) -> Result<Vec<(String, Settings)>, Error> {
+    let config = config::Config::load(path)?;
+    for diagnostic in config.diagnostics.iter() {
+        warn!("{}: {}", path, diagnostic);
     }
 
-    let listener = tokio_uds::UnixListener::bind(uds_path).map_err(Error::from)?;
-
-    let stream_res = listener.incoming()
-        .map_err(Error::from)
-        .map(|s| {
-            debug!("Stream connected at {:?}", s.peer_addr());
-            reader::EveReader::new(s)
-        }).flatten()
-        .produce(
-            args.topic.clone(),
-            key::BytesGenerator,
-            producer
-        ).for_each(|_| {
-        Ok(())
-    });
-
-    let res = rt.block_on(stream_res)?;
-
-    let _ = rt.shutdown_now().wait();
+    Ok(config.pipelines()?.into_iter().map(|(name, pipeline)| {
+        let mut merged = argv[..1].to_vec();
+        merged.extend(pipeline.to_args(&argv, SHORT_FLAGS));
+        merged.extend(argv[1..].iter().cloned());
+        (name, Settings::from_iter(merged))
+    }).collect())
+}
 
-    Ok(res)
+/// `diff-config old new --sample eve.json`: runs the sample through each pipeline of both
+/// config files with --inspect and prints how their routing, filtering, and keys differ,
+/// returning whether they do.
+fn diff_config(argv: &[String]) -> Result<bool, Error> {
+    let (old, new) = match (argv.get(2), argv.get(3)) {
+        (Some(old), Some(new)) if !old.starts_with("--") && !new.starts_with("--") => (old, new),
+        _ => return Err(Error::from("Usage: surikafka diff-config old.toml new.toml --sample eve.json"))
+    };
+    let sample = argv.iter().position(|a| a == "--sample")
+        .and_then(|i| argv.get(i + 1))
+        .ok_or_else(|| Error::from("diff-config requires --sample"))?;
+
+    let mut new_pipelines = config_pipelines(new, &argv[..1])?;
+    let mut differs = false;
+    for (name, old_settings) in config_pipelines(old, &argv[..1])? {
+        let new_settings = match new_pipelines.iter().position(|&(ref n, _)| *n == name) {
+            Some(i) => new_pipelines.remove(i).1,
+            None => {
+                println!("Pipeline {} is only in {}", name, old);
+                differs = true;
+                continue
+            }
+        };
+        let diff = ConfigDiff::new(
+            pipeline::inspect_sample(old_settings, sample)?,
+            pipeline::inspect_sample(new_settings, sample)?
+        );
+        println!("Pipeline {}: {}", name, diff);
+        differs |= !diff.is_empty();
+    }
+    for (name, _) in new_pipelines {
+        println!("Pipeline {} is only in {}", name, new);
+        differs = true;
+    }
+    Ok(differs)
 }
 
 fn main() {
-    let args = CommandLineArguments::from_args();
-
     let _ = env_logger::try_init();
 
-    run_main(args).err().iter().for_each(print_error);
+    guard::install_panic_hook();
+
+    let argv = command_line();
+    if argv.get(1).map(|a| a == "diff-config").unwrap_or(false) {
+        match diff_config(&argv) {
+            Ok(differs) => ::std::process::exit(if differs { 1 } else { 0 }),
+            Err(e) => {
+                print_error(&e);
+                ::std::process::exit(2)
+            }
+        }
+    }
+
+    let res = load_pipelines(argv)
+        .and_then(|pipelines| {
+            pipelines.into_iter()
+                .fold(PipelineGroup::new(), |group, (name, settings)| group.with_pipeline(&name, Pipeline::new(settings)))
+                .run_blocking()
+        });
 
     info!("Exiting");
-    ::std::process::exit(0);
+    match res {
+        Ok(()) => ::std::process::exit(0),
+        Err(e) => {
+            print_error(&e);
+            ::std::process::exit(1)
+        }
+    }
 }
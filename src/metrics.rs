@@ -0,0 +1,600 @@
+use super::hdrhistogram::Histogram;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::Duration
+};
+
+/// Highest latency tracked, in microseconds; longer latencies are clamped.
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+/// Largest message size tracked; larger sizes are clamped.
+const MAX_SIZE_BYTES: u64 = 1 << 30;
+
+fn to_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1000) as u64
+}
+
+fn from_micros(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1000) as u32)
+}
+
+/// Depth of an internal queue, with the highest depth seen since the last reset.
+#[derive(Clone)]
+pub struct QueueGauge {
+    name: String,
+    depth: Arc<AtomicUsize>,
+    high_watermark: Arc<AtomicUsize>
+}
+
+impl QueueGauge {
+    pub fn new(name: &str) -> QueueGauge {
+        QueueGauge {
+            name: name.to_string(),
+            depth: Arc::new(AtomicUsize::new(0)),
+            high_watermark: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn name(&self) -> &str { self.name.as_str() }
+    pub fn depth(&self) -> usize { self.depth.load(Ordering::SeqCst) }
+    pub fn high_watermark(&self) -> usize { self.high_watermark.load(Ordering::SeqCst) }
+
+    fn observe(&self, depth: usize) {
+        let mut high = self.high_watermark.load(Ordering::SeqCst);
+        while depth > high {
+            let previous = self.high_watermark.compare_and_swap(high, depth, Ordering::SeqCst);
+            if previous == high {
+                break
+            }
+            high = previous;
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        let depth = self.depth.fetch_add(n, Ordering::SeqCst) + n;
+        self.observe(depth);
+    }
+
+    pub fn sub(&self, n: usize) {
+        self.depth.fetch_sub(n, Ordering::SeqCst);
+    }
+
+    /// Resets the high watermark to the current depth, returning the previous high watermark.
+    pub fn reset_high_watermark(&self) -> usize {
+        self.high_watermark.swap(self.depth(), Ordering::SeqCst)
+    }
+}
+
+/// Monotonic count of events, such as panics contained in a pipeline stage.
+#[derive(Clone)]
+pub struct Counter {
+    name: String,
+    value: Arc<AtomicUsize>
+}
+
+impl Counter {
+    pub fn new(name: &str) -> Counter {
+        Counter {
+            name: name.to_string(),
+            value: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn name(&self) -> &str { self.name.as_str() }
+    pub fn value(&self) -> usize { self.value.load(Ordering::SeqCst) }
+
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::SeqCst);
+    }
+}
+
+/// Current value of something that goes up and down, such as librdkafka's queue length.
+#[derive(Clone)]
+pub struct Gauge {
+    name: String,
+    value: Arc<AtomicUsize>
+}
+
+impl Gauge {
+    pub fn new(name: &str) -> Gauge {
+        Gauge {
+            name: name.to_string(),
+            value: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn name(&self) -> &str { self.name.as_str() }
+    pub fn value(&self) -> usize { self.value.load(Ordering::SeqCst) }
+
+    pub fn set(&self, value: usize) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Latency distribution with 3 significant digits, from 1us to an hour. Percentiles cover the
+/// interval since the last snapshot; the count and sum cover the life of the histogram.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    name: String,
+    histogram: Arc<Mutex<Histogram<u64>>>,
+    count: Arc<AtomicUsize>,
+    sum_micros: Arc<AtomicUsize>
+}
+
+impl LatencyHistogram {
+    pub fn new(name: &str) -> LatencyHistogram {
+        LatencyHistogram {
+            name: name.to_string(),
+            histogram: Arc::new(Mutex::new(
+                Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).expect("Invalid histogram bounds")
+            )),
+            count: Arc::new(AtomicUsize::new(0)),
+            sum_micros: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn name(&self) -> &str { self.name.as_str() }
+    pub fn total_count(&self) -> usize { self.count.load(Ordering::SeqCst) }
+    pub fn total_sum(&self) -> Duration { from_micros(self.sum_micros.load(Ordering::SeqCst) as u64) }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = to_micros(latency).max(1);
+        let mut histogram = self.histogram.lock().expect("Histogram lock poisoned");
+        histogram.saturating_record(micros);
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.sum_micros.fetch_add(micros.min(MAX_LATENCY_MICROS) as usize, Ordering::SeqCst);
+    }
+
+    /// Percentiles since the last snapshot, resetting the histogram for the next interval.
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let snapshot = self.peek();
+        self.histogram.lock().expect("Histogram lock poisoned").reset();
+        snapshot
+    }
+
+    /// Percentiles since the last snapshot, without resetting the histogram.
+    pub fn peek(&self) -> LatencySnapshot {
+        let histogram = self.histogram.lock().expect("Histogram lock poisoned");
+        LatencySnapshot {
+            name: self.name.clone(),
+            count: histogram.len(),
+            p50: from_micros(histogram.value_at_quantile(0.5)),
+            p95: from_micros(histogram.value_at_quantile(0.95)),
+            p99: from_micros(histogram.value_at_quantile(0.99)),
+            p999: from_micros(histogram.value_at_quantile(0.999)),
+            max: from_micros(histogram.max())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySnapshot {
+    pub name: String,
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration
+}
+
+/// Distribution of message sizes in bytes, up to 1 GiB. Like `LatencyHistogram`, the count and
+/// sum cover the life of the histogram.
+#[derive(Clone)]
+pub struct SizeHistogram {
+    name: String,
+    histogram: Arc<Mutex<Histogram<u64>>>,
+    count: Arc<AtomicUsize>,
+    sum: Arc<AtomicUsize>
+}
+
+impl SizeHistogram {
+    pub fn new(name: &str) -> SizeHistogram {
+        SizeHistogram {
+            name: name.to_string(),
+            histogram: Arc::new(Mutex::new(
+                Histogram::new_with_bounds(1, MAX_SIZE_BYTES, 3).expect("Invalid histogram bounds")
+            )),
+            count: Arc::new(AtomicUsize::new(0)),
+            sum: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn name(&self) -> &str { self.name.as_str() }
+    pub fn total_count(&self) -> usize { self.count.load(Ordering::SeqCst) }
+    pub fn total_sum(&self) -> usize { self.sum.load(Ordering::SeqCst) }
+
+    pub fn record(&self, bytes: usize) {
+        let mut histogram = self.histogram.lock().expect("Histogram lock poisoned");
+        histogram.saturating_record((bytes as u64).max(1));
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.sum.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Percentiles since the last snapshot, resetting the histogram for the next interval.
+    pub fn snapshot(&self) -> SizeSnapshot {
+        let snapshot = self.peek();
+        self.histogram.lock().expect("Histogram lock poisoned").reset();
+        snapshot
+    }
+
+    /// Percentiles since the last snapshot, without resetting the histogram.
+    pub fn peek(&self) -> SizeSnapshot {
+        let histogram = self.histogram.lock().expect("Histogram lock poisoned");
+        SizeSnapshot {
+            name: self.name.clone(),
+            count: histogram.len(),
+            p50: histogram.value_at_quantile(0.5),
+            p95: histogram.value_at_quantile(0.95),
+            p99: histogram.value_at_quantile(0.99),
+            max: histogram.max()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeSnapshot {
+    pub name: String,
+    pub count: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64
+}
+
+/// Payload sizes by `event_type` and by topic, plus message and byte counters per topic, for
+/// capacity planning. Metrics are looked up once and cached.
+pub struct SizeMetrics {
+    registry: Registry,
+    histograms: HashMap<String, SizeHistogram>,
+    counters: HashMap<String, Counter>
+}
+
+impl SizeMetrics {
+    pub fn new(registry: Registry) -> SizeMetrics {
+        SizeMetrics {
+            registry: registry,
+            histograms: HashMap::new(),
+            counters: HashMap::new()
+        }
+    }
+
+    fn histogram(&mut self, name: String) -> &SizeHistogram {
+        let registry = &self.registry;
+        self.histograms.entry(name.clone()).or_insert_with(|| registry.size_histogram(&name))
+    }
+
+    fn counter(&mut self, name: String) -> &Counter {
+        let registry = &self.registry;
+        self.counters.entry(name.clone()).or_insert_with(|| registry.counter(&name))
+    }
+
+    pub fn record(&mut self, topic: &str, event_type: Option<&str>, bytes: usize) {
+        self.histogram(format!("payload_size.event_type.{}", event_type.unwrap_or("unknown"))).record(bytes);
+        self.histogram(format!("payload_size.topic.{}", topic)).record(bytes);
+        self.counter(format!("topic.{}.messages", topic)).incr();
+        self.counter(format!("topic.{}.bytes", topic)).add(bytes);
+    }
+}
+
+/// Bytes produced to a topic against the bytes read from the sources, see
+/// `Registry::amplification`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amplification {
+    pub topic: String,
+    pub bytes: usize,
+    /// Bytes sent to the topic per byte read, after filtering, transforms and encoding
+    pub ratio: f64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueSnapshot {
+    pub name: String,
+    pub depth: usize,
+    pub high_watermark: usize
+}
+
+/// Collection of the gauges of every stage in the pipeline.
+#[derive(Clone, Default)]
+pub struct Registry {
+    queues: Arc<Mutex<Vec<QueueGauge>>>,
+    counters: Arc<Mutex<Vec<Counter>>>,
+    gauges: Arc<Mutex<Vec<Gauge>>>,
+    histograms: Arc<Mutex<Vec<LatencyHistogram>>>,
+    sizes: Arc<Mutex<Vec<SizeHistogram>>>
+}
+
+impl Registry {
+    /// Returns the gauge named `name`, registering it if necessary.
+    pub fn queue(&self, name: &str) -> QueueGauge {
+        let mut queues = self.queues.lock().expect("Registry lock poisoned");
+        if let Some(gauge) = queues.iter().find(|q| q.name() == name) {
+            return gauge.clone()
+        }
+        let gauge = QueueGauge::new(name);
+        queues.push(gauge.clone());
+        gauge
+    }
+
+    /// Returns the counter named `name`, registering it if necessary.
+    pub fn counter(&self, name: &str) -> Counter {
+        let mut counters = self.counters.lock().expect("Registry lock poisoned");
+        if let Some(counter) = counters.iter().find(|c| c.name() == name) {
+            return counter.clone()
+        }
+        let counter = Counter::new(name);
+        counters.push(counter.clone());
+        counter
+    }
+
+    /// Returns the gauge named `name`, registering it if necessary.
+    pub fn gauge(&self, name: &str) -> Gauge {
+        let mut gauges = self.gauges.lock().expect("Registry lock poisoned");
+        if let Some(gauge) = gauges.iter().find(|g| g.name() == name) {
+            return gauge.clone()
+        }
+        let gauge = Gauge::new(name);
+        gauges.push(gauge.clone());
+        gauge
+    }
+
+    /// Returns the histogram named `name`, registering it if necessary.
+    pub fn histogram(&self, name: &str) -> LatencyHistogram {
+        let mut histograms = self.histograms.lock().expect("Registry lock poisoned");
+        if let Some(histogram) = histograms.iter().find(|h| h.name() == name) {
+            return histogram.clone()
+        }
+        let histogram = LatencyHistogram::new(name);
+        histograms.push(histogram.clone());
+        histogram
+    }
+
+    /// Percentiles of every registered histogram, resetting them for the next interval.
+    pub fn histogram_snapshots(&self) -> Vec<LatencySnapshot> {
+        let histograms = self.histograms.lock().expect("Registry lock poisoned");
+        histograms.iter().map(|h| h.snapshot()).collect()
+    }
+
+    /// Returns the size histogram named `name`, registering it if necessary.
+    pub fn size_histogram(&self, name: &str) -> SizeHistogram {
+        let mut sizes = self.sizes.lock().expect("Registry lock poisoned");
+        if let Some(histogram) = sizes.iter().find(|h| h.name() == name) {
+            return histogram.clone()
+        }
+        let histogram = SizeHistogram::new(name);
+        sizes.push(histogram.clone());
+        histogram
+    }
+
+    /// Percentiles of every registered size histogram, resetting them for the next interval.
+    pub fn size_snapshots(&self) -> Vec<SizeSnapshot> {
+        let sizes = self.sizes.lock().expect("Registry lock poisoned");
+        sizes.iter().map(|h| h.snapshot()).collect()
+    }
+
+    /// Current value of every registered counter.
+    pub fn counter_values(&self) -> Vec<(String, usize)> {
+        let counters = self.counters.lock().expect("Registry lock poisoned");
+        counters.iter()
+            .map(|c| (c.name().to_string(), c.value()))
+            .collect()
+    }
+
+    /// Current depth of every registered queue, without resetting the watermarks.
+    pub fn queue_depths(&self) -> Vec<(String, usize)> {
+        let queues = self.queues.lock().expect("Registry lock poisoned");
+        queues.iter()
+            .map(|q| (q.name().to_string(), q.depth()))
+            .collect()
+    }
+
+    /// Write amplification of every topic recorded by `SizeMetrics`, as its `topic.<t>.bytes`
+    /// counter over the counter named `input`. None until anything has been read.
+    pub fn amplification(&self, input: &str) -> Option<Vec<Amplification>> {
+        let counters = self.counter_values();
+        let read = counters.iter().find(|&&(ref name, _)| name == input).map(|&(_, v)| v).unwrap_or(0);
+        if read == 0 {
+            return None
+        }
+        Some(counters.iter()
+            .filter(|&&(ref name, _)| name.starts_with("topic.") && name.ends_with(".bytes"))
+            .map(|&(ref name, bytes)| Amplification {
+                topic: name["topic.".len()..name.len() - ".bytes".len()].to_string(),
+                bytes: bytes,
+                ratio: bytes as f64 / read as f64
+            })
+            .collect())
+    }
+
+    /// Current depths and high watermarks, resetting the watermarks for the next interval.
+    pub fn snapshot(&self) -> Vec<QueueSnapshot> {
+        let queues = self.queues.lock().expect("Registry lock poisoned");
+        queues.iter()
+            .map(|q| QueueSnapshot {
+                name: q.name().to_string(),
+                depth: q.depth(),
+                high_watermark: q.reset_high_watermark()
+            })
+            .collect()
+    }
+
+    /// Every registered metric in the Prometheus text exposition format, without resetting
+    /// anything. Names are prefixed with `surikafka_`, with dots and other invalid characters
+    /// replaced by underscores; latency summaries are in seconds.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        for counter in self.counters.lock().expect("Registry lock poisoned").iter() {
+            let name = format!("{}_total", prometheus_name(counter.name()));
+            out.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, counter.value()));
+        }
+        for queue in self.queues.lock().expect("Registry lock poisoned").iter() {
+            let name = format!("{}_depth", prometheus_name(queue.name()));
+            out.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, queue.depth()));
+        }
+        for gauge in self.gauges.lock().expect("Registry lock poisoned").iter() {
+            let name = prometheus_name(gauge.name());
+            out.push_str(&format!("# TYPE {} gauge\n{} {}\n", name, name, gauge.value()));
+        }
+        for histogram in self.histograms.lock().expect("Registry lock poisoned").iter() {
+            let name = format!("{}_seconds", prometheus_name(histogram.name()));
+            let peek = histogram.peek();
+            out.push_str(&format!("# TYPE {} summary\n", name));
+            for &(quantile, value) in [("0.5", peek.p50), ("0.95", peek.p95), ("0.99", peek.p99), ("0.999", peek.p999)].iter() {
+                out.push_str(&format!("{}{{quantile=\"{}\"}} {}\n", name, quantile, to_seconds(value)));
+            }
+            out.push_str(&format!("{}_sum {}\n{}_count {}\n", name, to_seconds(histogram.total_sum()), name, histogram.total_count()));
+        }
+        for histogram in self.sizes.lock().expect("Registry lock poisoned").iter() {
+            let name = format!("{}_bytes", prometheus_name(histogram.name()));
+            let peek = histogram.peek();
+            out.push_str(&format!("# TYPE {} summary\n", name));
+            for &(quantile, value) in [("0.5", peek.p50), ("0.95", peek.p95), ("0.99", peek.p99)].iter() {
+                out.push_str(&format!("{}{{quantile=\"{}\"}} {}\n", name, quantile, value));
+            }
+            out.push_str(&format!("{}_sum {}\n{}_count {}\n", name, histogram.total_sum(), name, histogram.total_count()));
+        }
+        out
+    }
+}
+
+fn to_seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// `surikafka_` followed by `name` with every character Prometheus doesn't allow replaced by `_`.
+pub fn prometheus_name(name: &str) -> String {
+    let sanitized: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    format!("surikafka_{}", sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_depth_and_watermark() {
+        let gauge = QueueGauge::new("test");
+
+        gauge.add(5);
+        gauge.sub(3);
+        gauge.add(1);
+
+        assert_eq!(gauge.depth(), 3);
+        assert_eq!(gauge.high_watermark(), 5);
+        assert_eq!(gauge.reset_high_watermark(), 5);
+        assert_eq!(gauge.high_watermark(), 3);
+    }
+
+    #[test]
+    fn registry_shares_gauges() {
+        let registry = Registry::default();
+
+        registry.queue("reader.pending").add(2);
+        registry.queue("reader.pending").add(1);
+        registry.queue("writer.in_flight").add(1);
+
+        assert_eq!(registry.snapshot(), vec![
+            QueueSnapshot { name: "reader.pending".to_string(), depth: 3, high_watermark: 3 },
+            QueueSnapshot { name: "writer.in_flight".to_string(), depth: 1, high_watermark: 1 }
+        ]);
+    }
+
+    #[test]
+    fn tracks_latency_percentiles() {
+        let histogram = LatencyHistogram::new("writer.produce_latency");
+
+        for ms in 1..101 {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let snapshot = histogram.snapshot();
+        let near = |d: Duration, ms: u64| {
+            (to_micros(d) as i64 - (ms * 1000) as i64).abs() <= (ms * 10) as i64
+        };
+
+        assert_eq!(snapshot.count, 100);
+        assert!(near(snapshot.p50, 50));
+        assert!(near(snapshot.p99, 99));
+        assert!(near(snapshot.max, 100));
+        assert_eq!(histogram.snapshot().count, 0);
+    }
+
+    #[test]
+    fn tracks_sizes_by_event_type_and_topic() {
+        let registry = Registry::default();
+        let mut sizes = SizeMetrics::new(registry.clone());
+
+        sizes.record("eve", Some("alert"), 1000);
+        sizes.record("eve", Some("flow"), 200);
+        sizes.record("eve", None, 10);
+
+        let snapshots = registry.size_snapshots();
+        let alert = snapshots.iter().find(|s| s.name == "payload_size.event_type.alert").expect("No alert sizes");
+        let topic = snapshots.iter().find(|s| s.name == "payload_size.topic.eve").expect("No topic sizes");
+
+        assert_eq!(alert.count, 1);
+        assert_eq!(topic.count, 3);
+        assert!(snapshots.iter().any(|s| s.name == "payload_size.event_type.unknown"));
+        assert!(registry.counter_values().contains(&("topic.eve.bytes".to_string(), 1210)));
+        assert!(registry.counter_values().contains(&("topic.eve.messages".to_string(), 3)));
+    }
+
+    #[test]
+    fn reports_amplification_per_topic() {
+        let registry = Registry::default();
+        let mut sizes = SizeMetrics::new(registry.clone());
+
+        assert_eq!(registry.amplification("reader.bytes"), None);
+
+        registry.counter("reader.bytes").add(1000);
+        sizes.record("eve", Some("alert"), 1200);
+        sizes.record("eve.dns", Some("dns"), 250);
+
+        assert_eq!(registry.amplification("reader.bytes"), Some(vec![
+            Amplification { topic: "eve".to_string(), bytes: 1200, ratio: 1.2 },
+            Amplification { topic: "eve.dns".to_string(), bytes: 250, ratio: 0.25 }
+        ]));
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let registry = Registry::default();
+        registry.counter("writer.delivered").add(3);
+        registry.queue("reader.pending").add(2);
+        registry.gauge("rdkafka.msg_cnt").set(7);
+        registry.histogram("writer.produce_latency").record(Duration::from_millis(20));
+
+        let text = registry.prometheus();
+
+        assert!(text.contains("# TYPE surikafka_writer_delivered_total counter\nsurikafka_writer_delivered_total 3\n"));
+        assert!(text.contains("surikafka_reader_pending_depth 2\n"));
+        assert!(text.contains("surikafka_rdkafka_msg_cnt 7\n"));
+        assert!(text.contains("surikafka_writer_produce_latency_seconds_count 1\n"));
+        assert!(text.contains("surikafka_writer_produce_latency_seconds{quantile=\"0.5\"} 0.02"));
+        assert_eq!(registry.histogram("writer.produce_latency").snapshot().count, 1);
+    }
+
+    #[test]
+    fn registry_shares_counters() {
+        let registry = Registry::default();
+
+        registry.counter("stage.panics").incr();
+        registry.counter("stage.panics").incr();
+
+        assert_eq!(registry.counter_values(), vec![("stage.panics".to_string(), 2)]);
+    }
+}
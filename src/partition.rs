@@ -0,0 +1,73 @@
+use super::key;
+
+pub trait PartitionStrategy {
+    fn partition(&self, key: &[u8]) -> Option<i32>;
+}
+
+/// Pins a sensor to a contiguous (wrapping) block of `subset` partitions out of `partitions`,
+/// chosen from a hash of the sensor id. Keys are spread within the block, so per-key ordering is
+/// kept while each sensor's traffic stays on a predictable set of partitions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorPinned {
+    partitions: i32,
+    subset: i32,
+    first: i32
+}
+
+impl SensorPinned {
+    pub fn new(sensor_id: &str, partitions: i32, subset: i32) -> SensorPinned {
+        let partitions = partitions.max(1);
+        let subset = subset.max(1).min(partitions);
+        let first = (key::fnv1a(sensor_id.as_bytes()) % partitions as u64) as i32;
+        SensorPinned {
+            partitions: partitions,
+            subset: subset,
+            first: first
+        }
+    }
+
+    pub fn assigned(&self) -> Vec<i32> {
+        (0..self.subset).map(|i| (self.first + i) % self.partitions).collect()
+    }
+}
+
+impl PartitionStrategy for SensorPinned {
+    fn partition(&self, key: &[u8]) -> Option<i32> {
+        let offset = (key::fnv1a(key) % self.subset as u64) as i32;
+        Some((self.first + offset) % self.partitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_to_subset() {
+        let pinned = SensorPinned::new("sensor-1", 12, 3);
+        let assigned = pinned.assigned();
+
+        assert_eq!(assigned.len(), 3);
+
+        for key in &["a", "b", "c", "d", "e", "f"] {
+            let partition = pinned.partition(key.as_bytes()).expect("No partition");
+            assert!(assigned.contains(&partition));
+            assert_eq!(Some(partition), pinned.partition(key.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn is_deterministic() {
+        assert_eq!(SensorPinned::new("sensor-1", 12, 3), SensorPinned::new("sensor-1", 12, 3));
+    }
+
+    #[test]
+    fn wraps_and_clamps() {
+        let pinned = SensorPinned::new("sensor-1", 4, 10);
+
+        let mut assigned = pinned.assigned();
+        assigned.sort();
+
+        assert_eq!(assigned, vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,119 @@
+use super::{
+    derive::Derivation,
+    serde_json::{
+        self,
+        Value
+    }
+};
+
+/// Name, type, and data of one DNS answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub rrname: String,
+    pub rrtype: String,
+    pub rdata: String,
+    pub ttl: Option<u64>
+}
+
+fn answer(value: &Value) -> Option<Answer> {
+    Some(Answer {
+        rrname: value.get("rrname")?.as_str()?.to_string(),
+        rrtype: value.get("rrtype")?.as_str()?.to_string(),
+        rdata: value.get("rdata")?.as_str()?.to_string(),
+        ttl: value.get("ttl").and_then(Value::as_u64)
+    })
+}
+
+/// Answers of a `dns` event. Suricata's version 1 format logs one answer per event with the
+/// fields at the top of `dns`; version 2 logs them all in a `dns.answers` array, and may also
+/// log them `grouped` by type, which is skipped since it repeats the array.
+pub fn answers(event: &Value) -> Vec<Answer> {
+    let dns = match event.get("dns") {
+        Some(d) => d,
+        None => return vec![]
+    };
+    if dns.get("type").and_then(Value::as_str) != Some("answer") {
+        return vec![]
+    }
+    match dns.get("answers").and_then(Value::as_array) {
+        Some(answers) => answers.iter().filter_map(answer).collect(),
+        None => answer(dns).into_iter().collect()
+    }
+}
+
+/// Derives one compact passive DNS record per answer, for a dedicated topic keyed by `rrname`.
+#[derive(Default)]
+pub struct PassiveDns;
+
+impl Derivation for PassiveDns {
+    fn event_type(&self) -> &str {
+        "dns"
+    }
+
+    fn derive(&mut self, event: &Value) -> Vec<Vec<u8>> {
+        answers(event).into_iter()
+            .map(|a| {
+                let record = json!({
+                    "timestamp": event.get("timestamp"),
+                    "rrname": a.rrname,
+                    "rrtype": a.rrtype,
+                    "rdata": a.rdata,
+                    "ttl": a.ttl
+                });
+                serde_json::to_vec(&record).expect("Record is always serializable")
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_v1_answers() {
+        let event = json!({
+            "event_type": "dns",
+            "dns": {"type": "answer", "rrname": "example.com", "rrtype": "A", "rdata": "93.184.216.34", "ttl": 300}
+        });
+
+        assert_eq!(answers(&event), vec![Answer {
+            rrname: "example.com".to_string(),
+            rrtype: "A".to_string(),
+            rdata: "93.184.216.34".to_string(),
+            ttl: Some(300)
+        }]);
+    }
+
+    #[test]
+    fn extracts_v2_answers() {
+        let event = json!({
+            "event_type": "dns",
+            "timestamp": "2018-06-01T00:00:00.000000+0000",
+            "dns": {
+                "type": "answer",
+                "rrname": "www.example.com",
+                "answers": [
+                    {"rrname": "www.example.com", "rrtype": "CNAME", "rdata": "example.com", "ttl": 60},
+                    {"rrname": "example.com", "rrtype": "A", "rdata": "93.184.216.34", "ttl": 300},
+                    {"rrname": "example.com", "rrtype": "SOA", "soa": {}}
+                ],
+                "grouped": {"A": ["93.184.216.34"]}
+            }
+        });
+
+        let records = PassiveDns.derive(&event);
+        let first: Value = serde_json::from_slice(&records[0]).expect("Failed to parse");
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(first["rrtype"], "CNAME");
+        assert_eq!(first["timestamp"], "2018-06-01T00:00:00.000000+0000");
+    }
+
+    #[test]
+    fn skips_queries() {
+        let event = json!({"event_type": "dns", "dns": {"type": "query", "rrname": "example.com", "rrtype": "A"}});
+
+        assert!(PassiveDns.derive(&event).is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+use super::errors::Error;
+use std::{
+    self,
+    fs::{
+        File,
+        OpenOptions
+    },
+    io::Write,
+    path::Path
+};
+
+const CRC_PREFIX: &'static str = "\ncrc32=";
+
+/// CRC-32 (IEEE) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Appends a `crc32=<hex>` trailer line to `payload`.
+pub fn seal(payload: &[u8]) -> Vec<u8> {
+    let mut sealed = payload.to_vec();
+    sealed.extend_from_slice(format!("{}{:08x}\n", CRC_PREFIX, crc32(payload)).as_bytes());
+    sealed
+}
+
+/// Returns the payload of sealed `contents` if its checksum matches. Contents without a trailer
+/// are returned as is, so state written before checksums were added still loads.
+pub fn unseal(contents: &[u8]) -> Option<&[u8]> {
+    let text = std::str::from_utf8(contents).ok()?;
+    match text.rfind(CRC_PREFIX) {
+        Some(idx) => {
+            let payload = &contents[..idx];
+            let expected = u32::from_str_radix(text[idx + CRC_PREFIX.len()..].trim(), 16).ok()?;
+            if crc32(payload) == expected {
+                Some(payload)
+            } else {
+                None
+            }
+        }
+        None => Some(contents)
+    }
+}
+
+/// Replaces `path` with `contents` such that a crash leaves either the old or the new contents:
+/// writes a temporary file alongside it, syncs it, renames it over `path`, then syncs the
+/// directory so the rename itself is durable.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<(), Error> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => std::path::PathBuf::from(".")
+    };
+    let file_name = path.file_name()
+        .ok_or_else(|| Error::from(format!("Invalid path {}", path.display())))?
+        .to_string_lossy()
+        .to_string();
+    let temp = dir.join(format!(".{}.tmp.{}", file_name, std::process::id()));
+
+    {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.into())
+    }
+
+    File::open(&dir)?.sync_all()?;
+
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn seals_and_unseals() {
+        let sealed = seal(b"1234");
+
+        assert_eq!(sealed, b"1234\ncrc32=9be3e0a3\n".to_vec());
+        assert_eq!(unseal(&sealed), Some(&b"1234"[..]));
+        assert_eq!(unseal(b"1235\ncrc32=9be3e0a3\n"), None);
+        assert_eq!(unseal(b"1234"), Some(&b"1234"[..]));
+    }
+
+    #[test]
+    fn writes_atomically() {
+        let dir = std::env::temp_dir().join(format!("surikafka-persist-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let path = dir.join("state");
+
+        write_atomic(&path, b"first").expect("Failed to write");
+        write_atomic(&path, b"second").expect("Failed to write");
+
+        assert_eq!(std::fs::read(&path).expect("Failed to read"), b"second".to_vec());
+        assert_eq!(std::fs::read_dir(&dir).expect("Failed to list").count(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,2773 @@
+use super::{
+    anomaly,
+    batch,
+    breaker,
+    budget,
+    canary,
+    cancel::{
+        CancellationToken,
+        WithCancellation
+    },
+    catchup,
+    certs,
+    checkpoint::{
+        self,
+        CheckpointStore
+    },
+    clock,
+    chrono,
+    context,
+    errors::{
+        Error,
+        ErrorKind
+    },
+    derive::{
+        self,
+        DerivedStreams
+    },
+    escalate,
+    eve,
+    extension,
+    fds::{
+        self,
+        FdAccounting,
+        FdKind
+    },
+    flowbits,
+    format,
+    futures::{
+        self,
+        Future,
+        IntoFuture,
+        Stream,
+        future,
+        sync::{
+            mpsc::UnboundedSender,
+            oneshot::{
+                self,
+                SpawnHandle
+            }
+        }
+    },
+    guard,
+    journal,
+    health::{
+        self,
+        WithDropMonitor
+    },
+    hlc,
+    iface,
+    inspect,
+    json,
+    key,
+    lag,
+    lineage,
+    metrics,
+    partition,
+    pdns,
+    persist,
+    presets,
+    print_error,
+    rdkafka::{
+        self,
+        producer::{
+            FutureProducer,
+            FutureRecord
+        }
+    },
+    reader,
+    recent,
+    registry,
+    remote,
+    replay,
+    routes,
+    shed,
+    shutdown::{
+        self,
+        Drained,
+        Shutdown,
+        ShutdownReport
+    },
+    sink::{
+        self,
+        SinkTap
+    },
+    source,
+    spool,
+    storm,
+    structopt::StructOpt,
+    suppress,
+    tenant,
+    throttle::{
+        ShipperContext,
+        ThrottleSignal
+    },
+    tokio::{
+        self,
+        io::AsyncRead,
+        runtime::{
+            Runtime,
+            TaskExecutor
+        }
+    },
+    topics::{
+        self,
+        TopicProvisioner
+    },
+    totals,
+    trace,
+    transform,
+    truncate,
+    verify,
+    warm,
+    writer::{
+        self,
+        WithProduce
+    }
+};
+#[cfg(feature = "admin")]
+use super::{
+    admin,
+    serde_json
+};
+#[cfg(feature = "archive")]
+use super::{
+    archive,
+    s3
+};
+#[cfg(feature = "enrichment")]
+use super::{
+    attack,
+    rules
+};
+#[cfg(feature = "sinks")]
+use super::{
+    elastic,
+    redis
+};
+#[cfg(feature = "plugins")]
+use super::tokio_signal;
+use std::{
+    self,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    }
+};
+
+/// Everything needed to build a pipeline; parsed from the command line by the binary, or built
+/// with `Settings::from_iter` or `Settings::default()` by embedders.
+#[derive(Debug, StructOpt, Clone)]
+pub struct Settings {
+    /// JSON, TOML (.toml), or YAML (.yaml) file of settings keyed by long flag name; flags on the
+    /// command line take precedence
+    #[structopt(long = "config")]
+    pub config: Option<String>,
+    #[structopt(long = "eve", short = "e", default_value="/tmp/suricata.alerts")]
+    pub eve_socket_path: String,
+    /// Accept EVE connections on this TCP address instead of the socket
+    #[structopt(long = "eve-tcp")]
+    pub eve_tcp: Option<std::net::SocketAddr>,
+    /// Read events from this file (optionally gzip compressed) instead of the socket
+    #[structopt(long = "eve-file")]
+    pub eve_file: Option<String>,
+    /// Also read the events of one capture interface from a file of its own, as label=path; may
+    /// be repeated. Events get an iface_label field usable with --key-by iface_label and as
+    /// {iface} in topics, and an iface header
+    #[structopt(long = "interface-file")]
+    pub interface_file: Vec<String>,
+    /// Format of the events of --eve-file or an --interface-file, as path=format; may be
+    /// repeated. One of eve (the default), zeek for Zeek JSON logs, osquery for osquery results,
+    /// or ndjson for any other NDJSON, which are normalized to EVE's event_type, timestamp, and
+    /// endpoint fields so they're filtered, keyed, and routed like EVE events
+    #[structopt(long = "source-format")]
+    pub source_format: Vec<String>,
+    /// Keep following --eve-file and --interface-file as they grow, across rotation and truncation
+    #[structopt(long = "follow")]
+    pub follow: bool,
+    /// Where to begin reading --eve-file: start, end, resume-checkpoint, or time:-15m
+    #[structopt(long = "start-position", default_value="start")]
+    pub start_position: source::StartPosition,
+    /// When resuming a followed file behind its end, tail it live from the end straight away and
+    /// read the backlog alongside at up to this many events a second; a restart part way resumes
+    /// both where they stopped
+    #[structopt(long = "catch-up-rate")]
+    pub catch_up_rate: Option<f64>,
+    /// When resuming a followed file behind its end, tail it live from the end straight away and
+    /// abandon whatever backlog is left after this many seconds
+    #[structopt(long = "catch-up-max-secs")]
+    pub catch_up_max_secs: Option<u64>,
+    #[structopt(long = "checkpoint-dir", default_value="/var/lib/surikafka")]
+    pub checkpoint_dir: String,
+    /// Where checkpoints are kept: file, under --checkpoint-dir, or a backend registered by
+    /// the embedder
+    #[structopt(long = "checkpoint-backend", default_value="file")]
+    pub checkpoint_backend: String,
+    /// Location handed to a --checkpoint-backend other than file, e.g. an etcd or Consul URL;
+    /// defaults to --checkpoint-dir
+    #[structopt(long = "checkpoint-location")]
+    pub checkpoint_location: Option<String>,
+    /// Carry the delivered, delivered bytes, and failed counters across restarts in
+    /// --checkpoint-dir, so they never reset on a deploy
+    #[structopt(long = "persist-counters")]
+    pub persist_counters: bool,
+    /// Seconds between saves of --persist-counters, which are also saved on shutdown
+    #[structopt(long = "persist-counters-secs", default_value="60")]
+    pub persist_counters_secs: u64,
+    /// On SIGTERM or SIGINT, how long to wait for records already read to be delivered before
+    /// exiting without saving the --eve-file checkpoint
+    #[structopt(long = "shutdown-grace-secs", default_value="30")]
+    pub shutdown_grace_secs: u64,
+    /// Instead of exiting without saving checkpoints after --shutdown-grace-secs, abandon the
+    /// records still buffered or in flight this many seconds after SIGTERM or SIGINT, saving the
+    /// checkpoints past them and recording how many were left behind
+    #[structopt(long = "shutdown-deadline-secs")]
+    pub shutdown_deadline_secs: Option<u64>,
+    /// Write a JSON report of how the pipeline stopped to this file: whether it drained, the
+    /// records abandoned in each queue, the byte range of each source they were read from, and
+    /// the position checkpointed for each source
+    #[structopt(long = "shutdown-report")]
+    pub shutdown_report: Option<String>,
+    /// Distinguishes the checkpoints of several shippers running on one host
+    #[structopt(long = "instance-id", default_value="default")]
+    pub instance_id: String,
+    /// Events longer than this are skipped as corrupt
+    #[structopt(long = "max-line-length", default_value="4194304")]
+    pub max_line_length: usize,
+    /// Escape invalid UTF-8 bytes as `\xNN` instead of replacing them with U+FFFD
+    #[structopt(long = "utf8-escape")]
+    pub utf8_escape: bool,
+    /// Debugging aid: remember the file and line each recent event was read from, and name it
+    /// when the event's delivery fails. Events changed before the writer, e.g. labeled or
+    /// transformed, aren't found
+    #[structopt(long = "trace-sources")]
+    pub trace_sources: bool,
+    #[structopt(long = "kafka", short = "k", default_value="kafka:9092")]
+    pub kafka_servers: String,
+    #[structopt(long = "topic", short = "t", default_value="eve-alerts")]
+    pub topic: String,
+    #[structopt(long = "control-topic", default_value="surikafka.control")]
+    pub control_topic: String,
+    #[structopt(long = "max-drop-rate", default_value="0.01")]
+    pub max_drop_rate: f64,
+    #[structopt(long = "min-drop-packets", default_value="1000")]
+    pub min_drop_packets: u64,
+    #[structopt(long = "sensor-id")]
+    pub sensor_id: Option<String>,
+    /// Records awaiting delivery at which to drop stats, sample flow, and sample dns events, e.g.
+    /// 10000,50000,100000; alerts are never shed
+    #[structopt(long = "shed-thresholds")]
+    pub shed_thresholds: Option<String>,
+    /// Back events up in a fixed size spool file while the writer is stalled, overwriting the
+    /// oldest once full
+    #[structopt(long = "spool-path")]
+    pub spool_path: Option<String>,
+    #[structopt(long = "spool-bytes", default_value="67108864")]
+    pub spool_bytes: u64,
+    /// Fraction of flow and dns events kept while they are sampled
+    #[structopt(long = "shed-sample-rate", default_value="0.1")]
+    pub shed_sample_rate: f64,
+    #[structopt(long = "registry-topic", default_value="sensors.registry")]
+    pub registry_topic: String,
+    /// File containing the secret used to HMAC message keys on the event topic
+    #[structopt(long = "key-secret-file")]
+    pub key_secret_file: Option<String>,
+    /// Key the event topic by payload, flow-id, five-tuple, or sensor, so a flow's events share a
+    /// partition
+    #[structopt(long = "key-strategy", default_value="payload")]
+    pub key_strategy: key::KeyStrategy,
+    /// Comma separated event dimensions (vlan, in_iface, tenant_id, iface_label) to key the event topic by
+    #[structopt(long = "key-by")]
+    pub key_by: Option<String>,
+    /// Key smb, krb5, nfs, rdp, mqtt, quic, pgsql, and frame events by their session or subject
+    /// rather than --key-by
+    #[structopt(long = "protocol-keys")]
+    pub protocol_keys: bool,
+    /// Key an event type by JSON pointers with --protocol-keys, as event_type=pointer,pointer,
+    /// replacing its default; may be repeated
+    #[structopt(long = "protocol-preset")]
+    pub protocol_preset: Vec<String>,
+    /// Key records with this keyer stage of --plugin-dir rather than --key-strategy
+    #[structopt(long = "key-plugin")]
+    pub key_plugin: Option<String>,
+    /// Number of partitions in the event topic, required for --sensor-partitions
+    #[structopt(long = "topic-partitions")]
+    pub topic_partitions: Option<i32>,
+    /// Pin this sensor to a fixed block of this many partitions of the event topic
+    #[structopt(long = "sensor-partitions")]
+    pub sensor_partitions: Option<i32>,
+    /// Stop producing for a cooldown once this fraction of recent deliveries have failed
+    #[structopt(long = "breaker-error-rate")]
+    pub breaker_error_rate: Option<f64>,
+    #[structopt(long = "breaker-cooldown-secs", default_value="30")]
+    pub breaker_cooldown_secs: u64,
+    /// Suricata rule files used to add rule metadata to alerts, may be repeated
+    #[structopt(long = "rules")]
+    pub rules: Vec<String>,
+    /// Tag alerts with ATT&CK techniques and tactics
+    #[structopt(long = "attack-tags")]
+    pub attack_tags: bool,
+    /// File mapping signature ids to ATT&CK ids, one `sid,technique[,tactic]` per line
+    #[structopt(long = "attack-mapping")]
+    pub attack_mapping: Option<String>,
+    /// Index metadata.flowbits and metadata.flowints into flowbits and flowint.<name> headers
+    #[structopt(long = "flowbits-headers")]
+    pub flowbits_headers: bool,
+    /// Comma separated flowbits and flowints indexed into headers, all of them if unset
+    #[structopt(long = "flowbits-names")]
+    pub flowbits_names: Option<String>,
+    /// Add sensor, event_type, timestamp, and shipper_version headers to every record
+    #[structopt(long = "eve-headers")]
+    pub eve_headers: bool,
+    /// Add ingest_time and ingest_hlc headers from a hybrid logical clock that keeps records
+    /// ordered across NTP step corrections and restarts, its state saved with the checkpoints
+    #[structopt(long = "hlc-headers")]
+    pub hlc_headers: bool,
+    /// Seconds ahead of the --hlc-headers clock its saved time is leased, renewed every half of
+    /// that, so a restart after a crash resumes past every timestamp stamped before it
+    #[structopt(long = "hlc-lease-secs", default_value="60")]
+    pub hlc_lease_secs: u64,
+    /// Static header added to every record, as name=value; may be given more than once
+    #[structopt(long = "header")]
+    pub header: Vec<String>,
+    /// Stamp records with a lineage header of sensor id, instance id, and a hash of the stage settings
+    #[structopt(long = "lineage")]
+    pub lineage: bool,
+    /// Consumer group on the event topic whose lag paces --eve-file backfill and drives
+    /// --sample-above-lag
+    #[structopt(long = "lag-group")]
+    pub lag_group: Option<String>,
+    #[structopt(long = "max-lag", default_value="100000")]
+    pub max_lag: usize,
+    #[structopt(long = "lag-interval-secs", default_value="10")]
+    pub lag_interval_secs: u64,
+    /// Sample --sample-event-types once the lag of --lag-group exceeds this many records, halving
+    /// their rate every --sample-adjust-secs while it stays above and doubling it again once the
+    /// lag is below half
+    #[structopt(long = "sample-above-lag")]
+    pub sample_above_lag: Option<usize>,
+    #[structopt(long = "sample-event-types", default_value="flow,netflow,dns,stats")]
+    pub sample_event_types: String,
+    /// Lowest fraction of --sample-event-types kept however far consumers fall behind
+    #[structopt(long = "sample-min-rate", default_value="0.05")]
+    pub sample_min_rate: f64,
+    #[structopt(long = "sample-adjust-secs", default_value="30")]
+    pub sample_adjust_secs: u64,
+    /// Every this many seconds, consume back recently produced records and compare their payload
+    /// hashes with what was sent, counting differences in verify.mismatched
+    #[structopt(long = "verify-interval-secs")]
+    pub verify_interval_secs: Option<u64>,
+    /// Most recent deliveries per partition verified each interval
+    #[structopt(long = "verify-window", default_value="100")]
+    pub verify_window: usize,
+    /// Send a surikafka_trailer record with the offsets, count, and checksum of every this many
+    /// records delivered to a partition to the control topic
+    #[structopt(long = "trailer-records")]
+    pub trailer_records: Option<usize>,
+    /// Topic with message.timestamp.type=LogAppendTime used to compare the sensor clock with
+    /// broker time, warning when they drift apart
+    #[structopt(long = "clock-topic")]
+    pub clock_topic: Option<String>,
+    /// Record config reloads, plugin rescans, circuit breaker changes, and spool drains as
+    /// surikafka_ops events on --ops-topic
+    #[structopt(long = "ops-journal")]
+    pub ops_journal: bool,
+    #[structopt(long = "ops-topic", default_value="surikafka.ops")]
+    pub ops_topic: String,
+    /// Compacted topic of central suppression decisions, read to its end before producing and
+    /// followed afterwards; suppressed alerts are dropped
+    #[structopt(long = "suppression-topic")]
+    pub suppression_topic: Option<String>,
+    /// Comma separated alert fields keying the records of --suppression-topic, e.g.
+    /// alert.signature_id,src_ip
+    #[structopt(long = "suppression-key", default_value="alert.signature_id")]
+    pub suppression_key: String,
+    /// Longest time to spend reading --suppression-topic before producing
+    #[structopt(long = "suppression-prime-secs", default_value="30")]
+    pub suppression_prime_secs: u64,
+    /// Alerts allowed a second for each --alert-rate-key, dropping the rest and emitting
+    /// surikafka_rate_limited records counting them every --alert-rate-summary-secs
+    #[structopt(long = "alert-rate-limit")]
+    pub alert_rate_limit: Option<f64>,
+    /// Alerts a key may send at once before --alert-rate-limit applies
+    #[structopt(long = "alert-rate-burst", default_value="100")]
+    pub alert_rate_burst: f64,
+    /// Comma separated alert fields keying --alert-rate-limit, e.g. src_ip or
+    /// alert.signature_id
+    #[structopt(long = "alert-rate-key", default_value="src_ip")]
+    pub alert_rate_key: String,
+    #[structopt(long = "alert-rate-summary-secs", default_value="60")]
+    pub alert_rate_summary_secs: u64,
+    /// Keys tracked by --alert-rate-limit before forgetting those with nothing dropped
+    #[structopt(long = "alert-rate-max-keys", default_value="100000")]
+    pub alert_rate_max_keys: usize,
+    /// Produce quota of a tenant sharing the shipper, as token=name:messages:bytes a second, may
+    /// be repeated; events carry their tenant's session token in --tenant-field, and those over
+    /// quota are dropped
+    #[structopt(long = "tenant-quota")]
+    pub tenant_quota: Vec<String>,
+    /// Field holding the session token of an event's tenant, removed before sending
+    #[structopt(long = "tenant-field", default_value="tenant_token")]
+    pub tenant_field: String,
+    /// Quota shared by tokens without a --tenant-quota, as messages:bytes a second; they're not
+    /// limited without it
+    #[structopt(long = "unknown-tenant-quota")]
+    pub unknown_tenant_quota: Option<String>,
+    #[structopt(long = "clock-interval-secs", default_value="300")]
+    pub clock_interval_secs: u64,
+    /// Clock skew tolerated before warning, in milliseconds
+    #[structopt(long = "max-clock-skew-ms", default_value="2000")]
+    pub max_clock_skew_ms: u64,
+    /// Replay --eve-file at its original event rate multiplied by this factor
+    #[structopt(long = "replay-speed")]
+    pub replay_speed: Option<f64>,
+    /// Wrap records in a CloudEvents 1.0 envelope: none, structured (JSON payload), or binary
+    /// (ce_* headers)
+    #[structopt(long = "envelope", default_value="none")]
+    pub envelope: writer::EnvelopeMode,
+    /// Serialize payloads as json, avro, or msgpack; as topic=codec for one topic, may be repeated
+    #[structopt(long = "codec")]
+    pub codec: Vec<String>,
+    /// Avro schema file of records sent with the avro codec, registered under <topic>-value of
+    /// every topic records are sent to: at startup for topic=avro, on a topic's first record
+    /// otherwise
+    #[structopt(long = "avro-schema")]
+    pub avro_schema: Option<String>,
+    #[structopt(long = "schema-registry-url", default_value="http://localhost:8081")]
+    pub schema_registry_url: String,
+    /// Deliveries awaiting a result from brokers at once; raise for busy sensors. With more than
+    /// one and --retry-attempts above 1, a retried record may land behind later records of its
+    /// partition, as reported with the route guarantees
+    #[structopt(long = "max-in-flight", default_value="1")]
+    pub max_in_flight: usize,
+    /// Send events as JSON arrays of up to this many events of one event type, with batch.count,
+    /// batch.first_timestamp, and batch.last_timestamp headers, for consumers that take arrays
+    #[structopt(long = "array-batch")]
+    pub array_batch: Option<usize>,
+    /// Longest an event waits for its array to fill before the array is sent
+    #[structopt(long = "array-batch-wait-ms", default_value="1000")]
+    pub array_batch_wait_ms: u64,
+    /// Acknowledgements to wait for: 0 (none), 1 (the leader), or all (every in sync replica)
+    #[structopt(long = "acks", default_value="1")]
+    pub acks: routes::Acks,
+    /// Events read before yielding to other tasks, such as the admin API, while working through
+    /// a backlog; 0 never yields early
+    #[structopt(long = "poll-budget", default_value="1024")]
+    pub poll_budget: usize,
+    /// Delivery attempts per record before giving up on it
+    #[structopt(long = "retry-attempts", default_value="1")]
+    pub retry_attempts: usize,
+    /// Delay before the first retry, doubled after every attempt
+    #[structopt(long = "retry-backoff-ms", default_value="100")]
+    pub retry_backoff_ms: u64,
+    #[structopt(long = "max-retry-backoff-ms", default_value="10000")]
+    pub max_retry_backoff_ms: u64,
+    /// Append records that couldn't be delivered to this file
+    #[structopt(long = "dead-letter-file")]
+    pub dead_letter_file: Option<String>,
+    /// Send records that couldn't be delivered to this topic
+    #[structopt(long = "dead-letter-topic")]
+    pub dead_letter_topic: Option<String>,
+    /// Stop the pipeline when a record can't be delivered, rather than dropping it
+    #[structopt(long = "fail-undelivered")]
+    pub fail_undelivered: bool,
+    /// Refuse to start with any setting that can lose events, such as shedding, sampling, rate
+    /// limits, or dead letters, or with a route delivered at most once; stop the pipeline on
+    /// undelivered records and on unparseable or overlong events rather than dropping them
+    #[structopt(long = "strict")]
+    pub strict: bool,
+    /// Also write the message key into a `surikafka.key` header or payload field
+    #[structopt(long = "key-placement", default_value="record")]
+    pub key_placement: writer::KeyPlacement,
+    /// Send each event type to its own topic, e.g. eve-{event_type} or eve-{iface}-{event_type};
+    /// --topic receives events without a topic
+    #[structopt(long = "topic-template")]
+    pub topic_template: Option<String>,
+    /// Topic of one event type as event_type=topic, e.g. alert=suricata.alerts, may be repeated;
+    /// takes precedence over --topic-template
+    #[structopt(long = "event-topic")]
+    pub event_topic: Vec<String>,
+    /// Longest an event may take to reach the producer and still be sent to its topic, as
+    /// topic=seconds, may be repeated; older events go to --expired-topic-template instead
+    #[structopt(long = "max-event-age")]
+    pub max_event_age: Vec<String>,
+    /// Topic receiving the events of a topic that are older than its --max-event-age
+    #[structopt(long = "expired-topic-template", default_value="{topic}.expired")]
+    pub expired_topic_template: String,
+    /// Also write the records of a topic to a second topic as topic=mirror_topic, may be repeated;
+    /// for migrating consumers between encodings, with the mirror's encoding set by --codec
+    #[structopt(long = "dual-write")]
+    pub dual_write: Vec<String>,
+    /// When to stop dual writing, as an RFC 3339 timestamp; dual writes never stop without it
+    #[structopt(long = "dual-write-until")]
+    pub dual_write_until: Option<String>,
+    /// Event topic that is compacted, may be repeated; its events must always have a key
+    #[structopt(long = "compacted-topic")]
+    pub compacted_topic: Vec<String>,
+    /// Comma separated event types that already have topics, defaulting to those of current
+    /// Suricata releases
+    #[structopt(long = "event-types")]
+    pub event_types: Option<String>,
+    /// Comma separated event types added to --event-types or its defaults, for event types of
+    /// Suricata releases newer than the shipper
+    #[structopt(long = "extra-event-types")]
+    pub extra_event_types: Option<String>,
+    /// What to do with other event types: catch-all sends them to --topic, create creates topics
+    /// in the background, sending to --topic until they exist and retrying failures
+    #[structopt(long = "unknown-event-types", default_value="catch-all")]
+    pub unknown_event_types: topics::UnknownEventTypes,
+    #[structopt(long = "new-topic-partitions", default_value="1")]
+    pub new_topic_partitions: i32,
+    #[structopt(long = "new-topic-replication", default_value="1")]
+    pub new_topic_replication: i32,
+    /// Topic config of created topics as name=value, may be repeated
+    #[structopt(long = "new-topic-config")]
+    pub new_topic_config: Vec<String>,
+    /// Compacted topic receiving a summary of the latest alert on each destination host
+    #[structopt(long = "latest-alert-topic")]
+    pub latest_alert_topic: Option<String>,
+    /// Topic receiving a compact passive DNS record for each DNS answer, keyed by rrname
+    #[structopt(long = "passive-dns-topic")]
+    pub passive_dns_topic: Option<String>,
+    /// Topic receiving each alert bundled with the events preceding it on the same flow, keyed
+    /// by src_ip
+    #[structopt(long = "alert-context-topic")]
+    pub alert_context_topic: Option<String>,
+    /// Events of a flow kept to bundle with its alerts
+    #[structopt(long = "alert-context-events", default_value="10")]
+    pub alert_context_events: usize,
+    /// Flows whose events are kept, forgetting the oldest beyond this many
+    #[structopt(long = "alert-context-flows", default_value="10000")]
+    pub alert_context_flows: usize,
+    /// Topic receiving certificate metadata from TLS events, keyed by fingerprint
+    #[structopt(long = "cert-topic")]
+    pub cert_topic: Option<String>,
+    /// Report each certificate at most once in this many seconds
+    #[structopt(long = "cert-window-secs", default_value="3600")]
+    pub cert_window_secs: u64,
+    /// Escalate anomaly events scoring at or above --anomaly-threshold as synthetic alerts
+    #[structopt(long = "anomaly-escalation")]
+    pub anomaly_escalation: bool,
+    /// File of anomaly weights between 0 and 1, one `type-or-event,weight` per line
+    #[structopt(long = "anomaly-weights")]
+    pub anomaly_weights: Option<String>,
+    #[structopt(long = "anomaly-threshold", default_value="0.8")]
+    pub anomaly_threshold: f64,
+    /// Topic receiving escalated anomalies, defaulting to --topic
+    #[structopt(long = "anomaly-alert-topic")]
+    pub anomaly_alert_topic: Option<String>,
+    /// Raise a meta-alert when a source triggers this many distinct signatures within
+    /// --rule-of-n-window-secs
+    #[structopt(long = "rule-of-n")]
+    pub rule_of_n: Option<usize>,
+    #[structopt(long = "rule-of-n-window-secs", default_value="300")]
+    pub rule_of_n_window_secs: u64,
+    /// Topic receiving meta-alerts, defaulting to --topic
+    #[structopt(long = "priority-topic")]
+    pub priority_topic: Option<String>,
+    /// Create derived topics on startup, compacted where they hold the latest record per key
+    #[structopt(long = "create-derived-topics")]
+    pub create_derived_topics: bool,
+    /// Topic of signed per-sensor config overrides (dropped event types, sampling) applied while
+    /// running; applied versions are acknowledged on --control-topic
+    #[structopt(long = "config-topic")]
+    pub config_topic: Option<String>,
+    /// File containing the secret config overrides are signed with, required for --config-topic
+    #[structopt(long = "config-secret-file")]
+    pub config_secret_file: Option<String>,
+    /// Also push events to Redis at this address, e.g. 127.0.0.1:6379
+    #[structopt(long = "redis-addr")]
+    pub redis_addr: Option<String>,
+    /// stream (XADD) or publish
+    #[structopt(long = "redis-mode", default_value="stream")]
+    pub redis_mode: sink::RedisMode,
+    /// Redis stream or channel, {event_type} is substituted
+    #[structopt(long = "redis-key", default_value="surikafka:{event_type}")]
+    pub redis_key: String,
+    /// Comma separated event types pushed to Redis, * for all
+    #[structopt(long = "redis-events", default_value="alert")]
+    pub redis_events: String,
+    /// Trim Redis streams to about this many entries
+    #[structopt(long = "redis-max-len")]
+    pub redis_max_len: Option<usize>,
+    /// Also write events to Elasticsearch or OpenSearch with the bulk API, e.g. http://127.0.0.1:9200
+    #[structopt(long = "elastic-url")]
+    pub elastic_url: Option<String>,
+    /// Index of each event, {event_type} and {date} (YYYY.MM.DD) are substituted
+    #[structopt(long = "elastic-index", default_value="surikafka-{event_type}-{date}")]
+    pub elastic_index: String,
+    /// Comma separated event types written to Elasticsearch, * for all
+    #[structopt(long = "elastic-events", default_value="*")]
+    pub elastic_events: String,
+    /// Also archive events as Parquet to S3 compatible storage at this endpoint, e.g. minio:9000;
+    /// requires the archive feature
+    #[structopt(long = "s3-endpoint")]
+    pub s3_endpoint: Option<String>,
+    #[structopt(long = "s3-bucket", default_value="surikafka")]
+    pub s3_bucket: String,
+    /// Key prefix of archived files, followed by event_type=<type>/date=<YYYY-MM-DD>/
+    #[structopt(long = "s3-prefix", default_value="")]
+    pub s3_prefix: String,
+    #[structopt(long = "s3-region", default_value="us-east-1")]
+    pub s3_region: String,
+    /// File containing `<access key>:<secret key>`, otherwise AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY are used
+    #[structopt(long = "s3-credentials-file")]
+    pub s3_credentials_file: Option<String>,
+    /// Comma separated event types archived, * for all
+    #[structopt(long = "s3-events", default_value="*")]
+    pub s3_events: String,
+    /// Events per archived file
+    #[structopt(long = "s3-file-events", default_value="100000")]
+    pub s3_file_events: usize,
+    /// Seconds before a partially filled file is uploaded
+    #[structopt(long = "s3-file-secs", default_value="300")]
+    pub s3_file_secs: u64,
+    /// Don't produce to Kafka at all, only to the other sinks; alarms are logged instead
+    #[structopt(long = "no-kafka")]
+    pub no_kafka: bool,
+    /// Print the events matching --filter with the topic, key, and headers they'd be sent with
+    /// instead of producing them, without writing to other topics or sinks or saving checkpoints;
+    /// `surikafka inspect` is short for it
+    #[structopt(long = "inspect")]
+    pub inspect: bool,
+    /// Events --inspect prints, as field=value, field!=value, or field~substring clauses joined by
+    /// " and ", e.g. "event_type=alert and topic!=eve-alerts"
+    #[structopt(long = "filter")]
+    pub filter: Option<String>,
+    /// With --inspect, follow --eve-file from its end rather than reading what it already has
+    #[structopt(long = "tail")]
+    pub tail: bool,
+    /// Maximum lengths in bytes of string fields, e.g. http.url=2048,smtp.subject=512; truncated
+    /// fields are listed in truncated_fields
+    #[structopt(long = "truncate-fields")]
+    pub truncate_fields: Option<String>,
+    /// Comma separated event types to drop, e.g. stats,netflow
+    #[structopt(long = "drop-event-types")]
+    pub drop_event_types: Option<String>,
+    /// Comma separated event types to keep, dropping all others
+    #[structopt(long = "keep-event-types")]
+    pub keep_event_types: Option<String>,
+    /// Comma separated fields to remove from events, e.g. payload,packet,http.http_request_body
+    #[structopt(long = "redact-fields")]
+    pub redact_fields: Option<String>,
+    /// Field set on every record, as name=value, e.g. site=ams1; may be given more than once
+    #[structopt(long = "static-field")]
+    pub static_field: Vec<String>,
+    /// Fields kept of the events sent to a topic, as topic=field,field, e.g.
+    /// eve-alerts=timestamp,flow_id,src_ip,src_port,dest_ip,dest_port,proto,alert; nested
+    /// fields are dotted paths. May be given once per topic
+    #[structopt(long = "project")]
+    pub project: Vec<String>,
+    /// Where --static-field goes: field, header, or both
+    #[structopt(long = "static-field-target", default_value="field")]
+    pub static_field_target: transform::InjectionTarget,
+    /// Directory of stage plugins, shared objects and WASM modules, rescanned on SIGHUP
+    #[structopt(long = "plugin-dir")]
+    pub plugin_dir: Option<String>,
+    /// Seconds between reports of internal queue depths, at least 1
+    #[structopt(long = "queue-report-secs", default_value="60")]
+    pub queue_report_secs: u64,
+    /// client.id reported to brokers; {hostname}, {sensor_id}, and {instance_id} are substituted
+    #[structopt(long = "client-id", default_value="surikafka-{hostname}")]
+    pub client_id: String,
+    /// client.rack, so consumers (the lag monitor) can fetch from a follower in the same rack
+    #[structopt(long = "client-rack")]
+    pub client_rack: Option<String>,
+    /// security.protocol of Kafka connections: plaintext, ssl, sasl_plaintext, or sasl_ssl
+    #[structopt(long = "security-protocol")]
+    pub security_protocol: Option<String>,
+    /// CA certificate file used to verify brokers
+    #[structopt(long = "ssl-ca-location")]
+    pub ssl_ca_location: Option<String>,
+    /// Client certificate file, for brokers requiring client authentication
+    #[structopt(long = "ssl-certificate-location")]
+    pub ssl_certificate_location: Option<String>,
+    #[structopt(long = "ssl-key-location")]
+    pub ssl_key_location: Option<String>,
+    /// File holding the password of --ssl-key-location
+    #[structopt(long = "ssl-key-password-file")]
+    pub ssl_key_password_file: Option<String>,
+    /// sasl.mechanisms, e.g. PLAIN or SCRAM-SHA-512
+    #[structopt(long = "sasl-mechanism")]
+    pub sasl_mechanism: Option<String>,
+    #[structopt(long = "sasl-username")]
+    pub sasl_username: Option<String>,
+    /// File holding the password of --sasl-username
+    #[structopt(long = "sasl-password-file")]
+    pub sasl_password_file: Option<String>,
+    /// Time the producer waits for a batch to fill before sending it, in milliseconds
+    #[structopt(long = "linger-ms")]
+    pub linger_ms: Option<u64>,
+    /// Most records in one batch
+    #[structopt(long = "batch-num-messages")]
+    pub batch_num_messages: Option<u64>,
+    /// Send events matching this filter, e.g. `event_type=alert and alert.severity=1`, on a
+    /// producer of their own that doesn't linger, and around --array-batch, so they aren't held
+    /// back behind bulk traffic; their latencies are in writer.immediate_produce_latency and
+    /// writer.immediate_event_latency
+    #[structopt(long = "immediate")]
+    pub immediate: Option<String>,
+    /// Compression of produced batches: none, gzip, snappy, lz4, or zstd
+    #[structopt(long = "compression-codec")]
+    pub compression_codec: Option<String>,
+    /// Compress payloads with zstd before producing, adding a content-encoding header, for
+    /// consumers and sinks that store records as sent
+    #[structopt(long = "zstd")]
+    pub zstd: bool,
+    #[structopt(long = "zstd-level", default_value="3")]
+    pub zstd_level: i32,
+    /// zstd dictionary to compress with, e.g. from `zstd --train`
+    #[structopt(long = "zstd-dictionary")]
+    pub zstd_dictionary: Option<String>,
+    /// Train a dictionary from this many bytes of sampled payloads, replacing --zstd-dictionary
+    /// once trained
+    #[structopt(long = "zstd-train-bytes")]
+    pub zstd_train_bytes: Option<usize>,
+    /// Sample one in this many payloads for training
+    #[structopt(long = "zstd-sample-every", default_value="10")]
+    pub zstd_sample_every: usize,
+    #[structopt(long = "zstd-dictionary-size", default_value="112640")]
+    pub zstd_dictionary_size: usize,
+    /// Compacted topic receiving every dictionary used, keyed by the id in each record's
+    /// zstd.dict_id header
+    #[structopt(long = "zstd-dictionary-topic")]
+    pub zstd_dictionary_topic: Option<String>,
+    /// Interval of librdkafka statistics, used to detect broker throttling; 0 disables them
+    #[structopt(long = "stats-interval-ms", default_value="5000")]
+    pub stats_interval_ms: u64,
+    /// Keep connections to every broker open through quiet periods, and wait for them at startup
+    #[structopt(long = "warm-connections")]
+    pub warm_connections: bool,
+    /// Metadata refresh interval keeping --warm-connections open
+    #[structopt(long = "warm-refresh-secs", default_value="30")]
+    pub warm_refresh_secs: u64,
+    /// Longest startup waits for --warm-connections before reading events anyway
+    #[structopt(long = "warm-timeout-secs", default_value="10")]
+    pub warm_timeout_secs: u64,
+    /// Before reading events, produce a surikafka_canary record to every topic written to and
+    /// require its delivery report (delivery) or reading it back (round-trip), failing startup
+    /// otherwise
+    #[structopt(long = "self-test")]
+    pub self_test: Option<canary::SelfTestMode>,
+    /// Time each topic's canary may take to be delivered, and read back
+    #[structopt(long = "self-test-timeout-secs", default_value="10")]
+    pub self_test_timeout_secs: u64,
+    /// Address to serve admin endpoints on, e.g. 127.0.0.1:9137, including Prometheus metrics on
+    /// /metrics
+    #[structopt(long = "admin-addr")]
+    pub admin_addr: Option<std::net::SocketAddr>,
+    /// Raw events kept for /events/recent on --admin-addr, a control endpoint taking a --filter
+    /// expression as `filter` and a `limit`; 0 disables it
+    #[structopt(long = "recent-events", default_value="1000")]
+    pub recent_events: usize,
+    /// File of admin tokens, one `<read|control> <token>` per line, required as
+    /// `Authorization: Bearer <token>`; without it admin endpoints are open
+    #[structopt(long = "admin-tokens-file")]
+    pub admin_tokens_file: Option<String>,
+    /// PKCS #12 archive with the certificate and key to serve admin endpoints over TLS
+    #[structopt(long = "admin-tls-identity")]
+    pub admin_tls_identity: Option<String>,
+    /// File holding the password of --admin-tls-identity
+    #[structopt(long = "admin-tls-password-file")]
+    pub admin_tls_password_file: Option<String>
+}
+
+impl Settings {
+    /// Parses `flags` as given on the command line, without the program name, returning an
+    /// error rather than exiting the process on invalid flags.
+    pub fn from_flags(flags: &[String]) -> Result<Settings, Error> {
+        let argv = std::iter::once("surikafka".to_string()).chain(flags.iter().cloned());
+        let matches = Settings::clap().get_matches_from_safe(argv)
+            .map_err(|e| Error::from(format!("Invalid settings: {}", e)))?;
+        Ok(Settings::from_clap(&matches))
+    }
+
+    /// These settings with everything that would write somewhere other than the inspected events
+    /// turned off: derived topics, the other sinks, the ops journal, clock checks, delivery
+    /// verification, the self-test, and the admin server.
+    pub fn for_inspection(mut self) -> Settings {
+        self.inspect = true;
+        self.no_kafka = false;
+        if self.tail {
+            self.follow = true;
+            self.start_position = source::StartPosition::End;
+        }
+        self.latest_alert_topic = None;
+        self.passive_dns_topic = None;
+        self.alert_context_topic = None;
+        self.cert_topic = None;
+        self.anomaly_escalation = false;
+        self.anomaly_weights = None;
+        self.rule_of_n = None;
+        self.redis_addr = None;
+        self.elastic_url = None;
+        self.s3_endpoint = None;
+        self.spool_path = None;
+        self.ops_journal = false;
+        self.clock_topic = None;
+        self.verify_interval_secs = None;
+        self.trailer_records = None;
+        self.self_test = None;
+        self.warm_connections = false;
+        self.admin_addr = None;
+        self
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings::from_iter(vec!["surikafka"])
+    }
+}
+
+
+type Producer = FutureProducer<ShipperContext>;
+
+fn register_sensor(args: &Settings, producer: &Producer) -> Result<Box<Future<Item=(), Error=Error> + Send>, Error> {
+    let registration = registry::SensorRegistration::detect(
+        args.sensor_id.clone(),
+        &format!("{:?}", args)
+    )?;
+    let payload = registration.to_bytes();
+    let delivery = producer.send(
+        FutureRecord::to(args.registry_topic.as_ref())
+            .key(registration.key())
+            .payload(&payload),
+        1000
+    );
+    let sensor_id = registration.sensor_id;
+
+    Ok(Box::new(delivery.map_err(Error::from).map(move |res| match res {
+        Ok( (p, o) ) => info!("Registered sensor {} at partition {}, offset {}", sensor_id, p, o),
+        Err( (e, _) ) => warn!("Failed to register sensor {}: {:?}", sensor_id, e)
+    })))
+}
+
+/// Kafka client settings shared by the producer and every consumer, including TLS and SASL.
+fn client_config(args: &Settings) -> Result<rdkafka::ClientConfig, Error> {
+    let sensor_id = args.sensor_id.clone().unwrap_or_else(registry::hostname);
+    let mut config = rdkafka::ClientConfig::new();
+    config
+        .set("bootstrap.servers", args.kafka_servers.as_str())
+        .set("client.id", &registry::expand_template(&args.client_id, &sensor_id, &args.instance_id));
+    let settings = vec![
+        ("client.rack", args.client_rack.as_ref()),
+        ("security.protocol", args.security_protocol.as_ref()),
+        ("ssl.ca.location", args.ssl_ca_location.as_ref()),
+        ("ssl.certificate.location", args.ssl_certificate_location.as_ref()),
+        ("ssl.key.location", args.ssl_key_location.as_ref()),
+        ("sasl.mechanisms", args.sasl_mechanism.as_ref()),
+        ("sasl.username", args.sasl_username.as_ref())
+    ];
+    for (name, value) in settings {
+        if let Some(value) = value {
+            config.set(name, value.as_str());
+        }
+    }
+    if let Some(ref path) = args.ssl_key_password_file {
+        config.set("ssl.key.password", std::fs::read_to_string(path)?.trim());
+    }
+    if let Some(ref path) = args.sasl_password_file {
+        config.set("sasl.password", std::fs::read_to_string(path)?.trim());
+    }
+    Ok(config)
+}
+
+/// Options every EVE reader of a pipeline is built with, so readers of each kind of source
+/// can't drift apart.
+#[derive(Clone)]
+struct ReaderOptions {
+    max_line_length: usize,
+    utf8_mode: json::Utf8Mode,
+    strict: bool,
+    pending_gauge: metrics::QueueGauge,
+    skipped: metrics::Counter,
+    cancellation: CancellationToken,
+    read_log: shutdown::ReadLog,
+    trace: Option<trace::SourceTrace>
+}
+
+impl ReaderOptions {
+    /// Reader of a connection, which has no position to checkpoint.
+    fn reader<T: AsyncRead>(&self, source: T) -> reader::EveReader<T> {
+        reader::EveReader::new(source)
+            .with_max_line_length(self.max_line_length)
+            .with_utf8_mode(self.utf8_mode)
+            .with_strict(self.strict)
+            .with_pending_gauge(self.pending_gauge.clone())
+            .with_skip_counter(self.skipped.clone())
+    }
+
+    /// Reader of `path`, advancing `position` past the events it passes on.
+    fn file_reader<T: AsyncRead>(&self, source: T, path: &str, position: Arc<AtomicUsize>) -> reader::EveReader<T> {
+        let reader = self.reader(source)
+            .with_position(position)
+            .with_cancellation(self.cancellation.clone())
+            .with_read_log(self.read_log.clone(), path);
+        match self.trace {
+            Some(ref trace) => reader.with_trace(trace.clone(), path),
+            None => reader
+        }
+    }
+}
+
+/// Settings that can lose events, which --strict refuses to start with.
+fn strict_violations(args: &Settings, map: &routes::TopicMap) -> Vec<String> {
+    let mut violations = vec![];
+    {
+        let mut violation = |set: bool, reason: &str| if set { violations.push(reason.to_string()) };
+        violation(args.shed_thresholds.is_some(), "--shed-thresholds sheds events under load");
+        violation(args.sample_above_lag.is_some(), "--sample-above-lag samples events when consumers fall behind");
+        violation(args.alert_rate_limit.is_some(), "--alert-rate-limit drops alerts over the limit");
+        violation(!args.tenant_quota.is_empty() || args.unknown_tenant_quota.is_some(), "--tenant-quota drops events over quota");
+        violation(args.dead_letter_file.is_some() || args.dead_letter_topic.is_some(), "--dead-letter-file and --dead-letter-topic route undelivered records away from their topic");
+        violation(!args.max_event_age.is_empty(), "--max-event-age routes expired events away from their topic");
+        violation(args.catch_up_max_secs.is_some(), "--catch-up-max-secs abandons the backlog left after it");
+        violation(args.truncate_fields.is_some(), "--truncate-fields cuts events short");
+        violation(args.shutdown_deadline_secs.is_some(), "--shutdown-deadline-secs abandons records outstanding at the deadline");
+        violation(args.config_topic.is_some(), "--config-topic applies remote overrides that drop and sample events");
+        violation(args.suppression_topic.is_some(), "--suppression-topic drops suppressed alerts");
+    }
+    for guarantee in map.guarantees() {
+        if guarantee.guarantee == routes::Guarantee::AtMostOnce {
+            violations.push(format!("{} is delivered at most once: {}", guarantee.route, guarantee.reasons.join("; ")));
+        }
+    }
+    violations
+}
+
+/// Topics with a --max-event-age, with the age and the topic of their expired events.
+fn max_event_ages(args: &Settings) -> Result<Vec<(String, std::time::Duration, String)>, Error> {
+    let mut ages = vec![];
+    for setting in args.max_event_age.iter() {
+        let mut parts = setting.rsplitn(2, '=');
+        match (parts.next().map(|s| s.trim().parse::<u64>()), parts.next().map(str::trim)) {
+            (Some(Ok(secs)), Some(topic)) if !topic.is_empty() => {
+                let archive = args.expired_topic_template.replace("{topic}", topic);
+                if archive == topic {
+                    bail!("--expired-topic-template sends expired events of {} back to it", topic);
+                }
+                ages.push( (topic.to_string(), std::time::Duration::from_secs(secs), archive) );
+            }
+            _ => bail!("Invalid --max-event-age {}, expected topic=seconds", setting)
+        }
+    }
+    Ok(ages)
+}
+
+/// Topics that are dual written, with their mirror topic, and when dual writing stops.
+fn dual_writes(args: &Settings) -> Result<(Vec<(String, String)>, Option<chrono::DateTime<chrono::Utc>>), Error> {
+    let mut mirrors = vec![];
+    for setting in args.dual_write.iter() {
+        let mut parts = setting.splitn(2, '=');
+        match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+            (Some(topic), Some(mirror)) if !topic.is_empty() && !mirror.is_empty() => {
+                if topic == mirror {
+                    bail!("--dual-write {} mirrors the topic to itself", setting);
+                }
+                mirrors.push( (topic.to_string(), mirror.to_string()) );
+            }
+            _ => bail!("Invalid --dual-write {}, expected topic=mirror_topic", setting)
+        }
+    }
+    let until = match args.dual_write_until {
+        Some(ref until) => match chrono::DateTime::parse_from_rfc3339(until) {
+            Ok(until) => Some(until.with_timezone(&chrono::Utc)),
+            Err(e) => bail!("Invalid --dual-write-until {}: {}", until, e)
+        },
+        None => None
+    };
+    Ok( (mirrors, until) )
+}
+
+/// Every topic events or derived records are written to, each once.
+fn self_test_topics(args: &Settings) -> Vec<String> {
+    let mut topics = vec![args.topic.clone()];
+    topics.extend(args.event_topic.iter().filter_map(|r| r.splitn(2, '=').nth(1)).map(|t| t.trim().to_string()));
+    topics.extend(max_event_ages(args).unwrap_or_default().into_iter().map(|(_, _, archive)| archive));
+    topics.extend(dual_writes(args).map(|(mirrors, _)| mirrors).unwrap_or_default().into_iter().map(|(_, mirror)| mirror));
+    let derived = vec![
+        &args.dead_letter_topic,
+        &args.latest_alert_topic,
+        &args.passive_dns_topic,
+        &args.alert_context_topic,
+        &args.cert_topic,
+        &args.anomaly_alert_topic,
+        &args.priority_topic,
+        &args.clock_topic
+    ];
+    topics.extend(derived.into_iter().filter_map(|t| t.clone()));
+    if args.ops_journal {
+        topics.push(args.ops_topic.clone());
+    }
+    let mut seen = std::collections::HashSet::new();
+    topics.retain(|t| seen.insert(t.clone()));
+    topics
+}
+
+fn topic_router(args: &Settings, registry: &metrics::Registry) -> Result<Option<topics::TopicRouter>, Error> {
+    let template = match args.topic_template {
+        Some(ref t) => t,
+        None if !args.event_topic.is_empty() => &args.topic,
+        None => return Ok(None)
+    };
+    let mut router = topics::TopicRouter::new(&args.topic, template)
+        .with_policy(args.unknown_event_types)
+        .with_discovered_counter(registry.counter("topics.discovered"));
+    for route in args.event_topic.iter() {
+        let mut parts = route.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(event_type), Some(topic)) => router = router.with_topic(event_type.trim(), topic.trim()),
+            _ => bail!("Invalid --event-topic {}, expected event_type=topic", route)
+        }
+    }
+    if let Some(ref event_types) = args.event_types {
+        router = router.with_known(event_types.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()));
+    }
+    if let Some(ref event_types) = args.extra_event_types {
+        router = router.with_extra_known(event_types.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()));
+    }
+    if args.unknown_event_types == topics::UnknownEventTypes::Create {
+        let mut provisioner = topics::KafkaProvisioner::new(
+            &client_config(args)?,
+            args.new_topic_partitions,
+            args.new_topic_replication
+        )?;
+        for setting in args.new_topic_config.iter() {
+            let mut parts = setting.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => provisioner = provisioner.with_config(name, value),
+                _ => bail!("Invalid --new-topic-config {}, expected name=value", setting)
+            }
+        }
+        router = router.with_provisioner(provisioner);
+    }
+    Ok(Some(router))
+}
+
+/// Routes of the event topics and the settings they depend on, see `routes::TopicMap`.
+fn topic_map(args: &Settings) -> Result<routes::TopicMap, Error> {
+    let keys = match (args.key_plugin.as_ref(), args.key_strategy, args.key_by.as_ref()) {
+        // Plugins are trusted to key what they're given
+        (Some(_), _, _) => routes::KeyCoverage::Every,
+        (None, key::KeyStrategy::Payload, None) | (None, key::KeyStrategy::Sensor, _) => routes::KeyCoverage::Every,
+        _ => routes::KeyCoverage::Partial
+    };
+    let (default_codec, selected) = codec_settings(args)?;
+    let codec = |topic: &str| selected.iter()
+        .find(|&&(ref t, _)| t == topic)
+        .map(|&(_, kind)| kind)
+        .or(default_codec)
+        .unwrap_or(writer::CodecKind::Json);
+
+    let mut map = routes::TopicMap::new(keys, args.key_placement, args.acks)
+        .with_verification(args.verify_interval_secs.is_some())
+        .with_array_batches(args.array_batch.is_some())
+        .with_failure_handling(args.fail_undelivered || args.strict || args.dead_letter_file.is_some() || args.dead_letter_topic.is_some())
+        // Events sent over the socket while the shipper is down are gone, whatever the position
+        .with_resume(args.start_position == source::StartPosition::Resume && (args.eve_file.is_some() || !args.interface_file.is_empty()))
+        .with_overwriting_spool(args.spool_path.is_some())
+        .with_reordered_retries(args.max_in_flight > 1 && args.retry_attempts > 1)
+        .with_route(routes::Route {
+            event_type: None,
+            topic: args.topic.clone(),
+            codec: codec(&args.topic),
+            compacted: args.compacted_topic.contains(&args.topic)
+        });
+    for setting in args.event_topic.iter() {
+        let mut parts = setting.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(event_type), Some(topic)) => {
+                let topic = topic.trim().to_string();
+                map = map.with_route(routes::Route {
+                    event_type: Some(event_type.trim().to_string()),
+                    codec: codec(&topic),
+                    compacted: args.compacted_topic.contains(&topic),
+                    topic: topic
+                });
+            }
+            _ => bail!("Invalid --event-topic {}, expected event_type=topic", setting)
+        }
+    }
+    for topic in args.compacted_topic.iter() {
+        if !map.routes().iter().any(|r| r.topic == *topic) {
+            bail!("--compacted-topic {} isn't an event topic", topic);
+        }
+    }
+    if let (Some(_), Some(partitions)) = (args.sensor_partitions, args.topic_partitions) {
+        map = map.with_pinned_partitions(partitions);
+        if args.topic_template.is_some() && args.unknown_event_types == topics::UnknownEventTypes::Create {
+            map = map.with_created_partitions(args.new_topic_partitions);
+        }
+    }
+    Ok(map)
+}
+
+/// The codec of every topic without one of its own, and the topics with one, from `--codec`.
+fn codec_settings(args: &Settings) -> Result<(Option<writer::CodecKind>, Vec<(String, writer::CodecKind)>), Error> {
+    let mut selected = vec![];
+    let mut default = None;
+    for setting in args.codec.iter() {
+        let mut parts = setting.rsplitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(kind), Some(topic)) => selected.push( (topic.trim().to_string(), kind.trim().parse::<writer::CodecKind>()?) ),
+            (Some(kind), None) => default = Some(kind.trim().parse::<writer::CodecKind>()?),
+            _ => bail!("Invalid --codec {}, expected codec or topic=codec", setting)
+        }
+    }
+    Ok( (default, selected) )
+}
+
+fn codecs(args: &Settings, registry: &metrics::Registry) -> Result<Option<writer::CodecSet>, Error> {
+    if args.codec.is_empty() {
+        return Ok(None)
+    }
+    let (default, selected) = codec_settings(args)?;
+
+    let avro = if default == Some(writer::CodecKind::Avro) || selected.iter().any(|&(_, kind)| kind == writer::CodecKind::Avro) {
+        let path = args.avro_schema.as_ref()
+            .ok_or_else(|| Error::from("--avro-schema is required by the avro codec"))?;
+        let (schema, text) = writer::AvroSchema::load(path)?;
+        Some( (schema, text, writer::SchemaRegistry::new(&args.schema_registry_url)?) )
+    } else {
+        None
+    };
+    let build = |topic: &str, kind: writer::CodecKind| -> Result<Box<writer::Codec + Send>, Error> {
+        let codec: Box<writer::Codec + Send> = match (kind, avro.as_ref()) {
+            (writer::CodecKind::Json, _) => Box::new(writer::Json),
+            (writer::CodecKind::MessagePack, _) => Box::new(writer::MessagePack),
+            (writer::CodecKind::Avro, Some(&(ref schema, ref text, ref schema_registry))) => {
+                Box::new(register_avro(topic, schema, text, schema_registry)?)
+            }
+            (writer::CodecKind::Avro, None) => unreachable!()
+        };
+        Ok(codec)
+    };
+
+    let mut set = writer::CodecSet::default()
+        .with_failure_counter(registry.counter("writer.encode_failed"));
+    match (default, avro.clone()) {
+        // Topics are only known once records are routed to them, so each is registered under
+        // its own subject on its first record
+        (Some(writer::CodecKind::Avro), Some( (schema, text, schema_registry) )) => {
+            set = set.with_factory(move |topic: &str| -> Result<Box<writer::Codec + Send>, Error> {
+                Ok(Box::new(register_avro(topic, &schema, &text, &schema_registry)?))
+            });
+        }
+        (Some(kind), _) => set = set.with_default(build(&args.topic, kind)?),
+        (None, _) => ()
+    }
+    for (topic, kind) in selected {
+        set = set.with_topic(&topic, build(&topic, kind)?);
+    }
+    Ok(Some(set))
+}
+
+/// Registers `text` under the value subject of `topic`, for its Avro codec.
+fn register_avro(topic: &str, schema: &writer::AvroSchema, text: &serde_json::Value, schema_registry: &writer::SchemaRegistry) -> Result<writer::Avro, Error> {
+    let id = schema_registry.register(&format!("{}-value", topic), text)?;
+    info!("Registered Avro schema of {} as id {}", topic, id);
+    Ok(writer::Avro::new(schema.clone(), id))
+}
+
+/// Creates a derived topic when `--create-derived-topics` is given; topics that already exist
+/// are left as they are.
+fn provision_derived(args: &Settings, topic: &str, compact: bool) -> Result<(), Error> {
+    if !args.create_derived_topics {
+        return Ok(())
+    }
+    let mut provisioner = topics::KafkaProvisioner::new(
+        &client_config(args)?,
+        args.new_topic_partitions,
+        args.new_topic_replication
+    )?;
+    if compact {
+        provisioner = provisioner.with_config("cleanup.policy", "compact");
+    }
+    provisioner.provision(topic)
+}
+
+/// Spawns a task producing the records sent on the returned channel to `topic`, keyed by
+/// `generator`, tracking the channel depth as `derived.<name>`.
+fn spawn_derived<K>(name: &str, topic: &str, generator: K, producer: &Producer, registry: &metrics::Registry) -> (UnboundedSender<Vec<u8>>, metrics::QueueGauge)
+    where K: key::KeyGenerator + Send + 'static,
+          K::Item: Sized
+{
+    let (sender, receiver) = futures::sync::mpsc::unbounded();
+    let gauge = registry.queue(&format!("derived.{}", name));
+    let received = gauge.clone();
+    let delivered = registry.counter(&format!("derived.{}.delivered", name));
+
+    let task = receiver
+        .inspect(move |_| received.sub(1))
+        .map_err(|_| Error::from_kind(ErrorKind::ReceiverError))
+        .produce(topic.to_string(), generator, producer.clone())
+        .for_each(move |stats| {
+            delivered.add(stats.alert_count());
+            Ok(())
+        }).map_err(|e| print_error(&e));
+
+    tokio::spawn(task);
+    (sender, gauge)
+}
+
+#[cfg(feature = "zstd-dict")]
+fn compressor(args: &Settings, producer: &Producer, registry: &metrics::Registry) -> Result<Option<writer::ZstdCompressor>, Error> {
+    if !args.zstd {
+        if args.zstd_dictionary.is_some() || args.zstd_train_bytes.is_some() {
+            bail!("--zstd-dictionary and --zstd-train-bytes require --zstd");
+        }
+        return Ok(None)
+    }
+    let mut compressor = writer::ZstdCompressor::new(args.zstd_level);
+    if let Some(ref topic) = args.zstd_dictionary_topic {
+        provision_derived(args, topic, true)?;
+        let (sender, gauge) = spawn_derived("zstd_dictionary", topic, writer::DictionaryKey, producer, registry);
+        compressor = compressor.with_publisher(sender, gauge);
+    } else if args.zstd_dictionary.is_some() || args.zstd_train_bytes.is_some() {
+        warn!("Compressing with a dictionary without --zstd-dictionary-topic, consumers must get it elsewhere");
+    }
+    if let Some(ref path) = args.zstd_dictionary {
+        compressor = compressor.with_dictionary(std::fs::read(path)?)?;
+    }
+    if let Some(bytes) = args.zstd_train_bytes {
+        let sampler = writer::DictionarySampler::new(args.zstd_sample_every, bytes);
+        compressor = compressor.with_training(sampler, args.zstd_dictionary_size);
+    }
+    Ok(Some(compressor))
+}
+
+#[cfg(not(feature = "zstd-dict"))]
+fn compressor(args: &Settings, _producer: &Producer, _registry: &metrics::Registry) -> Result<Option<Box<writer::PayloadCompressor + Send>>, Error> {
+    if args.zstd {
+        bail!("--zstd requires building with the zstd-dict feature");
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "archive")]
+fn archive_sink(args: &Settings, endpoint: &str, registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    let (access_key, secret_key) = match args.s3_credentials_file {
+        Some(ref path) => {
+            let contents = std::fs::read_to_string(path)?;
+            let mut parts = contents.trim().splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(access), Some(secret)) => (access.to_string(), secret.to_string()),
+                _ => bail!("Invalid S3 credentials file {}, expected <access key>:<secret key>", path)
+            }
+        }
+        None => (
+            std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| Error::from("AWS_ACCESS_KEY_ID is not set"))?,
+            std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| Error::from("AWS_SECRET_ACCESS_KEY is not set"))?
+        )
+    };
+    let credentials = s3::Credentials {
+        access_key: access_key,
+        secret_key: secret_key,
+        region: args.s3_region.clone()
+    };
+    let sensor_id = args.sensor_id.clone().unwrap_or_else(registry::hostname);
+    let archive = archive::ArchiveSink::new(s3::S3Client::new(endpoint, &args.s3_bucket, credentials), &args.s3_prefix, &sensor_id)
+        .with_limits(args.s3_file_events, std::time::Duration::from_secs(args.s3_file_secs));
+    let route = sink::SinkRoute::parse(&args.s3_events);
+    Ok(sink::SinkHandle::spawn(archive, route, sink::SinkConfig::default(), registry))
+}
+
+#[cfg(not(feature = "archive"))]
+fn archive_sink(_args: &Settings, _endpoint: &str, _registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    bail!("--s3-endpoint requires building with the archive feature")
+}
+
+/// Tags events with rule metadata and ATT&CK techniques, when configured.
+#[cfg(feature = "enrichment")]
+fn enrich<G>(args: &Settings, events: Box<Stream<Item=Vec<u8>, Error=Error> + Send>, stage_guard: &G) -> Result<Box<Stream<Item=Vec<u8>, Error=Error> + Send>, Error>
+    where G: Fn(&str) -> guard::StageGuard
+{
+    let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if args.rules.is_empty() {
+        events
+    } else {
+        let mut rules = rules::RuleSet::default();
+        for path in args.rules.iter() {
+            let loaded = rules.load(path)?;
+            info!("Loaded {} rules from {}", loaded, path);
+        }
+        Box::new(rules::RuleEnricher::new(events, rules).with_guard(stage_guard("rules")))
+    };
+
+    if args.attack_tags || args.attack_mapping.is_some() {
+        let mut tagger = attack::AttackTagger::default();
+        if let Some(ref path) = args.attack_mapping {
+            let loaded = tagger.load(path)?;
+            info!("Loaded {} ATT&CK mappings from {}", loaded, path);
+        }
+        Ok(Box::new(attack::AttackTagStream::new(events, tagger).with_guard(stage_guard("attack"))))
+    } else {
+        Ok(events)
+    }
+}
+
+#[cfg(not(feature = "enrichment"))]
+fn enrich<G>(args: &Settings, events: Box<Stream<Item=Vec<u8>, Error=Error> + Send>, _stage_guard: &G) -> Result<Box<Stream<Item=Vec<u8>, Error=Error> + Send>, Error>
+    where G: Fn(&str) -> guard::StageGuard
+{
+    if !args.rules.is_empty() || args.attack_tags || args.attack_mapping.is_some() {
+        bail!("--rules and --attack-tags require building with the enrichment feature");
+    }
+    Ok(events)
+}
+
+/// Loads the plugins in `dir`, rescanning it whenever the process gets SIGHUP.
+#[cfg(feature = "plugins")]
+fn load_plugins(dir: &str, cancellation: &CancellationToken, journal: Option<journal::OpsJournal>) -> Result<extension::PluginSet, Error> {
+    let plugins = extension::PluginSet::default();
+    let loaded = plugins.scan(dir)?;
+    info!("Loaded {} plugin stages from {}", loaded, dir);
+
+    let reloading = plugins.clone();
+    let dir = dir.to_string();
+    let reload = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP)
+        .flatten_stream()
+        .until_cancelled(cancellation.clone())
+        .for_each(move |_| {
+            match reloading.scan(&dir) {
+                Ok(loaded) => {
+                    info!("Reloaded {} plugin stages from {}", loaded, dir);
+                    if let Some(ref journal) = journal {
+                        journal.record(journal::OpsKind::PluginReload, json!({"dir": dir, "stages": loaded}));
+                    }
+                }
+                Err(e) => error!("Failed to rescan plugins in {}: {}", dir, e)
+            }
+            Ok(())
+        }).map_err(|e| error!("Plugin reload signal failed: {:?}", e));
+    tokio::spawn(reload);
+    Ok(plugins)
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_plugins(_dir: &str, _cancellation: &CancellationToken, _journal: Option<journal::OpsJournal>) -> Result<extension::PluginSet, Error> {
+    bail!("--plugin-dir requires building with the plugins feature")
+}
+
+#[cfg(feature = "sinks")]
+fn redis_sink(args: &Settings, addr: &str, registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    let redis = redis::RedisSink::new(addr, args.redis_mode, &args.redis_key);
+    let redis = match args.redis_max_len {
+        Some(max_len) => redis.with_max_len(max_len),
+        None => redis
+    };
+    let route = sink::SinkRoute::parse(&args.redis_events);
+    Ok(sink::SinkHandle::spawn(redis, route, sink::SinkConfig::default(), registry))
+}
+
+#[cfg(not(feature = "sinks"))]
+fn redis_sink(_args: &Settings, _addr: &str, _registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    bail!("--redis-addr requires building with the sinks feature")
+}
+
+#[cfg(feature = "sinks")]
+fn elastic_sink(args: &Settings, url: &str, registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    let elastic = elastic::ElasticSink::new(url, &args.elastic_index)?;
+    let route = sink::SinkRoute::parse(&args.elastic_events);
+    Ok(sink::SinkHandle::spawn(elastic, route, sink::SinkConfig::default(), registry))
+}
+
+#[cfg(not(feature = "sinks"))]
+fn elastic_sink(_args: &Settings, _url: &str, _registry: &metrics::Registry) -> Result<sink::SinkHandle, Error> {
+    bail!("--elastic-url requires building with the sinks feature")
+}
+
+/// Retries, then dead letters or fails on, records that couldn't be delivered. `None` keeps the
+/// writer's default of logging and dropping them.
+fn delivery_error_handler(args: &Settings, producer: &Producer, registry: &metrics::Registry) -> Result<Option<writer::ExponentialBackoff>, Error> {
+    let fallback: Box<writer::DeliveryErrorHandler + Send> = match (args.dead_letter_file.as_ref(), args.dead_letter_topic.as_ref()) {
+        (Some(_), Some(_)) => bail!("--dead-letter-file and --dead-letter-topic can't be used together"),
+        (Some(path), None) => Box::new(writer::DeadLetterFile::open(path)?.with_counter(registry.counter("writer.dead_letters"))),
+        (None, Some(topic)) => {
+            let (sender, gauge) = spawn_derived("dead_letters", topic, key::FlowIdKeyGenerator, producer, registry);
+            Box::new(writer::DeadLetterChannel::new(sender).with_queue_gauge(gauge))
+        }
+        (None, None) if args.fail_undelivered || args.strict => Box::new(writer::FailStream),
+        (None, None) if args.retry_attempts > 1 => Box::new(writer::LogAndDrop),
+        (None, None) => return Ok(None)
+    };
+    let backoff = writer::ExponentialBackoff::new(
+        args.retry_attempts.max(1),
+        std::time::Duration::from_millis(args.retry_backoff_ms),
+        std::time::Duration::from_millis(args.max_retry_backoff_ms)
+    );
+    Ok(Some(backoff
+        .with_fallback(fallback)
+        .with_retry_counter(registry.counter("writer.retries"))))
+}
+
+/// Event type filters, field redaction, and static fields, if any are configured.
+fn event_transforms(args: &Settings) -> Result<Option<transform::TransformChain>, Error> {
+    let mut filter = transform::EventTypeFilter::default();
+    if let Some(ref types) = args.keep_event_types {
+        filter = filter.with_allowed(types);
+    }
+    if let Some(ref types) = args.drop_event_types {
+        filter = filter.with_denied(types);
+    }
+    let mut chain = transform::TransformChain::default();
+    if args.keep_event_types.is_some() || args.drop_event_types.is_some() {
+        chain = chain.with_transform(filter);
+    }
+    if let Some(ref fields) = args.redact_fields {
+        chain = chain.with_transform(transform::Redactor::parse(fields)?);
+    }
+    if args.static_field_target.fields() {
+        let injector = args.static_field.iter()
+            .fold(Ok(transform::FieldInjector::default()), |injector, f| injector.and_then(|i| i.with_parsed(f)))?;
+        if !injector.is_empty() {
+            chain = chain.with_transform(injector);
+        }
+    }
+    Ok(if chain.is_empty() { None } else { Some(chain) })
+}
+
+/// Settings of the stages that can change records, hashed into their lineage.
+fn lineage_stages(args: &Settings) -> Vec<String> {
+    let mut stages = vec![];
+    if let Some(ref topic) = args.config_topic {
+        stages.push(format!("overrides={}", topic));
+    }
+    if let Some(ref topic) = args.suppression_topic {
+        stages.push(format!("suppressions={}:{}", topic, args.suppression_key));
+    }
+    if let Some(rate) = args.alert_rate_limit {
+        stages.push(format!("alert_rate_limit={}:{}", args.alert_rate_key, rate));
+    }
+    // Tokens are left out, they're secrets
+    for quota in args.tenant_quota.iter() {
+        stages.push(format!("tenant_quota={}", quota.splitn(2, '=').nth(1).unwrap_or("")));
+    }
+    if let Some(ref types) = args.drop_event_types {
+        stages.push(format!("drop_event_types={}", types));
+    }
+    if let Some(ref types) = args.keep_event_types {
+        stages.push(format!("keep_event_types={}", types));
+    }
+    if let Some(ref fields) = args.redact_fields {
+        stages.push(format!("redact={}", fields));
+    }
+    if let Some(ref dir) = args.plugin_dir {
+        stages.push(format!("plugins={}", dir));
+    }
+    if !args.static_field.is_empty() {
+        stages.push(format!("static_fields={}:{:?}", args.static_field.join(","), args.static_field_target));
+    }
+    for projection in args.project.iter() {
+        stages.push(format!("project={}", projection));
+    }
+    if let Some(ref fields) = args.truncate_fields {
+        stages.push(format!("truncate={}", fields));
+    }
+    for path in args.rules.iter() {
+        stages.push(format!("rules={}", path));
+    }
+    if args.attack_tags || args.attack_mapping.is_some() {
+        stages.push(format!("attack={}", args.attack_mapping.as_ref().map(|p| p.as_str()).unwrap_or("")));
+    }
+    if let Some(max_records) = args.array_batch {
+        stages.push(format!("array_batch={}", max_records));
+    }
+    for mirror in args.dual_write.iter() {
+        stages.push(format!("dual_write={}", mirror));
+    }
+    stages.push(format!("key_placement={:?}", args.key_placement));
+    stages.push(format!("envelope={:?}", args.envelope));
+    stages
+}
+
+fn event_key_generator(args: &Settings, plugins: Option<&extension::PluginSet>) -> Result<Box<key::KeyGenerator<Item=Vec<u8>> + Send>, Error> {
+    let generator: Box<key::KeyGenerator<Item=Vec<u8>> + Send> = if let Some(ref name) = args.key_plugin {
+        match plugins {
+            Some(plugins) => Box::new(extension::PluginKeyGenerator::new(plugins.clone(), name)),
+            None => bail!("--key-plugin requires --plugin-dir")
+        }
+    } else {
+        match (args.key_strategy, args.key_by.as_ref()) {
+            (key::KeyStrategy::Payload, Some(dimensions)) => Box::new(eve::DimensionGenerator::new(eve::parse_dimensions(dimensions)?)),
+            (key::KeyStrategy::Payload, None) => Box::new(key::BytesGenerator),
+            (_, Some(_)) => bail!("--key-by can only be used with --key-strategy payload"),
+            (key::KeyStrategy::FlowId, None) => Box::new(key::FlowIdKeyGenerator),
+            (key::KeyStrategy::FiveTuple, None) => Box::new(key::FiveTupleKeyGenerator),
+            (key::KeyStrategy::Sensor, None) => {
+                Box::new(key::SensorKeyGenerator::new(&args.sensor_id.clone().unwrap_or_else(registry::hostname)))
+            }
+        }
+    };
+
+    let generator: Box<key::KeyGenerator<Item=Vec<u8>> + Send> = if args.protocol_keys {
+        let mut presets = presets::PresetGenerator::new(generator);
+        for setting in args.protocol_preset.iter() {
+            presets = presets.with_preset(presets::ProtocolPreset::parse(setting)?);
+        }
+        Box::new(presets)
+    } else if !args.protocol_preset.is_empty() {
+        bail!("--protocol-preset requires --protocol-keys");
+    } else {
+        generator
+    };
+
+    if let Some(ref path) = args.key_secret_file {
+        let secret = std::fs::read_to_string(path)?;
+        Ok(Box::new(key::SaltedGenerator::new(generator, secret.trim().as_bytes().to_vec())))
+    } else {
+        Ok(generator)
+    }
+}
+
+/// A configured shipper: source, enrichment stages, and producer. Runs either on an existing
+/// runtime with `spawn_on`, or on a runtime of its own with `run_blocking`.
+pub struct Pipeline {
+    settings: Settings,
+    cancellation: CancellationToken,
+    source: Option<Box<Stream<Item=Vec<u8>, Error=Error> + Send>>,
+    registry: metrics::Registry,
+    inspector: Option<Box<FnMut(inspect::Inspection) + Send>>,
+    checkpoint_backends: checkpoint::CheckpointBackends
+}
+
+impl Pipeline {
+    pub fn new(settings: Settings) -> Pipeline {
+        Pipeline {
+            settings: if settings.inspect { settings.for_inspection() } else { settings },
+            cancellation: CancellationToken::new(),
+            source: None,
+            registry: metrics::Registry::default(),
+            inspector: None,
+            checkpoint_backends: checkpoint::CheckpointBackends::default()
+        }
+    }
+
+    /// Registers the pipeline's gauges and counters (including `writer.delivered` and
+    /// `writer.failed`) in `registry`, so the embedder can observe them.
+    pub fn with_registry(mut self, registry: metrics::Registry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Ships records from `source` instead of the configured file or socket.
+    pub fn with_source<S>(mut self, source: S) -> Self
+        where S: Stream<Item=Vec<u8>, Error=Error> + Send + 'static
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// With --inspect, hands the inspections of matching events to `inspector` rather than
+    /// printing them.
+    pub fn with_inspector<F>(mut self, inspector: F) -> Self
+        where F: FnMut(inspect::Inspection) + Send + 'static
+    {
+        self.inspector = Some(Box::new(inspector));
+        self
+    }
+
+    /// Registers `factory` as the checkpoint backend `name`, used when --checkpoint-backend
+    /// names it; it's opened with --checkpoint-location and --instance-id.
+    pub fn with_checkpoint_backend<F>(mut self, name: &str, factory: F) -> Self
+        where F: Fn(&checkpoint::BackendOptions) -> Result<Box<checkpoint::CheckpointStore + Send + Sync>, Error> + Send + Sync + 'static
+    {
+        self.checkpoint_backends.register(name, factory);
+        self
+    }
+
+    /// Stops the pipeline when `token` is cancelled instead of its own token.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Token that stops the source, lets in-flight records drain, and resolves the pipeline.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Future running the pipeline to completion. Background tasks are started with
+    /// `tokio::spawn`, so it must be polled from within a tokio runtime.
+    pub fn into_future(self) -> Box<Future<Item=(), Error=Error> + Send> {
+        Box::new(future::lazy(move || self.start()).flatten())
+    }
+
+    /// Runs the pipeline on `executor`, returning a handle that resolves when the pipeline
+    /// finishes. Dropping the handle cancels the pipeline; use `forget` to detach it.
+    pub fn spawn_on(self, executor: &TaskExecutor) -> SpawnHandle<(), Error> {
+        oneshot::spawn(self.into_future(), executor)
+    }
+
+    /// Runs the pipeline to completion on a runtime owned by the pipeline.
+    pub fn run_blocking(self) -> Result<(), Error> {
+        let mut rt = Runtime::new()?;
+        let res = rt.block_on(self.into_future());
+        let _ = rt.shutdown_now().wait();
+        res
+    }
+
+    fn start(self) -> Result<Box<Future<Item=(), Error=Error> + Send>, Error> {
+        let map = topic_map(&self.settings)?;
+        map.validate()?;
+        if self.settings.strict && !self.settings.inspect {
+            let violations = strict_violations(&self.settings, &map);
+            if !violations.is_empty() {
+                bail!("--strict can't guarantee lossless shipping: {}", violations.join(", "));
+            }
+        }
+        for guarantee in map.guarantees() {
+            info!("Delivery guarantee: {}", guarantee);
+        }
+        let throttle = ThrottleSignal::new(self.registry.counter("writer.throttled"));
+        let mut config = client_config(&self.settings)?;
+        if let Some(linger_ms) = self.settings.linger_ms {
+            config.set("linger.ms", &linger_ms.to_string());
+        }
+        if let Some(batch_num_messages) = self.settings.batch_num_messages {
+            config.set("batch.num.messages", &batch_num_messages.to_string());
+        }
+        if let Some(ref codec) = self.settings.compression_codec {
+            config.set("compression.codec", codec.as_str());
+        }
+        let broker_states = warm::BrokerStates::default();
+        if self.settings.warm_connections {
+            if self.settings.stats_interval_ms == 0 {
+                bail!("--warm-connections requires --stats-interval-ms");
+            }
+            warm::keep_warm(&mut config, std::time::Duration::from_secs(self.settings.warm_refresh_secs));
+        }
+        let producer: Producer = config
+            .set("produce.offset.report", "true")
+            .set("statistics.interval.ms", &self.settings.stats_interval_ms.to_string())
+            .set("message.timeout.ms", "5000")
+            .set("acks", self.settings.acks.config_value())
+            // with several deliveries in flight, one request per broker at a time keeps retried
+            // batches from overtaking later ones
+            .set("max.in.flight.requests.per.connection", if self.settings.max_in_flight > 1 { "1" } else { "1000000" })
+            .create_with_context(ShipperContext::new(throttle.clone())
+                .with_registry(self.registry.clone())
+                .with_broker_states(broker_states.clone()))
+            .map_err(|e| Error::from(format!("Failed to create producer: {:?}", e)))?;
+
+        let immediate: Option<Producer> = match self.settings.immediate {
+            Some(ref filter) if !self.settings.no_kafka => {
+                inspect::Filter::parse(filter)?;
+                let producer = config
+                    .set("linger.ms", "0")
+                    .create_with_context(ShipperContext::new(throttle.clone()))
+                    .map_err(|e| Error::from(format!("Failed to create immediate producer: {:?}", e)))?;
+                Some(producer)
+            }
+            _ => None
+        };
+
+        if self.settings.warm_connections && !self.settings.no_kafka {
+            warm::wait_until_up(&broker_states, std::time::Duration::from_secs(self.settings.warm_timeout_secs));
+        }
+
+        if let Some(mode) = self.settings.self_test {
+            if self.settings.no_kafka {
+                bail!("--self-test can't be used with --no-kafka");
+            }
+            let sensor_id = self.settings.sensor_id.clone().unwrap_or_else(registry::hostname);
+            canary::SelfTest::new(&client_config(&self.settings)?, &sensor_id)
+                .with_mode(mode)
+                .with_timeout(std::time::Duration::from_secs(self.settings.self_test_timeout_secs))
+                .run(&producer, &self_test_topics(&self.settings))?;
+            info!("Self-test passed, starting pipeline");
+        }
+
+        let registration: Box<Future<Item=(), Error=Error> + Send> = if self.settings.no_kafka || self.settings.inspect {
+            Box::new(future::ok(()))
+        } else {
+            register_sensor(&self.settings, &producer)?
+        };
+
+        Ok(Box::new(registration.and_then(move |_| self.build(producer, immediate, throttle).into_future().flatten())))
+    }
+
+    /// Builds the stages and spawns the background tasks (alarms, queue reports, admin server)
+    /// on the current runtime, returning the future driving the main event stream.
+    fn build(self, producer: Producer, immediate: Option<Producer>, throttle: ThrottleSignal) -> Result<Box<Future<Item=(), Error=Error> + Send>, Error> {
+        let args = self.settings;
+        let cancellation = self.cancellation;
+        let source = self.source;
+        let custom_source = source.is_some();
+        let mut inspector = self.inspector;
+
+        let sensor_id = args.sensor_id.clone().unwrap_or_else(registry::hostname);
+        let registry = self.registry;
+        let checkpoint_backends = self.checkpoint_backends;
+
+        let shutdown = Shutdown::new(cancellation.clone(), std::time::Duration::from_secs(args.shutdown_grace_secs));
+        let shutdown = match args.shutdown_deadline_secs {
+            Some(secs) => shutdown.with_deadline(std::time::Duration::from_secs(secs)),
+            None => shutdown
+        };
+        if !custom_source {
+            tokio::spawn(shutdown.on_signals());
+        }
+
+        let journal = if args.ops_journal {
+            if args.no_kafka {
+                bail!("--ops-journal can't be used with --no-kafka");
+            }
+            provision_derived(&args, &args.ops_topic, false)?;
+            let (sender, gauge) = spawn_derived("ops", &args.ops_topic, derive::FieldKey::new("sensor_id"), &producer, &registry);
+            info!("Journaling operational events to {}", args.ops_topic);
+            Some(journal::OpsJournal::new(sender, gauge, &sensor_id))
+        } else {
+            None
+        };
+
+        let plugins = match args.plugin_dir {
+            Some(ref dir) => Some(load_plugins(dir, &cancellation, journal.clone())?),
+            None => None
+        };
+
+        let generator = event_key_generator(&args, plugins.as_ref())?;
+
+        let pinned = match (args.sensor_partitions, args.topic_partitions) {
+            (Some(subset), Some(partitions)) => {
+                let pinned = partition::SensorPinned::new(&sensor_id, partitions, subset);
+                info!("Sensor {} pinned to partitions {:?}", sensor_id, pinned.assigned());
+                Some(pinned)
+            }
+            (Some(_), None) => bail!("--sensor-partitions requires --topic-partitions"),
+            _ => None
+        };
+
+        let read_log = shutdown::ReadLog::new(shutdown::DEFAULT_READ_LOG_CAPACITY);
+        let source_trace = if args.trace_sources {
+            Some(trace::SourceTrace::new(trace::DEFAULT_TRACE_CAPACITY))
+        } else {
+            None
+        };
+        let reader_options = ReaderOptions {
+            max_line_length: args.max_line_length,
+            utf8_mode: if args.utf8_escape { json::Utf8Mode::Escape } else { json::Utf8Mode::Lossy },
+            strict: args.strict,
+            pending_gauge: registry.queue("reader.pending"),
+            skipped: registry.counter("reader.skipped_lines"),
+            cancellation: cancellation.clone(),
+            read_log: read_log.clone(),
+            trace: source_trace.clone()
+        };
+        let accounting = FdAccounting::new(&registry);
+
+        let checkpoint_options = checkpoint::BackendOptions {
+            location: args.checkpoint_location.clone().unwrap_or_else(|| args.checkpoint_dir.clone()),
+            instance: args.instance_id.clone()
+        };
+        let checkpoints = Arc::new(checkpoint_backends.open(&args.checkpoint_backend, &checkpoint_options)?);
+        let persisted = if args.persist_counters && !args.inspect {
+            let totals = totals::PersistedCounters::restore(checkpoints.clone(), &registry, totals::PERSISTED_COUNTERS)?;
+            let interval = std::time::Duration::from_secs(args.persist_counters_secs.max(1));
+            let saver = totals.clone();
+            tokio::spawn(tokio::timer::Interval::new(std::time::Instant::now() + interval, interval)
+                .until_cancelled(cancellation.clone())
+                .for_each(move |_| {
+                    if let Err(e) = saver.save() {
+                        warn!("Failed to save counters: {}", e);
+                    }
+                    Ok(())
+                })
+                .map_err(|e| error!("Counter save timer failed: {:?}", e)));
+            Some(totals)
+        } else {
+            None
+        };
+        let hlc_clock = if args.hlc_headers {
+            let clock = hlc::HybridClock::new(checkpoints.load(hlc::CHECKPOINT_SOURCE)?);
+            if !args.inspect {
+                let lease_ms = args.hlc_lease_secs.max(1) * 1000;
+                checkpoints.save(hlc::CHECKPOINT_SOURCE, clock.lease(lease_ms))?;
+                let interval = std::time::Duration::from_millis(lease_ms / 2);
+                let (leaser, leased) = (clock.clone(), checkpoints.clone());
+                tokio::spawn(tokio::timer::Interval::new(std::time::Instant::now() + interval, interval)
+                    .until_cancelled(cancellation.clone())
+                    .for_each(move |_| {
+                        if let Err(e) = leased.save(hlc::CHECKPOINT_SOURCE, leaser.lease(lease_ms)) {
+                            warn!("Failed to renew the clock lease: {}", e);
+                        }
+                        Ok(())
+                    })
+                    .map_err(|e| error!("Clock lease timer failed: {:?}", e)));
+            }
+            Some(clock)
+        } else {
+            None
+        };
+        // Each file read gets its own checkpoint, labeled with its interface if it has one
+        let mut files: Vec<(Option<String>, String, Arc<AtomicUsize>)> = vec![];
+        if !custom_source {
+            if let Some(ref path) = args.eve_file {
+                files.push( (None, path.clone(), Arc::new(AtomicUsize::new(0))) );
+            }
+            for setting in args.interface_file.iter() {
+                let (label, path) = iface::parse_source(setting)?;
+                files.push( (Some(label), path, Arc::new(AtomicUsize::new(0))) );
+            }
+        }
+
+        let mut formats = std::collections::HashMap::new();
+        for setting in args.source_format.iter() {
+            let (path, source_format) = format::parse_source_format(setting)?;
+            if !files.iter().any(|&(_, ref file, _)| *file == path) {
+                bail!("--source-format given for {}, which isn't read with --eve-file or --interface-file", path);
+            }
+            formats.insert(path, source_format);
+        }
+
+        // Backlog ends and live positions of files being caught up on, saved with their checkpoints
+        let mut catch_ups: Vec<(String, catchup::CatchUpPositions)> = vec![];
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if let Some(source) = source {
+            source
+        } else if !files.is_empty() {
+            let mut readers: Vec<Box<Stream<Item=Vec<u8>, Error=Error> + Send>> = vec![];
+            for &(ref label, ref path, ref position) in files.iter() {
+                let offset = match args.start_position {
+                    source::StartPosition::End => source::content_length(path)?,
+                    source::StartPosition::Resume => checkpoints.load(path)?.unwrap_or(0),
+                    _ => 0
+                };
+                info!("Reading {} from offset {}", path, offset);
+                position.store(offset as usize, Ordering::SeqCst);
+
+                let catching_up = args.follow && args.start_position == source::StartPosition::Resume
+                    && (args.catch_up_rate.is_some() || args.catch_up_max_secs.is_some());
+                let end = if catching_up { source::content_length(path)? } else { 0 };
+                // A catch up stopped part way resumes both its backlog and its live reader, unless
+                // the file has shrunk past them since
+                let resumed = if catching_up { catchup::CatchUpPositions::load(&checkpoints, path)? } else { None };
+                let (split, live_from) = match resumed {
+                    Some( (split, live) ) if offset < split && split <= live && live <= end => (split, live),
+                    _ => (end, end)
+                };
+
+                let reader: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if catching_up && split > offset {
+                    info!("Catching up on {} bytes of {} while tailing it live from offset {}", split - offset, path, live_from);
+                    let live_position = Arc::new(AtomicUsize::new(live_from as usize));
+                    let tailer = source::FileTailer::open(path, live_from)?.with_position(live_position.clone());
+                    let live = reader_options.file_reader(accounting.track(FdKind::File, tailer), path, live_position.clone());
+                    let backlog = reader_options.file_reader(accounting.track(FdKind::File, source::open_eve_range(path, offset, split)?), path, position.clone());
+                    let catch_up = catchup::CatchUp::new(backlog, live, position.clone(), live_position, split as usize, &registry)
+                        .with_cancellation(cancellation.clone());
+                    catch_ups.push( (path.clone(), catch_up.positions()) );
+                    let catch_up = match args.catch_up_rate {
+                        Some(rate) => catch_up.with_rate(rate),
+                        None => catch_up
+                    };
+                    match args.catch_up_max_secs {
+                        Some(secs) => Box::new(catch_up.with_max_duration(std::time::Duration::from_secs(secs))),
+                        None => Box::new(catch_up)
+                    }
+                } else if args.follow {
+                    let tailer = source::FileTailer::open(path, offset)?.with_position(position.clone());
+                    Box::new(reader_options.file_reader(accounting.track(FdKind::File, tailer), path, position.clone()))
+                } else {
+                    Box::new(reader_options.file_reader(accounting.track(FdKind::File, source::open_eve_file(path, offset)?), path, position.clone()))
+                };
+                let reader: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match formats.get(path) {
+                    Some(&source_format) if source_format != format::SourceFormat::Eve => {
+                        info!("Reading {} as {:?}", path, source_format);
+                        Box::new(format::Normalized::new(reader, format::Normalizer::new(source_format, path)))
+                    }
+                    _ => reader
+                };
+                let reader: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match *label {
+                    Some(ref label) => Box::new(iface::Labeled::new(reader, label)),
+                    None => reader
+                };
+                readers.push(reader);
+            }
+            let first = readers.remove(0);
+            let reader = readers.into_iter().fold(first, |merged, reader| {
+                let merged: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = Box::new(merged.select(reader));
+                merged
+            });
+
+            let reader: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if let source::StartPosition::Since(age) = args.start_position {
+                Box::new(source::SinceFilter::new(reader, chrono::Utc::now() - age))
+            } else {
+                Box::new(reader)
+            };
+
+            let reader: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.replay_speed {
+                Some(speed) => Box::new(replay::Replay::new(reader, speed)),
+                None => reader
+            };
+
+            if let Some(ref group) = args.lag_group {
+                let interval = std::time::Duration::from_secs(args.lag_interval_secs);
+                let monitor = lag::LagMonitor::spawn(&client_config(&args)?, group, &args.topic, interval)?;
+                Box::new(lag::Paced::new(reader, monitor.handle(), args.max_lag, interval))
+            } else {
+                reader
+            }
+        } else if let Some(ref addr) = args.eve_tcp {
+            let connections = accounting.clone();
+            let reader_options = reader_options.clone();
+            Box::new(source::TcpSource::bind(addr, move |s| {
+                debug!("Stream connected from {:?}", s.peer_addr());
+                Box::new(reader_options.reader(connections.track(FdKind::Socket, s)))
+            })?)
+        } else {
+            let connections = accounting.clone();
+            let reader_options = reader_options.clone();
+            Box::new(source::UnixSocketSource::bind(&args.eve_socket_path, move |s| {
+                debug!("Stream connected at {:?}", s.peer_addr());
+                Box::new(reader_options.reader(connections.track(FdKind::Socket, s)))
+            })?)
+        };
+
+        let recent = if args.admin_addr.is_some() && args.recent_events > 0 {
+            Some(recent::RecentEvents::new(args.recent_events))
+        } else {
+            None
+        };
+
+        let read = registry.counter("reader.events");
+        let read_bytes = registry.counter("reader.bytes");
+        let recorded = recent.clone();
+        let events = events
+            .inspect(move |event| {
+                read.incr();
+                read_bytes.add(event.len());
+                if let Some(ref recorded) = recorded {
+                    recorded.record(event);
+                }
+            });
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if args.poll_budget > 0 {
+            Box::new(budget::Budgeted::new(events, args.poll_budget)
+                .with_yield_counter(registry.counter("reader.yields")))
+        } else {
+            Box::new(events)
+        };
+        // File readers stop themselves on cancellation once they've passed on what they parsed,
+        // so their checkpoints don't skip events
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if files.is_empty() {
+            Box::new(events.until_cancelled(cancellation.clone()))
+        } else {
+            events
+        };
+
+        let (alarm_sender, alarm_receiver) = futures::sync::mpsc::unbounded();
+
+        let alarms_gauge = registry.queue("alarms.channel");
+        let alarms_received = alarms_gauge.clone();
+
+        let alarms = alarm_receiver
+            .inspect(move |_| alarms_received.sub(1))
+            .map_err(|_| Error::from_kind(ErrorKind::ReceiverError));
+
+        if args.no_kafka || args.inspect {
+            tokio::spawn(alarms.for_each(|alarm| {
+                warn!("Alarm {}", String::from_utf8_lossy(&alarm));
+                Ok(())
+            }).map_err(|e| print_error(&e)));
+        } else {
+            tokio::spawn(alarms.produce(
+                args.control_topic.clone(),
+                key::BytesGenerator,
+                producer.clone()
+            ).for_each(|_| {
+                Ok(())
+            }).map_err(|e| print_error(&e)));
+        }
+
+        let guard_registry = registry.clone();
+        let quarantine_sender = alarm_sender.clone();
+        let quarantine_gauge = alarms_gauge.clone();
+        let stage_guard = move |stage: &str| {
+            guard::StageGuard::new(stage)
+                .with_panic_counter(guard_registry.counter(&format!("{}.panics", stage)))
+                .with_quarantine(quarantine_sender.clone())
+                .with_queue_gauge(quarantine_gauge.clone())
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.config_topic {
+            Some(ref topic) => {
+                let secret = match args.config_secret_file {
+                    Some(ref path) => std::fs::read_to_string(path)?.trim().as_bytes().to_vec(),
+                    None => bail!("--config-topic requires --config-secret-file")
+                };
+                let overrides = match journal {
+                    Some(ref journal) => remote::OverrideHandle::default().with_journal(journal.clone()),
+                    None => remote::OverrideHandle::default()
+                };
+                remote::ConfigListener::spawn(&client_config(&args)?, topic, secret, &sensor_id, overrides.clone(), alarm_sender.clone(), alarms_gauge.clone())?;
+                Box::new(remote::OverrideFilter::new(events, overrides))
+            }
+            None => events
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.suppression_topic {
+            Some(ref topic) => {
+                let key = suppress::SuppressionKey::parse(&args.suppression_key)?;
+                let table = suppress::SuppressionTable::default();
+                let timeout = std::time::Duration::from_secs(args.suppression_prime_secs);
+                let primed = suppress::SuppressionListener::spawn(&client_config(&args)?, topic, table.clone(), timeout)?;
+                info!("Primed {} suppressions from {}", primed, topic);
+                Box::new(suppress::SuppressionFilter::new(events, table, key)
+                    .with_drop_counter(registry.counter("suppress.dropped")))
+            }
+            None => events
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.alert_rate_limit {
+            Some(rate) => {
+                let key = suppress::SuppressionKey::parse(&args.alert_rate_key)?;
+                let limiter = storm::KeyedLimiter::new(
+                    storm::RateLimit { rate: rate, burst: args.alert_rate_burst.max(1.0) },
+                    args.alert_rate_max_keys
+                );
+                let interval = std::time::Duration::from_secs(args.alert_rate_summary_secs);
+                info!("Rate limiting alerts to {}/s by {}", rate, args.alert_rate_key);
+                Box::new(storm::StormLimiter::new(events, &args.alert_rate_key, key, limiter, interval, &registry))
+            }
+            None => events
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = if args.tenant_quota.is_empty() && args.unknown_tenant_quota.is_none() {
+            events
+        } else {
+            let mut quotas = tenant::TenantQuotas::new(&args.tenant_field, &registry);
+            for setting in args.tenant_quota.iter() {
+                let (token, name, quota) = tenant::parse_tenant(setting)?;
+                info!("Limiting tenant {} to {} messages and {} bytes a second", name, quota.messages, quota.bytes);
+                quotas = quotas.with_tenant(&token, &name, quota);
+            }
+            if let Some(ref quota) = args.unknown_tenant_quota {
+                quotas = quotas.with_unknown(tenant::Quota::parse(quota)?);
+            }
+            Box::new(tenant::TenantLimiter::new(events, quotas))
+        };
+
+        let clock_skew = match args.clock_topic {
+            Some(ref topic) => {
+                if args.no_kafka {
+                    bail!("--clock-topic can't be used with --no-kafka");
+                }
+                let check = clock::ClockCheck::spawn(
+                    &client_config(&args)?,
+                    producer.clone(),
+                    topic,
+                    &sensor_id,
+                    std::time::Duration::from_secs(args.clock_interval_secs),
+                    std::time::Duration::from_millis(args.max_clock_skew_ms),
+                    &registry
+                )?;
+                Some(check.handle())
+            }
+            None => None
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match event_transforms(&args)? {
+            Some(chain) => Box::new(transform::TransformStream::new(events, chain)
+                .with_guard(stage_guard("transform"))
+                .with_drop_counter(registry.counter("transform.dropped"))),
+            None => events
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match plugins {
+            Some(ref plugins) => Box::new(extension::PluginStream::new(events, plugins.clone())
+                .with_guard(stage_guard("plugins"))
+                .with_drop_counter(registry.counter("plugins.dropped"))),
+            None => events
+        };
+
+        let events: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.truncate_fields {
+            Some(ref fields) => {
+                let limits = truncate::FieldLimits::parse(fields)?;
+                Box::new(truncate::Truncator::new(events, limits).with_guard(stage_guard("truncate")))
+            }
+            None => events
+        };
+
+        let events = enrich(&args, events, &stage_guard)?;
+
+        let mut derived = DerivedStreams::new(events);
+        if let Some(ref topic) = args.latest_alert_topic {
+            provision_derived(&args, topic, true)?;
+            let (sender, gauge) = spawn_derived("latest_alert", topic, derive::FieldKey::new("host"), &producer, &registry);
+            derived = derived.with_derivation(derive::LatestAlert, sender, Some(gauge));
+        }
+        if let Some(ref topic) = args.passive_dns_topic {
+            provision_derived(&args, topic, false)?;
+            let (sender, gauge) = spawn_derived("passive_dns", topic, derive::FieldKey::new("rrname"), &producer, &registry);
+            derived = derived.with_derivation(pdns::PassiveDns, sender, Some(gauge));
+        }
+        if let Some(ref topic) = args.alert_context_topic {
+            provision_derived(&args, topic, false)?;
+            let (sender, gauge) = spawn_derived("alert_context", topic, derive::FieldKey::new("src_ip").with_address(), &producer, &registry);
+            derived = derived.with_derivation(context::AlertContext::new(args.alert_context_events, args.alert_context_flows), sender, Some(gauge));
+        }
+        if let Some(ref topic) = args.cert_topic {
+            provision_derived(&args, topic, false)?;
+            let (sender, gauge) = spawn_derived("certs", topic, derive::FieldKey::new("fingerprint"), &producer, &registry);
+            let window = std::time::Duration::from_secs(args.cert_window_secs);
+            derived = derived.with_derivation(certs::CertObservations::new(window), sender, Some(gauge));
+        }
+        if args.anomaly_escalation || args.anomaly_weights.is_some() {
+            let mut scorer = anomaly::AnomalyScorer::default().with_threshold(args.anomaly_threshold);
+            if let Some(ref path) = args.anomaly_weights {
+                let loaded = scorer.load(path)?;
+                info!("Loaded {} anomaly weights from {}", loaded, path);
+            }
+            let topic = args.anomaly_alert_topic.as_ref().unwrap_or(&args.topic);
+            let (sender, gauge) = spawn_derived("anomaly_alerts", topic, derive::FieldKey::new("src_ip").with_address(), &producer, &registry);
+            derived = derived.with_derivation(scorer, sender, Some(gauge));
+        }
+        if let Some(threshold) = args.rule_of_n {
+            let window = std::time::Duration::from_secs(args.rule_of_n_window_secs);
+            let topic = args.priority_topic.as_ref().unwrap_or(&args.topic);
+            let (sender, gauge) = spawn_derived("meta_alerts", topic, derive::FieldKey::new("src_ip").with_address(), &producer, &registry);
+            derived = derived.with_derivation(escalate::RuleOfN::new(threshold, window), sender, Some(gauge));
+        }
+
+        let mut sinks = SinkTap::new(derived);
+        if let Some(ref addr) = args.redis_addr {
+            sinks = sinks.with_sink(redis_sink(&args, addr, &registry)?);
+        }
+        if let Some(ref url) = args.elastic_url {
+            sinks = sinks.with_sink(elastic_sink(&args, url, &registry)?);
+        }
+        if let Some(ref endpoint) = args.s3_endpoint {
+            sinks = sinks.with_sink(archive_sink(&args, endpoint, &registry)?);
+        }
+        if let (Some(plugins), false) = (plugins.as_ref(), args.inspect) {
+            for stage in plugins.stages(&[extension::StageKind::Sink]) {
+                let sink = extension::PluginSink::new(plugins.clone(), &stage.name);
+                sinks = sinks.with_sink(sink::SinkHandle::spawn(sink, sink::SinkRoute::parse("*"), sink::SinkConfig::default(), &registry));
+            }
+        }
+        if args.no_kafka && sinks.is_empty() {
+            bail!("--no-kafka requires another sink, such as --elastic-url");
+        }
+
+        let report_registry = registry.clone();
+        let report = tokio::timer::Interval::new(
+            std::time::Instant::now(),
+            std::time::Duration::from_secs(args.queue_report_secs.max(1))
+        ).until_cancelled(cancellation.clone()).for_each(move |_| {
+            for queue in report_registry.snapshot() {
+                info!("Queue {} depth {} high watermark {}", queue.name, queue.depth, queue.high_watermark);
+            }
+            for (name, value) in report_registry.counter_values() {
+                info!("Counter {} is {}", name, value);
+            }
+            if let Some(open) = fds::process_open_fds() {
+                info!("Process has {} open file descriptors", open);
+            }
+            if let Some(ref skew) = clock_skew {
+                info!("Sensor clock skew against brokers is {} ms", skew.load(Ordering::SeqCst));
+            }
+            for latency in report_registry.histogram_snapshots() {
+                info!(
+                    "Latency {} count {} p50 {:?} p95 {:?} p99 {:?} p999 {:?} max {:?}",
+                    latency.name, latency.count, latency.p50, latency.p95, latency.p99, latency.p999, latency.max
+                );
+            }
+            for size in report_registry.size_snapshots() {
+                info!(
+                    "Size {} count {} p50 {} p95 {} p99 {} max {} bytes",
+                    size.name, size.count, size.p50, size.p95, size.p99, size.max
+                );
+            }
+            if let Some(amplification) = report_registry.amplification("reader.bytes") {
+                let mut total = 0.0;
+                for topic in amplification {
+                    info!("Topic {} sent {} bytes, {:.3} per byte read", topic.topic, topic.bytes, topic.ratio);
+                    report_registry.gauge(&format!("amplification.topic.{}.permille", topic.topic))
+                        .set((topic.ratio * 1000.0).round() as usize);
+                    total += topic.ratio;
+                }
+                info!("Write amplification is {:.3} bytes sent per byte read", total);
+                report_registry.gauge("amplification.permille").set((total * 1000.0).round() as usize);
+            }
+            Ok(())
+        }).map_err(|e| error!("Queue report timer failed: {:?}", e));
+
+        tokio::spawn(report);
+
+        if let Some(ref addr) = args.admin_addr {
+            serve_admin(&args, addr, accounting.clone(), plugins.clone(), recent.clone(), &registry, &cancellation)?;
+        }
+
+        let thresholds = health::DropThresholds {
+            max_drop_rate: args.max_drop_rate,
+            min_packets: args.min_drop_packets
+        };
+
+        let cooldown = std::time::Duration::from_secs(args.breaker_cooldown_secs);
+        let breaker = args.breaker_error_rate.map(|rate| {
+            let breaker = breaker::CircuitBreaker::new(breaker::BreakerConfig {
+                max_error_rate: rate,
+                cooldown: cooldown,
+                ..breaker::BreakerConfig::default()
+            });
+            match journal {
+                Some(ref journal) => breaker.with_journal(journal.clone()),
+                None => breaker
+            }
+        });
+
+        let shedder = match args.shed_thresholds {
+            Some(ref thresholds) => Some( (shed::ShedPolicy::parse(thresholds, args.shed_sample_rate)?, alarm_sender.clone(), alarms_gauge.clone()) ),
+            None => None
+        };
+
+        let monitored = sinks
+            .monitor_drops(thresholds, alarm_sender)
+            .with_queue_gauge(alarms_gauge);
+
+        let monitored: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match shedder {
+            Some( (policy, alarms, gauge) ) => {
+                Box::new(shed::Shedder::new(monitored, policy, registry.queue("writer.in_flight"), &registry)
+                    .with_alarms(alarms, gauge))
+            }
+            None => Box::new(monitored)
+        };
+
+        let monitored: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.sample_above_lag {
+            Some(threshold) => {
+                let group = args.lag_group.as_ref()
+                    .ok_or_else(|| Error::from("--sample-above-lag requires --lag-group"))?;
+                let interval = std::time::Duration::from_secs(args.lag_interval_secs);
+                let monitor = lag::LagMonitor::spawn(&client_config(&args)?, group, &args.topic, interval)?;
+                let policy = lag::SamplingPolicy::new(
+                    &args.sample_event_types,
+                    threshold,
+                    args.sample_min_rate,
+                    std::time::Duration::from_secs(args.sample_adjust_secs)
+                )?;
+                Box::new(lag::LagSampler::new(monitored, monitor.handle(), policy, &registry))
+            }
+            None => monitored
+        };
+
+        let monitored: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match args.spool_path {
+            Some(ref path) => {
+                let ring = spool::RingSpool::open(path, args.spool_bytes)?;
+                let (fill, drain) = spool::spool(monitored, ring, &registry);
+                tokio::spawn(fill);
+                let drain = match journal {
+                    Some(ref journal) => drain.with_journal(journal.clone()),
+                    None => drain
+                };
+                Box::new(drain)
+            }
+            None => monitored
+        };
+
+        let main: Box<Future<Item=(), Error=Error> + Send> = if args.no_kafka {
+            Box::new(monitored.for_each(|_| Ok(())))
+        } else {
+            let immediate_filter = match args.immediate {
+                Some(ref filter) => Some(inspect::Filter::parse(filter)?),
+                None => None
+            };
+            let monitored: Box<Stream<Item=Vec<u8>, Error=Error> + Send> = match (args.array_batch, immediate_filter.clone()) {
+                (Some(max_records), Some(filter)) => {
+                    let wait = std::time::Duration::from_millis(args.array_batch_wait_ms);
+                    Box::new(batch::ArrayBatcher::new(monitored, max_records, wait)
+                        .with_pending_gauge(registry.queue("batch.pending"))
+                        .with_bypass(move |e| filter.matches_event(e)))
+                }
+                (Some(max_records), None) => {
+                    let wait = std::time::Duration::from_millis(args.array_batch_wait_ms);
+                    Box::new(batch::ArrayBatcher::new(monitored, max_records, wait)
+                        .with_pending_gauge(registry.queue("batch.pending")))
+                }
+                (None, _) => monitored
+            };
+
+            let stream_res = monitored
+                .produce(
+                    args.topic.clone(),
+                    generator,
+                    producer.clone()
+                );
+
+            let stream_res = match topic_router(&args, &registry)? {
+                Some(router) => stream_res.with_topic_router(router),
+                None => stream_res
+            };
+
+            let stream_res = max_event_ages(&args)?.into_iter().fold(stream_res, |stream_res, (topic, max_age, archive)| {
+                info!("Sending events older than {:?} on {} to {}", max_age, topic, archive);
+                stream_res.with_max_age(&topic, max_age, &archive, registry.counter("writer.expired"))
+            });
+
+            let (mirrors, until) = dual_writes(&args)?;
+            let stream_res = mirrors.into_iter().fold(stream_res, |stream_res, (topic, mirror)| {
+                info!("Dual writing {} to {}", topic, mirror);
+                stream_res.with_mirror(&topic, &mirror, until, registry.counter("writer.mirrored"))
+            });
+
+            let stream_res = match pinned {
+                Some(pinned) => stream_res.with_partitioner(pinned),
+                None => stream_res
+            };
+
+            #[cfg(feature = "enrichment")]
+            let stream_res = if args.attack_tags || args.attack_mapping.is_some() {
+                stream_res.with_headers(attack::AttackHeaders)
+            } else {
+                stream_res
+            };
+
+            let stream_res = if args.flowbits_headers || args.flowbits_names.is_some() {
+                let headers = flowbits::FlowbitsHeaders::default();
+                match args.flowbits_names {
+                    Some(ref names) => stream_res.with_headers(headers.with_names(names)),
+                    None => stream_res.with_headers(headers)
+                }
+            } else {
+                stream_res
+            };
+
+            let stream_res = if args.eve_headers {
+                stream_res.with_headers(writer::EveHeaders::new(&sensor_id))
+            } else {
+                stream_res
+            };
+
+            let stream_res = match hlc_clock {
+                Some(ref clock) => stream_res.with_headers(hlc::HlcHeaders::new(clock.clone())),
+                None => stream_res
+            };
+
+            let stream_res = if args.array_batch.is_some() {
+                stream_res.with_headers(batch::BatchHeaders)
+            } else {
+                stream_res
+            };
+
+            let stream_res = if args.interface_file.is_empty() {
+                stream_res
+            } else {
+                stream_res.with_headers(iface::InterfaceHeaders)
+            };
+
+            let mut static_headers = args.header.iter()
+                .fold(Ok(writer::StaticHeaders::default()), |headers, h| headers.and_then(|s| s.with_parsed(h)))?;
+            if args.static_field_target.headers() {
+                static_headers = args.static_field.iter()
+                    .fold(Ok(static_headers), |headers, f| headers.and_then(|s| s.with_parsed(f)))?;
+            }
+            let stream_res = if static_headers.is_empty() {
+                stream_res
+            } else {
+                stream_res.with_headers(static_headers)
+            };
+
+            let stream_res = if args.lineage {
+                let hop = lineage::Hop {
+                    sensor_id: sensor_id.clone(),
+                    instance_id: args.instance_id.clone(),
+                    stages: lineage::stage_hash(&lineage_stages(&args))
+                };
+                info!("Stamping records with lineage stage hash {}", hop.stages);
+                stream_res.with_headers(lineage::LineageHeaders::new(&hop))
+            } else {
+                stream_res
+            };
+
+            let source = format!("surikafka/{}", sensor_id);
+            let stream_res = stream_res
+                .with_envelope(writer::CloudEvents::new(args.envelope, &source))
+                .with_key_placement(args.key_placement)
+                .with_throttle(throttle)
+                .with_latency_histogram(registry.histogram("writer.produce_latency"))
+                .with_event_latency_histogram(registry.histogram("writer.event_latency"))
+                .with_size_metrics(metrics::SizeMetrics::new(registry.clone()))
+                .with_in_flight_gauge(registry.queue("writer.in_flight"))
+                .with_max_in_flight(args.max_in_flight);
+
+            let stream_res = if args.project.is_empty() {
+                stream_res
+            } else {
+                let projections = args.project.iter()
+                    .fold(Ok(writer::ProjectionSet::default()), |set, p| set.and_then(|s| s.with_parsed(p)))?;
+                stream_res.with_projections(projections)
+            };
+
+            let stream_res = match source_trace {
+                Some(ref trace) => stream_res.with_trace(trace.clone()),
+                None => stream_res
+            };
+
+            let stream_res = match codecs(&args, &registry)? {
+                Some(codecs) => stream_res.with_codecs(codecs),
+                None => stream_res
+            };
+
+            let stream_res = match compressor(&args, &producer, &registry)? {
+                Some(compressor) => stream_res.with_compressor(compressor),
+                None => stream_res
+            };
+
+            let stream_res = match delivery_error_handler(&args, &producer, &registry)? {
+                Some(handler) => stream_res.with_error_handler(handler).with_retry_gauge(registry.queue("writer.retrying")),
+                None => stream_res
+            };
+
+            let stream_res = match args.verify_interval_secs {
+                Some(secs) => {
+                    let digest = verify::ProducedDigest::new(args.verify_window);
+                    verify::Verifier::spawn(&client_config(&args)?, digest.clone(), std::time::Duration::from_secs(secs), &registry)?;
+                    stream_res.with_digest(digest)
+                }
+                None => stream_res
+            };
+
+            let stream_res = match args.trailer_records {
+                Some(every) => {
+                    let (sender, gauge) = spawn_derived("trailers", &args.control_topic, derive::FieldKey::new("topic"), &producer, &registry);
+                    stream_res.with_trailers(verify::TrailerEmitter::new(every, sender).with_queue_gauge(gauge))
+                }
+                None => stream_res
+            };
+
+            let stream_res = match breaker {
+                Some(breaker) => stream_res.with_circuit_breaker(breaker),
+                None => stream_res
+            };
+
+            let stream_res = match (immediate_filter, immediate) {
+                (Some(filter), Some(immediate)) => stream_res
+                    .with_immediate(filter, immediate, registry.counter("writer.immediate"))
+                    .with_immediate_latency_histograms(
+                        registry.histogram("writer.immediate_produce_latency"),
+                        registry.histogram("writer.immediate_event_latency")
+                    ),
+                _ => stream_res
+            };
+
+            if args.inspect {
+                let filter = match args.filter {
+                    Some(ref filter) => inspect::Filter::parse(filter)?,
+                    None => inspect::Filter::all()
+                };
+                Box::new(stream_res.into_inspections()
+                    .filter(move |inspection| filter.matches(inspection))
+                    .for_each(move |inspection| {
+                        match inspector {
+                            Some(ref mut inspector) => inspector(inspection),
+                            None => inspect::print(&inspection)
+                        }
+                        Ok(())
+                    }))
+            } else {
+                let delivered = registry.counter("writer.delivered");
+                let delivered_bytes = registry.counter("writer.delivered_bytes");
+                let failed = registry.counter("writer.failed");
+
+                Box::new(stream_res.for_each(move |stats| {
+                    delivered.add(stats.alert_count());
+                    delivered_bytes.add(stats.alert_length());
+                    failed.add(stats.failure_count());
+                    Ok(())
+                }))
+            }
+        };
+
+        let socket_path = if custom_source || !files.is_empty() || args.eve_tcp.is_some() { None } else { Some(args.eve_socket_path.clone()) };
+
+        let inspecting = args.inspect;
+        let flusher = producer.clone();
+        let flush_ms = (args.shutdown_grace_secs * 1000).min(std::i32::MAX as u64) as i32;
+        let report_path = args.shutdown_report.clone();
+        let shutdown_registry = registry.clone();
+        let events_per_record = args.array_batch.unwrap_or(1);
+
+        let drain = shutdown.drain(main);
+        Ok(Box::new(drain.then(move |res| {
+            let (res, outcome) = match res {
+                Ok(Drained::Complete) => {
+                    // Derived topics and the journal are sent outside the main stream
+                    flusher.flush(flush_ms);
+                    (Ok( () ), Some(Drained::Complete))
+                }
+                Ok(Drained::TimedOut) => (Err(Error::from("Deliveries outstanding at the end of the shutdown grace period")), Some(Drained::TimedOut)),
+                Ok(Drained::Abandoned) => (Ok( () ), Some(Drained::Abandoned)),
+                Err(e) => (Err(e), None)
+            };
+            let mut positions = vec![];
+            for &(_, ref path, ref position) in files.iter().filter(|_| !inspecting) {
+                if res.is_ok() {
+                    let position = position.load(Ordering::SeqCst) as u64;
+                    checkpoints.save(path, position)?;
+                    if let Some(&(_, ref catch_up)) = catch_ups.iter().find(|&&(ref file, _)| file == path) {
+                        catch_up.save(&checkpoints, path)?;
+                    }
+                    positions.push( (path.clone(), position) );
+                } else {
+                    warn!("Not saving checkpoint of {}, records since the last one will be read again", path);
+                }
+            }
+            let report = outcome.map(|outcome| ShutdownReport::new(outcome, &shutdown_registry, &read_log, events_per_record, positions));
+            if let Some(ref report) = report {
+                if let Some(ref path) = report_path {
+                    persist::write_atomic(path, report.to_value().to_string().as_bytes())?;
+                }
+            }
+            let res = match report {
+                Some(ref report) if report.outcome == Drained::Abandoned => {
+                    Err(Error::from(format!("Abandoned {} records at the shutdown deadline: {}", report.abandoned_total(), report.to_value())))
+                }
+                _ => res
+            };
+            if let (Some(ref clock), false) = (hlc_clock, inspecting) {
+                checkpoints.save(hlc::CHECKPOINT_SOURCE, clock.last_physical_ms())?;
+            }
+            if let Some(ref persisted) = persisted {
+                persisted.save()?;
+            }
+            if let Some(ref path) = socket_path {
+                if let Err(e) = std::fs::remove_file(path) {
+                    debug!("Failed to remove socket {}: {}", path, e);
+                }
+            }
+            if accounting.total() > 0 {
+                warn!(
+                    "Pipeline stopped with {} files and {} sockets still open",
+                    accounting.open(FdKind::File), accounting.open(FdKind::Socket)
+                );
+            }
+            res
+        })))
+    }
+}
+
+/// Runs the events of the file `sample` through the pipeline of `settings` with --inspect,
+/// returning the inspections of those it would send. Only the sample is read, and lag pacing and
+/// sampling, which depend on a live consumer group, are turned off.
+pub fn inspect_sample(settings: Settings, sample: &str) -> Result<Vec<inspect::Inspection>, Error> {
+    let mut settings = settings.for_inspection();
+    settings.eve_file = Some(sample.to_string());
+    settings.interface_file = vec![];
+    settings.source_format = vec![];
+    settings.follow = false;
+    settings.start_position = source::StartPosition::Start;
+    settings.replay_speed = None;
+    settings.lag_group = None;
+    settings.sample_above_lag = None;
+    settings.filter = None;
+
+    let inspections = Arc::new(Mutex::new(vec![]));
+    let collected = inspections.clone();
+    Pipeline::new(settings)
+        .with_inspector(move |inspection| collected.lock().expect("Inspection lock poisoned").push(inspection))
+        .run_blocking()?;
+    let inspections = inspections.lock().expect("Inspection lock poisoned").drain(..).collect();
+    Ok(inspections)
+}
+
+#[cfg(feature = "admin")]
+fn fds_endpoint(accounting: FdAccounting) -> impl Fn(&admin::Request) -> admin::Response {
+    move |_| {
+        let process = fds::process_open_fds().map(|n| n.to_string()).unwrap_or_else(|| "unknown".to_string());
+        admin::Response::text(200, &format!(
+            "files {}\nsockets {}\nprocess {}\n",
+            accounting.open(FdKind::File), accounting.open(FdKind::Socket), process
+        ))
+    }
+}
+
+#[cfg(feature = "admin")]
+fn plugins_endpoint(plugins: extension::PluginSet) -> impl Fn(&admin::Request) -> admin::Response {
+    move |_| {
+        let status: Vec<serde_json::Value> = plugins.status().iter().map(extension::PluginStatus::to_value).collect();
+        admin::Response::ok("application/json", serde_json::to_vec(&status).unwrap_or_default())
+    }
+}
+
+/// The recent events matching the `filter` query parameter, as a JSON array of up to `limit`
+/// (all of them by default), oldest first.
+#[cfg(feature = "admin")]
+fn recent_events_endpoint(recent: recent::RecentEvents) -> impl Fn(&admin::Request) -> admin::Response {
+    move |request| {
+        let filter = match request.query_param("filter").map(inspect::Filter::parse) {
+            Some(Ok(filter)) => filter,
+            Some(Err(e)) => return admin::Response::text(400, &format!("{}\n", e)),
+            None => inspect::Filter::all()
+        };
+        let limit = match request.query_param("limit").map(str::parse::<usize>) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return admin::Response::text(400, "Invalid limit\n"),
+            None => std::usize::MAX
+        };
+        let events = recent.query(|e| filter.matches_event(e), limit);
+        admin::Response::ok("application/json", batch::join(&events))
+    }
+}
+
+#[cfg(feature = "admin")]
+fn guarantees_endpoint(guarantees: Vec<routes::RouteGuarantee>) -> impl Fn(&admin::Request) -> admin::Response {
+    let body: Vec<serde_json::Value> = guarantees.iter().map(routes::RouteGuarantee::to_value).collect();
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    move |_| admin::Response::ok("application/json", body.clone())
+}
+
+#[cfg(feature = "admin")]
+fn metrics_endpoint(registry: metrics::Registry) -> impl Fn(&admin::Request) -> admin::Response {
+    move |_| admin::Response::ok("text/plain; version=0.0.4", registry.prometheus().into_bytes())
+}
+
+#[cfg(feature = "admin")]
+fn admin_routes(accounting: FdAccounting, plugins: Option<extension::PluginSet>, registry: &metrics::Registry) -> admin::AdminServer {
+    let server = admin::AdminServer::default()
+        .route("/metrics", metrics_endpoint(registry.clone()))
+        .route("/debug/fds", fds_endpoint(accounting));
+    match plugins {
+        Some(plugins) => server.route("/plugins", plugins_endpoint(plugins)),
+        None => server
+    }
+}
+
+#[cfg(all(feature = "admin", feature = "profiling"))]
+fn admin_server(accounting: FdAccounting, plugins: Option<extension::PluginSet>, registry: &metrics::Registry) -> admin::AdminServer {
+    admin_routes(accounting, plugins, registry)
+        .route_with("/debug/pprof/profile", admin::Permission::Control, admin::profiling::profile)
+}
+
+#[cfg(all(feature = "admin", not(feature = "profiling")))]
+fn admin_server(accounting: FdAccounting, plugins: Option<extension::PluginSet>, registry: &metrics::Registry) -> admin::AdminServer {
+    admin_routes(accounting, plugins, registry)
+}
+
+#[cfg(all(feature = "admin", feature = "admin-tls"))]
+fn admin_tls(args: &Settings, server: admin::AdminServer) -> Result<admin::AdminServer, Error> {
+    match args.admin_tls_identity {
+        Some(ref path) => {
+            let password = match args.admin_tls_password_file {
+                Some(ref path) => std::fs::read_to_string(path)?.trim().to_string(),
+                None => String::new()
+            };
+            server.with_tls(&std::fs::read(path)?, &password)
+        }
+        None => Ok(server)
+    }
+}
+
+#[cfg(all(feature = "admin", not(feature = "admin-tls")))]
+fn admin_tls(args: &Settings, server: admin::AdminServer) -> Result<admin::AdminServer, Error> {
+    if args.admin_tls_identity.is_some() {
+        bail!("--admin-tls-identity requires building with the admin-tls feature");
+    }
+    Ok(server)
+}
+
+#[cfg(feature = "admin")]
+fn serve_admin(args: &Settings, addr: &std::net::SocketAddr, accounting: FdAccounting, plugins: Option<extension::PluginSet>, recent: Option<recent::RecentEvents>, registry: &metrics::Registry, cancellation: &CancellationToken) -> Result<(), Error> {
+    let mut server = admin_tls(args, admin_server(accounting, plugins, registry))?
+        .route("/routes/guarantees", guarantees_endpoint(topic_map(args)?.guarantees()));
+    if let Some(recent) = recent {
+        // Events may carry anything the sensor sees, so they're not for read tokens
+        server = server.route_with("/events/recent", admin::Permission::Control, recent_events_endpoint(recent));
+    }
+    if let Some(ref path) = args.admin_tokens_file {
+        let tokens = admin::parse_tokens(&std::fs::read_to_string(path)?)?;
+        if tokens.is_empty() {
+            bail!("Admin tokens file {} has no tokens", path);
+        }
+        for (permission, token) in tokens {
+            server = server.with_token(&token, permission);
+        }
+    }
+    let server = server.serve(addr)?
+        .select(cancellation.cancelled())
+        .map(|_| ())
+        .map_err(|_| ());
+    tokio::spawn(server);
+    Ok( () )
+}
+
+#[cfg(not(feature = "admin"))]
+fn serve_admin(_args: &Settings, _addr: &std::net::SocketAddr, _accounting: FdAccounting, _plugins: Option<extension::PluginSet>, _recent: Option<recent::RecentEvents>, _registry: &metrics::Registry, _cancellation: &CancellationToken) -> Result<(), Error> {
+    bail!("--admin-addr requires building with the admin feature")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_settings() {
+        let settings = Settings::default();
+
+        assert_eq!(settings.kafka_servers, "kafka:9092");
+        assert_eq!(settings.topic, "eve-alerts");
+        assert_eq!(settings.eve_file, None);
+        assert!(settings.rules.is_empty());
+    }
+
+    #[test]
+    fn parses_settings() {
+        let settings = Settings::from_iter(vec!["surikafka", "-k", "broker:9092", "--eve-file", "eve.json"]);
+
+        assert_eq!(settings.kafka_servers, "broker:9092");
+        assert_eq!(settings.eve_file, Some("eve.json".to_string()));
+    }
+
+    #[test]
+    fn lists_self_test_topics() {
+        let settings = Settings::from_iter(vec![
+            "surikafka", "--event-topic", "dns=eve-dns", "--event-topic", "alert=eve-alerts",
+            "--priority-topic", "eve-priority", "--self-test", "round-trip"
+        ]);
+
+        assert_eq!(settings.self_test, Some(canary::SelfTestMode::RoundTrip));
+        assert_eq!(self_test_topics(&settings), vec!["eve-alerts", "eve-dns", "eve-priority"]);
+    }
+
+    #[test]
+    fn parses_dual_writes() {
+        let settings = Settings::from_iter(vec![
+            "surikafka", "--dual-write", "eve-alerts=eve-alerts-avro", "--dual-write-until", "2024-06-01T00:00:00Z"
+        ]);
+
+        let (mirrors, until) = dual_writes(&settings).expect("Failed to parse dual writes");
+
+        assert_eq!(mirrors, vec![("eve-alerts".to_string(), "eve-alerts-avro".to_string())]);
+        assert_eq!(until.map(|u| u.to_rfc3339()), Some("2024-06-01T00:00:00+00:00".to_string()));
+        assert_eq!(self_test_topics(&settings), vec!["eve-alerts", "eve-alerts-avro"]);
+        assert!(dual_writes(&Settings::from_iter(vec!["surikafka", "--dual-write", "eve-alerts=eve-alerts"])).is_err());
+    }
+
+    #[test]
+    fn lists_strict_violations() {
+        let flags = vec!["surikafka", "--strict", "--eve-file", "eve.json", "--start-position", "resume", "--acks", "all"];
+        let settings = Settings::from_iter(flags.clone());
+        assert!(strict_violations(&settings, &topic_map(&settings).expect("Failed to map topics")).is_empty());
+
+        let settings = Settings::from_iter(flags.iter().cloned().chain(vec!["--shed-thresholds", "flow=1000", "--acks", "1"]));
+        let violations = strict_violations(&settings, &topic_map(&settings).expect("Failed to map topics"));
+        assert_eq!(violations.len(), 2);
+        assert!(violations[0].starts_with("--shed-thresholds"));
+        assert!(violations[1].contains("at most once"));
+
+        for &(flag, value) in [("--config-topic", "surikafka.config"), ("--suppression-topic", "surikafka.suppressions")].iter() {
+            let settings = Settings::from_iter(flags.iter().cloned().chain(vec![flag, value]));
+            let violations = strict_violations(&settings, &topic_map(&settings).expect("Failed to map topics"));
+            assert_eq!(violations.len(), 1, "{}", flag);
+            assert!(violations[0].starts_with(flag));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_flags() {
+        assert!(Settings::from_flags(&["--topic".to_string(), "alerts".to_string()]).is_ok());
+        assert!(Settings::from_flags(&["--no-such-flag".to_string()]).is_err());
+    }
+}
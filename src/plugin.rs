@@ -0,0 +1,199 @@
+//! Suricata (7.x) eve output plugin, so suricata.yaml can ship directly to Kafka:
+//!
+//! ```yaml
+//! plugins:
+//!   - /usr/lib/suricata/libsurikafka.so
+//! outputs:
+//!   - eve-log:
+//!       enabled: yes
+//!       filetype: surikafka
+//!       surikafka:
+//!         kafka: broker:9092
+//!         topic: eve
+//!         flags: --key-by vlan,interface
+//! ```
+use super::{
+    blocking::{
+        ShipperHandle,
+        SyncShipper
+    },
+    errors::Error,
+    guard::guarded,
+    pipeline::Settings
+};
+use std::{
+    self,
+    ffi::CStr,
+    os::raw::{
+        c_char,
+        c_int,
+        c_void
+    },
+    ptr,
+    slice,
+    time::Duration
+};
+
+const FLUSH_TIMEOUT_SECS: u64 = 10;
+
+/// Settings read from the `surikafka` node of the eve-log output, mapped to their flags.
+const CONF_KEYS: &'static [(&'static str, &'static str)] = &[
+    ("kafka", "--kafka"),
+    ("topic", "--topic"),
+    ("sensor-id", "--sensor-id"),
+    ("key-by", "--key-by")
+];
+
+pub enum ConfNode {}
+
+#[repr(C)]
+pub struct SCPlugin {
+    name: *const c_char,
+    license: *const c_char,
+    author: *const c_char,
+    init: extern "C" fn()
+}
+
+#[repr(C)]
+struct TailqEntry {
+    next: *mut SCEveFileType,
+    prev: *mut *mut SCEveFileType
+}
+
+#[repr(C)]
+pub struct SCEveFileType {
+    name: *const c_char,
+    init: extern "C" fn(*const ConfNode, bool, *mut *mut c_void) -> c_int,
+    thread_init: extern "C" fn(*mut c_void, c_int, *mut *mut c_void) -> c_int,
+    write: extern "C" fn(*const c_char, c_int, *mut c_void, *mut c_void) -> c_int,
+    thread_deinit: extern "C" fn(*mut c_void, *mut c_void),
+    deinit: extern "C" fn(*mut c_void),
+    entries: TailqEntry
+}
+
+extern "C" {
+    fn SCRegisterEveFileType(file_type: *mut SCEveFileType) -> bool;
+    fn ConfNodeLookupChild(node: *const ConfNode, name: *const c_char) -> *const ConfNode;
+    fn ConfGetChildValue(base: *const ConfNode, name: *const c_char, vptr: *mut *const c_char) -> c_int;
+}
+
+unsafe fn conf_value(node: *const ConfNode, name: &str) -> Option<String> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut value: *const c_char = ptr::null();
+    if ConfGetChildValue(node, name.as_ptr(), &mut value) == 1 && !value.is_null() {
+        Some(CStr::from_ptr(value).to_string_lossy().to_string())
+    } else {
+        None
+    }
+}
+
+unsafe fn conf_flags(conf: *const ConfNode) -> Vec<String> {
+    let mut flags = vec![];
+    if conf.is_null() {
+        return flags
+    }
+    let node = ConfNodeLookupChild(conf, b"surikafka\0".as_ptr() as *const c_char);
+    if node.is_null() {
+        return flags
+    }
+    for &(key, flag) in CONF_KEYS {
+        if let Some(value) = conf_value(node, key) {
+            flags.push(flag.to_string());
+            flags.push(value);
+        }
+    }
+    if let Some(extra) = conf_value(node, "flags") {
+        flags.extend(extra.split_whitespace().map(String::from));
+    }
+    flags
+}
+
+fn start(flags: &[String]) -> Result<ShipperHandle, Error> {
+    let settings = Settings::from_flags(flags)?;
+    SyncShipper::new(settings).start()
+}
+
+extern "C" fn eve_init(conf: *const ConfNode, _threaded: bool, init_data: *mut *mut c_void) -> c_int {
+    let handle = guarded(|| {
+        let flags = unsafe { conf_flags(conf) };
+        start(&flags)
+    });
+    match handle {
+        Ok(handle) => {
+            unsafe { *init_data = Box::into_raw(Box::new(handle)) as *mut c_void; }
+            0
+        }
+        Err(e) => {
+            error!("Failed to start surikafka output: {}", e);
+            -1
+        }
+    }
+}
+
+extern "C" fn eve_thread_init(_init_data: *mut c_void, _thread_id: c_int, thread_data: *mut *mut c_void) -> c_int {
+    unsafe { *thread_data = ptr::null_mut(); }
+    0
+}
+
+extern "C" fn eve_write(buffer: *const c_char, len: c_int, init_data: *mut c_void, _thread_data: *mut c_void) -> c_int {
+    if init_data.is_null() || buffer.is_null() || len < 0 {
+        return -1
+    }
+    let handle = unsafe { &*(init_data as *const ShipperHandle) };
+    let record = unsafe { slice::from_raw_parts(buffer as *const u8, len as usize) };
+    match guarded(|| handle.send(record)) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Failed to queue record: {}", e);
+            -1
+        }
+    }
+}
+
+extern "C" fn eve_thread_deinit(_init_data: *mut c_void, _thread_data: *mut c_void) {}
+
+extern "C" fn eve_deinit(init_data: *mut c_void) {
+    if init_data.is_null() {
+        return
+    }
+    let handle = unsafe { Box::from_raw(init_data as *mut ShipperHandle) };
+    let res = guarded(|| {
+        if !handle.flush(Duration::from_secs(FLUSH_TIMEOUT_SECS)) {
+            warn!("{} records not delivered before shutdown", handle.pending());
+        }
+        (*handle).close()
+    });
+    if let Err(e) = res {
+        error!("surikafka output failed: {}", e);
+    }
+}
+
+extern "C" fn plugin_init() {
+    let file_type = Box::new(SCEveFileType {
+        name: b"surikafka\0".as_ptr() as *const c_char,
+        init: eve_init,
+        thread_init: eve_thread_init,
+        write: eve_write,
+        thread_deinit: eve_thread_deinit,
+        deinit: eve_deinit,
+        entries: TailqEntry {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut()
+        }
+    });
+    // Suricata keeps the registration for the life of the process.
+    if !unsafe { SCRegisterEveFileType(Box::into_raw(file_type)) } {
+        error!("Failed to register surikafka eve filetype");
+    }
+}
+
+/// Entry point Suricata resolves when loading the plugin.
+#[no_mangle]
+pub extern "C" fn SCPluginRegister() -> *mut SCPlugin {
+    Box::into_raw(Box::new(SCPlugin {
+        name: b"surikafka\0".as_ptr() as *const c_char,
+        license: b"MIT\0".as_ptr() as *const c_char,
+        author: concat!(env!("CARGO_PKG_AUTHORS"), "\0").as_ptr() as *const c_char,
+        init: plugin_init
+    }))
+}
@@ -0,0 +1,66 @@
+use super::{
+    blocking::{
+        ShipperHandle,
+        SyncShipper
+    },
+    errors::Error,
+    pipeline::Settings,
+    pyo3::{
+        exceptions::RuntimeError,
+        prelude::*,
+        types::PyBytes
+    }
+};
+
+fn py_err(e: Error) -> PyErr {
+    RuntimeError::py_err(e.to_string())
+}
+
+/// `surikafka.Shipper(flags)`: a pipeline running on a background thread, fed with `send`.
+#[pyclass]
+struct Shipper {
+    handle: Option<ShipperHandle>,
+    token: PyToken
+}
+
+#[pymethods]
+impl Shipper {
+    #[new]
+    fn __new__(obj: &PyRawObject, flags: Vec<String>) -> PyResult<()> {
+        let settings = Settings::from_flags(&flags).map_err(py_err)?;
+        let handle = SyncShipper::new(settings).start().map_err(py_err)?;
+        obj.init(|token| Shipper {
+            handle: Some(handle),
+            token: token
+        })
+    }
+
+    fn send(&self, record: &PyBytes) -> PyResult<()> {
+        match self.handle {
+            Some(ref handle) => handle.send(record.as_bytes()).map_err(py_err),
+            None => Err(RuntimeError::py_err("Shipper closed"))
+        }
+    }
+
+    /// Waits for queued records to be delivered; the GIL is released meanwhile.
+    fn close(&mut self, py: Python) -> PyResult<()> {
+        match self.handle.take() {
+            Some(handle) => py.allow_threads(move || handle.close()).map_err(py_err),
+            None => Ok( () )
+        }
+    }
+}
+
+#[pymodinit]
+fn surikafka(_py: Python, m: &PyModule) -> PyResult<()> {
+    /// Ships the configured file or socket until it ends, like the surikafka binary.
+    #[pyfn(m, "run")]
+    fn run(py: Python, flags: Vec<String>) -> PyResult<()> {
+        let settings = Settings::from_flags(&flags).map_err(py_err)?;
+        py.allow_threads(move || SyncShipper::new(settings).run()).map_err(py_err)
+    }
+
+    m.add_class::<Shipper>()?;
+
+    Ok( () )
+}
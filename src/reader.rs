@@ -1,5 +1,6 @@
 use super::{
     bytes,
+    cancel::CancellationToken,
     errors::Error,
     futures::{
         Async,
@@ -7,15 +8,46 @@ use super::{
         Stream
     },
     json,
+    metrics::{
+        Counter,
+        QueueGauge
+    },
+    shutdown::ReadLog,
+    trace::SourceTrace,
     //nom,
     //json::JsonValue,
     tokio::io::AsyncRead
 };
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    }
+};
+
+/// Default upper bound on a single event; anything longer is treated as corrupt.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 4 * 1024 * 1024;
+const READ_RESERVE: usize = 64 * 1024;
 
 pub struct EveReader<T: AsyncRead> {
     inner: T,
     buffer: bytes::BytesMut,
-    pending_alerts: Vec<Vec<u8>>
+    pending_alerts: VecDeque<Vec<u8>>,
+    max_line_length: usize,
+    utf8_mode: json::Utf8Mode,
+    skipping: bool,
+    skipped: Counter,
+    strict: bool,
+    position: Option<Arc<AtomicUsize>>,
+    pending_gauge: Option<QueueGauge>,
+    trace: Option<(SourceTrace, Arc<String>)>,
+    cancellation: Option<CancellationToken>,
+    read_log: Option<(ReadLog, Arc<String>)>,
+    lines: usize
 }
 
 impl<T: AsyncRead> EveReader<T> {
@@ -23,17 +55,161 @@ impl<T: AsyncRead> EveReader<T> {
         EveReader {
             inner: inner,
             buffer: bytes::BytesMut::with_capacity(10_000_000),
-            pending_alerts: vec![]
+            pending_alerts: VecDeque::new(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            utf8_mode: json::Utf8Mode::default(),
+            skipping: false,
+            skipped: Counter::new("reader.skipped_lines"),
+            strict: false,
+            position: None,
+            pending_gauge: None,
+            trace: None,
+            cancellation: None,
+            read_log: None,
+            lines: 0
+        }
+    }
+
+    /// Incomplete events longer than `max_line_length` are discarded up to the next newline.
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn with_utf8_mode(mut self, utf8_mode: json::Utf8Mode) -> Self {
+        self.utf8_mode = utf8_mode;
+        self
+    }
+
+    /// Fail on unparseable events and events longer than `max_line_length` rather than skipping
+    /// them, for --strict.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Advances `position` by the number of bytes consumed from `inner`, for checkpointing.
+    pub fn with_position(mut self, position: Arc<AtomicUsize>) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Counts the events and lines skipped for being longer than `max_line_length`.
+    pub fn with_skip_counter(mut self, counter: Counter) -> Self {
+        self.skipped = counter;
+        self
+    }
+
+    /// Tracks the number of parsed events waiting to be polled.
+    pub fn with_pending_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.pending_gauge = Some(gauge);
+        self
+    }
+
+    /// Records the line each event was read from in `trace`, counting lines from where reading
+    /// started. Lines holding more than one event, or blank and unparseable lines within a single
+    /// read, can shift the count for the events after them until the next read.
+    pub fn with_trace(mut self, trace: SourceTrace, path: &str) -> Self {
+        self.trace = Some( (trace, Arc::new(path.to_string())) );
+        self
+    }
+
+    /// Stops reading once `token` is cancelled, ending after the events already parsed, so the
+    /// position covers exactly the events passed on.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Records the byte range of `path` each read's events were parsed from in `log`, to report
+    /// where abandoned records came from. Ranges are positions, so requires `with_position`.
+    pub fn with_read_log(mut self, log: ReadLog, path: &str) -> Self {
+        self.read_log = Some( (log, Arc::new(path.to_string())) );
+        self
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        let consumed = self.buffer.split_to(bytes);
+        if self.trace.is_some() {
+            self.lines += consumed.iter().filter(|b| **b == b'\n').count();
+        }
+        if let Some(ref position) = self.position {
+            position.fetch_add(bytes, Ordering::SeqCst);
+        }
+    }
+
+    pub fn skipped_lines(&self) -> usize { self.skipped.value() }
+
+    /// Drops buffered data up to and including the next newline, returning whether one was found.
+    fn skip_to_newline(&mut self) -> bool {
+        match self.buffer.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                self.consume(pos + 1);
+                self.skipping = false;
+                true
+            }
+            None => {
+                let len = self.buffer.len();
+                self.consume(len);
+                false
+            }
         }
     }
 
     pub fn collect_alerts(&mut self) -> Result<(), Error> {
-        let (rem, mut alerts) = json::JsonParser::parse(self.buffer.as_ref())?;
+        loop {
+            if self.skipping && !self.skip_to_newline() {
+                return Ok( () )
+            }
+
+            let (consumed, mut alerts) = {
+                let (rem, alerts) = if self.strict {
+                    json::JsonParser::parse_strict(self.buffer.as_ref(), self.utf8_mode)?
+                } else {
+                    json::JsonParser::parse_with(self.buffer.as_ref(), self.utf8_mode)?
+                };
+                (self.buffer.len() - rem.len(), alerts)
+            };
+
+            if let Some( (ref trace, ref path) ) = self.trace {
+                for (i, alert) in alerts.iter().enumerate() {
+                    trace.record(alert, path, self.lines + i + 1);
+                }
+            }
+
+            let max_line_length = self.max_line_length;
+            let parsed = alerts.len();
+            alerts.retain(|a| a.len() <= max_line_length);
+            if alerts.len() < parsed {
+                if self.strict {
+                    bail!("{} events longer than {} bytes", parsed - alerts.len(), max_line_length);
+                }
+                warn!("Skipping {} events longer than {} bytes", parsed - alerts.len(), max_line_length);
+                self.skipped.add(parsed - alerts.len());
+            }
 
-        self.pending_alerts.append(&mut alerts);
-        self.buffer.split_at(self.buffer.len() - rem.len());
+            if let Some(ref gauge) = self.pending_gauge {
+                gauge.add(alerts.len());
+            }
+            let start = self.position.as_ref().map(|p| p.load(Ordering::SeqCst) as u64);
+            let events = alerts.len();
+            self.pending_alerts.extend(alerts);
+            self.consume(consumed);
+            if let (Some(&(ref log, ref path)), Some(start)) = (self.read_log.as_ref(), start) {
+                log.record(path, start, start + consumed as u64, events);
+            }
+
+            if self.buffer.len() <= self.max_line_length {
+                return Ok( () )
+            }
 
-        Ok( () )
+            if self.strict {
+                bail!("Line longer than {} bytes", self.max_line_length);
+            }
+            warn!("Skipping line longer than {} bytes", self.max_line_length);
+            self.skipped.incr();
+            self.skipping = true;
+        }
     }
 }
 
@@ -42,24 +218,33 @@ impl<T: AsyncRead> Stream for EveReader<T> {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
-        if let Some(v) = self.pending_alerts.pop() {
-            Ok(Async::Ready(Some(v)))
-        } else {
+        loop {
+            if let Some(v) = self.pending_alerts.pop_front() {
+                if let Some(ref gauge) = self.pending_gauge {
+                    gauge.sub(1);
+                }
+                return Ok(Async::Ready(Some(v)))
+            }
+
+            if let Some(ref token) = self.cancellation {
+                if token.poll_cancelled().is_ready() {
+                    return Ok(Async::Ready(None))
+                }
+            }
+
+            if self.buffer.capacity() - self.buffer.len() < READ_RESERVE {
+                self.buffer.reserve(READ_RESERVE);
+            }
+
             let bytes_read = try_ready!(self.inner.read_buf(&mut self.buffer));
 
             if bytes_read == 0 {
-                Ok(Async::Ready(None))
-            } else {
-                debug!("Checking buffer after reading {} bytes", bytes_read);
+                return Ok(Async::Ready(None))
+            }
 
-                self.collect_alerts()?;
+            debug!("Checking buffer after reading {} bytes", bytes_read);
 
-                if let Some(v) = self.pending_alerts.pop() {
-                    Ok(Async::Ready(Some(v)))
-                } else {
-                    Ok(Async::Ready(None))
-                }
-            }
+            self.collect_alerts()?;
         }
     }
 }
@@ -179,12 +364,86 @@ mod tests {
         });
 
         assert_eq!(strings, vec![
-            "{\"another\":\"part being sent\"}".to_string().into_bytes(),
-            "{\"key1\":\"key with a paren set {}\",\"key2\":12345}".to_string().into_bytes()
+            "{\"key1\":\"key with a paren set {}\",\"key2\":12345}".to_string().into_bytes(),
+            "{\"another\":\"part being sent\"}".to_string().into_bytes()
         ]);
 
         send_complete.join().expect("Failed to send");
     }
+    #[test]
+    fn skips_long_lines() {
+        let _ = env_logger::try_init();
+
+        let input = format!(
+            "{{\"key\":\"{}\"}}\n{{\"key\":\"short\"}}\n",
+            std::iter::repeat("x").take(100).collect::<String>()
+        );
+
+        let skipped = Counter::new("reader.skipped_lines");
+        let mut reader = EveReader::new(std::io::Cursor::new(input.into_bytes()))
+            .with_max_line_length(50)
+            .with_skip_counter(skipped.clone());
+
+        let first = reader.poll().expect("Failed to read");
+
+        assert_eq!(first, Async::Ready(Some("{\"key\":\"short\"}".to_string().into_bytes())));
+        assert_eq!(reader.skipped_lines(), 1);
+        assert_eq!(skipped.value(), 1);
+    }
+
+    #[test]
+    fn fails_on_long_lines_when_strict() {
+        let input = format!(
+            "{{\"key\":\"{}\"}}\n{{\"key\":\"short\"}}\n",
+            std::iter::repeat("x").take(100).collect::<String>()
+        );
+
+        let mut reader = EveReader::new(std::io::Cursor::new(input.into_bytes()))
+            .with_max_line_length(50)
+            .with_strict(true);
+
+        assert!(reader.poll().is_err());
+    }
+
+    #[test]
+    fn passes_on_parsed_events_after_cancel() {
+        let token = CancellationToken::new();
+        let position = Arc::new(AtomicUsize::new(0));
+        let input = b"{\"key\":1}\n{\"key\":2}\n{\"key\":3}\n".to_vec();
+
+        let mut reader = EveReader::new(std::io::Cursor::new(input))
+            .with_position(position.clone())
+            .with_cancellation(token.clone());
+        let first = reader.poll().expect("Failed to read");
+        token.cancel();
+        let mut rest = vec![];
+        while let Async::Ready(Some(event)) = reader.poll().expect("Failed to read") {
+            rest.push(event);
+        }
+
+        assert!(first.is_ready());
+        assert_eq!(rest.len(), 2);
+        assert_eq!(position.load(Ordering::SeqCst), 30);
+    }
+
+    #[test]
+    fn traces_source_lines() {
+        let input = format!(
+            "{{\"key\":\"{}\"}}\n{{\"key\":\"second\"}}\n{{\"key\":\"third\"}}\n",
+            std::iter::repeat("x").take(100).collect::<String>()
+        );
+        let trace = SourceTrace::new(10);
+
+        let mut reader = EveReader::new(std::io::Cursor::new(input.into_bytes()))
+            .with_max_line_length(50)
+            .with_trace(trace.clone(), "eve.json");
+        while let Async::Ready(Some(_)) = reader.poll().expect("Failed to read") {}
+
+        let source = trace.lookup(b"{\"key\":\"third\"}").expect("Line not traced");
+        assert_eq!(source.to_string(), "eve.json:3");
+        assert_eq!(trace.lookup(b"{\"key\":\"second\"}").map(|s| s.line), Some(2));
+    }
+
     #[test]
     fn reads_single_eve_event() {
         let _ = env_logger::try_init();
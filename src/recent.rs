@@ -0,0 +1,69 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        Mutex
+    }
+};
+
+pub const DEFAULT_RECENT_EVENTS: usize = 1000;
+
+/// The last `capacity` raw events read, for operators to see what a sensor is emitting without
+/// logging in to tail its files. Shared between the reader, which records, and the admin server.
+#[derive(Clone)]
+pub struct RecentEvents {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<Vec<u8>>>>
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> RecentEvents {
+        RecentEvents {
+            capacity: capacity.max(1),
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity.max(1))))
+        }
+    }
+
+    pub fn record(&self, event: &[u8]) {
+        let mut events = self.events.lock().expect("Recent events lock poisoned");
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event.to_vec());
+    }
+
+    /// Up to `limit` of the most recent events `filter` accepts, oldest first.
+    pub fn query<F>(&self, filter: F, limit: usize) -> Vec<Vec<u8>>
+        where F: Fn(&[u8]) -> bool
+    {
+        let events = self.events.lock().expect("Recent events lock poisoned");
+        let mut matching: Vec<Vec<u8>> = events.iter().rev()
+            .filter(|e| filter(e))
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().expect("Recent events lock poisoned").len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_events() {
+        let recent = RecentEvents::new(3);
+        for i in 0..5 {
+            recent.record(format!("{{\"n\":{}}}", i).as_bytes());
+        }
+
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent.query(|_| true, 10), vec![b"{\"n\":2}".to_vec(), b"{\"n\":3}".to_vec(), b"{\"n\":4}".to_vec()]);
+        assert_eq!(recent.query(|e| e != b"{\"n\":4}", 1), vec![b"{\"n\":3}".to_vec()]);
+    }
+}
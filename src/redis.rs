@@ -0,0 +1,180 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    eve,
+    sink::{
+        RedisMode,
+        Sink
+    },
+    topics
+};
+use std::{
+    self,
+    io::{
+        BufRead,
+        BufReader,
+        Read,
+        Write
+    },
+    net::TcpStream,
+    time::Duration
+};
+
+const IO_TIMEOUT_SECS: u64 = 5;
+
+/// Encodes a command as a RESP array of bulk strings.
+pub fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+/// Reads one reply, returning an error for error replies. Only the simple, integer, and bulk
+/// string replies of `XADD` and `PUBLISH` are understood.
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<(), Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        bail!("Redis closed the connection");
+    }
+    let line = line.trim_right();
+    match line.chars().next() {
+        Some('+') | Some(':') => Ok( () ),
+        Some('-') => bail!("Redis error: {}", &line[1..]),
+        Some('$') => {
+            let len = line[1..].parse::<i64>().map_err(|_| Error::from(format!("Invalid Redis reply: {}", line)))?;
+            if len >= 0 {
+                let mut data = vec![0; len as usize + 2];
+                reader.read_exact(&mut data)?;
+            }
+            Ok( () )
+        }
+        _ => bail!("Unexpected Redis reply: {}", line)
+    }
+}
+
+/// Pushes events to Redis with `XADD` or `PUBLISH`, to a key templated by `{event_type}`.
+/// Commands of a batch are pipelined; the connection is reopened after any error.
+pub struct RedisSink {
+    addr: String,
+    mode: RedisMode,
+    key_template: String,
+    max_len: Option<usize>,
+    connection: Option<BufReader<TcpStream>>
+}
+
+impl RedisSink {
+    pub fn new(addr: &str, mode: RedisMode, key_template: &str) -> RedisSink {
+        RedisSink {
+            addr: addr.to_string(),
+            mode: mode,
+            key_template: key_template.to_string(),
+            max_len: None,
+            connection: None
+        }
+    }
+
+    /// Trims streams to about `max_len` entries (`MAXLEN ~`), so the cache stays bounded.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    fn key(&self, msg: &[u8]) -> String {
+        topics::expand_template(&self.key_template, eve::event_type(msg).unwrap_or("unknown"))
+    }
+
+    pub fn command(&self, msg: &[u8]) -> Vec<u8> {
+        let key = self.key(msg);
+        match self.mode {
+            RedisMode::Publish => encode_command(&[&b"PUBLISH"[..], key.as_bytes(), msg]),
+            RedisMode::Stream => match self.max_len {
+                Some(max_len) => {
+                    let max_len = max_len.to_string();
+                    encode_command(&[&b"XADD"[..], key.as_bytes(), b"MAXLEN", b"~", max_len.as_bytes(), b"*", b"event", msg])
+                }
+                None => encode_command(&[&b"XADD"[..], key.as_bytes(), b"*", b"event", msg])
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<&mut BufReader<TcpStream>, Error> {
+        if self.connection.is_none() {
+            let stream = TcpStream::connect(self.addr.as_str())?;
+            stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+            stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+            debug!("Connected to Redis at {}", self.addr);
+            self.connection = Some(BufReader::new(stream));
+        }
+        Ok(self.connection.as_mut().expect("Connection was just opened"))
+    }
+
+    fn pipeline(&mut self, commands: &[u8], count: usize) -> Result<(), Error> {
+        let connection = self.connect()?;
+        connection.get_mut().write_all(commands)?;
+        let mut first_error = None;
+        for _ in 0..count {
+            if let Err(e) = read_reply(connection) {
+                if let ErrorKind::Io(_) = *e.kind() {
+                    return Err(e)
+                }
+                first_error = first_error.or(Some(e));
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok( () )
+        }
+    }
+}
+
+impl Sink for RedisSink {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+        let commands: Vec<u8> = batch.iter().flat_map(|msg| self.command(msg)).collect();
+        let res = self.pipeline(&commands, batch.len());
+        if res.is_err() {
+            self.connection = None;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+        fn encodes_commands() {
+        let sink = RedisSink::new("127.0.0.1:6379", RedisMode::Stream, "surikafka:{event_type}").with_max_len(1000);
+        let publish = RedisSink::new("127.0.0.1:6379", RedisMode::Publish, "alerts");
+
+        assert_eq!(
+            sink.command(br#"{"event_type":"alert"}"#),
+            b"*8\r\n$4\r\nXADD\r\n$15\r\nsurikafka:alert\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n1000\r\n$1\r\n*\r\n$5\r\nevent\r\n$22\r\n{\"event_type\":\"alert\"}\r\n".to_vec()
+        );
+        assert_eq!(
+            publish.command(b"{}"),
+            b"*3\r\n$7\r\nPUBLISH\r\n$6\r\nalerts\r\n$2\r\n{}\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn reads_replies() {
+        let mut replies = std::io::Cursor::new(b"$15\r\n1526919030474-0\r\n:2\r\n-ERR wrong type\r\n$-1\r\n".to_vec());
+
+        assert!(read_reply(&mut replies).is_ok());
+        assert!(read_reply(&mut replies).is_ok());
+        assert!(read_reply(&mut replies).is_err());
+        assert!(read_reply(&mut replies).is_ok());
+        assert!(read_reply(&mut replies).is_err());
+    }
+}
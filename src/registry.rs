@@ -0,0 +1,153 @@
+use super::{
+    errors::Error,
+    key,
+    serde_json
+};
+use std;
+
+/// Record published to the sensor registry topic when the shipper starts. The topic is expected
+/// to be compacted and keyed by sensor id, so it always holds the latest record per sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorRegistration {
+    pub sensor_id: String,
+    pub hostname: String,
+    pub interfaces: Vec<String>,
+    pub suricata_version: Option<String>,
+    pub config_hash: String,
+    pub shipper_version: String,
+    pub started_at: u64
+}
+
+impl SensorRegistration {
+    pub fn detect(sensor_id: Option<String>, config: &str) -> Result<SensorRegistration, Error> {
+        let hostname = hostname();
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        Ok(SensorRegistration {
+            sensor_id: sensor_id.unwrap_or_else(|| hostname.clone()),
+            hostname: hostname,
+            interfaces: interfaces(),
+            suricata_version: suricata_version(),
+            config_hash: config_hash(config),
+            shipper_version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: started_at
+        })
+    }
+
+    pub fn key(&self) -> &str { self.sensor_id.as_str() }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let record = json!({
+            "sensor_id": self.sensor_id,
+            "hostname": self.hostname,
+            "interfaces": self.interfaces,
+            "suricata_version": self.suricata_version,
+            "config_hash": self.config_hash,
+            "shipper_version": self.shipper_version,
+            "started_at": self.started_at
+        });
+        serde_json::to_vec(&record).expect("Registration is always serializable")
+    }
+}
+
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Substitutes `{hostname}`, `{sensor_id}`, and `{instance_id}` in `template`.
+pub fn expand_template(template: &str, sensor_id: &str, instance_id: &str) -> String {
+    let mut expanded = template.replace("{sensor_id}", sensor_id).replace("{instance_id}", instance_id);
+    if expanded.contains("{hostname}") {
+        expanded = expanded.replace("{hostname}", &hostname());
+    }
+    expanded
+}
+
+fn interfaces() -> Vec<String> {
+    let mut interfaces: Vec<String> = std::fs::read_dir("/sys/class/net")
+        .map(|entries| {
+            entries.filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|name| name != "lo")
+                .collect()
+        })
+        .unwrap_or_else(|_| vec![]);
+    interfaces.sort();
+    interfaces
+}
+
+fn suricata_version() -> Option<String> {
+    std::process::Command::new("suricata")
+        .arg("-V")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|stdout| parse_suricata_version(&stdout))
+}
+
+fn parse_suricata_version(output: &str) -> Option<String> {
+    output.split("version")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .map(|v| v.to_string())
+}
+
+pub fn config_hash(config: &str) -> String {
+    format!("{:016x}", key::fnv1a(config.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn parses_suricata_version() {
+        assert_eq!(
+            parse_suricata_version("This is Suricata version 4.0.4 RELEASE\n"),
+            Some("4.0.4".to_string())
+        );
+        assert_eq!(parse_suricata_version("command not found"), None);
+    }
+
+    #[test]
+    fn expands_templates() {
+        assert_eq!(expand_template("surikafka-{sensor_id}-{instance_id}", "s1", "alerts"), "surikafka-s1-alerts");
+        assert_eq!(expand_template("{hostname}", "s1", "alerts"), hostname());
+        assert_eq!(expand_template("fixed", "s1", "alerts"), "fixed");
+    }
+
+    #[test]
+    fn config_hash_is_stable() {
+        assert_eq!(config_hash("a"), "af63dc4c8601ec8c");
+        assert_ne!(config_hash("topic=a"), config_hash("topic=b"));
+    }
+
+    #[test]
+    fn serializes_registration() {
+        let registration = SensorRegistration {
+            sensor_id: "sensor-1".to_string(),
+            hostname: "host".to_string(),
+            interfaces: vec!["eth0".to_string()],
+            suricata_version: None,
+            config_hash: config_hash(""),
+            shipper_version: "0.1.0".to_string(),
+            started_at: 1
+        };
+
+        let value: Value = serde_json::from_slice(&registration.to_bytes()).expect("Failed to parse");
+
+        assert_eq!(registration.key(), "sensor-1");
+        assert_eq!(value["hostname"], "host");
+        assert_eq!(value["interfaces"][0], "eth0");
+        assert_eq!(value["suricata_version"], Value::Null);
+    }
+}
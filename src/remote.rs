@@ -0,0 +1,380 @@
+use super::{
+    errors::Error,
+    eve,
+    futures::{
+        Async,
+        Poll,
+        Stream,
+        sync::mpsc::UnboundedSender
+    },
+    hmac::{
+        Hmac,
+        Mac
+    },
+    journal::{
+        OpsJournal,
+        OpsKind
+    },
+    metrics::QueueGauge,
+    rdkafka::{
+        ClientConfig,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        }
+    },
+    serde_json::{
+        self,
+        Value
+    },
+    sha2::Sha256
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        HashSet
+    },
+    sync::{
+        Arc,
+        RwLock
+    }
+};
+
+const METADATA_TIMEOUT_MS: i32 = 5000;
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+/// Settings a central manager may change on a running shipper.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    pub version: u64,
+    /// Event types not shipped at all
+    pub drop_event_types: HashSet<String>,
+    /// Fraction of events of an event type shipped, between 0 and 1
+    pub sample: HashMap<String, f64>
+}
+
+impl Overrides {
+    pub fn from_value(value: &Value) -> Result<Overrides, Error> {
+        let version = match value.get("version").and_then(Value::as_u64) {
+            Some(v) => v,
+            None => bail!("Config override has no version")
+        };
+        let drop_event_types = value.get("drop_event_types")
+            .and_then(Value::as_array)
+            .map(|types| types.iter().filter_map(Value::as_str).map(|t| t.to_string()).collect())
+            .unwrap_or_else(HashSet::new);
+        let mut sample = HashMap::new();
+        if let Some(rates) = value.get("sample").and_then(Value::as_object) {
+            for (event_type, rate) in rates.iter() {
+                match rate.as_f64() {
+                    Some(r) if r >= 0.0 && r <= 1.0 => {
+                        sample.insert(event_type.clone(), r);
+                    }
+                    _ => bail!("Invalid sample rate {} for {}", rate, event_type)
+                }
+            }
+        }
+        Ok(Overrides {
+            version: version,
+            drop_event_types: drop_event_types,
+            sample: sample
+        })
+    }
+}
+
+/// Verifies a record of the config topic, `{"config": "<json>", "signature": "<hex hmac-sha256>"}`,
+/// returning the overrides if the signature matches and the config targets `sensor_id` or every
+/// sensor (`"sensor_id": "*"`).
+pub fn verify(record: &[u8], secret: &[u8], sensor_id: &str) -> Result<Option<Overrides>, Error> {
+    let envelope: Value = serde_json::from_slice(record)
+        .map_err(|e| Error::from(format!("Invalid config record: {}", e)))?;
+    let (config, signature) = match (envelope.get("config").and_then(Value::as_str), envelope.get("signature").and_then(Value::as_str)) {
+        (Some(c), Some(s)) => (c, s),
+        _ => bail!("Config record must have config and signature strings")
+    };
+    let signature = match from_hex(signature) {
+        Some(s) => s,
+        None => bail!("Config signature is not hex")
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.input(config.as_bytes());
+    if mac.verify(&signature).is_err() {
+        bail!("Config signature does not match");
+    }
+
+    let config: Value = serde_json::from_str(config)
+        .map_err(|e| Error::from(format!("Invalid config: {}", e)))?;
+    match config.get("sensor_id").and_then(Value::as_str) {
+        Some(id) if id == sensor_id || id == "*" => Ok(Some(Overrides::from_value(&config)?)),
+        _ => Ok(None)
+    }
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Overrides currently applied, shared between the config listener and the pipeline.
+#[derive(Clone, Default)]
+pub struct OverrideHandle {
+    current: Arc<RwLock<Overrides>>,
+    journal: Option<OpsJournal>
+}
+
+impl OverrideHandle {
+    /// Records applied overrides in the ops journal.
+    pub fn with_journal(mut self, journal: OpsJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn current(&self) -> Overrides {
+        self.current.read().expect("Overrides lock poisoned").clone()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.current.read().expect("Overrides lock poisoned").version
+    }
+
+    /// Applies `overrides` unless an equal or newer version is already applied.
+    pub fn apply(&self, overrides: Overrides) -> bool {
+        let mut current = self.current.write().expect("Overrides lock poisoned");
+        if overrides.version <= current.version {
+            return false
+        }
+        if let Some(ref journal) = self.journal {
+            journal.record(OpsKind::ConfigReload, json!({
+                "from_version": current.version,
+                "version": overrides.version
+            }));
+        }
+        *current = overrides;
+        true
+    }
+}
+
+/// Drops and samples events by the currently applied overrides.
+pub struct OverrideFilter<S> {
+    inner: S,
+    overrides: OverrideHandle,
+    version: u64,
+    applied: Overrides,
+    credit: HashMap<String, f64>
+}
+
+impl<S> OverrideFilter<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(inner: S, overrides: OverrideHandle) -> OverrideFilter<S> {
+        OverrideFilter {
+            inner: inner,
+            overrides: overrides,
+            version: 0,
+            applied: Overrides::default(),
+            credit: HashMap::new()
+        }
+    }
+
+    fn keep(&mut self, msg: &Vec<u8>) -> bool {
+        if self.overrides.version() != self.version {
+            self.applied = self.overrides.current();
+            self.version = self.applied.version;
+            self.credit.clear();
+        }
+        let event_type = match eve::event_type(msg) {
+            Some(t) => t,
+            None => return true
+        };
+        if self.applied.drop_event_types.contains(event_type) {
+            return false
+        }
+        match self.applied.sample.get(event_type) {
+            Some(&rate) => {
+                let credit = self.credit.entry(event_type.to_string()).or_insert(0.0);
+                *credit += rate;
+                if *credit >= 1.0 {
+                    *credit -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => true
+        }
+    }
+}
+
+impl<S> Stream for OverrideFilter<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if self.keep(msg.as_ref()) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Acknowledgement sent to the control topic once overrides are applied, completing the
+/// handshake with the central manager.
+pub fn ack_event(sensor_id: &str, version: u64) -> Vec<u8> {
+    let event = json!({
+        "event_type": "surikafka_config_ack",
+        "sensor_id": sensor_id,
+        "version": version
+    });
+    serde_json::to_vec(&event).expect("Ack is always serializable")
+}
+
+/// Consumes the config topic from the beginning on a background thread, since the consumer
+/// blocks, applying each verified override for this sensor as it arrives. `acks_gauge` counts
+/// acknowledgements sent; the receiving side is expected to subtract as it consumes them.
+pub struct ConfigListener;
+
+impl ConfigListener {
+    pub fn spawn(
+        client: &ClientConfig,
+        topic: &str,
+        secret: Vec<u8>,
+        sensor_id: &str,
+        overrides: OverrideHandle,
+        acks: UnboundedSender<Vec<u8>>,
+        acks_gauge: QueueGauge
+    ) -> Result<(), Error> {
+        let consumer: BaseConsumer = client.clone()
+            .set("group.id", &format!("surikafka-config-{}", sensor_id))
+            .set("enable.auto.commit", "false")
+            .create()
+            .map_err(|e| Error::from(format!("Failed to create config consumer: {:?}", e)))?;
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT_MS)
+            .map_err(|e| Error::from(format!("Failed to fetch metadata: {:?}", e)))?;
+        let mut assignment = TopicPartitionList::new();
+        for t in metadata.topics().iter().filter(|t| t.name() == topic) {
+            for p in t.partitions() {
+                assignment.add_partition_offset(topic, p.id(), Offset::Beginning);
+            }
+        }
+        consumer.assign(&assignment)
+            .map_err(|e| Error::from(format!("Failed to assign partitions: {:?}", e)))?;
+
+        let topic = topic.to_string();
+        let sensor_id = sensor_id.to_string();
+        std::thread::spawn(move || {
+            loop {
+                if Arc::strong_count(&overrides.current) == 1 {
+                    return
+                }
+                let record = match consumer.poll(POLL_TIMEOUT_MS) {
+                    None => continue,
+                    Some(Err(e)) => {
+                        warn!("Failed to consume {}: {:?}", topic, e);
+                        continue
+                    }
+                    Some(Ok(m)) => m.payload().map(|p| p.to_vec())
+                };
+                let record = match record {
+                    Some(r) => r,
+                    None => continue
+                };
+                match verify(&record, &secret, &sensor_id) {
+                    Ok(Some(o)) => {
+                        let version = o.version;
+                        if overrides.apply(o) {
+                            info!("Applied config version {} from {}", version, topic);
+                            if acks.unbounded_send(ack_event(&sensor_id, version)).is_err() {
+                                return
+                            }
+                            acks_gauge.add(1);
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!("Ignoring config record from {}: {}", topic, e)
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream
+    };
+
+    fn sign(config: &str, secret: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+        mac.input(config.as_bytes());
+        let signature: String = mac.result().code().iter().map(|b| format!("{:02x}", b)).collect();
+        serde_json::to_vec(&json!({"config": config, "signature": signature})).expect("Failed to serialize")
+    }
+
+    #[test]
+    fn verifies_signed_configs() {
+        let config = r#"{"sensor_id":"s1","version":2,"drop_event_types":["flow"],"sample":{"dns":0.5}}"#;
+
+        let overrides = verify(&sign(config, b"secret"), b"secret", "s1")
+            .expect("Failed to verify")
+            .expect("No overrides");
+
+        assert_eq!(overrides.version, 2);
+        assert!(overrides.drop_event_types.contains("flow"));
+        assert_eq!(overrides.sample.get("dns"), Some(&0.5));
+        assert!(verify(&sign(config, b"other"), b"secret", "s1").is_err());
+        assert_eq!(verify(&sign(config, b"secret"), b"secret", "s2").expect("Failed to verify"), None);
+    }
+
+    #[test]
+    fn applies_newer_versions_only() {
+        let handle = OverrideHandle::default();
+
+        assert!(handle.apply(Overrides { version: 2, ..Overrides::default() }));
+        assert!(!handle.apply(Overrides { version: 1, ..Overrides::default() }));
+        assert_eq!(handle.current().version, 2);
+    }
+
+    #[test]
+    fn drops_and_samples_events() {
+        let handle = OverrideHandle::default();
+        let mut drop_event_types = HashSet::new();
+        drop_event_types.insert("flow".to_string());
+        let mut sample = HashMap::new();
+        sample.insert("dns".to_string(), 0.5);
+        handle.apply(Overrides { version: 1, drop_event_types: drop_event_types, sample: sample });
+
+        let events: Vec<Vec<u8>> = vec![
+            r#"{"event_type":"flow"}"#, r#"{"event_type":"dns"}"#, r#"{"event_type":"dns"}"#,
+            r#"{"event_type":"alert"}"#, r#"{"event_type":"dns"}"#, r#"{"event_type":"dns"}"#
+        ].into_iter().map(|e| e.to_string().into_bytes()).collect();
+
+        let kept = OverrideFilter::new(stream::iter_ok::<_, ()>(events), handle)
+            .collect()
+            .wait()
+            .expect("Failed to filter");
+
+        assert_eq!(kept.len(), 3);
+        assert!(kept.iter().all(|e| eve::event_type(e) != Some("flow")));
+    }
+}
@@ -0,0 +1,144 @@
+use super::{
+    chrono::{
+        DateTime,
+        FixedOffset
+    },
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    runtime::Delay,
+    serde_json::{
+        self,
+        Value
+    },
+    source
+};
+use std::{
+    self,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+fn event_time(msg: &Vec<u8>) -> Option<DateTime<FixedOffset>> {
+    serde_json::from_slice::<Value>(msg).ok()
+        .and_then(|v| v.get("timestamp").and_then(Value::as_str).and_then(source::parse_timestamp))
+}
+
+/// Wall clock offset from the start of a replay at which an event should be emitted, given its
+/// offset from the first event. Events out of order are emitted immediately.
+pub fn scaled_offset(event_offset: ::chrono::Duration, speed: f64) -> Duration {
+    let micros = (event_offset.num_microseconds().unwrap_or(0).max(0) as f64 / speed) as u64;
+    Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1000) as u32)
+}
+
+/// Re-emits archived events with their original inter-arrival times, divided by `speed`.
+pub struct Replay<S: Stream> {
+    inner: S,
+    speed: f64,
+    origin: Option<(DateTime<FixedOffset>, Instant)>,
+    pending: Option<(S::Item, Delay)>
+}
+
+impl<S> Replay<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(inner: S, speed: f64) -> Replay<S> {
+        Replay {
+            inner: inner,
+            speed: if speed > 0.0 { speed } else { 1.0 },
+            origin: None,
+            pending: None
+        }
+    }
+
+    fn due(&mut self, msg: &Vec<u8>) -> Option<Instant> {
+        let time = event_time(msg)?;
+        if let Some( (first, started) ) = self.origin {
+            return Some(started + scaled_offset(time.signed_duration_since(first), self.speed))
+        }
+        self.origin = Some( (time, Instant::now()) );
+        None
+    }
+}
+
+impl<S> Stream for Replay<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some( (msg, mut delay) ) = self.pending.take() {
+            return match delay.poll() {
+                Ok(Async::NotReady) => {
+                    self.pending = Some( (msg, delay) );
+                    Ok(Async::NotReady)
+                }
+                Ok(Async::Ready(())) => Ok(Async::Ready(Some(msg))),
+                Err(e) => {
+                    error!("Replay timer failed: {:?}", e);
+                    Ok(Async::Ready(Some(msg)))
+                }
+            }
+        }
+
+        match try_ready!(self.inner.poll()) {
+            Some(msg) => {
+                match self.due(msg.as_ref()) {
+                    Some(due) if due > Instant::now() => {
+                        self.pending = Some( (msg, Delay::new(due)) );
+                        self.poll()
+                    }
+                    _ => Ok(Async::Ready(Some(msg)))
+                }
+            }
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures,
+        tokio
+    };
+
+    #[test]
+    fn scales_offsets() {
+        assert_eq!(scaled_offset(::chrono::Duration::seconds(10), 1.0), Duration::from_secs(10));
+        assert_eq!(scaled_offset(::chrono::Duration::seconds(10), 10.0), Duration::from_secs(1));
+        assert_eq!(scaled_offset(::chrono::Duration::seconds(-5), 1.0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn replays_with_original_spacing() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+
+        let events = vec![
+            r#"{"timestamp":"2018-06-01T00:00:00.000000+0000"}"#.to_string().into_bytes(),
+            r#"{"timestamp":"2018-06-01T00:00:01.000000+0000"}"#.to_string().into_bytes(),
+            r#"{"no_timestamp":true}"#.to_string().into_bytes(),
+            r#"{"timestamp":"2018-06-01T00:00:02.000000+0000"}"#.to_string().into_bytes()
+        ];
+
+        let started = Instant::now();
+
+        let replayed = rt.block_on(Replay::new(futures::stream::iter_ok::<_, ()>(events.clone()), 10.0).collect())
+            .expect("Failed to replay");
+
+        let elapsed = Instant::now() - started;
+
+        assert_eq!(replayed, events);
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,371 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    serde_json::Value,
+    writer::{
+        CodecKind,
+        KeyPlacement
+    }
+};
+use std::{
+    self,
+    fmt
+};
+
+/// Acknowledgements the producer waits for, as librdkafka's `acks`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acks {
+    /// Fire and forget: no delivery reports carry errors or offsets
+    None,
+    Leader,
+    All
+}
+
+impl Acks {
+    pub fn config_value(&self) -> &'static str {
+        match *self {
+            Acks::None => "0",
+            Acks::Leader => "1",
+            Acks::All => "all"
+        }
+    }
+}
+
+impl std::str::FromStr for Acks {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Acks, Error> {
+        match s {
+            "0" => Ok(Acks::None),
+            "1" => Ok(Acks::Leader),
+            "all" | "-1" => Ok(Acks::All),
+            _ => Err(Error::from_kind(ErrorKind::InvalidAcks(s.to_string())))
+        }
+    }
+}
+
+/// What a consumer of a route can count on receiving of the events read, with the settings
+/// in effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Guarantee {
+    AtMostOnce,
+    AtLeastOnce,
+    /// At least once, to a compacted topic whose every event is keyed, so duplicates collapse
+    ExactlyOnce
+}
+
+impl fmt::Display for Guarantee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Guarantee::AtMostOnce => write!(f, "at-most-once"),
+            Guarantee::AtLeastOnce => write!(f, "at-least-once"),
+            Guarantee::ExactlyOnce => write!(f, "exactly-once")
+        }
+    }
+}
+
+/// The effective delivery guarantee of a route, with why it's no stronger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteGuarantee {
+    pub route: String,
+    pub topic: String,
+    pub guarantee: Guarantee,
+    pub reasons: Vec<String>
+}
+
+impl RouteGuarantee {
+    pub fn to_value(&self) -> Value {
+        json!({
+            "route": self.route,
+            "topic": self.topic,
+            "guarantee": self.guarantee.to_string(),
+            "reasons": self.reasons
+        })
+    }
+}
+
+impl fmt::Display for RouteGuarantee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is {}", self.route, self.guarantee)?;
+        if !self.reasons.is_empty() {
+            write!(f, ": {}", self.reasons.join("; "))?;
+        }
+        Ok( () )
+    }
+}
+
+/// Whether the key strategy keys every event, or leaves some (e.g. stats, without a flow) with
+/// an empty key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyCoverage {
+    Every,
+    Partial
+}
+
+/// Where one event type, or with no event type the events without a topic of their own, is
+/// written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub event_type: Option<String>,
+    pub topic: String,
+    pub codec: CodecKind,
+    pub compacted: bool
+}
+
+impl Route {
+    fn name(&self) -> String {
+        match self.event_type {
+            Some(ref event_type) => format!("{} route to {}", event_type, self.topic),
+            None => format!("default route to {}", self.topic)
+        }
+    }
+}
+
+/// The routes of the event topics with the producer settings they depend on, checked together
+/// at startup so combinations the brokers would only reject (or silently mangle) once events
+/// flow fail before anything is read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicMap {
+    routes: Vec<Route>,
+    keys: KeyCoverage,
+    key_placement: KeyPlacement,
+    acks: Acks,
+    verified: bool,
+    array_batches: bool,
+    handles_failures: bool,
+    pinned_partitions: Option<i32>,
+    created_partitions: Option<i32>,
+    resumes: bool,
+    overwriting_spool: bool,
+    reorders_retries: bool
+}
+
+impl TopicMap {
+    pub fn new(keys: KeyCoverage, key_placement: KeyPlacement, acks: Acks) -> TopicMap {
+        TopicMap {
+            routes: vec![],
+            keys: keys,
+            key_placement: key_placement,
+            acks: acks,
+            verified: false,
+            array_batches: false,
+            handles_failures: false,
+            pinned_partitions: None,
+            created_partitions: None,
+            resumes: false,
+            overwriting_spool: false,
+            reorders_retries: false
+        }
+    }
+
+    pub fn with_route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Deliveries are verified against produced offsets.
+    pub fn with_verification(mut self, verified: bool) -> Self {
+        self.verified = verified;
+        self
+    }
+
+    /// Events are sent as JSON array batches.
+    pub fn with_array_batches(mut self, array_batches: bool) -> Self {
+        self.array_batches = array_batches;
+        self
+    }
+
+    /// Failed deliveries are dead lettered or fail the pipeline once retries are used up; retries
+    /// alone still drop what they can't deliver.
+    pub fn with_failure_handling(mut self, handles_failures: bool) -> Self {
+        self.handles_failures = handles_failures;
+        self
+    }
+
+    /// Sensors are pinned to partitions of topics with `partitions` partitions.
+    pub fn with_pinned_partitions(mut self, partitions: i32) -> Self {
+        self.pinned_partitions = Some(partitions);
+        self
+    }
+
+    /// Topics of newly discovered event types are created with `partitions` partitions.
+    pub fn with_created_partitions(mut self, partitions: i32) -> Self {
+        self.created_partitions = Some(partitions);
+        self
+    }
+
+    /// Reading resumes from the checkpoint after a restart.
+    pub fn with_resume(mut self, resumes: bool) -> Self {
+        self.resumes = resumes;
+        self
+    }
+
+    /// Events are backed up in a spool overwriting the oldest once full.
+    pub fn with_overwriting_spool(mut self, overwriting_spool: bool) -> Self {
+        self.overwriting_spool = overwriting_spool;
+        self
+    }
+
+    /// Failed deliveries are retried while later records are in flight, so a retried record may
+    /// land behind later ones of its partition.
+    pub fn with_reordered_retries(mut self, reorders_retries: bool) -> Self {
+        self.reorders_retries = reorders_retries;
+        self
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Every problem with the map, empty if it's consistent.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for route in self.routes.iter() {
+            if route.compacted && self.keys == KeyCoverage::Partial {
+                problems.push(format!("{} is compacted, but the key strategy leaves some events without a key, which compaction would collapse into one", route.name()));
+            }
+            if route.codec == CodecKind::Avro && self.key_placement == KeyPlacement::Field {
+                problems.push(format!("{} encodes with avro, which drops the key field of --key-placement field", route.name()));
+            }
+            if route.codec != CodecKind::Json && self.array_batches {
+                problems.push(format!("{} encodes with {:?}, which can't encode array batches", route.name(), route.codec));
+            }
+        }
+        if self.acks == Acks::None && self.verified {
+            problems.push("acks=0 deliveries have no offsets to verify".to_string());
+        }
+        if self.acks == Acks::None && self.handles_failures {
+            problems.push("acks=0 deliveries never report failures to retry or dead letter".to_string());
+        }
+        if let (Some(pinned), Some(created)) = (self.pinned_partitions, self.created_partitions) {
+            if created < pinned {
+                problems.push(format!("sensors are pinned to partitions of {} partition topics, but discovered event types get topics of {}", pinned, created));
+            }
+        }
+        problems
+    }
+
+    /// What each route delivers. Records are produced without idempotence, so a retry or a
+    /// read after a restart may duplicate them, and only compaction makes them exactly once.
+    pub fn guarantees(&self) -> Vec<RouteGuarantee> {
+        let mut losses = vec![];
+        match self.acks {
+            Acks::None => losses.push("acks=0 never reports failed deliveries".to_string()),
+            Acks::Leader => losses.push("acks=1 loses records the leader fails before replicating".to_string()),
+            Acks::All => {}
+        }
+        if !self.handles_failures {
+            losses.push("undelivered records are dropped without a dead letter or --fail-undelivered".to_string());
+        }
+        if !self.resumes {
+            losses.push("reading doesn't resume from the checkpoint after a restart".to_string());
+        }
+        if self.overwriting_spool {
+            losses.push("the spool overwrites its oldest events once full".to_string());
+        }
+        self.routes.iter().map(|route| {
+            let (guarantee, mut reasons) = if !losses.is_empty() {
+                (Guarantee::AtMostOnce, losses.clone())
+            } else if !route.compacted {
+                (Guarantee::AtLeastOnce, vec!["records may be duplicated by retries or by reading again after a restart".to_string()])
+            } else if self.keys == KeyCoverage::Every {
+                (Guarantee::ExactlyOnce, vec![])
+            } else {
+                (Guarantee::AtLeastOnce, vec!["duplicates of events without a key aren't collapsed by compaction".to_string()])
+            };
+            if self.reorders_retries {
+                reasons.push("retried records may land behind later records of their partition".to_string());
+            }
+            RouteGuarantee {
+                route: route.name(),
+                topic: route.topic.clone(),
+                guarantee: guarantee,
+                reasons: reasons
+            }
+        }).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!("Invalid topic map: {}", problems.join("; "));
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(event_type: &str, topic: &str, codec: CodecKind, compacted: bool) -> Route {
+        Route {
+            event_type: Some(event_type.to_string()),
+            topic: topic.to_string(),
+            codec: codec,
+            compacted: compacted
+        }
+    }
+
+    #[test]
+    fn accepts_consistent_map() {
+        let map = TopicMap::new(KeyCoverage::Every, KeyPlacement::Field, Acks::All)
+            .with_route(route("alert", "eve-alerts", CodecKind::Json, true))
+            .with_route(route("flow", "eve-flow", CodecKind::MessagePack, false))
+            .with_verification(true)
+            .with_pinned_partitions(12)
+            .with_created_partitions(12);
+
+        assert!(map.validate().is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let map = TopicMap::new(KeyCoverage::Partial, KeyPlacement::Field, Acks::None)
+            .with_route(route("alert", "eve-alerts", CodecKind::Avro, true))
+            .with_verification(true)
+            .with_failure_handling(true)
+            .with_pinned_partitions(12)
+            .with_created_partitions(1);
+
+        let problems = map.problems();
+
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].starts_with("alert route to eve-alerts is compacted"));
+        assert!(map.validate().is_err());
+    }
+
+    #[test]
+    fn derives_route_guarantees() {
+        let map = TopicMap::new(KeyCoverage::Every, KeyPlacement::Record, Acks::All)
+            .with_route(route("alert", "eve-alerts", CodecKind::Json, true))
+            .with_route(route("flow", "eve-flow", CodecKind::Json, false))
+            .with_failure_handling(true)
+            .with_resume(true);
+
+        let guarantees = map.guarantees();
+
+        assert_eq!(guarantees[0].guarantee, Guarantee::ExactlyOnce);
+        assert_eq!(guarantees[0].to_string(), "alert route to eve-alerts is exactly-once");
+        assert_eq!(guarantees[1].guarantee, Guarantee::AtLeastOnce);
+
+        let lossy = map.clone().with_overwriting_spool(true).guarantees();
+        assert!(lossy.iter().all(|g| g.guarantee == Guarantee::AtMostOnce));
+        assert_eq!(lossy[1].reasons, vec!["the spool overwrites its oldest events once full".to_string()]);
+        assert_eq!(lossy[1].to_value()["guarantee"], "at-most-once");
+
+        let reordered = map.with_reordered_retries(true).guarantees();
+        assert_eq!(reordered[0].guarantee, Guarantee::ExactlyOnce);
+        assert_eq!(reordered[0].reasons, vec!["retried records may land behind later records of their partition".to_string()]);
+    }
+
+    #[test]
+    fn parses_acks() {
+        assert_eq!("all".parse::<Acks>().expect("Failed to parse"), Acks::All);
+        assert_eq!("-1".parse::<Acks>().expect("Failed to parse").config_value(), "all");
+        assert_eq!("0".parse::<Acks>().expect("Failed to parse"), Acks::None);
+        assert!("2".parse::<Acks>().is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    guard::StageGuard,
+    serde_json::{
+        self,
+        Map,
+        Value
+    }
+};
+use std::{
+    self,
+    collections::{
+        BTreeMap,
+        HashMap
+    },
+    io::BufRead,
+    path::Path
+};
+
+/// Metadata from a suricata rule, attached to the alerts it generates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleMeta {
+    pub msg: Option<String>,
+    pub references: Vec<String>,
+    pub cves: Vec<String>,
+    pub metadata: BTreeMap<String, Vec<String>>
+}
+
+impl RuleMeta {
+    pub fn to_value(&self) -> Value {
+        let metadata: Map<String, Value> = self.metadata.iter()
+            .map(|(k, v)| (k.clone(), json!(v)))
+            .collect();
+        json!({
+            "msg": self.msg,
+            "references": self.references,
+            "cve": self.cves,
+            "metadata": metadata
+        })
+    }
+}
+
+/// Splits the options section of a rule on unescaped `;`, honouring quoted strings.
+fn split_options(options: &str) -> Vec<String> {
+    let mut result = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                let option = current.trim().to_string();
+                if !option.is_empty() {
+                    result.push(option);
+                }
+                current.clear();
+            }
+            _ => current.push(c)
+        }
+    }
+    result
+}
+
+/// Parses a single rule line, returning its sid and metadata. Comments, blank lines, and lines
+/// without a sid are ignored.
+pub fn parse_rule(line: &str) -> Option<(u64, RuleMeta)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None
+    }
+    let start = line.find('(')?;
+    let end = line.rfind(')')?;
+    if end <= start {
+        return None
+    }
+
+    let mut sid = None;
+    let mut meta = RuleMeta::default();
+
+    for option in split_options(&line[start + 1..end]) {
+        let mut parts = option.splitn(2, ':');
+        let keyword = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        match keyword {
+            "sid" => sid = value.parse::<u64>().ok(),
+            "msg" => meta.msg = Some(value.to_string()),
+            "reference" => {
+                let mut reference = value.splitn(2, ',');
+                let kind = reference.next().unwrap_or("").trim();
+                let id = reference.next().unwrap_or("").trim();
+                if kind.eq_ignore_ascii_case("cve") {
+                    meta.cves.push(format!("CVE-{}", id));
+                }
+                meta.references.push(format!("{},{}", kind, id));
+            }
+            "metadata" => {
+                for entry in value.split(',') {
+                    let mut kv = entry.trim().splitn(2, ' ');
+                    let key = kv.next().unwrap_or("").trim();
+                    let v = kv.next().unwrap_or("").trim();
+                    if key.is_empty() {
+                        continue
+                    }
+                    if key == "cve" {
+                        let cve = v.replace('_', "-");
+                        if !meta.cves.contains(&cve) {
+                            meta.cves.push(cve);
+                        }
+                    }
+                    meta.metadata.entry(key.to_string()).or_insert_with(Vec::new).push(v.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    sid.map(|sid| (sid, meta))
+}
+
+#[derive(Default)]
+pub struct RuleSet {
+    rules: HashMap<u64, RuleMeta>
+}
+
+impl RuleSet {
+    pub fn parse<R: BufRead>(&mut self, reader: R) -> Result<usize, Error> {
+        let mut count = 0;
+        for line in reader.lines() {
+            if let Some( (sid, meta) ) = parse_rule(&line?) {
+                self.rules.insert(sid, meta);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, Error> {
+        let file = std::fs::File::open(path)?;
+        self.parse(std::io::BufReader::new(file))
+    }
+
+    pub fn get(&self, sid: u64) -> Option<&RuleMeta> {
+        self.rules.get(&sid)
+    }
+
+    pub fn len(&self) -> usize { self.rules.len() }
+
+    /// Adds a `rule` object to alert events whose signature is known, returning `None` for events
+    /// that are left unchanged.
+    pub fn enrich(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        let mut event: Value = serde_json::from_slice(msg).ok()?;
+        let sid = event.pointer("/alert/signature_id").and_then(Value::as_u64)?;
+        let meta = self.get(sid)?;
+        event.as_object_mut()?.insert("rule".to_string(), meta.to_value());
+        serde_json::to_vec(&event).ok()
+    }
+}
+
+pub struct RuleEnricher<S> {
+    inner: S,
+    rules: RuleSet,
+    guard: StageGuard
+}
+
+impl<S> RuleEnricher<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, rules: RuleSet) -> RuleEnricher<S> {
+        RuleEnricher {
+            inner: inner,
+            rules: rules,
+            guard: StageGuard::new("rules")
+        }
+    }
+
+    pub fn with_guard(mut self, guard: StageGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+}
+
+impl<S> Stream for RuleEnricher<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    let rules = &self.rules;
+                    if let Some(msg) = self.guard.run(msg, |m| rules.enrich(m)) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULE: &'static str = r#"alert http $EXTERNAL_NET any -> $HOME_NET any (msg:"ET EXPLOIT Apache log4j RCE Attempt (CVE-2021-44228)\; jndi"; flow:established,to_server; content:"${jndi:"; reference:cve,2021-44228; reference:url,example.com/log4j; classtype:attempted-admin; sid:2034647; rev:2; metadata:attack_target Server, cve CVE_2021_44228, mitre_tactic_id TA0001, mitre_technique_id T1190;)"#;
+
+    #[test]
+    fn parses_rule() {
+        let (sid, meta) = parse_rule(RULE).expect("Failed to parse");
+
+        assert_eq!(sid, 2034647);
+        assert_eq!(meta.msg, Some("ET EXPLOIT Apache log4j RCE Attempt (CVE-2021-44228); jndi".to_string()));
+        assert_eq!(meta.references, vec!["cve,2021-44228".to_string(), "url,example.com/log4j".to_string()]);
+        assert_eq!(meta.cves, vec!["CVE-2021-44228".to_string()]);
+        assert_eq!(meta.metadata["mitre_technique_id"], vec!["T1190".to_string()]);
+    }
+
+    #[test]
+    fn ignores_comments() {
+        assert_eq!(parse_rule(&format!("# {}", RULE)), None);
+        assert_eq!(parse_rule(""), None);
+        assert_eq!(parse_rule("alert ip any any -> any any (msg:\"no sid\";)"), None);
+    }
+
+    #[test]
+    fn enriches_alerts() {
+        let mut rules = RuleSet::default();
+
+        assert_eq!(rules.parse(RULE.as_bytes()).expect("Failed to parse"), 1);
+
+        let alert = r#"{"event_type":"alert","alert":{"signature_id":2034647}}"#.as_bytes();
+        let enriched: Value = serde_json::from_slice(&rules.enrich(alert).expect("Not enriched"))
+            .expect("Failed to parse");
+
+        assert_eq!(enriched["rule"]["cve"][0], "CVE-2021-44228");
+        assert_eq!(enriched["rule"]["metadata"]["mitre_tactic_id"][0], "TA0001");
+
+        assert_eq!(rules.enrich(r#"{"event_type":"alert","alert":{"signature_id":1}}"#.as_bytes()), None);
+        assert_eq!(rules.enrich(r#"{"event_type":"flow"}"#.as_bytes()), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! The timers and task spawning of the pipeline's stages, behind a shim so the stages can be
+//! embedded in applications on async-std rather than tokio. tokio is used unless the
+//! `async-std-runtime` feature is enabled. Sockets, the admin server, and `Pipeline` itself
+//! still run on tokio, `Pipeline` on a runtime of its own.
+
+use super::{
+    errors::Error,
+    futures::{
+        Future,
+        Poll
+    }
+};
+#[cfg(not(feature = "async-std-runtime"))]
+use super::tokio;
+#[cfg(feature = "async-std-runtime")]
+use super::{
+    async_std,
+    futures03::{
+        self,
+        FutureExt,
+        compat::{
+            Compat,
+            Future01CompatExt
+        }
+    }
+};
+#[cfg(feature = "async-std-runtime")]
+use std::pin::Pin;
+use std::time::Instant;
+#[cfg(feature = "async-std-runtime")]
+use std::time::Duration;
+
+/// Resolves at `deadline`, on the selected runtime's timer.
+#[cfg(not(feature = "async-std-runtime"))]
+pub struct Delay {
+    inner: tokio::timer::Delay
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+impl Delay {
+    pub fn new(deadline: Instant) -> Delay {
+        Delay {
+            inner: tokio::timer::Delay::new(deadline)
+        }
+    }
+}
+
+#[cfg(not(feature = "async-std-runtime"))]
+impl Future for Delay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.inner.poll().map_err(|e| Error::from(format!("Timer failed: {}", e)))
+    }
+}
+
+/// Resolves at `deadline`, on the selected runtime's timer.
+#[cfg(feature = "async-std-runtime")]
+pub struct Delay {
+    inner: Compat<Pin<Box<futures03::Future<Output=Result<(), Error>> + Send>>>
+}
+
+#[cfg(feature = "async-std-runtime")]
+impl Delay {
+    pub fn new(deadline: Instant) -> Delay {
+        let now = Instant::now();
+        let wait = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        let sleep: Pin<Box<futures03::Future<Output=Result<(), Error>> + Send>> =
+            Box::pin(async_std::task::sleep(wait).map(Ok));
+        Delay {
+            inner: Compat::new(sleep)
+        }
+    }
+}
+
+#[cfg(feature = "async-std-runtime")]
+impl Future for Delay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.inner.poll()
+    }
+}
+
+/// Runs `future` in the background on the selected runtime, which must be the one running the
+/// caller.
+#[cfg(not(feature = "async-std-runtime"))]
+pub fn spawn<F>(future: F)
+    where F: Future<Item=(), Error=()> + Send + 'static
+{
+    tokio::spawn(future);
+}
+
+/// Runs `future` in the background on the selected runtime, which must be the one running the
+/// caller.
+#[cfg(feature = "async-std-runtime")]
+pub fn spawn<F>(future: F)
+    where F: Future<Item=(), Error=()> + Send + 'static
+{
+    async_std::task::spawn(future.compat().map(|_| ()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(not(feature = "async-std-runtime"))]
+    #[test]
+    fn delays_until_deadline() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let start = Instant::now();
+
+        rt.block_on(Delay::new(start + Duration::from_millis(20))).expect("Timer failed");
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "async-std-runtime")]
+    #[test]
+    fn delays_until_deadline() {
+        let start = Instant::now();
+
+        async_std::task::block_on(Delay::new(start + Duration::from_millis(20)).compat()).expect("Timer failed");
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
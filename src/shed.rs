@@ -0,0 +1,263 @@
+use super::{
+    errors::Error,
+    eve,
+    futures::{
+        Async,
+        Poll,
+        Stream,
+        sync::mpsc::UnboundedSender
+    },
+    metrics::{
+        Counter,
+        QueueGauge,
+        Registry
+    },
+    serde_json
+};
+use std::collections::HashMap;
+
+/// Steps of the overload ladder. Each level also sheds what the levels below it shed; alerts
+/// are never shed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShedLevel {
+    Normal,
+    DropStats,
+    SampleFlow,
+    SampleDns
+}
+
+impl ShedLevel {
+    const LADDER: [ShedLevel; 3] = [ShedLevel::DropStats, ShedLevel::SampleFlow, ShedLevel::SampleDns];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ShedLevel::Normal => "normal",
+            ShedLevel::DropStats => "drop_stats",
+            ShedLevel::SampleFlow => "sample_flow",
+            ShedLevel::SampleDns => "sample_dns"
+        }
+    }
+}
+
+/// Backlogs at which each level of the ladder is entered. A level is left once the backlog falls
+/// below half its threshold, so the policy doesn't flap around a threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShedPolicy {
+    thresholds: [usize; 3],
+    sample_rate: f64
+}
+
+impl ShedPolicy {
+    /// Parses comma separated backlogs for drop_stats, sample_flow, and sample_dns, e.g.
+    /// `10000,50000,100000`.
+    pub fn parse(thresholds: &str, sample_rate: f64) -> Result<ShedPolicy, Error> {
+        let parsed = thresholds.split(',')
+            .map(|t| t.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| Error::from(format!("Invalid shed thresholds {}", thresholds)))?;
+        if parsed.len() != 3 || parsed[0] > parsed[1] || parsed[1] > parsed[2] {
+            bail!("Expected three increasing shed thresholds, got {}", thresholds);
+        }
+        if sample_rate < 0.0 || sample_rate > 1.0 {
+            bail!("Shed sample rate {} is not between 0 and 1", sample_rate);
+        }
+        Ok(ShedPolicy {
+            thresholds: [parsed[0], parsed[1], parsed[2]],
+            sample_rate: sample_rate
+        })
+    }
+
+    /// Level to apply at `backlog`, coming from `current`.
+    pub fn level(&self, current: ShedLevel, backlog: usize) -> ShedLevel {
+        let mut level = ShedLevel::Normal;
+        for (threshold, &step) in self.thresholds.iter().zip(ShedLevel::LADDER.iter()) {
+            let enter = if step <= current { threshold / 2 } else { *threshold };
+            if backlog >= enter {
+                level = step;
+            }
+        }
+        level
+    }
+
+    /// Fraction of events of `event_type` kept at `level`.
+    pub fn rate(&self, level: ShedLevel, event_type: &str) -> f64 {
+        match event_type {
+            "stats" if level >= ShedLevel::DropStats => 0.0,
+            "flow" | "netflow" if level >= ShedLevel::SampleFlow => self.sample_rate,
+            "dns" if level >= ShedLevel::SampleDns => self.sample_rate,
+            _ => 1.0
+        }
+    }
+}
+
+/// Sheds low value events by a `ShedPolicy` while the writer's backlog, the records awaiting
+/// delivery reports, is high, rather than stalling the whole pipeline. Level changes are logged,
+/// counted in `shed.transitions`, and sent to `alarms` as `surikafka_overload` events; shed
+/// events are counted in `shed.<event_type>`.
+pub struct Shedder<S> {
+    inner: S,
+    policy: ShedPolicy,
+    backlog: QueueGauge,
+    level: ShedLevel,
+    registry: Registry,
+    shed: HashMap<String, Counter>,
+    transitions: Counter,
+    credit: HashMap<String, f64>,
+    alarms: Option<(UnboundedSender<Vec<u8>>, QueueGauge)>
+}
+
+impl<S> Shedder<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, policy: ShedPolicy, backlog: QueueGauge, registry: &Registry) -> Shedder<S> {
+        Shedder {
+            inner: inner,
+            policy: policy,
+            backlog: backlog,
+            level: ShedLevel::Normal,
+            registry: registry.clone(),
+            shed: HashMap::new(),
+            transitions: registry.counter("shed.transitions"),
+            credit: HashMap::new(),
+            alarms: None
+        }
+    }
+
+    /// Reports level changes to `alarms`, counting them in `gauge`.
+    pub fn with_alarms(mut self, alarms: UnboundedSender<Vec<u8>>, gauge: QueueGauge) -> Self {
+        self.alarms = Some( (alarms, gauge) );
+        self
+    }
+
+    pub fn level(&self) -> ShedLevel { self.level }
+
+    fn transition(&mut self, level: ShedLevel, backlog: usize) {
+        if level > self.level {
+            warn!("Backlog of {} records, shedding at level {}", backlog, level.name());
+        } else {
+            info!("Backlog down to {} records, shedding at level {}", backlog, level.name());
+        }
+        self.level = level;
+        self.credit.clear();
+        self.transitions.incr();
+        if let Some( (ref alarms, ref gauge) ) = self.alarms {
+            let event = json!({
+                "event_type": "surikafka_overload",
+                "level": level.name(),
+                "backlog": backlog
+            });
+            let event = serde_json::to_vec(&event).expect("Overload event is always serializable");
+            if alarms.unbounded_send(event).is_err() {
+                error!("Alarm receiver closed, dropping overload event");
+            } else {
+                gauge.add(1);
+            }
+        }
+    }
+
+    fn keep(&mut self, msg: &Vec<u8>) -> bool {
+        let backlog = self.backlog.depth();
+        let level = self.policy.level(self.level, backlog);
+        if level != self.level {
+            self.transition(level, backlog);
+        }
+        if self.level == ShedLevel::Normal {
+            return true
+        }
+        let event_type = match eve::event_type(msg) {
+            Some(t) => t,
+            None => return true
+        };
+        let rate = self.policy.rate(self.level, event_type);
+        if rate >= 1.0 {
+            return true
+        }
+        let credit = self.credit.entry(event_type.to_string()).or_insert(0.0);
+        *credit += rate;
+        if *credit >= 1.0 {
+            *credit -= 1.0;
+            return true
+        }
+        let registry = &self.registry;
+        self.shed.entry(event_type.to_string())
+            .or_insert_with(|| registry.counter(&format!("shed.{}", event_type)))
+            .incr();
+        false
+    }
+}
+
+impl<S> Stream for Shedder<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if self.keep(&msg) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream,
+        sync::mpsc
+    };
+
+    #[test]
+    fn climbs_the_ladder_with_hysteresis() {
+        let policy = ShedPolicy::parse("10,20,40", 0.5).expect("Failed to parse");
+
+        assert_eq!(policy.level(ShedLevel::Normal, 9), ShedLevel::Normal);
+        assert_eq!(policy.level(ShedLevel::Normal, 25), ShedLevel::SampleFlow);
+        assert_eq!(policy.level(ShedLevel::SampleFlow, 12), ShedLevel::SampleFlow);
+        assert_eq!(policy.level(ShedLevel::SampleFlow, 9), ShedLevel::DropStats);
+        assert_eq!(policy.level(ShedLevel::SampleDns, 4), ShedLevel::Normal);
+        assert!(ShedPolicy::parse("20,10,40", 0.5).is_err());
+        assert!(ShedPolicy::parse("10,20", 0.5).is_err());
+    }
+
+    #[test]
+    fn never_sheds_alerts() {
+        let registry = Registry::default();
+        let backlog = registry.queue("writer.in_flight");
+        let policy = ShedPolicy::parse("1,2,3", 0.5).expect("Failed to parse");
+        backlog.add(5);
+        let events = vec![
+            br#"{"event_type":"alert"}"#.to_vec(),
+            br#"{"event_type":"stats"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"dns"}"#.to_vec(),
+            br#"{"event_type":"dns"}"#.to_vec()
+        ];
+        let (sender, receiver) = mpsc::unbounded();
+
+        let kept: Vec<Vec<u8>> = Shedder::new(stream::iter_ok::<_, ()>(events), policy, backlog, &registry)
+            .with_alarms(sender, registry.queue("alarms.channel"))
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(kept, vec![
+            br#"{"event_type":"alert"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec(),
+            br#"{"event_type":"dns"}"#.to_vec()
+        ]);
+        assert_eq!(registry.counter("shed.stats").value(), 1);
+        assert_eq!(registry.counter("shed.flow").value(), 1);
+        assert_eq!(registry.counter("shed.transitions").value(), 1);
+        let overload: serde_json::Value = serde_json::from_slice(&receiver.collect().wait().expect("Failed to receive")[0])
+            .expect("Failed to parse");
+        assert_eq!(overload["level"], "sample_dns");
+    }
+}
@@ -0,0 +1,366 @@
+use super::{
+    cancel::CancellationToken,
+    errors::Error,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    metrics::Registry,
+    runtime::Delay,
+    serde_json::{
+        self,
+        Value
+    },
+    tokio_signal
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// How a pipeline finished draining, see `Shutdown::drain`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Drained {
+    /// Every record read was delivered or failed
+    Complete,
+    /// The grace period expired with deliveries outstanding
+    TimedOut,
+    /// The hard deadline passed and the outstanding records were abandoned
+    Abandoned
+}
+
+/// Queues of records read but not yet delivered, by name or, ending in a dot, name prefix. A
+/// shutdown past its deadline abandons what they hold. Spooled records stay on disk for the next
+/// run, so aren't among them.
+pub const OUTSTANDING_QUEUES: &'static [&'static str] = &["reader.pending", "batch.pending", "writer.in_flight", "writer.retrying", "derived."];
+/// Queues of the above counting events rather than records.
+const EVENT_QUEUES: &'static [&'static str] = &["reader.pending", "batch.pending"];
+/// Queues of the above holding records of the main stream, as opposed to copies sent to derived
+/// topics.
+const MAIN_QUEUES: &'static [&'static str] = &["reader.pending", "batch.pending", "writer.in_flight", "writer.retrying"];
+/// Default number of recent events whose byte ranges a `ReadLog` keeps.
+pub const DEFAULT_READ_LOG_CAPACITY: usize = 1_000_000;
+
+fn is_outstanding(queue: &str) -> bool {
+    OUTSTANDING_QUEUES.iter().any(|q| queue == *q || (q.ends_with('.') && queue.starts_with(q)))
+}
+
+struct ReadChunk {
+    source: Arc<String>,
+    start: u64,
+    end: u64,
+    events: usize
+}
+
+#[derive(Default)]
+struct ReadLogInner {
+    chunks: VecDeque<ReadChunk>,
+    events: usize
+}
+
+/// Byte ranges the file readers parsed their events from, most recent last, so a shutdown that
+/// abandons records can name where they were read from. Keeps the ranges of about the last
+/// `capacity` events.
+#[derive(Clone)]
+pub struct ReadLog {
+    inner: Arc<Mutex<ReadLogInner>>,
+    capacity: usize
+}
+
+impl ReadLog {
+    pub fn new(capacity: usize) -> ReadLog {
+        ReadLog {
+            inner: Arc::new(Mutex::new(ReadLogInner::default())),
+            capacity: capacity.max(1)
+        }
+    }
+
+    /// Records that `events` events were parsed from `start` to `end` of `source`.
+    pub fn record(&self, source: &Arc<String>, start: u64, end: u64, events: usize) {
+        if events == 0 {
+            return
+        }
+        let mut inner = self.inner.lock().expect("Read log lock poisoned");
+        inner.chunks.push_back(ReadChunk { source: source.clone(), start: start, end: end, events: events });
+        inner.events += events;
+        while inner.chunks.front().map(|c| inner.events - c.events >= self.capacity).unwrap_or(false) {
+            if let Some(chunk) = inner.chunks.pop_front() {
+                inner.events -= chunk.events;
+            }
+        }
+    }
+
+    /// Byte range of each source holding the most recent `events` events: from the start of the
+    /// oldest of them to where reading stopped. Reads are assumed to be delivered in the order
+    /// they were parsed, as the writer sends them.
+    pub fn recent(&self, events: usize) -> Vec<(String, u64, u64)> {
+        let inner = self.inner.lock().expect("Read log lock poisoned");
+        let mut ranges: Vec<(String, u64, u64)> = vec![];
+        let mut covered = 0;
+        for chunk in inner.chunks.iter().rev() {
+            if covered >= events {
+                break
+            }
+            covered += chunk.events;
+            let existing = ranges.iter().position(|&(ref source, _, _)| source == chunk.source.as_str());
+            match existing {
+                Some(i) => ranges[i].1 = ranges[i].1.min(chunk.start),
+                None => ranges.push( (chunk.source.to_string(), chunk.start, chunk.end) )
+            }
+        }
+        ranges.sort();
+        ranges
+    }
+}
+
+/// What a shutdown left behind: the records still held in each queue, the byte range of each
+/// source they were read from, and the position each source was checkpointed at. Abandoned
+/// records were all read from within those ranges, and won't be read again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownReport {
+    pub outcome: Drained,
+    pub abandoned: Vec<(String, usize)>,
+    /// Records left in the spool, delivered by the next run
+    pub spooled: usize,
+    pub ranges: Vec<(String, u64, u64)>,
+    pub positions: Vec<(String, u64)>
+}
+
+impl ShutdownReport {
+    /// Counts the records of the `OUTSTANDING_QUEUES` of `registry`. Records in flight or
+    /// retrying may each hold up to `events_per_record` events, with array batching; ranges are
+    /// found in `read_log` for as many events as could be abandoned.
+    pub fn new(outcome: Drained, registry: &Registry, read_log: &ReadLog, events_per_record: usize, positions: Vec<(String, u64)>) -> ShutdownReport {
+        let depths = registry.queue_depths();
+        let (abandoned, ranges) = if outcome == Drained::Abandoned {
+            let abandoned: Vec<(String, usize)> = depths.iter()
+                .filter(|&&(ref queue, depth)| depth > 0 && is_outstanding(queue))
+                .cloned()
+                .collect();
+            let events = abandoned.iter()
+                .filter(|&&(ref queue, _)| MAIN_QUEUES.contains(&queue.as_str()))
+                .map(|&(ref queue, depth)| if EVENT_QUEUES.contains(&queue.as_str()) { depth } else { depth * events_per_record.max(1) })
+                .sum();
+            (abandoned, read_log.recent(events))
+        } else {
+            (vec![], vec![])
+        };
+        ShutdownReport {
+            outcome: outcome,
+            abandoned: abandoned,
+            spooled: depths.iter().find(|&&(ref queue, _)| queue == "spool.records").map(|&(_, depth)| depth).unwrap_or(0),
+            ranges: ranges,
+            positions: positions
+        }
+    }
+
+    pub fn abandoned_total(&self) -> usize {
+        self.abandoned.iter().map(|&(_, count)| count).sum()
+    }
+
+    pub fn to_value(&self) -> Value {
+        json!({
+            "outcome": match self.outcome {
+                Drained::Complete => "complete",
+                Drained::TimedOut => "timed-out",
+                Drained::Abandoned => "abandoned"
+            },
+            "abandoned": self.abandoned_total(),
+            "abandoned_by_queue": self.abandoned.iter().map(|&(ref queue, count)| (queue.clone(), json!(count))).collect::<serde_json::Map<String, Value>>(),
+            "spooled": self.spooled,
+            "abandoned_ranges": self.ranges.iter().map(|&(ref source, start, end)| json!({ "source": source, "start": start, "end": end })).collect::<Vec<Value>>(),
+            "positions": self.positions.iter().map(|&(ref source, position)| json!({ "source": source, "position": position })).collect::<Vec<Value>>()
+        })
+    }
+}
+
+/// Stops a pipeline without losing what it already read. Triggering cancels the source, so no
+/// more records are pulled while those already read pass through the stages; `drain` then waits
+/// for their deliveries for up to the grace period. Only once everything has drained can the
+/// source checkpoint be persisted without dropping events on restart.
+#[derive(Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    grace: Duration,
+    deadline: Option<Duration>
+}
+
+impl Shutdown {
+    pub fn new(token: CancellationToken, grace: Duration) -> Shutdown {
+        Shutdown {
+            token: token,
+            grace: grace,
+            deadline: None
+        }
+    }
+
+    /// Rather than giving up after the grace period, abandon whatever is outstanding `deadline`
+    /// after the shutdown was triggered, resolving the drain as `Drained::Abandoned`.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn grace(&self) -> Duration { self.grace }
+
+    /// Triggers on SIGTERM or SIGINT. The returned future must be spawned on the runtime; it
+    /// resolves once the shutdown is triggered either way.
+    pub fn on_signals(&self) -> Box<Future<Item=(), Error=()> + Send> {
+        let sigterm = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM).flatten_stream();
+        let sigint = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGINT).flatten_stream();
+        let shutdown = self.clone();
+        let signalled = sigterm.select(sigint)
+            .into_future()
+            .map_err(|(e, _)| error!("Shutdown signal failed: {:?}", e))
+            .map(move |(signal, _)| {
+                if let Some(signal) = signal {
+                    info!("Received signal {}, draining before exit", signal);
+                    shutdown.trigger();
+                }
+            });
+        Box::new(signalled.select(self.token.cancelled()).map(|_| ()).map_err(|_| ()))
+    }
+
+    /// Runs `main` to completion, giving up once it has taken longer than the grace period, or
+    /// the deadline, after the shutdown was triggered.
+    pub fn drain<F>(&self, main: F) -> Drain<F>
+        where F: Future<Item=(), Error=Error>
+    {
+        Drain {
+            inner: main,
+            shutdown: self.clone(),
+            deadline: None
+        }
+    }
+}
+
+pub struct Drain<F> {
+    inner: F,
+    shutdown: Shutdown,
+    deadline: Option<Delay>
+}
+
+impl<F> Future for Drain<F>
+    where F: Future<Item=(), Error=Error>
+{
+    type Item = Drained;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(()) = self.inner.poll()? {
+            return Ok(Async::Ready(Drained::Complete))
+        }
+        if self.deadline.is_none() {
+            if self.shutdown.token.poll_cancelled().is_not_ready() {
+                return Ok(Async::NotReady)
+            }
+            let wait = self.shutdown.deadline.unwrap_or(self.shutdown.grace);
+            self.deadline = Some(Delay::new(Instant::now() + wait));
+        }
+        match self.deadline.as_mut().map(|d| d.poll()) {
+            Some(Ok(Async::Ready(()))) => match self.shutdown.deadline {
+                Some(deadline) => {
+                    warn!("Deliveries still outstanding {:?} after shutdown, abandoning them", deadline);
+                    Ok(Async::Ready(Drained::Abandoned))
+                }
+                None => {
+                    warn!("Deliveries still outstanding {:?} after shutdown, giving up", self.shutdown.grace);
+                    Ok(Async::Ready(Drained::TimedOut))
+                }
+            },
+            Some(Err(e)) => Err(Error::from(format!("Shutdown timer failed: {:?}", e))),
+            _ => Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::future,
+        tokio
+    };
+    use std;
+
+    #[test]
+    fn completes_when_drained() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let shutdown = Shutdown::new(CancellationToken::new(), Duration::from_secs(5));
+        shutdown.trigger();
+
+        assert_eq!(rt.block_on(shutdown.drain(future::ok(()))).expect("Drain failed"), Drained::Complete);
+    }
+
+    #[test]
+    fn gives_up_after_grace() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let shutdown = Shutdown::new(CancellationToken::new(), Duration::from_millis(20));
+
+        let trigger = shutdown.clone();
+        let triggering = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            trigger.trigger();
+        });
+
+        // Never resolves, like a pipeline stuck on outstanding deliveries
+        let stuck = future::empty::<(), Error>();
+
+        assert_eq!(rt.block_on(shutdown.drain(stuck)).expect("Drain failed"), Drained::TimedOut);
+
+        triggering.join().expect("Failed to trigger");
+    }
+
+    #[test]
+    fn abandons_at_deadline() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let shutdown = Shutdown::new(CancellationToken::new(), Duration::from_secs(60))
+            .with_deadline(Duration::from_millis(20));
+        shutdown.trigger();
+
+        let outcome = rt.block_on(shutdown.drain(future::empty::<(), Error>())).expect("Drain failed");
+        assert_eq!(outcome, Drained::Abandoned);
+
+        let registry = Registry::default();
+        registry.queue("reader.pending").add(2);
+        registry.queue("writer.in_flight").add(3);
+        registry.queue("derived.dead_letters").add(1);
+        registry.queue("spool.records").add(4);
+        registry.queue("alarms.channel").add(5);
+        let read_log = ReadLog::new(100);
+        let eve = Arc::new("eve.json".to_string());
+        let dns = Arc::new("dns.json".to_string());
+        read_log.record(&eve, 0, 100, 4);
+        read_log.record(&dns, 0, 50, 2);
+        read_log.record(&eve, 100, 200, 3);
+        let report = ShutdownReport::new(outcome, &registry, &read_log, 1, vec![("eve.json".to_string(), 200)]);
+
+        assert_eq!(report.abandoned_total(), 6);
+        assert_eq!(report.to_value(), json!({
+            "outcome": "abandoned",
+            "abandoned": 6,
+            "abandoned_by_queue": { "reader.pending": 2, "writer.in_flight": 3, "derived.dead_letters": 1 },
+            "spooled": 4,
+            "abandoned_ranges": [{ "source": "dns.json", "start": 0, "end": 50 }, { "source": "eve.json", "start": 100, "end": 200 }],
+            "positions": [{ "source": "eve.json", "position": 200 }]
+        }));
+        assert_eq!(read_log.recent(3), vec![("eve.json".to_string(), 100, 200)]);
+    }
+}
@@ -0,0 +1,375 @@
+//! End to end regression harness for the whole shipping path. `PcapSimulation` starts Suricata in
+//! its unix socket runmode, has it process a pcap into an EVE file, runs the shipper binary on
+//! that file, then reads the expected topics back and checks their records against
+//! `Expectation`s. Needs Suricata and a reachable Kafka cluster, e.g. the one in
+//! docker-compose.yaml, and topics that are new or empty:
+//!
+//! ```ignore
+//! let outcome = PcapSimulation::new("target/debug/surikafka", "tests/http.pcap", "localhost:9092")
+//!     .with_flag("--topic-template").with_flag("sim-{event_type}")
+//!     .expect(Expectation::topic("sim-alert").with_count(2).with_key("10.0.0.1"))
+//!     .expect(Expectation::topic("sim-http").with_count(6))
+//!     .run()?;
+//! outcome.assert_met();
+//! ```
+
+use super::{
+    errors::Error,
+    serde_json::{
+        self,
+        Value
+    },
+    testkit::{
+        self,
+        Record
+    }
+};
+use std::{
+    self,
+    io::{
+        Read,
+        Write
+    },
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::{
+        Child,
+        Command,
+        Stdio
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Interval between polls of Suricata's socket and of the shipper.
+const POLL_INTERVAL_MS: u64 = 200;
+
+/// What one topic should hold after the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    topic: String,
+    count: Option<usize>,
+    keys: Vec<String>
+}
+
+impl Expectation {
+    pub fn topic(topic: &str) -> Expectation {
+        Expectation {
+            topic: topic.to_string(),
+            count: None,
+            keys: vec![]
+        }
+    }
+
+    /// The topic holds exactly `count` records.
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// Some record of the topic is keyed `key`. May be called several times.
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.keys.push(key.to_string());
+        self
+    }
+
+    /// How `records` of the topic fall short of the expectation.
+    pub fn check(&self, records: &[Record]) -> Vec<String> {
+        let mut failures = vec![];
+        if let Some(count) = self.count {
+            if records.len() != count {
+                failures.push(format!("{} has {} records, expected {}", self.topic, records.len(), count));
+            }
+        }
+        for key in self.keys.iter() {
+            if !records.iter().any(|r| r.key.as_ref().map(|k| k.as_slice() == key.as_bytes()).unwrap_or(false)) {
+                failures.push(format!("{} has no record keyed {}", self.topic, key));
+            }
+        }
+        failures
+    }
+}
+
+/// The records read back from each expected topic, and the expectations they didn't meet.
+#[derive(Debug, Default, PartialEq)]
+pub struct SimulationOutcome {
+    pub records: Vec<(String, Vec<Record>)>,
+    pub failures: Vec<String>
+}
+
+impl SimulationOutcome {
+    pub fn is_met(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn assert_met(&self) {
+        assert!(self.is_met(), "Simulation didn't meet expectations: {}", self.failures.join("; "));
+    }
+}
+
+/// Replays a pcap through Suricata and the shipper, see the module docs.
+pub struct PcapSimulation {
+    binary: PathBuf,
+    pcap: PathBuf,
+    kafka_servers: String,
+    suricata: PathBuf,
+    suricata_config: Option<PathBuf>,
+    dir: PathBuf,
+    deadline: Duration,
+    extra_flags: Vec<String>,
+    expectations: Vec<Expectation>
+}
+
+impl PcapSimulation {
+    /// Ships the EVE output of `pcap` with `binary` to `kafka_servers`.
+    pub fn new(binary: &str, pcap: &str, kafka_servers: &str) -> PcapSimulation {
+        PcapSimulation {
+            binary: PathBuf::from(binary),
+            pcap: PathBuf::from(pcap),
+            kafka_servers: kafka_servers.to_string(),
+            suricata: PathBuf::from("suricata"),
+            suricata_config: None,
+            dir: std::env::temp_dir().join(format!("surikafka-simulation-{}", std::process::id())),
+            deadline: Duration::from_secs(120),
+            extra_flags: vec![],
+            expectations: vec![]
+        }
+    }
+
+    /// Suricata binary to run, `suricata` from the path by default.
+    pub fn with_suricata(mut self, suricata: &str) -> Self {
+        self.suricata = PathBuf::from(suricata);
+        self
+    }
+
+    /// Suricata configuration to run with, which must enable the eve-log output to eve.json;
+    /// Suricata's default configuration otherwise.
+    pub fn with_suricata_config(mut self, config: &str) -> Self {
+        self.suricata_config = Some(PathBuf::from(config));
+        self
+    }
+
+    /// Working directory of Suricata's socket and output and of the checkpoints, removed after
+    /// the run.
+    pub fn with_dir(mut self, dir: &str) -> Self {
+        self.dir = PathBuf::from(dir);
+        self
+    }
+
+    /// Longest each of Suricata, the shipper, and reading back may take.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Passes `flag` to the shipper, e.g. to test routing or keying settings.
+    pub fn with_flag(mut self, flag: &str) -> Self {
+        self.extra_flags.push(flag.to_string());
+        self
+    }
+
+    /// Checks a topic after the run. May be called once per topic.
+    pub fn expect(mut self, expectation: Expectation) -> Self {
+        self.expectations.push(expectation);
+        self
+    }
+
+    fn socket_path(&self) -> PathBuf {
+        self.dir.join("suricata.socket")
+    }
+
+    fn output_dir(&self) -> PathBuf {
+        self.dir.join("output")
+    }
+
+    fn eve_path(&self) -> PathBuf {
+        self.output_dir().join("eve.json")
+    }
+
+    /// Runs Suricata over the pcap, leaving its EVE output in `eve_path`.
+    fn run_suricata(&self) -> Result<(), Error> {
+        let mut command = Command::new(&self.suricata);
+        if let Some(ref config) = self.suricata_config {
+            command.arg("-c").arg(config);
+        }
+        let mut suricata = command
+            .arg(format!("--unix-socket={}", self.socket_path().display()))
+            .arg("-l").arg(&self.dir)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| Error::from(format!("Failed to start {}: {}", self.suricata.display(), e)))?;
+        let result = self.process_pcap(&mut suricata);
+        let _ = suricata.kill();
+        suricata.wait()?;
+        result
+    }
+
+    fn process_pcap(&self, suricata: &mut Child) -> Result<(), Error> {
+        let deadline = Instant::now() + self.deadline;
+        let mut socket = loop {
+            match UnixStream::connect(self.socket_path()) {
+                Ok(socket) => break SuricataSocket::handshake(socket)?,
+                Err(_) if Instant::now() < deadline => {
+                    if let Some(status) = suricata.try_wait()? {
+                        bail!("Suricata exited with {} before opening its socket", status);
+                    }
+                    std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                }
+                Err(e) => bail!("Failed to connect to Suricata's socket {}: {}", self.socket_path().display(), e)
+            }
+        };
+        let pcap = std::fs::canonicalize(&self.pcap)?;
+        socket.command("pcap-file", Some(json!({
+            "filename": pcap.display().to_string(),
+            "output-dir": self.output_dir().display().to_string()
+        })))?;
+        // The pcap is queued, then current while processed
+        loop {
+            let queued = socket.command("pcap-file-number", None)?;
+            let current = socket.command("pcap-current", None)?;
+            if queued == json!(0) && current == json!("None") {
+                break
+            }
+            if Instant::now() >= deadline {
+                bail!("Timed out waiting for Suricata to process {}", self.pcap.display());
+            }
+            std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        // Outputs are flushed on shutdown
+        socket.command("shutdown", None)?;
+        while suricata.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                bail!("Timed out waiting for Suricata to shut down");
+            }
+            std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        Ok( () )
+    }
+
+    /// Ships the EVE output to the end and waits for the shipper to exit.
+    fn run_shipper(&self) -> Result<(), Error> {
+        let mut child = Command::new(&self.binary)
+            .arg("--eve-file").arg(self.eve_path())
+            .arg("--checkpoint-dir").arg(self.dir.join("checkpoints"))
+            .arg("--kafka").arg(&self.kafka_servers)
+            .args(&self.extra_flags)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| Error::from(format!("Failed to start {}: {}", self.binary.display(), e)))?;
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if !status.success() {
+                    bail!("Shipper exited with {}", status);
+                }
+                return Ok( () )
+            }
+            if started.elapsed() >= self.deadline {
+                let _ = child.kill();
+                child.wait()?;
+                bail!("Timed out waiting for the shipper to ship {}", self.eve_path().display());
+            }
+            std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    /// Runs the pcap through Suricata and the shipper, then checks the expected topics.
+    pub fn run(&self) -> Result<SimulationOutcome, Error> {
+        info!("Simulating {} through {} and {}", self.pcap.display(), self.suricata.display(), self.binary.display());
+        let _ = std::fs::remove_dir_all(&self.dir);
+        std::fs::create_dir_all(self.output_dir())?;
+        std::fs::create_dir_all(self.dir.join("checkpoints"))?;
+
+        let result = self.run_suricata().and_then(|_| {
+            if !self.eve_path().exists() {
+                bail!("Suricata wrote no {}, is its eve-log output enabled?", self.eve_path().display());
+            }
+            self.run_shipper()
+        });
+        let result = result.and_then(|_| {
+            let mut outcome = SimulationOutcome::default();
+            for expectation in self.expectations.iter() {
+                let records = testkit::read_topic(&self.kafka_servers, &expectation.topic, self.deadline)?;
+                outcome.failures.extend(expectation.check(&records));
+                outcome.records.push( (expectation.topic.clone(), records) );
+            }
+            Ok(outcome)
+        });
+        let _ = std::fs::remove_dir_all(&self.dir);
+        result
+    }
+}
+
+/// Client of Suricata's unix socket protocol: a version handshake, then one JSON command and
+/// reply at a time.
+struct SuricataSocket {
+    stream: UnixStream
+}
+
+impl SuricataSocket {
+    fn handshake(stream: UnixStream) -> Result<SuricataSocket, Error> {
+        let mut socket = SuricataSocket {
+            stream: stream
+        };
+        socket.stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        socket.request(&json!({"version": "0.2"}))?;
+        Ok(socket)
+    }
+
+    /// Sends `command`, returning the message of its reply.
+    fn command(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, Error> {
+        let request = match arguments {
+            Some(arguments) => json!({"command": command, "arguments": arguments}),
+            None => json!({"command": command})
+        };
+        self.request(&request)
+    }
+
+    fn request(&mut self, request: &Value) -> Result<Value, Error> {
+        self.stream.write_all(request.to_string().as_bytes())?;
+        // Replies aren't delimited, so read until they parse
+        let mut received = vec![];
+        let mut buf = [0u8; 4096];
+        let reply: Value = loop {
+            let read = self.stream.read(&mut buf)?;
+            if read == 0 {
+                bail!("Suricata closed its socket");
+            }
+            received.extend_from_slice(&buf[..read]);
+            if let Ok(reply) = serde_json::from_slice(&received) {
+                break reply
+            }
+        };
+        if reply.get("return").and_then(Value::as_str) != Some("OK") {
+            bail!("Suricata rejected {}: {}", request, reply.get("message").unwrap_or(&Value::Null));
+        }
+        Ok(reply.get("message").cloned().unwrap_or(Value::Null))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &str) -> Record {
+        Record {
+            partition: 0,
+            offset: 0,
+            key: Some(key.as_bytes().to_vec()),
+            payload: Some(br#"{"event_type":"alert"}"#.to_vec())
+        }
+    }
+
+    #[test]
+    fn checks_counts_and_keys() {
+        let records = vec![record("10.0.0.1"), record("10.0.0.2")];
+
+        assert!(Expectation::topic("sim-alert").with_count(2).with_key("10.0.0.2").check(&records).is_empty());
+        assert_eq!(
+            Expectation::topic("sim-alert").with_count(3).with_key("10.0.0.3").check(&records),
+            vec!["sim-alert has 2 records, expected 3".to_string(), "sim-alert has no record keyed 10.0.0.3".to_string()]
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    eve,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    metrics::{
+        Counter,
+        QueueGauge,
+        Registry
+    }
+};
+use std::{
+    self,
+    collections::HashSet,
+    sync::mpsc::{
+        self,
+        RecvTimeoutError,
+        SyncSender,
+        TrySendError
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Destination other than Kafka that events are written to in batches, from a thread of its own
+/// since the clients block.
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error>;
+
+    /// Called once the pipeline has stopped, after the last batch.
+    fn close(&mut self) -> Result<(), Error> {
+        Ok( () )
+    }
+}
+
+/// How events are pushed to Redis, here rather than with `RedisSink` so `--redis-mode` parses in
+/// builds without the sinks feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedisMode {
+    /// `XADD` to a stream, readable later by dashboards
+    Stream,
+    /// `PUBLISH` to a channel, seen only by current subscribers
+    Publish
+}
+
+impl std::str::FromStr for RedisMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RedisMode, Error> {
+        match s {
+            "stream" | "xadd" => Ok(RedisMode::Stream),
+            "publish" | "pubsub" => Ok(RedisMode::Publish),
+            _ => Err(Error::from_kind(ErrorKind::InvalidRedisMode(s.to_string())))
+        }
+    }
+}
+
+/// Event types a sink receives; all of them when `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SinkRoute {
+    event_types: Option<HashSet<String>>
+}
+
+impl SinkRoute {
+    pub fn all() -> SinkRoute {
+        SinkRoute::default()
+    }
+
+    /// Parses a comma separated list of event types, `*` or empty meaning all of them.
+    pub fn parse(s: &str) -> SinkRoute {
+        let event_types: HashSet<String> = s.split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if event_types.is_empty() || event_types.contains("*") {
+            SinkRoute::all()
+        } else {
+            SinkRoute {
+                event_types: Some(event_types)
+            }
+        }
+    }
+
+    pub fn matches(&self, msg: &[u8]) -> bool {
+        match self.event_types {
+            None => true,
+            Some(ref types) => eve::event_type(msg).map(|t| types.contains(t)).unwrap_or(false)
+        }
+    }
+}
+
+/// How a sink thread batches events.
+#[derive(Debug, Clone)]
+pub struct SinkConfig {
+    /// Events buffered for the sink before further events are dropped
+    pub capacity: usize,
+    pub batch_size: usize,
+    pub flush_interval: Duration
+}
+
+impl Default for SinkConfig {
+    fn default() -> SinkConfig {
+        SinkConfig {
+            capacity: 10000,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1)
+        }
+    }
+}
+
+/// Sending side of a sink thread. Events are dropped rather than blocking the pipeline when the
+/// sink falls behind, counted in `sink.<name>.dropped`.
+pub struct SinkHandle {
+    route: SinkRoute,
+    sender: SyncSender<Vec<u8>>,
+    queue: QueueGauge,
+    dropped: Counter
+}
+
+impl SinkHandle {
+    /// Starts a thread writing the events sent to the handle to `sink`; the thread closes the
+    /// sink and exits once the handle is dropped.
+    pub fn spawn<K>(mut sink: K, route: SinkRoute, config: SinkConfig, registry: &Registry) -> SinkHandle
+        where K: Sink + 'static
+    {
+        let name = sink.name().to_string();
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(config.capacity);
+        let queue = registry.queue(&format!("sink.{}", name));
+        let written = registry.counter(&format!("sink.{}.written", name));
+        let failed = registry.counter(&format!("sink.{}.failed", name));
+        let received = queue.clone();
+
+        std::thread::spawn(move || {
+            let mut batch = vec![];
+            let mut deadline = Instant::now() + config.flush_interval;
+            loop {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+                let disconnected = match receiver.recv_timeout(timeout) {
+                    Ok(msg) => {
+                        received.sub(1);
+                        batch.push(msg);
+                        if batch.len() < config.batch_size {
+                            continue
+                        }
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true
+                };
+                if !batch.is_empty() {
+                    match sink.write(&batch) {
+                        Ok(()) => written.add(batch.len()),
+                        Err(e) => {
+                            error!("Failed to write {} events to {}: {}", batch.len(), name, e);
+                            failed.add(batch.len());
+                        }
+                    }
+                    batch.clear();
+                }
+                deadline = Instant::now() + config.flush_interval;
+                if disconnected {
+                    if let Err(e) = sink.close() {
+                        error!("Failed to close {}: {}", name, e);
+                    }
+                    return
+                }
+            }
+        });
+
+        SinkHandle {
+            route: route,
+            sender: sender,
+            queue: queue,
+            dropped: registry.counter(&format!("sink.{}.dropped", name))
+        }
+    }
+
+    pub fn send(&self, msg: &[u8]) {
+        if !self.route.matches(msg) {
+            return
+        }
+        match self.sender.try_send(msg.to_vec()) {
+            Ok(()) => self.queue.add(1),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => self.dropped.incr()
+        }
+    }
+}
+
+/// Passes events through unchanged, copying them to every sink whose route they match.
+pub struct SinkTap<S> {
+    inner: S,
+    sinks: Vec<SinkHandle>
+}
+
+impl<S> SinkTap<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(inner: S) -> SinkTap<S> {
+        SinkTap {
+            inner: inner,
+            sinks: vec![]
+        }
+    }
+
+    pub fn with_sink(mut self, sink: SinkHandle) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl<S> Stream for SinkTap<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(msg) => {
+                for sink in self.sinks.iter() {
+                    sink.send(msg.as_ref());
+                }
+                Ok(Async::Ready(Some(msg)))
+            }
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream
+    };
+    use std::sync::{
+        Arc,
+        Mutex
+    };
+
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        closed: Arc<Mutex<bool>>
+    }
+
+    impl Sink for RecordingSink {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn write(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+            self.written.lock().expect("Lock poisoned").extend(batch.iter().cloned());
+            Ok( () )
+        }
+
+        fn close(&mut self) -> Result<(), Error> {
+            *self.closed.lock().expect("Lock poisoned") = true;
+            Ok( () )
+        }
+    }
+
+    #[test]
+    fn parses_routes() {
+        let route = SinkRoute::parse("alert, dns");
+
+        assert!(route.matches(br#"{"event_type":"alert"}"#));
+        assert!(!route.matches(br#"{"event_type":"flow"}"#));
+        assert!(!route.matches(b"not json"));
+        assert_eq!(SinkRoute::parse("*"), SinkRoute::all());
+        assert!(SinkRoute::parse("").matches(br#"{"event_type":"flow"}"#));
+    }
+
+    #[test]
+    fn copies_routed_events_to_sinks() {
+        let registry = Registry::default();
+        let sink = RecordingSink::default();
+        let handle = SinkHandle::spawn(sink.clone(), SinkRoute::parse("alert"), SinkConfig::default(), &registry);
+        let events = vec![
+            br#"{"event_type":"alert"}"#.to_vec(),
+            br#"{"event_type":"flow"}"#.to_vec()
+        ];
+
+        let passed = SinkTap::new(stream::iter_ok::<_, ()>(events.clone()))
+            .with_sink(handle)
+            .collect()
+            .wait()
+            .expect("Failed to tap");
+
+        assert_eq!(passed, events);
+        for _ in 0..100 {
+            if *sink.closed.lock().expect("Lock poisoned") {
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*sink.written.lock().expect("Lock poisoned"), vec![events[0].clone()]);
+        assert!(registry.counter_values().contains(&("sink.recording.written".to_string(), 1)));
+    }
+
+    #[test]
+    fn parses_redis_modes() {
+        assert_eq!("xadd".parse::<RedisMode>().expect("Failed to parse"), RedisMode::Stream);
+        assert_eq!("publish".parse::<RedisMode>().expect("Failed to parse"), RedisMode::Publish);
+        assert!("lpush".parse::<RedisMode>().is_err());
+    }
+}
@@ -0,0 +1,617 @@
+use super::{
+    chrono::{
+        DateTime,
+        Duration,
+        FixedOffset,
+        Utc
+    },
+    errors::{
+        Error,
+        ErrorKind
+    },
+    flate2::read::MultiGzDecoder,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    runtime::Delay,
+    serde_json::{
+        self,
+        Value
+    },
+    tokio::{
+        io::AsyncRead,
+        net::{
+            TcpListener,
+            tcp
+        }
+    },
+    tokio_uds::{
+        self,
+        UnixListener
+    }
+};
+use std::{
+    self,
+    io::{
+        BufRead,
+        BufReader,
+        Read,
+        Seek,
+        SeekFrom
+    },
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::{
+        Path,
+        PathBuf
+    },
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::Instant
+};
+
+/// Where to begin reading a file source on startup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartPosition {
+    Start,
+    End,
+    /// Offset saved in the checkpoint store, or the start of the file if there is none
+    Resume,
+    /// First event whose timestamp is no older than this
+    Since(Duration)
+}
+
+impl FromStr for StartPosition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StartPosition, Error> {
+        let invalid = || Error::from_kind(ErrorKind::InvalidStartPosition(s.to_string()));
+        match s {
+            "start" => Ok(StartPosition::Start),
+            "end" => Ok(StartPosition::End),
+            "resume" | "resume-checkpoint" => Ok(StartPosition::Resume),
+            _ if s.starts_with("time:-") => {
+                let spec = &s["time:-".len()..];
+                if spec.len() < 2 {
+                    return Err(invalid())
+                }
+                let (amount, unit) = spec.split_at(spec.len() - 1);
+                let amount = amount.parse::<i64>().map_err(|_| invalid())?;
+                let duration = match unit {
+                    "s" => Duration::seconds(amount),
+                    "m" => Duration::minutes(amount),
+                    "h" => Duration::hours(amount),
+                    "d" => Duration::days(amount),
+                    _ => return Err(invalid())
+                };
+                Ok(StartPosition::Since(duration))
+            }
+            _ => Err(invalid())
+        }
+    }
+}
+
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f%z").ok()
+}
+
+/// Date of an event as `YYYY.MM.DD`, from its timestamp or else the current time.
+pub fn event_date(event: &Value) -> String {
+    event.get("timestamp")
+        .and_then(Value::as_str)
+        .and_then(parse_timestamp)
+        .map(|ts| ts.format("%Y.%m.%d").to_string())
+        .unwrap_or_else(|| Utc::now().format("%Y.%m.%d").to_string())
+}
+
+/// Drops events until the first one with a timestamp at or after `cutoff`, then passes
+/// everything through.
+pub struct SinceFilter<S> {
+    inner: S,
+    cutoff: DateTime<Utc>,
+    reached: bool
+}
+
+impl<S> SinceFilter<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(inner: S, cutoff: DateTime<Utc>) -> SinceFilter<S> {
+        SinceFilter {
+            inner: inner,
+            cutoff: cutoff,
+            reached: false
+        }
+    }
+
+    fn is_recent(&self, msg: &Vec<u8>) -> bool {
+        serde_json::from_slice::<Value>(msg).ok()
+            .and_then(|v| v.get("timestamp").and_then(Value::as_str).and_then(parse_timestamp))
+            .map(|ts| ts.with_timezone(&Utc) >= self.cutoff)
+            .unwrap_or(false)
+    }
+}
+
+impl<S> Stream for SinceFilter<S>
+    where S: Stream,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if self.reached || self.is_recent(msg.as_ref()) {
+                        self.reached = true;
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Adapts a blocking reader (file, decompressor) to `AsyncRead`. Reads never return
+/// `WouldBlock`, so this is only suitable for finite local files.
+pub struct BlockingRead<R: Read> {
+    inner: R
+}
+
+impl<R: Read> BlockingRead<R> {
+    pub fn new(inner: R) -> BlockingRead<R> {
+        BlockingRead {
+            inner: inner
+        }
+    }
+}
+
+impl<R: Read> Read for BlockingRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Read> AsyncRead for BlockingRead<R> {}
+
+pub fn is_gzip<R: BufRead>(reader: &mut R) -> Result<bool, Error> {
+    let buf = reader.fill_buf()?;
+    Ok(buf.len() >= GZIP_MAGIC.len() && buf[..GZIP_MAGIC.len()] == GZIP_MAGIC)
+}
+
+/// Wraps `reader` in a gzip decoder if its content starts with the gzip magic bytes, so archived
+/// logs can be read without any extra configuration.
+pub fn decompressed<R: Read + Send + 'static>(reader: R) -> Result<Box<Read + Send>, Error> {
+    let mut buffered = BufReader::new(reader);
+    if is_gzip(&mut buffered)? {
+        debug!("Detected gzip content");
+        Ok(Box::new(MultiGzDecoder::new(buffered)))
+    } else {
+        Ok(Box::new(buffered))
+    }
+}
+
+/// Opens a file source at `offset` bytes into its content. Compressed files cannot be seeked,
+/// so offsets are skipped over in the decompressed stream instead.
+pub fn open_eve_file<P: AsRef<std::path::Path>>(path: P, offset: u64) -> Result<BlockingRead<Box<Read + Send>>, Error> {
+    Ok(BlockingRead::new(open_at(path.as_ref(), offset)?))
+}
+
+/// Opens a file source to read from `offset` up to `end`, e.g. the backlog behind where a tailer
+/// started.
+pub fn open_eve_range<P: AsRef<std::path::Path>>(path: P, offset: u64, end: u64) -> Result<BlockingRead<Box<Read + Send>>, Error> {
+    let reader = open_at(path.as_ref(), offset)?;
+    Ok(BlockingRead::new(Box::new(reader.take(end.saturating_sub(offset)))))
+}
+
+fn open_at(path: &std::path::Path, offset: u64) -> Result<Box<Read + Send>, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut probe = BufReader::new(std::fs::File::open(path)?);
+    if offset == 0 || is_gzip(&mut probe)? {
+        let mut reader = decompressed(file)?;
+        std::io::copy(&mut reader.by_ref().take(offset), &mut std::io::sink())?;
+        Ok(reader)
+    } else {
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Length of the content of a file source, for starting at its end. Only compressed files are
+/// read through; plain ones are sized from their metadata.
+pub fn content_length<P: AsRef<std::path::Path>>(path: P) -> Result<u64, Error> {
+    let file = std::fs::File::open(path.as_ref())?;
+    let mut probe = BufReader::new(std::fs::File::open(path.as_ref())?);
+    if is_gzip(&mut probe)? {
+        let mut reader = decompressed(file)?;
+        Ok(std::io::copy(&mut reader, &mut std::io::sink())?)
+    } else {
+        Ok(file.metadata()?.len())
+    }
+}
+
+/// Events read from one connection.
+pub type Connection = Box<Stream<Item=Vec<u8>, Error=Error> + Send>;
+
+/// Accepts connections from Suricata's EVE output and merges the events read from all of them.
+/// A connection that ends or fails is dropped, and the listener keeps accepting, so Suricata
+/// reconnects after a restart without restarting the shipper; a connection left half open by a
+/// crashed Suricata doesn't hold back the new one.
+pub struct ListenerSource<I> {
+    incoming: Option<I>,
+    open: Box<FnMut(I::Item) -> Connection + Send>,
+    connections: Vec<Connection>
+}
+
+/// Listens on a unix domain socket, for `filetype: unix_stream` outputs.
+pub type UnixSocketSource = ListenerSource<tokio_uds::Incoming>;
+
+/// Listens on a TCP address, e.g. for EVE forwarded by a syslog relay.
+pub type TcpSource = ListenerSource<tcp::Incoming>;
+
+impl<I> ListenerSource<I>
+    where I: Stream<Error=std::io::Error>
+{
+    /// `open` sets up the reader of each accepted connection.
+    pub fn new<F>(incoming: I, open: F) -> ListenerSource<I>
+        where F: FnMut(I::Item) -> Connection + Send + 'static
+    {
+        ListenerSource {
+            incoming: Some(incoming),
+            open: Box::new(open),
+            connections: vec![]
+        }
+    }
+
+    pub fn connections(&self) -> usize { self.connections.len() }
+}
+
+impl ListenerSource<tokio_uds::Incoming> {
+    /// Binds `path`, replacing a socket left behind by an earlier run.
+    pub fn bind<P, F>(path: P, open: F) -> Result<UnixSocketSource, Error>
+        where P: AsRef<Path>,
+              F: FnMut(tokio_uds::UnixStream) -> Connection + Send + 'static
+    {
+        if path.as_ref().exists() {
+            std::fs::remove_file(path.as_ref())?;
+        }
+        let listener = UnixListener::bind(path.as_ref())?;
+        info!("Listening for events on {}", path.as_ref().display());
+        Ok(ListenerSource::new(listener.incoming(), open))
+    }
+}
+
+impl ListenerSource<tcp::Incoming> {
+    pub fn bind<F>(addr: &SocketAddr, open: F) -> Result<TcpSource, Error>
+        where F: FnMut(tcp::TcpStream) -> Connection + Send + 'static
+    {
+        let listener = TcpListener::bind(addr)?;
+        info!("Listening for events on {}", addr);
+        Ok(ListenerSource::new(listener.incoming(), open))
+    }
+}
+
+impl<I> Stream for ListenerSource<I>
+    where I: Stream<Error=std::io::Error>
+{
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut accepting = false;
+        if let Some(mut incoming) = self.incoming.take() {
+            loop {
+                match incoming.poll() {
+                    Ok(Async::Ready(Some(connection))) => {
+                        debug!("Accepted a connection, {} already open", self.connections.len());
+                        self.connections.push((self.open)(connection));
+                    }
+                    Ok(Async::Ready(None)) => break,
+                    Ok(Async::NotReady) => {
+                        accepting = true;
+                        break
+                    }
+                    // e.g. too many open files; the listener itself is still usable
+                    Err(e) => warn!("Failed to accept a connection: {}", e)
+                }
+            }
+            if accepting {
+                self.incoming = Some(incoming);
+            }
+        }
+
+        let mut i = 0;
+        while i < self.connections.len() {
+            match self.connections[i].poll() {
+                Ok(Async::Ready(Some(msg))) => return Ok(Async::Ready(Some(msg))),
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(None)) => {
+                    info!("Connection closed, waiting for Suricata to reconnect");
+                    self.connections.swap_remove(i);
+                }
+                Err(e) => {
+                    warn!("Dropping connection after read error: {}", e);
+                    self.connections.swap_remove(i);
+                }
+            }
+        }
+
+        if accepting || !self.connections.is_empty() {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Device and inode of a file, which change when a log is rotated by renaming it.
+fn identity(metadata: &std::fs::Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Follows a growing eve.json like `tail -F`, as an `AsyncRead` for `EveReader`. At the end of the
+/// file it polls for more data every `poll_interval`, reopens the path once the file was rotated
+/// (renamed and recreated, as by logrotate before Suricata reopens its outputs), and starts over
+/// when the file was truncated in place (copytruncate).
+pub struct FileTailer {
+    path: PathBuf,
+    file: std::fs::File,
+    identity: (u64, u64),
+    offset: u64,
+    poll_interval: std::time::Duration,
+    delay: Option<Delay>,
+    position: Option<Arc<AtomicUsize>>
+}
+
+impl FileTailer {
+    /// Opens `path` at `offset`, starting over if the file is shorter, e.g. because it was
+    /// rotated while the shipper was stopped.
+    pub fn open<P: AsRef<Path>>(path: P, offset: u64) -> Result<FileTailer, Error> {
+        let mut file = std::fs::File::open(path.as_ref())?;
+        let metadata = file.metadata()?;
+        let offset = if offset > metadata.len() {
+            warn!("{} is shorter than offset {}, reading from the start", path.as_ref().display(), offset);
+            0
+        } else {
+            offset
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(FileTailer {
+            path: path.as_ref().to_path_buf(),
+            file: file,
+            identity: identity(&metadata),
+            offset: offset,
+            poll_interval: std::time::Duration::from_millis(250),
+            delay: None,
+            position: None
+        })
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: std::time::Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Resets `position`, the checkpointed offset advanced by `EveReader`, when starting over on a
+    /// new or truncated file.
+    pub fn with_position(mut self, position: Arc<AtomicUsize>) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    pub fn offset(&self) -> u64 { self.offset }
+
+    fn restart(&mut self, file: std::fs::File, identity: (u64, u64)) -> Result<(), std::io::Error> {
+        self.file = file;
+        self.identity = identity;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.offset = 0;
+        if let Some(ref position) = self.position {
+            position.store(0, Ordering::SeqCst);
+        }
+        Ok( () )
+    }
+
+    /// At the end of the file, switches to a rotated or truncated file. Returns whether there may
+    /// be more to read.
+    fn check_rotation(&mut self) -> Result<bool, std::io::Error> {
+        let current = match std::fs::metadata(&self.path) {
+            Ok(m) => m,
+            // renamed, and not yet recreated
+            Err(_) => return Ok(false)
+        };
+        if identity(&current) != self.identity {
+            info!("{} was rotated, reopening", self.path.display());
+            let file = std::fs::File::open(&self.path)?;
+            let opened = file.metadata()?;
+            self.restart(file, identity(&opened))?;
+            return Ok(true)
+        }
+        if current.len() < self.offset {
+            info!("{} was truncated, reading from the start", self.path.display());
+            let file = self.file.try_clone()?;
+            let identity = self.identity;
+            self.restart(file, identity)?;
+            return Ok(true)
+        }
+        Ok(false)
+    }
+}
+
+impl Read for FileTailer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        loop {
+            if let Some(mut delay) = self.delay.take() {
+                match delay.poll() {
+                    Ok(Async::NotReady) => {
+                        self.delay = Some(delay);
+                        return Err(std::io::ErrorKind::WouldBlock.into())
+                    }
+                    Ok(Async::Ready(())) => {}
+                    Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Tail timer failed: {:?}", e)))
+                }
+            }
+            let read = self.file.read(buf)?;
+            if read > 0 {
+                self.offset += read as u64;
+                return Ok(read)
+            }
+            if !self.check_rotation()? {
+                self.delay = Some(Delay::new(Instant::now() + self.poll_interval));
+            }
+        }
+    }
+}
+
+impl AsyncRead for FileTailer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{
+        Compression,
+        write::GzEncoder
+    };
+    use std::io::Write;
+
+    const EVENTS: &'static str = "{\"event_type\":\"alert\"}\n{\"event_type\":\"flow\"}\n";
+
+    fn read_all(mut reader: Box<Read + Send>) -> String {
+        let mut out = String::new();
+        reader.read_to_string(&mut out).expect("Failed to read");
+        out
+    }
+
+    #[test]
+    fn passes_through_plain() {
+        let reader = decompressed(std::io::Cursor::new(EVENTS.as_bytes().to_vec())).expect("Failed to open");
+
+        assert_eq!(read_all(reader), EVENTS);
+    }
+
+    #[test]
+    fn decompresses_gzip() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(EVENTS.as_bytes()).expect("Failed to compress");
+        let compressed = encoder.finish().expect("Failed to compress");
+
+        let reader = decompressed(std::io::Cursor::new(compressed)).expect("Failed to open");
+
+        assert_eq!(read_all(reader), EVENTS);
+    }
+
+    #[test]
+    fn measures_content_length() {
+        let dir = std::env::temp_dir().join(format!("surikafka-length-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(EVENTS.as_bytes()).expect("Failed to compress");
+        std::fs::write(dir.join("eve.json"), EVENTS).expect("Failed to write");
+        std::fs::write(dir.join("eve.json.gz"), encoder.finish().expect("Failed to compress")).expect("Failed to write");
+
+        assert_eq!(content_length(dir.join("eve.json")).expect("Failed to measure"), EVENTS.len() as u64);
+        assert_eq!(content_length(dir.join("eve.json.gz")).expect("Failed to measure"), EVENTS.len() as u64);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn survives_reconnects() {
+        use futures::stream;
+
+        let incoming = stream::iter_ok::<_, std::io::Error>(vec![
+            Ok(vec![b"{\"n\":1}".to_vec()]),
+            Err("Connection reset"),
+            Ok(vec![b"{\"n\":2}".to_vec(), b"{\"n\":3}".to_vec()])
+        ]);
+        let source = ListenerSource::new(incoming, |connection: Result<Vec<Vec<u8>>, &'static str>| -> Connection {
+            match connection {
+                Ok(events) => Box::new(stream::iter_ok(events)),
+                Err(e) => Box::new(stream::once(Err(Error::from(e))))
+            }
+        });
+
+        let mut events: Vec<Vec<u8>> = source.wait().collect::<Result<_, _>>().expect("Source failed");
+        events.sort();
+
+        assert_eq!(events, vec![b"{\"n\":1}".to_vec(), b"{\"n\":2}".to_vec(), b"{\"n\":3}".to_vec()]);
+    }
+
+    #[test]
+    fn follows_rotation_and_truncation() {
+        let dir = std::env::temp_dir().join(format!("surikafka-tail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create dir");
+        let path = dir.join("eve.json");
+        std::fs::write(&path, "{\"n\":1}\n").expect("Failed to write");
+        let mut buf = [0u8; 64];
+
+        let mut tailer = FileTailer::open(&path, 0).expect("Failed to open");
+        assert_eq!(&buf[..tailer.read(&mut buf).expect("Failed to read")], b"{\"n\":1}\n");
+
+        std::fs::rename(&path, dir.join("eve.json.1")).expect("Failed to rotate");
+        std::fs::write(&path, "{\"n\":2}\n").expect("Failed to write");
+        assert_eq!(&buf[..tailer.read(&mut buf).expect("Failed to read")], b"{\"n\":2}\n");
+
+        std::fs::write(&path, "{}\n").expect("Failed to truncate");
+        assert_eq!(&buf[..tailer.read(&mut buf).expect("Failed to read")], b"{}\n");
+        assert_eq!(tailer.offset(), 3);
+
+        assert_eq!(FileTailer::open(&path, 100).expect("Failed to open").offset(), 0);
+        std::fs::remove_dir_all(&dir).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn parses_start_positions() {
+        assert_eq!("start".parse::<StartPosition>().expect("Failed to parse"), StartPosition::Start);
+        assert_eq!("end".parse::<StartPosition>().expect("Failed to parse"), StartPosition::End);
+        assert_eq!("resume-checkpoint".parse::<StartPosition>().expect("Failed to parse"), StartPosition::Resume);
+        assert_eq!(
+            "time:-15m".parse::<StartPosition>().expect("Failed to parse"),
+            StartPosition::Since(Duration::minutes(15))
+        );
+        assert!("time:-15y".parse::<StartPosition>().is_err());
+        assert!("time:-m".parse::<StartPosition>().is_err());
+        assert!("middle".parse::<StartPosition>().is_err());
+    }
+
+    #[test]
+    fn filters_events_before_cutoff() {
+        use futures::{
+            Future,
+            stream
+        };
+
+        let events = vec![
+            r#"{"timestamp":"2018-06-01T00:00:00.000000+0000"}"#.to_string().into_bytes(),
+            r#"{"timestamp":"2018-06-01T00:10:00.000000+0000"}"#.to_string().into_bytes(),
+            r#"{"timestamp":"2018-06-01T00:05:00.000000+0000"}"#.to_string().into_bytes()
+        ];
+        let cutoff = parse_timestamp("2018-06-01T00:05:00.000000+0000").expect("Failed to parse").with_timezone(&Utc);
+
+        let filtered = SinceFilter::new(stream::iter_ok::<_, ()>(events.clone()), cutoff)
+            .collect()
+            .wait()
+            .expect("Failed to filter");
+
+        assert_eq!(filtered, events[1..].to_vec());
+    }
+
+    #[test]
+    fn empty_is_not_gzip() {
+        let mut reader = BufReader::new(std::io::Cursor::new(vec![0x1f]));
+
+        assert!(!is_gzip(&mut reader).expect("Failed to check"));
+    }
+}
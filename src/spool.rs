@@ -0,0 +1,403 @@
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream,
+        task::{
+            self,
+            Task
+        }
+    },
+    journal::{
+        OpsJournal,
+        OpsKind
+    },
+    metrics::{
+        Counter,
+        QueueGauge,
+        Registry
+    }
+};
+use std::{
+    self,
+    fs::{
+        File,
+        OpenOptions
+    },
+    io::{
+        Read,
+        Seek,
+        SeekFrom,
+        Write
+    },
+    path::Path,
+    sync::{
+        Arc,
+        Mutex
+    }
+};
+
+const MAGIC: &'static [u8; 8] = b"SKSPOOL1";
+const HEADER_LEN: u64 = 48;
+const LEN_BYTES: u64 = 4;
+/// Length marking that the next record starts at the beginning of the ring.
+const WRAP: u32 = std::u32::MAX;
+/// Records the spool must have held for its emptying to be journaled as a drain, so short bursts
+/// the writer keeps up with aren't.
+const DRAIN_MIN_RECORDS: u64 = 1000;
+
+fn encode_u64(buf: &mut [u8], value: u64) {
+    for i in 0..8 {
+        buf[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+fn decode_u64(buf: &[u8]) -> u64 {
+    (0..8).fold(0, |value, i| value | (buf[i] as u64) << (8 * i))
+}
+
+/// Fixed size spool file used as a ring: once full, pushing a record overwrites the oldest
+/// records. Meant for sensors with small disks, where losing the oldest events of a long outage
+/// is better than filling the disk or stalling capture.
+pub struct RingSpool {
+    file: File,
+    capacity: u64,
+    head: u64,
+    tail: u64,
+    used: u64,
+    count: u64
+}
+
+impl RingSpool {
+    /// Opens the spool at `path`, keeping its records if it was created with the same capacity.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: u64) -> Result<RingSpool, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path.as_ref())?;
+        let mut header = [0u8; HEADER_LEN as usize];
+        let existing = file.metadata()?.len() == HEADER_LEN + capacity
+            && file.read_exact(&mut header).is_ok()
+            && &header[..8] == MAGIC
+            && decode_u64(&header[8..16]) == capacity;
+        let mut spool = RingSpool {
+            file: file,
+            capacity: capacity,
+            head: 0,
+            tail: 0,
+            used: 0,
+            count: 0
+        };
+        if existing {
+            spool.head = decode_u64(&header[16..24]);
+            spool.tail = decode_u64(&header[24..32]);
+            spool.used = decode_u64(&header[32..40]);
+            spool.count = decode_u64(&header[40..48]);
+            info!("Resuming spool {} with {} records", path.as_ref().display(), spool.count);
+        } else {
+            spool.file.set_len(HEADER_LEN + capacity)?;
+            spool.save()?;
+        }
+        Ok(spool)
+    }
+
+    pub fn len(&self) -> u64 { self.count }
+
+    pub fn is_empty(&self) -> bool { self.count == 0 }
+
+    fn save(&mut self) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[..8].copy_from_slice(MAGIC);
+        encode_u64(&mut header[8..16], self.capacity);
+        encode_u64(&mut header[16..24], self.head);
+        encode_u64(&mut header[24..32], self.tail);
+        encode_u64(&mut header[32..40], self.used);
+        encode_u64(&mut header[40..48], self.count);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        Ok( () )
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        self.file.write_all(data)?;
+        Ok( () )
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        self.file.read_exact(buf)?;
+        Ok( () )
+    }
+
+    /// Appends `record`, returning the number of old records overwritten to make room for it.
+    pub fn push(&mut self, record: &[u8]) -> Result<usize, Error> {
+        let needed = LEN_BYTES + record.len() as u64;
+        if needed > self.capacity {
+            bail!("Record of {} bytes doesn't fit a spool of {} bytes", record.len(), self.capacity);
+        }
+        let mut overwritten = 0;
+        loop {
+            let wrap = self.capacity - self.head < needed;
+            let waste = if wrap { self.capacity - self.head } else { 0 };
+            if self.capacity - self.used >= needed + waste {
+                break
+            }
+            self.take()?;
+            overwritten += 1;
+        }
+        if self.capacity - self.head < needed {
+            if self.capacity - self.head >= LEN_BYTES {
+                let head = self.head;
+                self.write_at(head, &[0xff; LEN_BYTES as usize])?;
+            }
+            self.used += self.capacity - self.head;
+            self.head = 0;
+        }
+        let len = record.len() as u32;
+        let mut framed = vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+        framed.extend_from_slice(record);
+        let head = self.head;
+        self.write_at(head, &framed)?;
+        self.head += needed;
+        self.used += needed;
+        self.count += 1;
+        self.save()?;
+        Ok(overwritten)
+    }
+
+    /// Removes and returns the oldest record.
+    pub fn pop(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let record = self.take()?;
+        if record.is_some() {
+            self.save()?;
+        }
+        Ok(record)
+    }
+
+    fn take(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.count == 0 {
+            return Ok(None)
+        }
+        let mut len_buf = [0u8; LEN_BYTES as usize];
+        if self.capacity - self.tail >= LEN_BYTES {
+            let tail = self.tail;
+            self.read_at(tail, &mut len_buf)?;
+        }
+        let mut len = len_buf.iter().rev().fold(0u32, |len, b| len << 8 | *b as u32);
+        if self.capacity - self.tail < LEN_BYTES || len == WRAP {
+            self.used -= self.capacity - self.tail;
+            self.tail = 0;
+            self.read_at(0, &mut len_buf)?;
+            len = len_buf.iter().rev().fold(0u32, |len, b| len << 8 | *b as u32);
+        }
+        let mut record = vec![0u8; len as usize];
+        let tail = self.tail;
+        self.read_at(tail + LEN_BYTES, &mut record)?;
+        self.tail += LEN_BYTES + len as u64;
+        self.used -= LEN_BYTES + len as u64;
+        self.count -= 1;
+        if self.count == 0 {
+            self.head = 0;
+            self.tail = 0;
+            self.used = 0;
+        }
+        Ok(Some(record))
+    }
+}
+
+struct Shared {
+    spool: RingSpool,
+    finished: bool,
+    error: Option<Error>,
+    drain: Option<Task>
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(task) = self.drain.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Reads events from a stream into the spool as fast as they arrive, so a stalled writer backs
+/// events up on disk rather than in the source. Spawn it alongside its `SpoolDrain`.
+pub struct SpoolFill<S> {
+    inner: S,
+    shared: Arc<Mutex<Shared>>,
+    overwritten: Counter,
+    records: QueueGauge
+}
+
+impl<S> Future for SpoolFill<S>
+    where S: Stream<Item=Vec<u8>, Error=Error>
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let polled = self.inner.poll();
+            let mut shared = self.shared.lock().expect("Spool lock poisoned");
+            match polled {
+                Ok(Async::Ready(Some(msg))) => {
+                    match shared.spool.push(&msg) {
+                        Ok(overwritten) => {
+                            if overwritten > 0 {
+                                warn!("Spool full, overwrote {} oldest records", overwritten);
+                                self.overwritten.add(overwritten);
+                                self.records.sub(overwritten);
+                            }
+                            self.records.add(1);
+                        }
+                        Err(e) => {
+                            shared.error = Some(e);
+                            shared.finished = true;
+                            shared.wake();
+                            return Ok(Async::Ready(()))
+                        }
+                    }
+                    shared.wake();
+                }
+                Ok(Async::Ready(None)) => {
+                    shared.finished = true;
+                    shared.wake();
+                    return Ok(Async::Ready(()))
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    shared.error = Some(e);
+                    shared.finished = true;
+                    shared.wake();
+                    return Ok(Async::Ready(()))
+                }
+            }
+        }
+    }
+}
+
+/// Events of the spool, oldest first. Ends once the filling stream has ended and the spool is
+/// empty.
+pub struct SpoolDrain {
+    shared: Arc<Mutex<Shared>>,
+    records: QueueGauge,
+    drained: u64,
+    peak: u64,
+    journal: Option<OpsJournal>
+}
+
+impl SpoolDrain {
+    /// Records in the ops journal whenever the spool empties after backing up at least
+    /// `DRAIN_MIN_RECORDS` records.
+    pub fn with_journal(mut self, journal: OpsJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+}
+
+impl Stream for SpoolDrain {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut shared = self.shared.lock().expect("Spool lock poisoned");
+        self.peak = self.peak.max(shared.spool.len());
+        if let Some(record) = shared.spool.pop()? {
+            self.records.sub(1);
+            self.drained += 1;
+            return Ok(Async::Ready(Some(record)))
+        }
+        if self.peak >= DRAIN_MIN_RECORDS {
+            info!("Drained {} spooled records", self.drained);
+            if let Some(ref journal) = self.journal {
+                journal.record(OpsKind::SpoolDrained, json!({"records": self.drained, "peak": self.peak}));
+            }
+        }
+        self.drained = 0;
+        self.peak = 0;
+        if let Some(e) = shared.error.take() {
+            return Err(e)
+        }
+        if shared.finished {
+            return Ok(Async::Ready(None))
+        }
+        shared.drain = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Splits `inner` around `spool`. Records already in the spool from an earlier run are drained
+/// first; `spool.overwritten` counts records lost to overwriting and `spool.records` the records
+/// held.
+pub fn spool<S>(inner: S, spool: RingSpool, registry: &Registry) -> (SpoolFill<S>, SpoolDrain)
+    where S: Stream<Item=Vec<u8>, Error=Error>
+{
+    let records = registry.queue("spool.records");
+    records.add(spool.len() as usize);
+    let shared = Arc::new(Mutex::new(Shared {
+        spool: spool,
+        finished: false,
+        error: None,
+        drain: None
+    }));
+    let fill = SpoolFill {
+        inner: inner,
+        shared: shared.clone(),
+        overwritten: registry.counter("spool.overwritten"),
+        records: records.clone()
+    };
+    let drain = SpoolDrain {
+        shared: shared,
+        records: records,
+        drained: 0,
+        peak: 0,
+        journal: None
+    };
+    (fill, drain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("surikafka-{}-{}.spool", name, std::process::id()))
+    }
+
+    #[test]
+    fn overwrites_oldest_records() {
+        let path = path("ring");
+        let _ = std::fs::remove_file(&path);
+        let mut spool = RingSpool::open(&path, 32).expect("Failed to open");
+
+        assert_eq!(spool.push(b"aaaaaaaaaa").expect("Failed to push"), 0);
+        assert_eq!(spool.push(b"bbbbbbbbbb").expect("Failed to push"), 0);
+        assert_eq!(spool.push(b"cccccccccc").expect("Failed to push"), 1);
+        assert_eq!(spool.pop().expect("Failed to pop"), Some(b"bbbbbbbbbb".to_vec()));
+        assert_eq!(spool.push(b"dddd").expect("Failed to push"), 0);
+        assert!(spool.push(&[0u8; 40]).is_err());
+
+        let mut reopened = RingSpool::open(&path, 32).expect("Failed to reopen");
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.pop().expect("Failed to pop"), Some(b"cccccccccc".to_vec()));
+        assert_eq!(reopened.pop().expect("Failed to pop"), Some(b"dddd".to_vec()));
+        assert_eq!(reopened.pop().expect("Failed to pop"), None);
+        std::fs::remove_file(&path).expect("Failed to clean up");
+    }
+
+    #[test]
+    fn drains_spooled_events() {
+        let path = path("drain");
+        let _ = std::fs::remove_file(&path);
+        let registry = Registry::default();
+        let events = vec![b"one".to_vec(), b"two".to_vec()];
+        let (fill, drain) = spool(stream::iter_ok(events.clone()), RingSpool::open(&path, 1024).expect("Failed to open"), &registry);
+
+        fill.wait().expect("Fill failed");
+
+        assert_eq!(drain.collect().wait().expect("Drain failed"), events);
+        assert_eq!(registry.queue("spool.records").depth(), 0);
+        std::fs::remove_file(&path).expect("Failed to clean up");
+    }
+}
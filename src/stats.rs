@@ -1,15 +1,26 @@
+use super::breaker::BreakerState;
 use std;
 
 pub struct Stats {
     alert_count: usize,
     alert_length: usize,
-    produce_time: std::time::Duration
+    produce_time: std::time::Duration,
+    failure_count: usize,
+    breaker_state: BreakerState,
+    breaker_transitions: usize
 }
 
 impl Stats {
     pub fn alert_count(&self) -> usize { self.alert_count }
     pub fn alert_length(&self) -> usize { self.alert_length }
     pub fn produce_time(&self) -> std::time::Duration { self.produce_time }
+    pub fn failure_count(&self) -> usize { self.failure_count }
+    pub fn breaker_state(&self) -> BreakerState { self.breaker_state }
+    pub fn breaker_transitions(&self) -> usize { self.breaker_transitions }
+
+    pub fn is_empty(&self) -> bool {
+        self.alert_count == 0 && self.failure_count == 0
+    }
 
     pub fn mark(
         &mut self,
@@ -21,6 +32,17 @@ impl Stats {
         self.produce_time += produce_time;
         self
     }
+
+    pub fn mark_failure(&mut self) -> &mut Self {
+        self.failure_count += 1;
+        self
+    }
+
+    pub fn set_breaker(&mut self, state: BreakerState, transitions: usize) -> &mut Self {
+        self.breaker_state = state;
+        self.breaker_transitions = transitions;
+        self
+    }
 }
 
 impl Default for Stats {
@@ -28,7 +50,10 @@ impl Default for Stats {
         Stats {
             alert_count: 0,
             alert_length: 0,
-            produce_time: std::time::Duration::from_secs(0)
+            produce_time: std::time::Duration::from_secs(0),
+            failure_count: 0,
+            breaker_state: BreakerState::Closed,
+            breaker_transitions: 0
         }
     }
-}
\ No newline at end of file
+}
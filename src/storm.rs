@@ -0,0 +1,289 @@
+use super::{
+    chrono::Utc,
+    eve,
+    futures::{
+        Async,
+        Future,
+        Poll,
+        Stream
+    },
+    metrics::{
+        Counter,
+        Gauge,
+        Registry
+    },
+    runtime::Delay,
+    serde_json::{
+        self,
+        Value
+    },
+    suppress::SuppressionKey
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+fn to_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// Alerts allowed per key: `rate` a second on average, with bursts of up to `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: usize
+}
+
+impl Bucket {
+    fn admit(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = if now > self.updated { to_secs(now - self.updated) } else { 0.0 };
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.limited += 1;
+            false
+        }
+    }
+}
+
+/// Token buckets by key, counting what each key had dropped since the last summary. Keys beyond
+/// `max_keys` evict the buckets with nothing to summarize, which are full again soon after a key
+/// goes quiet, so memory stays bounded while a scan rotates through source addresses.
+pub struct KeyedLimiter {
+    limit: RateLimit,
+    max_keys: usize,
+    buckets: HashMap<String, Bucket>
+}
+
+impl KeyedLimiter {
+    pub fn new(limit: RateLimit, max_keys: usize) -> KeyedLimiter {
+        KeyedLimiter {
+            limit: limit,
+            max_keys: max_keys.max(1),
+            buckets: HashMap::new()
+        }
+    }
+
+    pub fn admit(&mut self, key: &str, now: Instant) -> bool {
+        if !self.buckets.contains_key(key) && self.buckets.len() >= self.max_keys {
+            self.buckets.retain(|_, b| b.limited > 0);
+        }
+        let limit = self.limit;
+        self.buckets.entry(key.to_string())
+            .or_insert_with(|| Bucket { tokens: limit.burst, updated: now, limited: 0 })
+            .admit(&limit, now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Keys that dropped alerts since the last call, with how many, resetting the counts.
+    pub fn take_limited(&mut self) -> Vec<(String, usize)> {
+        let mut limited: Vec<(String, usize)> = self.buckets.iter_mut()
+            .filter(|&(_, ref b)| b.limited > 0)
+            .map(|(k, b)| (k.clone(), std::mem::replace(&mut b.limited, 0)))
+            .collect();
+        limited.sort();
+        limited
+    }
+}
+
+/// A `surikafka_rate_limited` record telling consumers how many alerts of `key` were dropped
+/// over the last `interval`.
+pub fn summary(key_fields: &str, key: &str, dropped: usize, interval: Duration) -> Vec<u8> {
+    let event = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "event_type": "surikafka_rate_limited",
+        "key_fields": key_fields,
+        "key": key,
+        "dropped": dropped,
+        "interval_secs": interval.as_secs()
+    });
+    serde_json::to_vec(&event).expect("Summary is always serializable")
+}
+
+/// Rate limits alerts by key, e.g. `src_ip` or `alert.signature_id`, so a single scanning host
+/// raising millions of identical alerts can't take the whole produce budget from everything
+/// else. Alerts over the limit are dropped and counted in `storm.dropped`; every `interval` that
+/// a key dropped any, a summary record with the count is emitted in their place. Alerts missing
+/// a key field are never limited.
+pub struct StormLimiter<S> {
+    inner: S,
+    key: SuppressionKey,
+    key_fields: String,
+    limiter: KeyedLimiter,
+    interval: Duration,
+    flush: Option<Delay>,
+    summaries: VecDeque<Vec<u8>>,
+    inner_done: bool,
+    dropped: Counter,
+    keys: Gauge
+}
+
+impl<S> StormLimiter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, key_fields: &str, key: SuppressionKey, limiter: KeyedLimiter, interval: Duration, registry: &Registry) -> StormLimiter<S> {
+        StormLimiter {
+            inner: inner,
+            key: key,
+            key_fields: key_fields.to_string(),
+            limiter: limiter,
+            interval: interval,
+            flush: None,
+            summaries: VecDeque::new(),
+            inner_done: false,
+            dropped: registry.counter("storm.dropped"),
+            keys: registry.gauge("storm.keys")
+        }
+    }
+
+    fn admit(&mut self, msg: &Vec<u8>) -> bool {
+        if eve::event_type(msg) != Some("alert") {
+            return true
+        }
+        let key = match serde_json::from_slice::<Value>(msg).ok().and_then(|v| self.key.key(&v)) {
+            Some(k) => k,
+            None => return true
+        };
+        let admitted = self.limiter.admit(&key, Instant::now());
+        self.keys.set(self.limiter.len());
+        if !admitted {
+            self.dropped.incr();
+            if self.flush.is_none() {
+                self.flush = Some(Delay::new(Instant::now() + self.interval));
+            }
+        }
+        admitted
+    }
+
+    fn summarize(&mut self) {
+        for (key, dropped) in self.limiter.take_limited() {
+            warn!("Rate limited {} alerts of {} {} over {:?}", dropped, self.key_fields, key, self.interval);
+            self.summaries.push_back(summary(&self.key_fields, &key, dropped, self.interval));
+        }
+    }
+}
+
+impl<S> Stream for StormLimiter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let due = match self.flush.as_mut().map(|d| d.poll()) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(e)) => {
+                error!("Rate limit summary timer failed: {:?}", e);
+                true
+            }
+            _ => false
+        };
+        if due {
+            self.flush = None;
+            self.summarize();
+        }
+        loop {
+            if let Some(summary) = self.summaries.pop_front() {
+                return Ok(Async::Ready(Some(summary)))
+            }
+            if self.inner_done {
+                return Ok(Async::Ready(None))
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if self.admit(&msg) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => {
+                    // Summarize what was dropped before the end rather than losing the counts
+                    self.inner_done = true;
+                    self.flush = None;
+                    self.summarize();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        futures::stream,
+        tokio
+    };
+
+    #[test]
+    fn limits_each_key() {
+        let mut limiter = KeyedLimiter::new(RateLimit { rate: 1.0, burst: 2.0 }, 10);
+        let start = Instant::now();
+
+        assert!(limiter.admit("10.0.0.1", start));
+        assert!(limiter.admit("10.0.0.1", start));
+        assert!(!limiter.admit("10.0.0.1", start));
+        assert!(limiter.admit("10.0.0.2", start));
+        assert!(limiter.admit("10.0.0.1", start + Duration::from_millis(1500)));
+        assert!(!limiter.admit("10.0.0.1", start + Duration::from_millis(1500)));
+
+        assert_eq!(limiter.take_limited(), vec![ ("10.0.0.1".to_string(), 2) ]);
+        assert!(limiter.take_limited().is_empty());
+    }
+
+    #[test]
+    fn evicts_quiet_keys() {
+        let mut limiter = KeyedLimiter::new(RateLimit { rate: 1.0, burst: 1.0 }, 2);
+        let now = Instant::now();
+
+        limiter.admit("a", now);
+        limiter.admit("a", now);
+        limiter.admit("b", now);
+        limiter.admit("c", now);
+
+        assert_eq!(limiter.len(), 2);
+        assert_eq!(limiter.take_limited(), vec![ ("a".to_string(), 1) ]);
+    }
+
+    #[test]
+    fn summarizes_dropped_alerts() {
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+        let registry = Registry::default();
+        let mut events: Vec<Vec<u8>> = (0..5)
+            .map(|_| br#"{"event_type":"alert","src_ip":"10.0.0.1"}"#.to_vec())
+            .collect();
+        events.push(br#"{"event_type":"alert","src_ip":"10.0.0.2"}"#.to_vec());
+        events.push(br#"{"event_type":"flow","src_ip":"10.0.0.1"}"#.to_vec());
+        let key = SuppressionKey::parse("src_ip").expect("Failed to parse");
+        let limiter = KeyedLimiter::new(RateLimit { rate: 0.0, burst: 2.0 }, 100);
+
+        let limited = StormLimiter::new(stream::iter_ok::<_, ()>(events), "src_ip", key, limiter, Duration::from_secs(60), &registry);
+        let out = rt.block_on(limited.collect()).expect("Stream failed");
+
+        assert_eq!(out.len(), 5);
+        let summary: Value = serde_json::from_slice(&out[4]).expect("Failed to parse");
+        assert_eq!(summary["event_type"], "surikafka_rate_limited");
+        assert_eq!(summary["key"], "10.0.0.1");
+        assert_eq!(summary["dropped"], 3);
+        assert!(registry.counter_values().contains(&("storm.dropped".to_string(), 3)));
+    }
+}
@@ -0,0 +1,311 @@
+use super::{
+    errors::Error,
+    eve,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    key::KeyGenerator,
+    metrics::Counter,
+    rdkafka::{
+        ClientConfig,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        }
+    },
+    serde_json::{
+        self,
+        Value
+    },
+    transform
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        HashSet
+    },
+    sync::{
+        Arc,
+        RwLock
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const METADATA_TIMEOUT_MS: i32 = 5000;
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+/// Fields of an alert making up the key of a suppression, e.g. `alert.signature_id,src_ip`. The
+/// same key is expected on the records of the suppression topic, so whatever produces them can
+/// use this as its `KeyGenerator`. Values are joined with `|`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuppressionKey {
+    fields: Vec<Vec<String>>
+}
+
+impl Default for SuppressionKey {
+    fn default() -> SuppressionKey {
+        SuppressionKey {
+            fields: vec![ vec!["alert".to_string(), "signature_id".to_string()] ]
+        }
+    }
+}
+
+impl SuppressionKey {
+    /// Parses a comma separated list of dotted paths.
+    pub fn parse(s: &str) -> Result<SuppressionKey, Error> {
+        let fields = transform::split_list(s).iter()
+            .map(|f| transform::dotted_path(f))
+            .collect::<Result<Vec<_>, _>>()?;
+        if fields.is_empty() {
+            bail!("Suppression key {} has no fields", s);
+        }
+        Ok(SuppressionKey {
+            fields: fields
+        })
+    }
+
+    /// Key of `event`, `None` if any of the fields is missing.
+    pub fn key(&self, event: &Value) -> Option<String> {
+        let mut parts = vec![];
+        for path in self.fields.iter() {
+            let value = path.iter().fold(Some(event), |v, segment| v.and_then(|v| v.get(segment.as_str())))?;
+            parts.push(match *value {
+                Value::String(ref s) => s.clone(),
+                Value::Null | Value::Array(_) | Value::Object(_) => return None,
+                ref v => v.to_string()
+            });
+        }
+        Some(parts.join("|"))
+    }
+}
+
+impl KeyGenerator for SuppressionKey {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        serde_json::from_slice::<Value>(msg).ok()
+            .and_then(|v| self.key(&v))
+            .map(|k| k.into_bytes())
+            .unwrap_or_else(Vec::new)
+    }
+}
+
+/// Suppressions in force, shared between the suppression listener and the pipeline.
+#[derive(Clone, Default)]
+pub struct SuppressionTable {
+    keys: Arc<RwLock<HashSet<String>>>
+}
+
+impl SuppressionTable {
+    /// Applies a record of the suppression topic: a tombstone or `{"suppress": false}` lifts the
+    /// suppression of `key`, any other record puts it in force.
+    pub fn apply(&self, key: &str, payload: Option<&[u8]>) {
+        let lifted = match payload {
+            None => true,
+            Some(p) => serde_json::from_slice::<Value>(p).ok()
+                .and_then(|v| v.get("suppress").and_then(Value::as_bool))
+                .map(|suppress| !suppress)
+                .unwrap_or(false)
+        };
+        let mut keys = self.keys.write().expect("Suppressions lock poisoned");
+        if lifted {
+            keys.remove(key);
+        } else {
+            keys.insert(key.to_string());
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.read().expect("Suppressions lock poisoned").contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().expect("Suppressions lock poisoned").len()
+    }
+
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.keys) > 1
+    }
+}
+
+fn apply_record<M: Message>(table: &SuppressionTable, record: &M) {
+    match record.key().map(std::str::from_utf8) {
+        Some(Ok(key)) => table.apply(key, record.payload()),
+        _ => debug!("Ignoring suppression record without a text key at offset {}", record.offset())
+    }
+}
+
+/// Consumes a compacted suppression topic, so suppressions decided centrally survive shipper
+/// restarts. `spawn` reads the topic up to its current end before returning, priming the table
+/// before anything is produced, then keeps applying new records on a background thread, since
+/// the consumer blocks, until the table is dropped elsewhere.
+pub struct SuppressionListener;
+
+impl SuppressionListener {
+    /// Returns the number of suppressions in force once primed. Gives up priming after
+    /// `timeout`, carrying on with the suppressions read so far.
+    pub fn spawn(client: &ClientConfig, topic: &str, table: SuppressionTable, timeout: Duration) -> Result<usize, Error> {
+        let consumer: BaseConsumer = client.clone()
+            .set("group.id", "surikafka-suppressions")
+            .set("enable.auto.commit", "false")
+            .create()
+            .map_err(|e| Error::from(format!("Failed to create suppression consumer: {:?}", e)))?;
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT_MS)
+            .map_err(|e| Error::from(format!("Failed to fetch metadata: {:?}", e)))?;
+        let mut assignment = TopicPartitionList::new();
+        let mut remaining = HashMap::new();
+        for t in metadata.topics().iter().filter(|t| t.name() == topic) {
+            for p in t.partitions() {
+                assignment.add_partition_offset(topic, p.id(), Offset::Beginning);
+                let (low, high) = consumer.fetch_watermarks(topic, p.id(), METADATA_TIMEOUT_MS)
+                    .map_err(|e| Error::from(format!("Failed to fetch watermarks of {}/{}: {:?}", topic, p.id(), e)))?;
+                if high > low {
+                    remaining.insert(p.id(), high - 1);
+                }
+            }
+        }
+        consumer.assign(&assignment)
+            .map_err(|e| Error::from(format!("Failed to assign partitions: {:?}", e)))?;
+
+        let deadline = Instant::now() + timeout;
+        while !remaining.is_empty() {
+            if Instant::now() >= deadline {
+                warn!("Timed out priming suppressions from {}, {} partitions not read to the end", topic, remaining.len());
+                break
+            }
+            match consumer.poll(POLL_TIMEOUT_MS) {
+                None => (),
+                Some(Err(e)) => warn!("Failed to consume {}: {:?}", topic, e),
+                Some(Ok(m)) => {
+                    apply_record(&table, &m);
+                    if remaining.get(&m.partition()).map(|&last| m.offset() >= last).unwrap_or(false) {
+                        remaining.remove(&m.partition());
+                    }
+                }
+            }
+        }
+        let primed = table.len();
+
+        let topic = topic.to_string();
+        std::thread::spawn(move || {
+            while table.is_shared() {
+                match consumer.poll(POLL_TIMEOUT_MS) {
+                    None => (),
+                    Some(Err(e)) => warn!("Failed to consume {}: {:?}", topic, e),
+                    Some(Ok(m)) => apply_record(&table, &m)
+                }
+            }
+        });
+        Ok(primed)
+    }
+}
+
+/// Drops alerts whose suppression key is in the table, counting them in `suppress.dropped`.
+pub struct SuppressionFilter<S> {
+    inner: S,
+    table: SuppressionTable,
+    key: SuppressionKey,
+    dropped: Counter
+}
+
+impl<S> SuppressionFilter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, table: SuppressionTable, key: SuppressionKey) -> SuppressionFilter<S> {
+        SuppressionFilter {
+            inner: inner,
+            table: table,
+            key: key,
+            dropped: Counter::new("suppress.dropped")
+        }
+    }
+
+    pub fn with_drop_counter(mut self, counter: Counter) -> Self {
+        self.dropped = counter;
+        self
+    }
+
+    fn suppressed(&self, msg: &Vec<u8>) -> bool {
+        if eve::event_type(msg) != Some("alert") {
+            return false
+        }
+        serde_json::from_slice::<Value>(msg).ok()
+            .and_then(|v| self.key.key(&v))
+            .map(|k| self.table.contains(&k))
+            .unwrap_or(false)
+    }
+}
+
+impl<S> Stream for SuppressionFilter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if !self.suppressed(&msg) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                    self.dropped.incr();
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream
+    };
+
+    #[test]
+    fn keys_alerts() {
+        let key = SuppressionKey::parse("alert.signature_id, src_ip").expect("Failed to parse");
+        let alert = json!({"event_type": "alert", "src_ip": "10.0.0.1", "alert": {"signature_id": 2000001}});
+
+        assert_eq!(key.key(&alert), Some("2000001|10.0.0.1".to_string()));
+        assert_eq!(key.key(&json!({"alert": {"signature_id": 1}})), None);
+        assert_eq!(SuppressionKey::default().key(&alert), Some("2000001".to_string()));
+        assert!(SuppressionKey::parse(" , ").is_err());
+        assert!(SuppressionKey::parse("alert..signature_id").is_err());
+    }
+
+    #[test]
+    fn drops_suppressed_alerts() {
+        let table = SuppressionTable::default();
+        table.apply("1", Some(br#"{"suppress":true}"#));
+        table.apply("2", Some(br#"{"suppress":true}"#));
+        table.apply("2", None);
+        table.apply("3", Some(br#"{"suppress":false}"#));
+        let events = vec![
+            br#"{"event_type":"alert","alert":{"signature_id":1}}"#.to_vec(),
+            br#"{"event_type":"alert","alert":{"signature_id":2}}"#.to_vec(),
+            br#"{"event_type":"dns","alert":{"signature_id":1}}"#.to_vec()
+        ];
+        let dropped = Counter::new("suppress.dropped");
+
+        let kept: Vec<Vec<u8>> = SuppressionFilter::new(stream::iter_ok::<_, ()>(events), table.clone(), SuppressionKey::default())
+            .with_drop_counter(dropped.clone())
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(table.len(), 1);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(dropped.value(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    metrics::{
+        Counter,
+        Registry
+    },
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    collections::HashMap,
+    time::Instant
+};
+
+/// Name metrics of events whose token isn't configured are counted under.
+pub const UNKNOWN_TENANT: &'static str = "unknown";
+
+/// What one tenant may produce a second. Up to a second's worth may be sent in a burst.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub messages: f64,
+    pub bytes: f64
+}
+
+impl Quota {
+    /// Parses `messages:bytes` a second, e.g. `5000:10000000`.
+    pub fn parse(s: &str) -> Result<Quota, Error> {
+        let mut parts = s.splitn(2, ':').map(|p| p.trim().parse::<f64>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(messages)), Some(Ok(bytes))) if messages > 0.0 && bytes > 0.0 => Ok(Quota {
+                messages: messages,
+                bytes: bytes
+            }),
+            _ => bail!("Invalid tenant quota {}, expected messages:bytes a second", s)
+        }
+    }
+}
+
+/// Parses a tenant as `token=name:messages:bytes`, e.g. `3f9a...=soc-east:5000:10000000`.
+pub fn parse_tenant(s: &str) -> Result<(String, String, Quota), Error> {
+    let mut parts = s.splitn(2, '=');
+    let (token, rest) = match (parts.next().map(str::trim), parts.next()) {
+        (Some(token), Some(rest)) if !token.is_empty() => (token, rest),
+        _ => bail!("Invalid tenant {}, expected token=name:messages:bytes", s)
+    };
+    let mut parts = rest.splitn(2, ':');
+    match (parts.next().map(str::trim), parts.next()) {
+        (Some(name), Some(quota)) if !name.is_empty() => Ok( (token.to_string(), name.to_string(), Quota::parse(quota)?) ),
+        _ => bail!("Invalid tenant {}, expected token=name:messages:bytes", s)
+    }
+}
+
+struct Tenant {
+    quota: Quota,
+    messages: f64,
+    bytes: f64,
+    updated: Instant,
+    sent: Counter,
+    sent_bytes: Counter,
+    dropped: Counter
+}
+
+impl Tenant {
+    fn new(name: &str, quota: Quota, registry: &Registry) -> Tenant {
+        Tenant {
+            quota: quota,
+            messages: quota.messages,
+            bytes: quota.bytes,
+            updated: Instant::now(),
+            sent: registry.counter(&format!("tenant.{}.messages", name)),
+            sent_bytes: registry.counter(&format!("tenant.{}.bytes", name)),
+            dropped: registry.counter(&format!("tenant.{}.dropped", name))
+        }
+    }
+
+    /// Takes a message of `length` bytes from the allowances if both have room for it.
+    fn admit(&mut self, length: usize, now: Instant) -> bool {
+        let elapsed = if now > self.updated { now - self.updated } else { Default::default() };
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.messages = (self.messages + elapsed * self.quota.messages).min(self.quota.messages);
+        self.bytes = (self.bytes + elapsed * self.quota.bytes).min(self.quota.bytes);
+        self.updated = now;
+        // A message bigger than a whole second's bytes is let through on a full allowance rather
+        // than never
+        let fits = self.bytes >= length as f64 || self.bytes >= self.quota.bytes;
+        if self.messages >= 1.0 && fits {
+            self.messages -= 1.0;
+            self.bytes -= length as f64;
+            self.sent.incr();
+            self.sent_bytes.add(length);
+            true
+        } else {
+            self.dropped.incr();
+            false
+        }
+    }
+}
+
+/// Produce quotas of the tenants sharing the shipper, each identified by the session token its
+/// events carry in `field`. Events without a token aren't limited.
+pub struct TenantQuotas {
+    field: String,
+    registry: Registry,
+    tenants: HashMap<String, Tenant>,
+    unknown: Option<Tenant>
+}
+
+impl TenantQuotas {
+    pub fn new(field: &str, registry: &Registry) -> TenantQuotas {
+        TenantQuotas {
+            field: field.to_string(),
+            registry: registry.clone(),
+            tenants: HashMap::new(),
+            unknown: None
+        }
+    }
+
+    /// Limits the events carrying `token` to `quota`, counted in `tenant.<name>.messages`,
+    /// `tenant.<name>.bytes`, and `tenant.<name>.dropped`.
+    pub fn with_tenant(mut self, token: &str, name: &str, quota: Quota) -> Self {
+        let tenant = Tenant::new(name, quota, &self.registry);
+        self.tenants.insert(token.to_string(), tenant);
+        self
+    }
+
+    /// Limits the events of tokens without a quota of their own, together, to `quota`. They
+    /// aren't limited without one.
+    pub fn with_unknown(mut self, quota: Quota) -> Self {
+        self.unknown = Some(Tenant::new(UNKNOWN_TENANT, quota, &self.registry));
+        self
+    }
+
+    /// The event to send, without its token, or `None` if its tenant is over quota.
+    pub fn admit(&mut self, msg: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+        let mut event: Value = match serde_json::from_slice(&msg) {
+            Ok(event) => event,
+            Err(_) => return Some(msg)
+        };
+        let token = match event.as_object_mut().and_then(|e| e.remove(&self.field)) {
+            Some(Value::String(token)) => token,
+            Some(_) | None => return Some(msg)
+        };
+        let admitted = match self.tenants.get_mut(&token) {
+            Some(tenant) => tenant.admit(msg.len(), now),
+            None => self.unknown.as_mut().map(|t| t.admit(msg.len(), now)).unwrap_or(true)
+        };
+        if !admitted {
+            return None
+        }
+        Some(serde_json::to_vec(&event).unwrap_or(msg))
+    }
+}
+
+/// Drops the events of tenants over their produce quota, so one tenant's flood can't starve the
+/// others of the shared producer. Events are dropped rather than held back, since holding back
+/// one tenant's events would hold back everything behind them too.
+pub struct TenantLimiter<S> {
+    inner: S,
+    quotas: TenantQuotas
+}
+
+impl<S> TenantLimiter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, quotas: TenantQuotas) -> TenantLimiter<S> {
+        TenantLimiter {
+            inner: inner,
+            quotas: quotas
+        }
+    }
+}
+
+impl<S> Stream for TenantLimiter<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    if let Some(msg) = self.quotas.admit(msg, Instant::now()) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_each_tenant_to_its_quota() {
+        let registry = Registry::default();
+        let (token, name, quota) = parse_tenant("abc=soc-east:2:1000").expect("Failed to parse");
+        let mut quotas = TenantQuotas::new("tenant_token", &registry)
+            .with_tenant(&token, &name, quota)
+            .with_unknown(Quota::parse("1:1000").expect("Failed to parse"));
+        let now = Instant::now();
+        let east = br#"{"event_type":"alert","tenant_token":"abc"}"#.to_vec();
+        let other = br#"{"event_type":"alert","tenant_token":"xyz"}"#.to_vec();
+
+        assert_eq!(quotas.admit(east.clone(), now), Some(br#"{"event_type":"alert"}"#.to_vec()));
+        assert!(quotas.admit(east.clone(), now).is_some());
+        assert_eq!(quotas.admit(east.clone(), now), None);
+        assert!(quotas.admit(other.clone(), now).is_some());
+        assert_eq!(quotas.admit(other.clone(), now), None);
+        assert!(quotas.admit(br#"{"event_type":"alert"}"#.to_vec(), now).is_some());
+        assert!(quotas.admit(east.clone(), now + Duration::from_millis(500)).is_some());
+
+        let counters: HashMap<String, usize> = registry.counter_values().into_iter().collect();
+        assert_eq!(counters["tenant.soc-east.messages"], 3);
+        assert_eq!(counters["tenant.soc-east.dropped"], 1);
+        assert_eq!(counters["tenant.unknown.dropped"], 1);
+        assert!(parse_tenant("abc=soc-east:2").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Exactly-once test kit for the file source. `ChaosRun` writes a corpus of numbered events to an
+//! EVE file, runs the shipper binary on it with `--start-position resume-checkpoint`, kills it with
+//! SIGKILL at random points and restarts it until it has shipped the whole file, then reads the
+//! topic back and reports which events are missing or were produced more than once. Needs a
+//! reachable Kafka cluster, e.g. the one in docker-compose.yaml:
+//!
+//! ```ignore
+//! let outcome = ChaosRun::new("target/debug/surikafka", "localhost:9092", "eve-chaos")
+//!     .with_events(10000)
+//!     .with_kills(5)
+//!     .run()?;
+//! outcome.assert_exactly_once();
+//! ```
+
+use super::{
+    errors::Error,
+    rdkafka::{
+        ClientConfig,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        }
+    },
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    self,
+    collections::{
+        BTreeMap,
+        HashMap
+    },
+    io::Write,
+    path::PathBuf,
+    process::{
+        Child,
+        Command,
+        Stdio
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH
+    }
+};
+
+/// Field numbering the events of the corpus, and the synthetic events of `ordering` per key.
+pub const SEQUENCE_FIELD: &'static str = "surikafka_seq";
+
+const METADATA_TIMEOUT_MS: i32 = 5000;
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+/// Xorshift generator for kill points; seeded so a failing run can be repeated.
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 { 0 } else { self.next() % bound }
+    }
+}
+
+/// EVE line of event `seq` of the corpus.
+pub fn corpus_event(seq: u64) -> Vec<u8> {
+    let mut line = serde_json::to_vec(&json!({
+        "timestamp": "2018-01-01T00:00:00.000000+0000",
+        "event_type": "alert",
+        "src_ip": "10.0.0.1",
+        "dest_ip": "10.0.0.2",
+        "alert": {"signature_id": 2000000 + seq % 100},
+        SEQUENCE_FIELD: seq
+    })).expect("Corpus event is always serializable");
+    line.push(b'\n');
+    line
+}
+
+/// Events read back from the topic that don't match the corpus exactly once.
+#[derive(Debug, Default, PartialEq)]
+pub struct Outcome {
+    /// Events of the corpus never produced
+    pub missing: Vec<u64>,
+    /// Events produced more than once, with the number of copies
+    pub duplicated: BTreeMap<u64, usize>,
+    /// Records that aren't corpus events
+    pub unexpected: usize,
+    /// Times the shipper was killed
+    pub kills: usize
+}
+
+impl Outcome {
+    /// Compares the sequence numbers read back with a corpus of `events` events.
+    pub fn compare<I: IntoIterator<Item=u64>>(events: u64, produced: I) -> Outcome {
+        let mut copies = HashMap::new();
+        let mut unexpected = 0;
+        for seq in produced {
+            if seq < events {
+                *copies.entry(seq).or_insert(0) += 1;
+            } else {
+                unexpected += 1;
+            }
+        }
+        Outcome {
+            missing: (0..events).filter(|seq| !copies.contains_key(seq)).collect(),
+            duplicated: copies.into_iter().filter(|&(_, n)| n > 1).collect(),
+            unexpected: unexpected,
+            kills: 0
+        }
+    }
+
+    pub fn is_exactly_once(&self) -> bool {
+        self.missing.is_empty() && self.duplicated.is_empty() && self.unexpected == 0
+    }
+
+    pub fn assert_exactly_once(&self) {
+        assert!(
+            self.is_exactly_once(),
+            "After {} kills, {} events missing (first {:?}), {} duplicated (first {:?}), {} unexpected",
+            self.kills,
+            self.missing.len(), self.missing.iter().take(10).collect::<Vec<_>>(),
+            self.duplicated.len(), self.duplicated.iter().take(10).collect::<Vec<_>>(),
+            self.unexpected
+        );
+    }
+}
+
+/// Ships a known corpus while killing and restarting the shipper, see the module docs.
+pub struct ChaosRun {
+    binary: PathBuf,
+    kafka_servers: String,
+    topic: String,
+    dir: PathBuf,
+    events: u64,
+    kills: usize,
+    max_uptime: Duration,
+    deadline: Duration,
+    seed: u64,
+    extra_flags: Vec<String>
+}
+
+impl ChaosRun {
+    /// Runs `binary` against `kafka_servers`, producing to `topic`, which should be new or empty.
+    pub fn new(binary: &str, kafka_servers: &str, topic: &str) -> ChaosRun {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(1) | 1;
+        ChaosRun {
+            binary: PathBuf::from(binary),
+            kafka_servers: kafka_servers.to_string(),
+            topic: topic.to_string(),
+            dir: std::env::temp_dir().join(format!("surikafka-chaos-{}-{}", topic, std::process::id())),
+            events: 10000,
+            kills: 5,
+            max_uptime: Duration::from_secs(3),
+            deadline: Duration::from_secs(300),
+            seed: seed,
+            extra_flags: vec![]
+        }
+    }
+
+    /// Events in the corpus.
+    pub fn with_events(mut self, events: u64) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Times to kill the shipper before letting it finish.
+    pub fn with_kills(mut self, kills: usize) -> Self {
+        self.kills = kills;
+        self
+    }
+
+    /// Longest the shipper runs before being killed; each kill happens at a random point within it.
+    pub fn with_max_uptime(mut self, max_uptime: Duration) -> Self {
+        self.max_uptime = max_uptime;
+        self
+    }
+
+    /// Longest the final, uninterrupted run may take to ship the rest of the corpus.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Seed of the kill points, logged by `run` so failures can be repeated.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed | 1;
+        self
+    }
+
+    /// Working directory of the corpus and checkpoints, removed after the run.
+    pub fn with_dir(mut self, dir: &str) -> Self {
+        self.dir = PathBuf::from(dir);
+        self
+    }
+
+    /// Passes `flag` to the shipper, e.g. to test delivery settings.
+    pub fn with_flag(mut self, flag: &str) -> Self {
+        self.extra_flags.push(flag.to_string());
+        self
+    }
+
+    fn corpus_path(&self) -> PathBuf {
+        self.dir.join("eve.json")
+    }
+
+    fn write_corpus(&self) -> Result<(), Error> {
+        let _ = std::fs::remove_dir_all(&self.dir);
+        std::fs::create_dir_all(self.dir.join("checkpoints"))?;
+        let mut file = std::io::BufWriter::new(std::fs::File::create(self.corpus_path())?);
+        for seq in 0..self.events {
+            file.write_all(&corpus_event(seq))?;
+        }
+        file.flush()?;
+        Ok( () )
+    }
+
+    fn spawn(&self) -> Result<Child, Error> {
+        Command::new(&self.binary)
+            .arg("--eve-file").arg(self.corpus_path())
+            .arg("--start-position").arg("resume-checkpoint")
+            .arg("--checkpoint-dir").arg(self.dir.join("checkpoints"))
+            .arg("--kafka").arg(&self.kafka_servers)
+            .arg("--topic").arg(&self.topic)
+            .args(&self.extra_flags)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|e| Error::from(format!("Failed to start {}: {}", self.binary.display(), e)))
+    }
+
+    /// Ships the corpus, then reads the topic back and compares it with the corpus.
+    pub fn run(&self) -> Result<Outcome, Error> {
+        info!("Chaos run of {} events with {} kills, seed {}", self.events, self.kills, self.seed);
+        self.write_corpus()?;
+        let mut rng = Xorshift(self.seed);
+        let max_uptime_ms = self.max_uptime.as_secs() * 1000 + self.max_uptime.subsec_nanos() as u64 / 1000000;
+        let mut kills = 0;
+        for _ in 0..self.kills {
+            let mut child = self.spawn()?;
+            std::thread::sleep(Duration::from_millis(rng.below(max_uptime_ms)));
+            match child.try_wait()? {
+                Some(status) => info!("Shipper exited with {} before being killed", status),
+                None => {
+                    child.kill()?;
+                    kills += 1;
+                }
+            }
+            child.wait()?;
+        }
+
+        let mut child = self.spawn()?;
+        let started = Instant::now();
+        let mut produced = self.read_back()?;
+        while (produced.len() as u64) < self.events && started.elapsed() < self.deadline {
+            if child.try_wait()?.is_some() {
+                break
+            }
+            std::thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64));
+            produced = self.read_back()?;
+        }
+        let _ = child.kill();
+        child.wait()?;
+        let produced = self.read_back()?;
+
+        let mut outcome = Outcome::compare(self.events, produced);
+        outcome.kills = kills;
+        let _ = std::fs::remove_dir_all(&self.dir);
+        Ok(outcome)
+    }
+
+    /// Sequence numbers of the committed records of the topic, read from the beginning up to the
+    /// current high watermarks.
+    pub fn read_back(&self) -> Result<Vec<u64>, Error> {
+        // Anything that isn't a corpus event counts as unexpected
+        Ok(read_topic(&self.kafka_servers, &self.topic, self.deadline)?.into_iter()
+            .map(|r| r.payload
+                .and_then(|p| serde_json::from_slice::<Value>(&p).ok())
+                .and_then(|v| v.get(SEQUENCE_FIELD).and_then(Value::as_u64))
+                .unwrap_or(std::u64::MAX))
+            .collect())
+    }
+}
+
+/// A record read back from a topic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Option<Vec<u8>>
+}
+
+fn consumer(client: &ClientConfig) -> Result<BaseConsumer, Error> {
+    client.clone()
+        .set("group.id", "surikafka-testkit")
+        .set("enable.auto.commit", "false")
+        .set("isolation.level", "read_committed")
+        .create()
+        .map_err(|e| Error::from(format!("Failed to create consumer: {:?}", e)))
+}
+
+fn partition_watermarks(consumer: &BaseConsumer, topic: &str) -> Result<Vec<(i32, i64, i64)>, Error> {
+    let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT_MS)
+        .map_err(|e| Error::from(format!("Failed to fetch metadata: {:?}", e)))?;
+    let mut watermarks = vec![];
+    for t in metadata.topics().iter().filter(|t| t.name() == topic) {
+        for p in t.partitions() {
+            let (low, high) = consumer.fetch_watermarks(topic, p.id(), METADATA_TIMEOUT_MS)
+                .map_err(|e| Error::from(format!("Failed to fetch watermarks of {}/{}: {:?}", topic, p.id(), e)))?;
+            watermarks.push( (p.id(), low, high) );
+        }
+    }
+    Ok(watermarks)
+}
+
+/// Low and high watermark of every partition of `topic`.
+pub fn watermarks(client: &ClientConfig, topic: &str) -> Result<Vec<(i32, i64, i64)>, Error> {
+    partition_watermarks(&consumer(client)?, topic)
+}
+
+/// The committed records of `topic`, read from the beginning up to the current high watermarks,
+/// failing if that takes longer than `timeout`.
+pub fn read_topic(kafka_servers: &str, topic: &str, timeout: Duration) -> Result<Vec<Record>, Error> {
+    let mut client = ClientConfig::new();
+    client.set("bootstrap.servers", kafka_servers);
+    let start: Vec<(i32, i64)> = watermarks(&client, topic)?.into_iter()
+        .map(|(partition, low, _)| (partition, low))
+        .collect();
+    read_from(&client, topic, &start, timeout)
+}
+
+/// The committed records of the partitions of `topic` in `start`, read from their offsets there
+/// up to the current high watermarks, failing if that takes longer than `timeout`.
+pub fn read_from(client: &ClientConfig, topic: &str, start: &[(i32, i64)], timeout: Duration) -> Result<Vec<Record>, Error> {
+    let consumer = consumer(client)?;
+    let mut assignment = TopicPartitionList::new();
+    let mut remaining = HashMap::new();
+    for (partition, _, high) in partition_watermarks(&consumer, topic)? {
+        if let Some(&(_, offset)) = start.iter().find(|&&(p, _)| p == partition) {
+            assignment.add_partition_offset(topic, partition, Offset::Offset(offset));
+            if high > offset {
+                remaining.insert(partition, high);
+            }
+        }
+    }
+    consumer.assign(&assignment)
+        .map_err(|e| Error::from(format!("Failed to assign partitions: {:?}", e)))?;
+
+    let mut records = vec![];
+    let deadline = Instant::now() + timeout;
+    while !remaining.is_empty() {
+        if Instant::now() >= deadline {
+            bail!("Timed out reading back {}, {} partitions not read to the end", topic, remaining.len());
+        }
+        match consumer.poll(POLL_TIMEOUT_MS) {
+            // Transaction markers take offsets too, so the last offset may never be consumed
+            None => {
+                let position = consumer.position()
+                    .map_err(|e| Error::from(format!("Failed to get position: {:?}", e)))?;
+                for p in position.elements() {
+                    if let (Offset::Offset(offset), Some(&high)) = (p.offset(), remaining.get(&p.partition())) {
+                        if offset >= high {
+                            remaining.remove(&p.partition());
+                        }
+                    }
+                }
+            }
+            Some(Err(e)) => warn!("Failed to consume {}: {:?}", topic, e),
+            Some(Ok(m)) => {
+                records.push(Record {
+                    partition: m.partition(),
+                    offset: m.offset(),
+                    key: m.key().map(|k| k.to_vec()),
+                    payload: m.payload().map(|p| p.to_vec())
+                });
+                if remaining.get(&m.partition()).map(|&high| m.offset() + 1 >= high).unwrap_or(false) {
+                    remaining.remove(&m.partition());
+                }
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_with_corpus() {
+        let outcome = Outcome::compare(5, vec![0, 1, 1, 3, 4, 1, 9]);
+
+        assert_eq!(outcome.missing, vec![2]);
+        assert_eq!(outcome.duplicated.into_iter().collect::<Vec<_>>(), vec![(1, 3)]);
+        assert_eq!(outcome.unexpected, 1);
+        assert!(Outcome::compare(3, vec![2, 0, 1]).is_exactly_once());
+    }
+
+    #[test]
+    fn numbers_corpus_events() {
+        let event: Value = serde_json::from_slice(&corpus_event(42)).expect("Failed to parse");
+
+        assert_eq!(event[SEQUENCE_FIELD], 42);
+        assert_eq!(event["event_type"], "alert");
+    }
+}
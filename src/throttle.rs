@@ -0,0 +1,190 @@
+use super::{
+    metrics::{
+        Counter,
+        Registry
+    },
+    rdkafka::{
+        ClientContext,
+        statistics::Statistics
+    },
+    warm::BrokerStates
+};
+use std::{
+    self,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicUsize,
+            Ordering
+        }
+    },
+    time::Duration
+};
+
+const MIN_PACING_MS: u64 = 1;
+
+/// Broker throttle time reported in librdkafka statistics, shared between the client context
+/// and the writer.
+#[derive(Clone)]
+pub struct ThrottleSignal {
+    throttle_ms: Arc<AtomicUsize>,
+    events: Counter
+}
+
+impl ThrottleSignal {
+    /// `events` counts transitions into throttling.
+    pub fn new(events: Counter) -> ThrottleSignal {
+        ThrottleSignal {
+            throttle_ms: Arc::new(AtomicUsize::new(0)),
+            events: events
+        }
+    }
+
+    pub fn set(&self, throttle_ms: usize) {
+        let previous = self.throttle_ms.swap(throttle_ms, Ordering::SeqCst);
+        if previous == 0 && throttle_ms > 0 {
+            warn!("Brokers are throttling produce requests by {}ms", throttle_ms);
+            self.events.incr();
+        } else if previous > 0 && throttle_ms == 0 {
+            info!("Brokers stopped throttling produce requests");
+        }
+    }
+
+    pub fn throttle(&self) -> Duration {
+        Duration::from_millis(self.throttle_ms.load(Ordering::SeqCst) as u64)
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttle_ms.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Client context that feeds broker throttle times from the statistics callback (enabled with
+/// `statistics.interval.ms`) into a `ThrottleSignal`, and optionally the rest of the statistics
+/// into `rdkafka.*` gauges.
+pub struct ShipperContext {
+    throttle: ThrottleSignal,
+    registry: Option<Registry>,
+    broker_states: Option<BrokerStates>
+}
+
+impl ShipperContext {
+    pub fn new(throttle: ThrottleSignal) -> ShipperContext {
+        ShipperContext {
+            throttle: throttle,
+            registry: None,
+            broker_states: None
+        }
+    }
+
+    /// Sets gauges for the client's queue and totals, and for each broker's queues, errors, and
+    /// average round trip time in microseconds.
+    pub fn with_registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Records the connection state of each broker in `states`.
+    pub fn with_broker_states(mut self, states: BrokerStates) -> Self {
+        self.broker_states = Some(states);
+        self
+    }
+
+    fn record(&self, registry: &Registry, statistics: &Statistics) {
+        registry.gauge("rdkafka.msg_cnt").set(statistics.msg_cnt.max(0) as usize);
+        registry.gauge("rdkafka.msg_size").set(statistics.msg_size.max(0) as usize);
+        registry.gauge("rdkafka.txmsgs").set(statistics.txmsgs.max(0) as usize);
+        registry.gauge("rdkafka.txmsg_bytes").set(statistics.txmsg_bytes.max(0) as usize);
+        for broker in statistics.brokers.values() {
+            let gauge = |field: &str| registry.gauge(&format!("rdkafka.broker.{}.{}", broker.nodeid, field));
+            gauge("outbuf_cnt").set(broker.outbuf_cnt.max(0) as usize);
+            gauge("waitresp_cnt").set(broker.waitresp_cnt.max(0) as usize);
+            gauge("txerrs").set(broker.txerrs.max(0) as usize);
+            if let Some(ref rtt) = broker.rtt {
+                gauge("rtt_avg_us").set(rtt.avg.max(0) as usize);
+            }
+        }
+    }
+}
+
+impl ClientContext for ShipperContext {
+    fn stats(&self, statistics: Statistics) {
+        let throttle = statistics.brokers.values()
+            .filter_map(|b| b.throttle.as_ref())
+            .map(|w| w.avg.max(0) as usize)
+            .max()
+            .unwrap_or(0);
+        self.throttle.set(throttle);
+        if let Some(ref registry) = self.registry {
+            self.record(registry, &statistics);
+        }
+        if let Some(ref states) = self.broker_states {
+            for broker in statistics.brokers.values() {
+                states.update(broker.nodeid, &broker.state);
+            }
+        }
+    }
+}
+
+/// Delay between sends, doubled while brokers throttle (starting from the throttle time) and
+/// halved once they stop, up to `max`.
+pub struct Pacing {
+    delay: Duration,
+    max: Duration
+}
+
+impl Pacing {
+    pub fn new(max: Duration) -> Pacing {
+        Pacing {
+            delay: Duration::from_millis(0),
+            max: max
+        }
+    }
+
+    pub fn delay(&self) -> Duration { self.delay }
+
+    pub fn update(&mut self, throttle: Duration) -> Duration {
+        let min = Duration::from_millis(MIN_PACING_MS);
+        self.delay = if throttle > Duration::from_millis(0) {
+            std::cmp::min(self.max, std::cmp::max(throttle, std::cmp::max(self.delay * 2, min)))
+        } else if self.delay / 2 < min {
+            Duration::from_millis(0)
+        } else {
+            self.delay / 2
+        };
+        self.delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_throttle_events() {
+        let counter = Counter::new("writer.throttled");
+        let signal = ThrottleSignal::new(counter.clone());
+
+        signal.set(50);
+        signal.set(80);
+        signal.set(0);
+        signal.set(10);
+
+        assert_eq!(counter.value(), 2);
+        assert_eq!(signal.throttle(), Duration::from_millis(10));
+        assert!(signal.is_throttled());
+    }
+
+    #[test]
+    fn backs_off_and_recovers() {
+        let mut pacing = Pacing::new(Duration::from_millis(100));
+
+        assert_eq!(pacing.update(Duration::from_millis(0)), Duration::from_millis(0));
+        assert_eq!(pacing.update(Duration::from_millis(20)), Duration::from_millis(20));
+        assert_eq!(pacing.update(Duration::from_millis(20)), Duration::from_millis(40));
+        assert_eq!(pacing.update(Duration::from_millis(20)), Duration::from_millis(80));
+        assert_eq!(pacing.update(Duration::from_millis(20)), Duration::from_millis(100));
+        assert_eq!(pacing.update(Duration::from_millis(0)), Duration::from_millis(50));
+        assert_eq!(pacing.update(Duration::from_millis(0)), Duration::from_millis(25));
+    }
+}
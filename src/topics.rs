@@ -0,0 +1,441 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    eve,
+    futures::Future,
+    metrics::Counter,
+    rdkafka::{
+        ClientConfig,
+        admin::{
+            AdminClient,
+            AdminOptions,
+            NewTopic,
+            TopicReplication
+        },
+        client::DefaultClientContext,
+        types::RDKafkaError
+    }
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        HashSet
+    },
+    sync::{
+        Arc,
+        Mutex,
+        mpsc::{
+            self,
+            Receiver,
+            TryRecvError
+        }
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const DEFAULT_PROVISION_BACKOFF_MS: u64 = 1_000;
+/// Retries of a failed topic creation back off up to 2^6 times the first backoff.
+const MAX_PROVISION_BACKOFF_SHIFT: u32 = 6;
+
+/// Event types written by current Suricata releases, assumed to already have topics. Types of
+/// newer releases can be added with `TopicRouter::with_extra_known` rather than waiting for this
+/// list.
+pub const DEFAULT_EVENT_TYPES: &'static [&'static str] = &[
+    "alert", "anomaly", "dhcp", "dnp3", "dns", "drop", "fileinfo", "flow", "frame", "ftp", "http",
+    "ikev2", "krb5", "mqtt", "netflow", "nfs", "pgsql", "quic", "rdp", "smb", "smtp", "snmp", "ssh",
+    "stats", "tftp", "tls"
+];
+
+/// What to do with an event type that isn't known to have a topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownEventTypes {
+    /// Send it to the default topic, warning once per event type
+    CatchAll,
+    /// Create its topic from the template, sending its events to the default topic until that
+    /// succeeds; failed creations are retried with backoff
+    Create
+}
+
+impl std::str::FromStr for UnknownEventTypes {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<UnknownEventTypes, Error> {
+        match s {
+            "catch-all" => Ok(UnknownEventTypes::CatchAll),
+            "create" => Ok(UnknownEventTypes::Create),
+            _ => Err(Error::from_kind(ErrorKind::InvalidEventTypePolicy(s.to_string())))
+        }
+    }
+}
+
+/// Creates topics for newly discovered event types.
+pub trait TopicProvisioner {
+    fn provision(&self, topic: &str) -> Result<(), Error>;
+}
+
+/// Creates topics with the admin API, treating topics that already exist as created.
+pub struct KafkaProvisioner {
+    client: AdminClient<DefaultClientContext>,
+    partitions: i32,
+    replication: i32,
+    config: Vec<(String, String)>
+}
+
+impl KafkaProvisioner {
+    pub fn new(config: &ClientConfig, partitions: i32, replication: i32) -> Result<KafkaProvisioner, Error> {
+        let client = config.create::<AdminClient<DefaultClientContext>>()
+            .map_err(|e| Error::from(format!("Failed to create admin client: {:?}", e)))?;
+        Ok(KafkaProvisioner {
+            client: client,
+            partitions: partitions,
+            replication: replication,
+            config: vec![]
+        })
+    }
+
+    /// Topic level config, e.g. `retention.ms`, applied to every created topic.
+    pub fn with_config(mut self, name: &str, value: &str) -> Self {
+        self.config.push( (name.to_string(), value.to_string()) );
+        self
+    }
+}
+
+impl TopicProvisioner for KafkaProvisioner {
+    /// Blocks until the brokers answer; `TopicRouter` calls it on a thread of its own.
+    fn provision(&self, topic: &str) -> Result<(), Error> {
+        let new_topic = self.config.iter().fold(
+            NewTopic::new(topic, self.partitions, TopicReplication::Fixed(self.replication)),
+            |t, &(ref name, ref value)| t.set(name, value)
+        );
+        let results = self.client.create_topics(&[new_topic], &AdminOptions::new())
+            .wait()
+            .map_err(|e| Error::from(format!("Failed to create topic {}: {:?}", topic, e)))?;
+        for result in results {
+            match result {
+                Ok(_) | Err( (_, RDKafkaError::TopicAlreadyExists) ) => (),
+                Err( (name, e) ) => bail!("Failed to create topic {}: {:?}", name, e)
+            }
+        }
+        Ok( () )
+    }
+}
+
+/// Replaces characters Kafka doesn't allow in topic names with `_`.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Substitutes `{event_type}` in `template`.
+pub fn expand_template(template: &str, event_type: &str) -> String {
+    template.replace("{event_type}", &sanitize(event_type))
+}
+
+/// Where creating the topic of a discovered event type stands.
+enum Provisioning {
+    /// Creating on a thread of its own, which sends the result, after `attempts` earlier failures
+    Pending(Receiver<Result<(), Error>>, u32),
+    /// Failed `attempts` times, tried again once `retry_at` passed
+    Failed {
+        retry_at: Instant,
+        attempts: u32
+    }
+}
+
+/// Chooses the topic of each event from its `event_type` and a topic name template. Events
+/// without an `event_type`, or with one that has no topic, go to the default topic.
+pub struct TopicRouter {
+    default_topic: String,
+    template: String,
+    known: HashSet<String>,
+    policy: UnknownEventTypes,
+    provisioner: Option<Arc<Mutex<Box<TopicProvisioner + Send>>>>,
+    provisioning: HashMap<String, Provisioning>,
+    provision_backoff: Duration,
+    routes: HashMap<String, String>,
+    discovered: Option<Counter>
+}
+
+impl TopicRouter {
+    pub fn new(default_topic: &str, template: &str) -> TopicRouter {
+        TopicRouter {
+            default_topic: default_topic.to_string(),
+            template: template.to_string(),
+            known: DEFAULT_EVENT_TYPES.iter().map(|t| t.to_string()).collect(),
+            policy: UnknownEventTypes::CatchAll,
+            provisioner: None,
+            provisioning: HashMap::new(),
+            provision_backoff: Duration::from_millis(DEFAULT_PROVISION_BACKOFF_MS),
+            routes: HashMap::new(),
+            discovered: None
+        }
+    }
+
+    /// Replaces the event types assumed to already have topics.
+    pub fn with_known<I: IntoIterator<Item=String>>(mut self, event_types: I) -> Self {
+        self.known = event_types.into_iter().collect();
+        self
+    }
+
+    /// Adds to the known event types rather than replacing them.
+    pub fn with_extra_known<I: IntoIterator<Item=String>>(mut self, event_types: I) -> Self {
+        self.known.extend(event_types);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: UnknownEventTypes) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Used by the `create` policy; without one, unknown event types go to the default topic.
+    pub fn with_provisioner<P>(mut self, provisioner: P) -> Self
+        where P: TopicProvisioner + Send + 'static
+    {
+        self.provisioner = Some(Arc::new(Mutex::new(Box::new(provisioner))));
+        self
+    }
+
+    /// Wait before the first retry of a failed topic creation, doubling with every failure.
+    pub fn with_provision_backoff(mut self, backoff: Duration) -> Self {
+        self.provision_backoff = backoff;
+        self
+    }
+
+    /// Sends `event_type` to `topic` instead of the topic named by the template.
+    pub fn with_topic(mut self, event_type: &str, topic: &str) -> Self {
+        self.routes.insert(event_type.to_string(), topic.to_string());
+        self
+    }
+
+    /// Counts event types seen for the first time that weren't known.
+    pub fn with_discovered_counter(mut self, counter: Counter) -> Self {
+        self.discovered = Some(counter);
+        self
+    }
+
+    /// Topic of `msg`. `{iface}` in the template or explicit topics is replaced with the
+    /// record's interface label, or `unlabeled`.
+    pub fn route(&mut self, msg: &[u8]) -> String {
+        let topic = self.route_event_type(msg);
+        if topic.contains("{iface}") {
+            let label = eve::iface_label(msg);
+            topic.replace("{iface}", &sanitize(label.as_ref().map(|l| &**l).unwrap_or("unlabeled")))
+        } else {
+            topic
+        }
+    }
+
+    fn route_event_type(&mut self, msg: &[u8]) -> String {
+        let event_type = match eve::event_type(msg) {
+            Some(t) => t,
+            None => return self.default_topic.clone()
+        };
+        if let Some(topic) = self.routes.get(event_type) {
+            return topic.clone()
+        }
+        match self.discover(event_type) {
+            Some(topic) => {
+                self.routes.insert(event_type.to_string(), topic.clone());
+                topic
+            }
+            None => self.default_topic.clone()
+        }
+    }
+
+    /// Topic of an event type without a route yet, or None while its topic is being created.
+    fn discover(&mut self, event_type: &str) -> Option<String> {
+        let topic = expand_template(&self.template, event_type);
+        // without a placeholder every event type shares the default topic
+        if self.known.contains(event_type) || !self.template.contains("{event_type}") {
+            return Some(topic)
+        }
+        let provisioner = match (self.policy, self.provisioner.clone()) {
+            (UnknownEventTypes::Create, Some(provisioner)) => provisioner,
+            _ => {
+                if let Some(ref counter) = self.discovered {
+                    counter.incr();
+                }
+                warn!("Discovered event type {} without a topic, sending it to {}", event_type, self.default_topic);
+                return Some(self.default_topic.clone())
+            }
+        };
+        let attempts = match self.provisioning.remove(event_type) {
+            None => {
+                if let Some(ref counter) = self.discovered {
+                    counter.incr();
+                }
+                info!("Discovered event type {}, creating topic {} and sending it to {} meanwhile", event_type, topic, self.default_topic);
+                0
+            }
+            Some(Provisioning::Pending(receiver, attempts)) => {
+                let received = receiver.try_recv();
+                let res = match received {
+                    Ok(res) => res,
+                    Err(TryRecvError::Empty) => {
+                        self.provisioning.insert(event_type.to_string(), Provisioning::Pending(receiver, attempts));
+                        return None
+                    }
+                    Err(TryRecvError::Disconnected) => Err(Error::from("Provisioner panicked".to_string()))
+                };
+                match res {
+                    Ok(()) => {
+                        info!("Created topic {} of event type {}", topic, event_type);
+                        return Some(topic)
+                    }
+                    Err(e) => {
+                        let backoff = self.provision_backoff * (1 << attempts.min(MAX_PROVISION_BACKOFF_SHIFT));
+                        warn!("Failed to create topic {}, sending {} to {} and retrying in {:?}: {}", topic, event_type, self.default_topic, backoff, e);
+                        self.provisioning.insert(event_type.to_string(), Provisioning::Failed {
+                            retry_at: Instant::now() + backoff,
+                            attempts: attempts + 1
+                        });
+                        return None
+                    }
+                }
+            }
+            Some(Provisioning::Failed { retry_at, attempts }) => {
+                if Instant::now() < retry_at {
+                    self.provisioning.insert(event_type.to_string(), Provisioning::Failed {
+                        retry_at: retry_at,
+                        attempts: attempts
+                    });
+                    return None
+                }
+                attempts
+            }
+        };
+        // Creating a topic blocks until the brokers answer, which mustn't stall the writer
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let res = provisioner.lock()
+                .map_err(|_| Error::from("Provisioner lock poisoned".to_string()))
+                .and_then(|provisioner| provisioner.provision(&topic));
+            let _ = sender.send(res);
+        });
+        self.provisioning.insert(event_type.to_string(), Provisioning::Pending(receiver, attempts));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct RecordingProvisioner {
+        created: Arc<Mutex<Vec<String>>>,
+        attempts: Arc<Mutex<usize>>,
+        failures: usize
+    }
+
+    impl TopicProvisioner for RecordingProvisioner {
+        fn provision(&self, topic: &str) -> Result<(), Error> {
+            let mut attempts = self.attempts.lock().expect("Lock poisoned");
+            *attempts += 1;
+            if *attempts <= self.failures {
+                bail!("Broker unavailable");
+            }
+            self.created.lock().expect("Lock poisoned").push(topic.to_string());
+            Ok( () )
+        }
+    }
+
+    /// Routes `msg` until it goes to `topic`, returning the topics it went to before.
+    fn route_until(router: &mut TopicRouter, msg: &[u8], topic: &str) -> Vec<String> {
+        let mut before = vec![];
+        for _ in 0..500 {
+            let routed = router.route(msg);
+            if routed == topic {
+                return before
+            }
+            before.push(routed);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        panic!("Never routed to {}, only {:?}", topic, before);
+    }
+
+    #[test]
+    fn expands_templates() {
+        assert_eq!(expand_template("eve-{event_type}", "alert"), "eve-alert");
+        assert_eq!(expand_template("eve-{event_type}", "a/b c"), "eve-a_b_c");
+    }
+
+    #[test]
+    fn routes_known_types() {
+        let mut router = TopicRouter::new("eve", "eve-{event_type}");
+
+        assert_eq!(router.route(br#"{"event_type":"alert"}"#), "eve-alert");
+        assert_eq!(router.route(br#"{"event_type":"quic"}"#), "eve");
+        assert_eq!(router.route(br#"{"timestamp":"x"}"#), "eve");
+    }
+
+    #[test]
+    fn routes_by_interface_label() {
+        let mut router = TopicRouter::new("eve-{iface}", "eve-{iface}-{event_type}")
+            .with_topic("flow", "flows.{iface}");
+
+        assert_eq!(router.route(br#"{"iface_label":"tap","event_type":"alert"}"#), "eve-tap-alert");
+        assert_eq!(router.route(br#"{"iface_label":"span 2","event_type":"alert"}"#), "eve-span_2-alert");
+        assert_eq!(router.route(br#"{"iface_label":"tap","event_type":"flow"}"#), "flows.tap");
+        assert_eq!(router.route(br#"{"event_type":"quic"}"#), "eve-unlabeled");
+    }
+
+    #[test]
+    fn routes_explicit_topics() {
+        let mut router = TopicRouter::new("suricata", "suricata")
+            .with_topic("alert", "suricata.alerts")
+            .with_topic("quic", "suricata.quic");
+
+        assert_eq!(router.route(br#"{"event_type":"alert"}"#), "suricata.alerts");
+        assert_eq!(router.route(br#"{"event_type":"quic"}"#), "suricata.quic");
+        assert_eq!(router.route(br#"{"event_type":"flow"}"#), "suricata");
+        assert_eq!(router.route(br#"{"event_type":"other"}"#), "suricata");
+    }
+
+    #[test]
+    fn creates_topics_for_unknown_types() {
+        let provisioner = RecordingProvisioner::default();
+        let discovered = Counter::new("topics.discovered");
+        let mut router = TopicRouter::new("eve", "eve-{event_type}")
+            .with_known(vec!["alert".to_string()])
+            .with_policy(UnknownEventTypes::Create)
+            .with_provisioner(provisioner.clone())
+            .with_discovered_counter(discovered.clone());
+
+        let before = route_until(&mut router, br#"{"event_type":"quic"}"#, "eve-quic");
+        assert!(before.iter().all(|t| t == "eve"));
+        assert_eq!(router.route(br#"{"event_type":"quic"}"#), "eve-quic");
+        assert_eq!(router.route(br#"{"event_type":"alert"}"#), "eve-alert");
+
+        assert_eq!(*provisioner.created.lock().expect("Lock poisoned"), vec!["eve-quic".to_string()]);
+        assert_eq!(discovered.value(), 1);
+    }
+
+    #[test]
+    fn retries_failed_creations() {
+        let provisioner = RecordingProvisioner { failures: 2, ..RecordingProvisioner::default() };
+        let mut router = TopicRouter::new("eve", "eve-{event_type}")
+            .with_policy(UnknownEventTypes::Create)
+            .with_provisioner(provisioner.clone())
+            .with_provision_backoff(Duration::from_millis(5));
+
+        let before = route_until(&mut router, br#"{"event_type":"quic"}"#, "eve-quic");
+
+        assert!(!before.is_empty() && before.iter().all(|t| t == "eve"));
+        assert_eq!(*provisioner.attempts.lock().expect("Lock poisoned"), 3);
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("create".parse::<UnknownEventTypes>().expect("Failed to parse"), UnknownEventTypes::Create);
+        assert!("drop".parse::<UnknownEventTypes>().is_err());
+    }
+}
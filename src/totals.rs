@@ -0,0 +1,103 @@
+use super::{
+    checkpoint::CheckpointStore,
+    errors::Error,
+    metrics::{
+        Counter,
+        Registry
+    }
+};
+use std::sync::Arc;
+
+/// Counters `--persist-counters` keeps across restarts: records delivered, their bytes, and
+/// failed deliveries.
+pub const PERSISTED_COUNTERS: &'static [&'static str] = &["writer.delivered", "writer.delivered_bytes", "writer.failed"];
+
+fn source(counter: &str) -> String {
+    format!("counter.{}", counter)
+}
+
+/// Cumulative counters carried across restarts in a checkpoint store, so dashboards relying on
+/// counters only ever increasing don't see them reset on every deploy. Totals are written behind:
+/// periodically and when the pipeline stops, so a crash loses what was counted since the last
+/// save.
+pub struct PersistedCounters<C: CheckpointStore> {
+    store: Arc<C>,
+    counters: Vec<Counter>
+}
+
+impl<C: CheckpointStore> Clone for PersistedCounters<C> {
+    fn clone(&self) -> PersistedCounters<C> {
+        PersistedCounters {
+            store: self.store.clone(),
+            counters: self.counters.clone()
+        }
+    }
+}
+
+impl<C: CheckpointStore> PersistedCounters<C> {
+    /// Adds the totals saved in `store` to the counters of `registry` named `names`, which
+    /// should be restored before anything is counted.
+    pub fn restore(store: C, registry: &Registry, names: &[&str]) -> Result<PersistedCounters<C>, Error> {
+        let mut counters = vec![];
+        for name in names {
+            let counter = registry.counter(name);
+            if let Some(saved) = store.load(&source(name))? {
+                counter.add(saved as usize);
+            }
+            counters.push(counter);
+        }
+        Ok(PersistedCounters {
+            store: Arc::new(store),
+            counters: counters
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        for counter in self.counters.iter() {
+            self.store.save(&source(counter.name()), counter.value() as u64)?;
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        collections::HashMap
+    };
+
+    #[derive(Default)]
+    struct MemoryStore {
+        offsets: RefCell<HashMap<String, u64>>
+    }
+
+    impl CheckpointStore for MemoryStore {
+        fn load(&self, source: &str) -> Result<Option<u64>, Error> {
+            Ok(self.offsets.borrow().get(source).cloned())
+        }
+
+        fn save(&self, source: &str, offset: u64) -> Result<(), Error> {
+            self.offsets.borrow_mut().insert(source.to_string(), offset);
+            Ok( () )
+        }
+    }
+
+    #[test]
+    fn carries_totals_across_restarts() {
+        let store = MemoryStore::default();
+        store.save("counter.writer.delivered", 40).expect("Failed to save");
+        let registry = Registry::default();
+
+        let persisted = PersistedCounters::restore(store, &registry, PERSISTED_COUNTERS).expect("Failed to restore");
+        registry.counter("writer.delivered").add(2);
+        registry.counter("writer.failed").incr();
+        persisted.save().expect("Failed to save");
+
+        assert_eq!(registry.counter("writer.delivered").value(), 42);
+        assert_eq!(persisted.store.load("counter.writer.delivered").expect("Failed to load"), Some(42));
+        assert_eq!(persisted.store.load("counter.writer.failed").expect("Failed to load"), Some(1));
+        assert_eq!(persisted.store.load("counter.writer.delivered_bytes").expect("Failed to load"), Some(0));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    self,
+    collections::{
+        HashMap,
+        VecDeque,
+        hash_map::DefaultHasher
+    },
+    hash::{
+        Hash,
+        Hasher
+    },
+    sync::{
+        Arc,
+        Mutex
+    }
+};
+
+/// Default number of records whose source is remembered.
+pub const DEFAULT_TRACE_CAPACITY: usize = 100_000;
+
+/// The file and line an event was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub path: Arc<String>,
+    pub line: usize
+}
+
+impl std::fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.path, self.line)
+    }
+}
+
+fn record_hash(msg: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    msg.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct TraceTable {
+    capacity: usize,
+    order: VecDeque<u64>,
+    sources: HashMap<u64, SourceLine>
+}
+
+/// Remembers where the most recent `capacity` records were read from, keyed by a hash of their
+/// bytes, so a failed delivery can name the exact line of the file that produced it. Records
+/// stay plain bytes through every stage; the writer looks up what it was handed, so records
+/// changed on the way (labels, transforms, redaction) aren't found and are logged without a
+/// source. Meant for debugging: it costs a hash and a lock per event.
+#[derive(Clone)]
+pub struct SourceTrace {
+    table: Arc<Mutex<TraceTable>>
+}
+
+impl SourceTrace {
+    pub fn new(capacity: usize) -> SourceTrace {
+        SourceTrace {
+            table: Arc::new(Mutex::new(TraceTable {
+                capacity: capacity.max(1),
+                order: VecDeque::new(),
+                sources: HashMap::new()
+            }))
+        }
+    }
+
+    /// Records that `msg` was read from `line` of `path`, forgetting the oldest record if full.
+    pub fn record(&self, msg: &[u8], path: &Arc<String>, line: usize) {
+        let hash = record_hash(msg);
+        let mut table = self.table.lock().expect("Trace lock poisoned");
+        if table.sources.insert(hash, SourceLine { path: path.clone(), line: line }).is_some() {
+            // Identical records keep the latest line, and their place in line for eviction
+            return
+        }
+        table.order.push_back(hash);
+        if table.order.len() > table.capacity {
+            if let Some(oldest) = table.order.pop_front() {
+                table.sources.remove(&oldest);
+            }
+        }
+    }
+
+    /// Where `msg` was read from, if it was recorded and hasn't been forgotten.
+    pub fn lookup(&self, msg: &[u8]) -> Option<SourceLine> {
+        let table = self.table.lock().expect("Trace lock poisoned");
+        table.sources.get(&record_hash(msg)).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.lock().expect("Trace lock poisoned").sources.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_recent_sources() {
+        let trace = SourceTrace::new(2);
+        let path = Arc::new("/var/log/suricata/eve.json".to_string());
+
+        trace.record(b"{\"flow_id\":1}", &path, 1);
+        trace.record(b"{\"flow_id\":2}", &path, 2);
+        trace.record(b"{\"flow_id\":3}", &path, 3);
+
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace.lookup(b"{\"flow_id\":1}"), None);
+        let source = trace.lookup(b"{\"flow_id\":3}").expect("Source forgotten");
+        assert_eq!(source.line, 3);
+        assert_eq!(source.to_string(), "/var/log/suricata/eve.json:3");
+    }
+}
@@ -0,0 +1,343 @@
+use super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    guard::StageGuard,
+    metrics::Counter,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use std::{
+    self,
+    collections::HashSet
+};
+
+/// Rewrites or drops one event.
+pub trait Transform {
+    /// Returns the event to send on, or `None` to drop it.
+    fn apply(&self, event: Value) -> Option<Value>;
+}
+
+impl<T: Transform + ?Sized> Transform for Box<T> {
+    fn apply(&self, event: Value) -> Option<Value> {
+        (**self).apply(event)
+    }
+}
+
+/// Splits a comma separated list, skipping empty entries.
+pub fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(str::trim).filter(|e| !e.is_empty()).map(|e| e.to_string()).collect()
+}
+
+/// Splits a dotted path such as `http.http_request_body` into its fields.
+pub fn dotted_path(path: &str) -> Result<Vec<String>, Error> {
+    let segments: Vec<String> = path.split('.').map(|s| s.to_string()).collect();
+    if segments.iter().any(|s| s.is_empty()) {
+        bail!("Invalid field path {}", path);
+    }
+    Ok(segments)
+}
+
+/// Keeps or drops events by event type. Events without an event type are kept unless only
+/// listed types are allowed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventTypeFilter {
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>
+}
+
+impl EventTypeFilter {
+    /// Keeps only events of the comma separated types, e.g. `alert,anomaly`.
+    pub fn with_allowed(mut self, event_types: &str) -> Self {
+        self.allow = Some(split_list(event_types).into_iter().collect());
+        self
+    }
+
+    /// Drops events of the comma separated types, e.g. `stats,netflow`.
+    pub fn with_denied(mut self, event_types: &str) -> Self {
+        self.deny.extend(split_list(event_types));
+        self
+    }
+}
+
+impl Transform for EventTypeFilter {
+    fn apply(&self, event: Value) -> Option<Value> {
+        let keep = {
+            let event_type = event.get("event_type").and_then(Value::as_str);
+            let allowed = match (self.allow.as_ref(), event_type) {
+                (Some(allow), Some(t)) => allow.contains(t),
+                (Some(_), None) => false,
+                (None, _) => true
+            };
+            allowed && !event_type.map(|t| self.deny.contains(t)).unwrap_or(false)
+        };
+        if keep { Some(event) } else { None }
+    }
+}
+
+/// Removes fields by dotted path, e.g. `payload` or `http.http_request_body`, so sensitive data
+/// doesn't leave the sensor.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Redactor {
+    paths: Vec<Vec<String>>
+}
+
+impl Redactor {
+    /// Parses a comma separated list of dotted paths.
+    pub fn parse(s: &str) -> Result<Redactor, Error> {
+        Ok(Redactor {
+            paths: split_list(s).iter().map(|p| dotted_path(p)).collect::<Result<_, _>>()?
+        })
+    }
+}
+
+impl Transform for Redactor {
+    fn apply(&self, mut event: Value) -> Option<Value> {
+        for path in self.paths.iter() {
+            let (field, parents) = match path.split_last() {
+                Some(split) => split,
+                None => continue
+            };
+            let parent = parents.iter().fold(Some(&mut event), |v, segment| v.and_then(|v| v.get_mut(segment.as_str())));
+            if let Some(&mut Value::Object(ref mut object)) = parent {
+                object.remove(field.as_str());
+            }
+        }
+        Some(event)
+    }
+}
+
+/// Where static fields are written: into the record, into its headers, or both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InjectionTarget {
+    Field,
+    Header,
+    Both
+}
+
+impl InjectionTarget {
+    pub fn fields(&self) -> bool { *self != InjectionTarget::Header }
+    pub fn headers(&self) -> bool { *self != InjectionTarget::Field }
+}
+
+impl std::str::FromStr for InjectionTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<InjectionTarget, Error> {
+        match s {
+            "field" => Ok(InjectionTarget::Field),
+            "header" => Ok(InjectionTarget::Header),
+            "both" => Ok(InjectionTarget::Both),
+            _ => Err(Error::from_kind(ErrorKind::InvalidInjectionTarget(s.to_string())))
+        }
+    }
+}
+
+/// Sets the same top level fields on every event, e.g. `site`, `datacenter`, or `environment`,
+/// so consumers get deployment metadata without enriching records downstream. Injected fields
+/// replace any the event already has.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FieldInjector {
+    fields: Vec<(String, Value)>
+}
+
+impl FieldInjector {
+    pub fn with_field(mut self, name: &str, value: Value) -> Self {
+        self.fields.push( (name.to_string(), value) );
+        self
+    }
+
+    /// Parses a field as `name=value`, e.g. `site=ams1`; the value is always a string.
+    pub fn with_parsed(self, field: &str) -> Result<Self, Error> {
+        let mut parts = field.splitn(2, '=');
+        match (parts.next().map(str::trim), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => Ok(self.with_field(name, Value::String(value.to_string()))),
+            _ => bail!("Invalid static field {}, expected name=value", field)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl Transform for FieldInjector {
+    fn apply(&self, mut event: Value) -> Option<Value> {
+        if let Value::Object(ref mut object) = event {
+            for &(ref name, ref value) in self.fields.iter() {
+                object.insert(name.clone(), value.clone());
+            }
+        }
+        Some(event)
+    }
+}
+
+/// Applies transforms in order, stopping at the first that drops the event.
+#[derive(Default)]
+pub struct TransformChain {
+    transforms: Vec<Box<Transform + Send>>
+}
+
+impl TransformChain {
+    pub fn with_transform<T>(mut self, transform: T) -> Self
+        where T: Transform + Send + 'static
+    {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+}
+
+impl Transform for TransformChain {
+    fn apply(&self, event: Value) -> Option<Value> {
+        self.transforms.iter().fold(Some(event), |event, t| event.and_then(|e| t.apply(e)))
+    }
+}
+
+/// Runs a `Transform` over the events of a stream, between the source and the writer. Records
+/// that aren't JSON are passed through unchanged; dropped events are counted in
+/// `transform.dropped`.
+pub struct TransformStream<S, T> {
+    inner: S,
+    transform: T,
+    guard: StageGuard,
+    dropped: Counter
+}
+
+impl<S, T> TransformStream<S, T>
+    where S: Stream<Item=Vec<u8>>,
+          T: Transform
+{
+    pub fn new(inner: S, transform: T) -> TransformStream<S, T> {
+        TransformStream {
+            inner: inner,
+            transform: transform,
+            guard: StageGuard::new("transform"),
+            dropped: Counter::new("transform.dropped")
+        }
+    }
+
+    pub fn with_guard(mut self, guard: StageGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    pub fn with_drop_counter(mut self, counter: Counter) -> Self {
+        self.dropped = counter;
+        self
+    }
+}
+
+impl<S, T> Stream for TransformStream<S, T>
+    where S: Stream<Item=Vec<u8>>,
+          T: Transform
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    let transform = &self.transform;
+                    let mut dropped = false;
+                    let transformed = self.guard.run(msg, |m| {
+                        let event: Value = serde_json::from_slice(m).ok()?;
+                        match transform.apply(event) {
+                            Some(event) => serde_json::to_vec(&event).ok(),
+                            None => {
+                                dropped = true;
+                                None
+                            }
+                        }
+                    });
+                    match transformed {
+                        Some(_) if dropped => self.dropped.incr(),
+                        Some(msg) => return Ok(Async::Ready(Some(msg))),
+                        None => ()
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        stream
+    };
+
+    #[test]
+    fn filters_event_types() {
+        let deny = EventTypeFilter::default().with_denied("stats, netflow");
+        let allow = EventTypeFilter::default().with_allowed("alert");
+
+        assert_eq!(deny.apply(json!({"event_type": "stats"})), None);
+        assert_eq!(deny.apply(json!({"event_type": "alert"})), Some(json!({"event_type": "alert"})));
+        assert_eq!(deny.apply(json!({})), Some(json!({})));
+        assert_eq!(allow.apply(json!({"event_type": "dns"})), None);
+        assert_eq!(allow.apply(json!({})), None);
+        assert_eq!(allow.apply(json!({"event_type": "alert"})), Some(json!({"event_type": "alert"})));
+    }
+
+    #[test]
+    fn redacts_fields() {
+        let redactor = Redactor::parse("payload,http.http_request_body,tls.missing").expect("Failed to parse");
+        let event = json!({"payload": "abc", "http": {"url": "/", "http_request_body": "secret"}, "tls": "x"});
+
+        assert_eq!(redactor.apply(event), Some(json!({"http": {"url": "/"}, "tls": "x"})));
+        assert!(Redactor::parse("http.").is_err());
+    }
+
+    #[test]
+    fn injects_static_fields() {
+        let injector = FieldInjector::default()
+            .with_parsed("site=ams1").expect("Failed to parse")
+            .with_parsed(" environment =prod=blue").expect("Failed to parse");
+
+        assert_eq!(
+            injector.apply(json!({"event_type": "alert", "site": "spoofed"})),
+            Some(json!({"event_type": "alert", "site": "ams1", "environment": "prod=blue"}))
+        );
+        assert_eq!(injector.apply(json!([1])), Some(json!([1])));
+        assert!(FieldInjector::default().with_parsed("site").is_err());
+        assert!("header".parse::<InjectionTarget>().expect("Failed to parse").headers());
+        assert!(!"header".parse::<InjectionTarget>().expect("Failed to parse").fields());
+        assert!("record".parse::<InjectionTarget>().is_err());
+    }
+
+    #[test]
+    fn transforms_streams() {
+        let chain = TransformChain::default()
+            .with_transform(EventTypeFilter::default().with_denied("stats"))
+            .with_transform(Redactor::parse("packet").expect("Failed to parse"));
+        let events = vec![
+            br#"{"event_type":"stats"}"#.to_vec(),
+            br#"{"event_type":"alert","packet":"AAAA"}"#.to_vec(),
+            b"not json".to_vec()
+        ];
+        let dropped = Counter::new("transform.dropped");
+
+        let sent: Vec<Vec<u8>> = TransformStream::new(stream::iter_ok::<_, ()>(events), chain)
+            .with_drop_counter(dropped.clone())
+            .collect().wait().expect("Stream failed");
+
+        assert_eq!(sent, vec![br#"{"event_type":"alert"}"#.to_vec(), b"not json".to_vec()]);
+        assert_eq!(dropped.value(), 1);
+    }
+}
@@ -0,0 +1,165 @@
+use super::{
+    errors::Error,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    guard::StageGuard,
+    serde_json::{
+        self,
+        Value
+    }
+};
+
+/// Field of truncated records listing the paths that were cut short.
+pub const TRUNCATED_FIELDS: &'static str = "truncated_fields";
+
+/// Longest prefix of `s` of at most `max` bytes that ends on a character boundary.
+pub fn truncate_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Maximum lengths in bytes of string fields, by dotted path.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FieldLimits {
+    limits: Vec<(String, usize)>
+}
+
+impl FieldLimits {
+    /// Parses a comma separated list of `path=bytes`, e.g. `http.url=2048,smtp.subject=512`.
+    pub fn parse(s: &str) -> Result<FieldLimits, Error> {
+        let mut limits = vec![];
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let (path, max) = match (parts.next(), parts.next()) {
+                (Some(path), Some(max)) => (path.trim(), max.trim()),
+                _ => bail!("Invalid field limit {}, expected path=bytes", entry)
+            };
+            let max = max.parse::<usize>()
+                .map_err(|_| Error::from(format!("Invalid length in field limit {}", entry)))?;
+            limits.push( (path.to_string(), max) );
+        }
+        Ok(FieldLimits {
+            limits: limits
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Truncates the limited fields of `msg`, listing them in `truncated_fields`. Returns `None`
+    /// if nothing was truncated.
+    pub fn apply(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        let mut event: Value = serde_json::from_slice(msg).ok()?;
+        let mut truncated = vec![];
+        for &(ref path, max) in self.limits.iter() {
+            let field = path.split('.').fold(Some(&mut event), |v, segment| v.and_then(|v| v.get_mut(segment)));
+            if let Some(field) = field {
+                let shortened = match *field {
+                    Value::String(ref s) if s.len() > max => truncate_utf8(s, max).to_string(),
+                    _ => continue
+                };
+                *field = Value::String(shortened);
+                truncated.push(Value::String(path.clone()));
+            }
+        }
+        if truncated.is_empty() {
+            return None
+        }
+        let mut fields = event.get(TRUNCATED_FIELDS).and_then(Value::as_array).cloned().unwrap_or_else(Vec::new);
+        fields.extend(truncated);
+        event[TRUNCATED_FIELDS] = Value::Array(fields);
+        serde_json::to_vec(&event).ok()
+    }
+}
+
+/// Truncates long fields of events, e.g. `http.url`, so oversized values don't push records
+/// past broker limits or bloat downstream indices.
+pub struct Truncator<S> {
+    inner: S,
+    limits: FieldLimits,
+    guard: StageGuard
+}
+
+impl<S> Truncator<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    pub fn new(inner: S, limits: FieldLimits) -> Truncator<S> {
+        Truncator {
+            inner: inner,
+            limits: limits,
+            guard: StageGuard::new("truncate")
+        }
+    }
+
+    pub fn with_guard(mut self, guard: StageGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+}
+
+impl<S> Stream for Truncator<S>
+    where S: Stream<Item=Vec<u8>>
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => {
+                    let limits = &self.limits;
+                    if let Some(msg) = self.guard.run(msg, |m| limits.apply(m)) {
+                        return Ok(Async::Ready(Some(msg)))
+                    }
+                }
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate_utf8("abcdef", 4), "abcd");
+        assert_eq!(truncate_utf8("ab\u{e9}cd", 3), "ab");
+        assert_eq!(truncate_utf8("ab\u{e9}cd", 4), "ab\u{e9}");
+        assert_eq!(truncate_utf8("abc", 10), "abc");
+    }
+
+    #[test]
+    fn parses_limits() {
+        let limits = FieldLimits::parse("http.url=2048, smtp.subject=512").expect("Failed to parse");
+
+        assert_eq!(limits.limits, vec![("http.url".to_string(), 2048), ("smtp.subject".to_string(), 512)]);
+        assert!(FieldLimits::parse("http.url").is_err());
+        assert!(FieldLimits::parse("http.url=big").is_err());
+    }
+
+    #[test]
+    fn marks_truncated_fields() {
+        let limits = FieldLimits::parse("http.url=4,http.hostname=4,smtp.subject=2").expect("Failed to parse");
+        let msg = br#"{"event_type":"http","http":{"url":"/index.html","hostname":"a.b"}}"#;
+
+        let truncated: Value = serde_json::from_slice(&limits.apply(msg).expect("Not truncated"))
+            .expect("Failed to parse");
+
+        assert_eq!(truncated["http"]["url"], "/ind");
+        assert_eq!(truncated["http"]["hostname"], "a.b");
+        assert_eq!(truncated["truncated_fields"], json!(["http.url"]));
+        assert_eq!(limits.apply(br#"{"http":{"url":"/"}}"#), None);
+    }
+}
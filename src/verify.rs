@@ -0,0 +1,346 @@
+use super::{
+    errors::Error,
+    futures::sync::mpsc::UnboundedSender,
+    metrics::{
+        Counter,
+        QueueGauge,
+        Registry
+    },
+    rdkafka::{
+        ClientConfig,
+        Message,
+        Offset,
+        TopicPartitionList,
+        consumer::{
+            BaseConsumer,
+            Consumer
+        }
+    },
+    serde_json::{
+        self,
+        Value
+    },
+    sha2::{
+        Digest,
+        Sha256
+    }
+};
+use std::{
+    self,
+    collections::{
+        HashMap,
+        VecDeque
+    },
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const POLL_TIMEOUT_MS: i32 = 500;
+const FETCH_TIMEOUT_SECS: u64 = 10;
+
+/// Hash of a record payload as sent, compared with the payload read back from the broker: the
+/// first 8 bytes of its SHA-256 as a big endian integer, so consumers can compute it too.
+pub fn payload_hash(payload: &[u8]) -> u64 {
+    Sha256::digest(payload).iter().take(8).fold(0, |hash, b| hash << 8 | *b as u64)
+}
+
+/// Topic and payload hash of a record awaiting its delivery report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub topic: String,
+    pub hash: u64
+}
+
+/// Payload hashes of the most recent deliveries, up to `window` per partition. Shared between
+/// the writer, which records deliveries, and the verifier thread, which drains them.
+#[derive(Clone)]
+pub struct ProducedDigest {
+    window: usize,
+    partitions: Arc<Mutex<HashMap<(String, i32), VecDeque<(i64, u64)>>>>
+}
+
+impl ProducedDigest {
+    pub fn new(window: usize) -> ProducedDigest {
+        ProducedDigest {
+            window: window.max(1),
+            partitions: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    pub fn record(&self, fingerprint: &Fingerprint, partition: i32, offset: i64) {
+        let mut partitions = self.partitions.lock().expect("Digest lock poisoned");
+        let recent = partitions.entry( (fingerprint.topic.clone(), partition) ).or_insert_with(VecDeque::new);
+        if recent.len() >= self.window {
+            recent.pop_front();
+        }
+        recent.push_back( (offset, fingerprint.hash) );
+    }
+
+    /// Takes the recorded deliveries, so each is verified once.
+    pub fn drain(&self) -> Vec<(String, i32, Vec<(i64, u64)>)> {
+        let mut partitions = self.partitions.lock().expect("Digest lock poisoned");
+        partitions.drain()
+            .map(|( (topic, partition), recent)| (topic, partition, recent.into_iter().collect()))
+            .collect()
+    }
+
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.partitions) > 1
+    }
+}
+
+/// Trailer covering `records`, `(offset, payload_hash)` pairs of one partition. The checksum is
+/// the hex SHA-256 of the big endian payload hashes in offset order.
+pub fn trailer(topic: &str, partition: i32, records: &mut Vec<(i64, u64)>) -> Value {
+    records.sort();
+    let mut checksum = Sha256::default();
+    for &(_, hash) in records.iter() {
+        let bytes: Vec<u8> = (0..8).rev().map(|i| (hash >> (8 * i)) as u8).collect();
+        checksum.input(&bytes);
+    }
+    json!({
+        "event_type": "surikafka_trailer",
+        "topic": topic,
+        "partition": partition,
+        "first_offset": records.first().map(|&(o, _)| o),
+        "last_offset": records.last().map(|&(o, _)| o),
+        "records": records.len(),
+        "checksum": checksum.result().iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    })
+}
+
+/// Sends a trailer after every `every` delivered records of a partition, so consumers can detect
+/// gaps and altered records cheaply: the offsets covered, the record count, and a checksum they
+/// recompute from the records read. Records are counted in the order delivery reports arrive,
+/// so a trailer may leave out an offset within its range that a later trailer covers.
+pub struct TrailerEmitter {
+    every: usize,
+    pending: HashMap<(String, i32), Vec<(i64, u64)>>,
+    trailers: UnboundedSender<Vec<u8>>,
+    gauge: Option<QueueGauge>
+}
+
+impl TrailerEmitter {
+    pub fn new(every: usize, trailers: UnboundedSender<Vec<u8>>) -> TrailerEmitter {
+        TrailerEmitter {
+            every: every.max(1),
+            pending: HashMap::new(),
+            trailers: trailers,
+            gauge: None
+        }
+    }
+
+    /// Counts trailers sent; the receiving side is expected to subtract as it consumes them.
+    pub fn with_queue_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.gauge = Some(gauge);
+        self
+    }
+
+    pub fn record(&mut self, fingerprint: &Fingerprint, partition: i32, offset: i64) {
+        let partition_key = (fingerprint.topic.clone(), partition);
+        let full = {
+            let pending = self.pending.entry(partition_key.clone()).or_insert_with(Vec::new);
+            pending.push( (offset, fingerprint.hash) );
+            pending.len() >= self.every
+        };
+        if !full {
+            return
+        }
+        if let Some(mut records) = self.pending.remove(&partition_key) {
+            let trailer = trailer(&fingerprint.topic, partition, &mut records);
+            let trailer = serde_json::to_vec(&trailer).expect("Trailer is always serializable");
+            if self.trailers.unbounded_send(trailer).is_err() {
+                error!("Trailer receiver closed, dropping trailer of {}/{}", fingerprint.topic, partition);
+            } else if let Some(ref gauge) = self.gauge {
+                gauge.add(1);
+            }
+        }
+    }
+}
+
+/// Outcome of comparing produced hashes with the records read back.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Comparison {
+    pub matched: usize,
+    /// Offsets whose payload differs from what was produced
+    pub mismatched: Vec<i64>,
+    /// Offsets that could not be read back, e.g. already deleted by retention
+    pub missing: Vec<i64>
+}
+
+pub fn compare(expected: &[(i64, u64)], consumed: &HashMap<i64, u64>) -> Comparison {
+    let mut comparison = Comparison::default();
+    for &(offset, hash) in expected {
+        match consumed.get(&offset) {
+            Some(h) if *h == hash => comparison.matched += 1,
+            Some(_) => comparison.mismatched.push(offset),
+            None => comparison.missing.push(offset)
+        }
+    }
+    comparison
+}
+
+/// Reads back `from..=to` of a partition, hashing the payloads by offset.
+fn consume_back(consumer: &BaseConsumer, topic: &str, partition: i32, from: i64, to: i64) -> Result<HashMap<i64, u64>, Error> {
+    let mut assignment = TopicPartitionList::new();
+    assignment.add_partition_offset(topic, partition, Offset::Offset(from));
+    consumer.assign(&assignment)
+        .map_err(|e| Error::from(format!("Failed to assign partition: {:?}", e)))?;
+
+    let deadline = Instant::now() + Duration::from_secs(FETCH_TIMEOUT_SECS);
+    let mut consumed = HashMap::new();
+    while Instant::now() < deadline {
+        match consumer.poll(POLL_TIMEOUT_MS) {
+            None => continue,
+            Some(Err(e)) => bail!("Failed to consume {}/{}: {:?}", topic, partition, e),
+            Some(Ok(m)) => {
+                consumed.insert(m.offset(), payload_hash(m.payload().unwrap_or(&[])));
+                if m.offset() >= to {
+                    break
+                }
+            }
+        }
+    }
+    Ok(consumed)
+}
+
+/// Periodically consumes back the offsets recorded in a `ProducedDigest` and compares payload
+/// hashes, detecting silent corruption or cluster side transforms of produced records. Runs on a
+/// thread of its own since the consumer blocks, and stops once the digest is dropped elsewhere.
+pub struct Verifier;
+
+impl Verifier {
+    /// `client` carries the connection settings shared with the producer.
+    pub fn spawn(client: &ClientConfig, digest: ProducedDigest, interval: Duration, registry: &Registry) -> Result<(), Error> {
+        let consumer: BaseConsumer = client.clone()
+            .set("group.id", "surikafka-verify")
+            .set("enable.auto.commit", "false")
+            .set("check.crcs", "true")
+            .create()
+            .map_err(|e| Error::from(format!("Failed to create verification consumer: {:?}", e)))?;
+        let matched = registry.counter("verify.matched");
+        let mismatched = registry.counter("verify.mismatched");
+        let missing = registry.counter("verify.missing");
+
+        std::thread::spawn(move || {
+            while digest.is_shared() {
+                std::thread::sleep(interval);
+                for (topic, partition, expected) in digest.drain() {
+                    verify_partition(&consumer, &topic, partition, &expected, &matched, &mismatched, &missing);
+                }
+            }
+        });
+        Ok( () )
+    }
+}
+
+fn verify_partition(
+    consumer: &BaseConsumer,
+    topic: &str,
+    partition: i32,
+    expected: &[(i64, u64)],
+    matched: &Counter,
+    mismatched: &Counter,
+    missing: &Counter
+) {
+    let from = expected.iter().map(|&(o, _)| o).min().unwrap_or(0);
+    let to = expected.iter().map(|&(o, _)| o).max().unwrap_or(0);
+    let consumed = match consume_back(consumer, topic, partition, from, to) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Failed to verify {}/{}: {}", topic, partition, e);
+            return
+        }
+    };
+    let comparison = compare(expected, &consumed);
+    matched.add(comparison.matched);
+    mismatched.add(comparison.mismatched.len());
+    missing.add(comparison.missing.len());
+    if !comparison.mismatched.is_empty() {
+        error!(
+            "{} records of {}/{} differ from what was produced, e.g. offset {}",
+            comparison.mismatched.len(), topic, partition, comparison.mismatched[0]
+        );
+    }
+    if !comparison.missing.is_empty() {
+        warn!("{} produced records of {}/{} could not be read back", comparison.missing.len(), topic, partition);
+    }
+    debug!("Verified {} records of {}/{}", comparison.matched, topic, partition);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        Future,
+        Stream,
+        sync::mpsc
+    };
+
+    fn fingerprint(payload: &[u8]) -> Fingerprint {
+        Fingerprint {
+            topic: "eve".to_string(),
+            hash: payload_hash(payload)
+        }
+    }
+
+    #[test]
+    fn keeps_a_window_per_partition() {
+        let digest = ProducedDigest::new(2);
+        digest.record(&fingerprint(b"a"), 0, 10);
+        digest.record(&fingerprint(b"b"), 0, 11);
+        digest.record(&fingerprint(b"c"), 0, 12);
+        digest.record(&fingerprint(b"d"), 1, 5);
+
+        let mut drained = digest.drain();
+        drained.sort();
+
+        assert_eq!(drained, vec![
+            ("eve".to_string(), 0, vec![(11, payload_hash(b"b")), (12, payload_hash(b"c"))]),
+            ("eve".to_string(), 1, vec![(5, payload_hash(b"d"))])
+        ]);
+        assert!(digest.drain().is_empty());
+    }
+
+    #[test]
+    fn emits_trailers() {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut trailers = TrailerEmitter::new(2, sender);
+        trailers.record(&fingerprint(b"b"), 0, 11);
+        trailers.record(&fingerprint(b"c"), 1, 5);
+        trailers.record(&fingerprint(b"a"), 0, 10);
+        drop(trailers);
+
+        let sent = receiver.collect().wait().expect("Failed to receive");
+        let trailer: Value = serde_json::from_slice(&sent[0]).expect("Failed to parse");
+        let mut expected = Sha256::default();
+        expected.input(&Sha256::digest(b"a")[..8]);
+        expected.input(&Sha256::digest(b"b")[..8]);
+
+        assert_eq!(sent.len(), 1);
+        assert_eq!(trailer["first_offset"], 10);
+        assert_eq!(trailer["last_offset"], 11);
+        assert_eq!(trailer["records"], 2);
+        assert_eq!(trailer["checksum"], expected.result().iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    }
+
+    #[test]
+    fn compares_hashes() {
+        let expected = vec![(1, payload_hash(b"a")), (2, payload_hash(b"b")), (3, payload_hash(b"c"))];
+        let mut consumed = HashMap::new();
+        consumed.insert(1, payload_hash(b"a"));
+        consumed.insert(2, payload_hash(b"B"));
+
+        assert_eq!(compare(&expected, &consumed), Comparison {
+            matched: 1,
+            mismatched: vec![2],
+            missing: vec![3]
+        });
+    }
+}
@@ -0,0 +1,105 @@
+use super::rdkafka::ClientConfig;
+use std::{
+    self,
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+const WAIT_POLL_MS: u64 = 100;
+
+/// Connection state of each broker learned from cluster metadata, by node id, as last reported
+/// in librdkafka statistics. Bootstrap brokers, which have no node id, aren't tracked.
+#[derive(Clone, Default)]
+pub struct BrokerStates {
+    states: Arc<Mutex<BTreeMap<i32, String>>>
+}
+
+impl BrokerStates {
+    pub fn update(&self, node_id: i32, state: &str) {
+        if node_id < 0 {
+            return
+        }
+        self.states.lock().expect("Broker state lock poisoned").insert(node_id, state.to_string());
+    }
+
+    pub fn snapshot(&self) -> Vec<(i32, String)> {
+        self.states.lock().expect("Broker state lock poisoned").iter()
+            .map(|(id, state)| (*id, state.clone()))
+            .collect()
+    }
+
+    /// Brokers that aren't connected, `None` until any broker has been reported.
+    pub fn down(&self) -> Option<Vec<i32>> {
+        let states = self.snapshot();
+        if states.is_empty() {
+            return None
+        }
+        Some(states.into_iter().filter(|&(_, ref state)| state != "UP").map(|(id, _)| id).collect())
+    }
+}
+
+/// Keeps producer connections open through quiet periods: metadata of every topic is refreshed
+/// every `refresh`, which also connects to every broker, and TCP keepalives stop firewalls and
+/// NAT gateways from dropping idle connections. The first alert after a quiet hour then goes out
+/// on an open connection to a known leader instead of waiting on a reconnect and a metadata
+/// round trip.
+pub fn keep_warm(config: &mut ClientConfig, refresh: Duration) {
+    let refresh_ms = refresh.as_secs() * 1000 + refresh.subsec_nanos() as u64 / 1000000;
+    config
+        .set("topic.metadata.refresh.interval.ms", &refresh_ms.to_string())
+        .set("topic.metadata.refresh.sparse", "false")
+        .set("socket.keepalive.enable", "true");
+}
+
+/// Waits until statistics report every broker connected, or `timeout`, returning whether they
+/// were. Statistics are needed for the broker states, so `statistics.interval.ms` must be set.
+pub fn wait_until_up(states: &BrokerStates, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match states.down() {
+            Some(ref down) if down.is_empty() => {
+                info!("Connected to {} brokers", states.snapshot().len());
+                return true
+            }
+            down => {
+                if Instant::now() >= deadline {
+                    match down {
+                        Some(down) => warn!("Brokers {:?} still not connected after {:?}, starting anyway", down, timeout),
+                        None => warn!("No broker states reported after {:?}, starting anyway", timeout)
+                    }
+                    return false
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(WAIT_POLL_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_learned_brokers() {
+        let states = BrokerStates::default();
+        assert_eq!(states.down(), None);
+
+        states.update(-1, "UP");
+        assert_eq!(states.down(), None);
+
+        states.update(1, "UP");
+        states.update(2, "CONNECT");
+        assert_eq!(states.down(), Some(vec![2]));
+        assert!(!wait_until_up(&states, Duration::from_millis(0)));
+
+        states.update(2, "UP");
+        assert!(wait_until_up(&states, Duration::from_millis(0)));
+    }
+}
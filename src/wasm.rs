@@ -0,0 +1,216 @@
+//! Stage plugins compiled to WASM, run with wasmi. A module exports its `memory` and
+//!
+//! ```text
+//! surikafka_alloc(len: i32) -> i32
+//! surikafka_stages(len: i32) -> i32
+//! ```
+//!
+//! `surikafka_stages` returns the address of a JSON list of the stages the module declares and
+//! writes its length as a little endian `u32` at `len`, e.g.
+//! `[{"abi_version": 1, "kind": 1, "name": "redact", "export": "redact"}]`, with kinds numbered
+//! as in `extension::StageDeclaration`. Each stage's export takes
+//! `(record: i32, len: i32, output: i32) -> i32`: it is handed the record in a buffer from
+//! `surikafka_alloc`, returns `STAGE_KEEP`, `STAGE_DROP`, or a negative error, and may hand back
+//! a buffer of its own by writing its nonzero address and length as little endian `u32`s at
+//! `output`. Buffers are released with `surikafka_free(ptr: i32, len: i32)` if it is exported.
+//!
+//! wasmi instances can't be shared between threads, so each module runs on a thread of its own
+//! that its stages call over a channel; the thread exits once the stages are unloaded.
+use super::{
+    errors::Error,
+    serde_json::{
+        self,
+        Value
+    },
+    wasmi::{
+        self,
+        ExternVal,
+        ImportsBuilder,
+        MemoryRef,
+        ModuleInstance,
+        ModuleRef,
+        NopExternals,
+        RuntimeValue
+    }
+};
+use std::{
+    self,
+    os::raw::c_int,
+    path::Path,
+    sync::{
+        Mutex,
+        mpsc::{
+            self,
+            Sender
+        }
+    },
+    thread
+};
+
+/// A stage declared by a module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub abi_version: u32,
+    pub kind: u32,
+    pub name: String,
+    /// Function processing the stage's records, the stage's name if not declared
+    pub export: String
+}
+
+/// Status and output of a stage's export.
+pub type CallResult = Result<(c_int, Option<Vec<u8>>), Error>;
+
+struct Call {
+    export: String,
+    record: Vec<u8>,
+    reply: Sender<CallResult>
+}
+
+/// A loaded module, shared by its stages.
+pub struct Module {
+    calls: Mutex<Sender<Call>>
+}
+
+impl Module {
+    /// Loads the module at `path` on a thread of its own, with the stages it declares.
+    pub fn load(path: &Path) -> Result<(Module, Vec<Declaration>), Error> {
+        let bytes = std::fs::read(path)?;
+        let (calls, received) = mpsc::channel::<Call>();
+        let (declare, declared) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("wasm-{}", path.display()))
+            .spawn(move || {
+                let instance = match Instance::new(&bytes) {
+                    Ok(instance) => instance,
+                    Err(e) => {
+                        let _ = declare.send(Err(e));
+                        return
+                    }
+                };
+                let _ = declare.send(instance.declarations());
+                for call in received {
+                    let _ = call.reply.send(instance.call(&call.export, &call.record));
+                }
+            })?;
+        let declarations = declared.recv().map_err(|_| Error::from("WASM plugin stopped while loading"))??;
+        Ok( (Module { calls: Mutex::new(calls) }, declarations) )
+    }
+
+    /// Runs the stage exported as `export` over `record`.
+    pub fn call(&self, export: &str, record: &[u8]) -> CallResult {
+        let (reply, result) = mpsc::channel();
+        let call = Call {
+            export: export.to_string(),
+            record: record.to_vec(),
+            reply: reply
+        };
+        self.calls.lock().expect("WASM plugin lock poisoned").send(call)
+            .map_err(|_| Error::from("WASM plugin stopped"))?;
+        result.recv().map_err(|_| Error::from("WASM plugin stopped"))?
+    }
+}
+
+fn wasm_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::from(format!("WASM plugin failed: {}", e))
+}
+
+struct Instance {
+    instance: ModuleRef,
+    memory: MemoryRef
+}
+
+impl Instance {
+    fn new(bytes: &[u8]) -> Result<Instance, Error> {
+        let module = wasmi::Module::from_buffer(bytes).map_err(wasm_error)?;
+        let instance = ModuleInstance::new(&module, &ImportsBuilder::default()).map_err(wasm_error)?
+            .run_start(&mut NopExternals).map_err(wasm_error)?;
+        let memory = match instance.export_by_name("memory") {
+            Some(ExternVal::Memory(memory)) => memory,
+            _ => bail!("WASM plugin exports no memory")
+        };
+        Ok(Instance {
+            instance: instance,
+            memory: memory
+        })
+    }
+
+    fn invoke(&self, export: &str, args: &[RuntimeValue]) -> Result<i32, Error> {
+        match self.instance.invoke_export(export, args, &mut NopExternals).map_err(wasm_error)? {
+            Some(RuntimeValue::I32(value)) => Ok(value),
+            _ => bail!("WASM plugin export {} didn't return an i32", export)
+        }
+    }
+
+    fn alloc(&self, len: usize) -> Result<u32, Error> {
+        Ok(self.invoke("surikafka_alloc", &[RuntimeValue::I32(len as i32)])? as u32)
+    }
+
+    fn free(&self, at: u32, len: usize) {
+        if self.instance.export_by_name("surikafka_free").is_none() {
+            return
+        }
+        let args = [RuntimeValue::I32(at as i32), RuntimeValue::I32(len as i32)];
+        if let Err(e) = self.instance.invoke_export("surikafka_free", &args, &mut NopExternals) {
+            warn!("WASM plugin failed to free a buffer: {}", e);
+        }
+    }
+
+    fn read(&self, at: u32, len: usize) -> Result<Vec<u8>, Error> {
+        self.memory.get(at, len).map_err(wasm_error)
+    }
+
+    fn read_u32(&self, at: u32) -> Result<u32, Error> {
+        let bytes = self.read(at, 4)?;
+        Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+    }
+
+    fn declarations(&self) -> Result<Vec<Declaration>, Error> {
+        let len_at = self.alloc(4)?;
+        let at = self.invoke("surikafka_stages", &[RuntimeValue::I32(len_at as i32)])? as u32;
+        let len = self.read_u32(len_at)? as usize;
+        self.free(len_at, 4);
+        let declared = match serde_json::from_slice::<Value>(&self.read(at, len)?) {
+            Ok(Value::Array(declared)) => declared,
+            _ => bail!("WASM plugin declared its stages with something other than a JSON list")
+        };
+        declared.iter().map(|declaration| {
+            let number = |field: &str| declaration.get(field).and_then(Value::as_u64).map(|n| n as u32);
+            let text = |field: &str| declaration.get(field).and_then(Value::as_str).map(str::to_string);
+            match (number("abi_version"), number("kind"), text("name")) {
+                (Some(abi_version), Some(kind), Some(name)) => {
+                    let export = text("export").unwrap_or_else(|| name.clone());
+                    Ok(Declaration {
+                        abi_version: abi_version,
+                        kind: kind,
+                        name: name,
+                        export: export
+                    })
+                }
+                _ => bail!("Invalid WASM stage declaration {}", declaration)
+            }
+        }).collect()
+    }
+
+    fn call(&self, export: &str, record: &[u8]) -> CallResult {
+        let at = self.alloc(record.len())?;
+        let output_at = self.alloc(8)?;
+        self.memory.set(at, record).map_err(wasm_error)?;
+        self.memory.set(output_at, &[0; 8]).map_err(wasm_error)?;
+        let args = [RuntimeValue::I32(at as i32), RuntimeValue::I32(record.len() as i32), RuntimeValue::I32(output_at as i32)];
+        let result = self.invoke(export, &args)
+            .and_then(|rc| Ok( (rc as c_int, self.output(output_at)?) ));
+        self.free(at, record.len());
+        self.free(output_at, 8);
+        result
+    }
+
+    fn output(&self, at: u32) -> Result<Option<Vec<u8>>, Error> {
+        let (data, len) = (self.read_u32(at)?, self.read_u32(at + 4)? as usize);
+        if data == 0 {
+            return Ok(None)
+        }
+        let output = self.read(data, len)?;
+        self.free(data, len);
+        Ok(Some(output))
+    }
+}
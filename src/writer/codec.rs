@@ -0,0 +1,580 @@
+use super::super::{
+    errors::Error,
+    http,
+    metrics::Counter,
+    serde_json::{
+        self,
+        Map,
+        Value
+    }
+};
+use std::{
+    self,
+    borrow::Cow,
+    collections::HashMap,
+    io::{
+        Read,
+        Write
+    },
+    net::TcpStream,
+    str::FromStr,
+    time::Duration
+};
+
+const IO_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_REGISTRY_PORT: u16 = 8081;
+
+/// Serializes record payloads, after the key is embedded and the envelope applied.
+pub trait Codec {
+    /// Returns the payload to send, `None` to send it unchanged.
+    fn encode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+}
+
+impl<C: Codec + ?Sized> Codec for Box<C> {
+    fn encode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        (**self).encode(payload)
+    }
+}
+
+/// Codecs selectable by name, e.g. with `--codec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodecKind {
+    Json,
+    Avro,
+    MessagePack
+}
+
+impl FromStr for CodecKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(CodecKind::Json),
+            "avro" => Ok(CodecKind::Avro),
+            "msgpack" => Ok(CodecKind::MessagePack),
+            _ => bail!("Invalid codec: {}, expected json, avro, or msgpack", s)
+        }
+    }
+}
+
+/// Sends the JSON records as they are.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, _payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+}
+
+/// Converts JSON records to MessagePack.
+pub struct MessagePack;
+
+impl MessagePack {
+    fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [u8; 3]) {
+        if len <= fix_max {
+            out.push(fix | len as u8);
+        } else if markers[0] != 0 && len <= 0xff {
+            out.extend_from_slice(&[markers[0], len as u8]);
+        } else if len <= 0xffff {
+            out.extend_from_slice(&[markers[1], (len >> 8) as u8, len as u8]);
+        } else {
+            out.extend_from_slice(&[markers[2], (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        }
+    }
+
+    fn write(out: &mut Vec<u8>, value: &Value) {
+        match *value {
+            Value::Null => out.push(0xc0),
+            Value::Bool(b) => out.push(if b { 0xc3 } else { 0xc2 }),
+            Value::Number(ref n) => {
+                if let Some(i) = n.as_i64() {
+                    if i >= -32 && i < 128 {
+                        out.push(i as u8);
+                    } else {
+                        out.push(0xd3);
+                        out.extend((0..8).rev().map(|b| (i >> (8 * b)) as u8));
+                    }
+                } else if let Some(u) = n.as_u64() {
+                    out.push(0xcf);
+                    out.extend((0..8).rev().map(|b| (u >> (8 * b)) as u8));
+                } else {
+                    let bits = n.as_f64().unwrap_or(0.0).to_bits();
+                    out.push(0xcb);
+                    out.extend((0..8).rev().map(|b| (bits >> (8 * b)) as u8));
+                }
+            }
+            Value::String(ref s) => {
+                MessagePack::write_len(out, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::Array(ref a) => {
+                MessagePack::write_len(out, a.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+                for v in a.iter() {
+                    MessagePack::write(out, v);
+                }
+            }
+            Value::Object(ref o) => {
+                MessagePack::write_len(out, o.len(), 0x80, 15, [0, 0xde, 0xdf]);
+                for (k, v) in o.iter() {
+                    MessagePack::write(out, &Value::String(k.clone()));
+                    MessagePack::write(out, v);
+                }
+            }
+        }
+    }
+}
+
+impl Codec for MessagePack {
+    fn encode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value: Value = serde_json::from_slice(payload)
+            .map_err(|e| Error::from(format!("Record isn't JSON: {}", e)))?;
+        let mut out = Vec::with_capacity(payload.len());
+        MessagePack::write(&mut out, &value);
+        Ok(Some(out))
+    }
+}
+
+/// Field of an Avro record schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvroField {
+    pub name: String,
+    pub schema: AvroSchema,
+    pub default: Option<Value>
+}
+
+/// Avro schema, without named type references.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AvroSchema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<AvroField>),
+    Enum(Vec<String>),
+    Array(Box<AvroSchema>),
+    Map(Box<AvroSchema>),
+    Union(Vec<AvroSchema>),
+    Fixed(usize)
+}
+
+fn write_long(out: &mut Vec<u8>, n: i64) {
+    let mut zigzag = ((n << 1) ^ (n >> 63)) as u64;
+    while zigzag >= 0x80 {
+        out.push((zigzag as u8 & 0x7f) | 0x80);
+        zigzag >>= 7;
+    }
+    out.push(zigzag as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(out, bytes.len() as i64);
+    out.extend_from_slice(bytes);
+}
+
+impl AvroSchema {
+    pub fn parse(schema: &Value) -> Result<AvroSchema, Error> {
+        let (kind, definition) = match *schema {
+            Value::String(ref kind) => (kind.as_str(), None),
+            Value::Array(ref branches) => {
+                return Ok(AvroSchema::Union(branches.iter().map(AvroSchema::parse).collect::<Result<_, _>>()?))
+            }
+            Value::Object(ref definition) => match definition.get("type") {
+                Some(&Value::String(ref kind)) => (kind.as_str(), Some(definition)),
+                Some(nested) => return AvroSchema::parse(nested),
+                None => bail!("Avro schema without a type: {}", schema)
+            },
+            _ => bail!("Invalid Avro schema: {}", schema)
+        };
+        let attribute = |name: &str| definition.and_then(|d| d.get(name))
+            .ok_or_else(|| Error::from(format!("Avro {} schema without {}", kind, name)));
+        Ok(match kind {
+            "null" => AvroSchema::Null,
+            "boolean" => AvroSchema::Boolean,
+            "int" => AvroSchema::Int,
+            "long" => AvroSchema::Long,
+            "float" => AvroSchema::Float,
+            "double" => AvroSchema::Double,
+            "bytes" => AvroSchema::Bytes,
+            "string" => AvroSchema::String,
+            "record" => {
+                let fields = attribute("fields")?.as_array()
+                    .ok_or_else(|| Error::from("Avro record fields must be an array"))?;
+                AvroSchema::Record(fields.iter().map(|f| {
+                    Ok(AvroField {
+                        name: f.get("name").and_then(Value::as_str)
+                            .ok_or_else(|| Error::from(format!("Avro field without a name: {}", f)))?
+                            .to_string(),
+                        schema: AvroSchema::parse(f.get("type").unwrap_or(&Value::Null))?,
+                        default: f.get("default").cloned()
+                    })
+                }).collect::<Result<_, Error>>()?)
+            }
+            "enum" => AvroSchema::Enum(attribute("symbols")?.as_array()
+                .map(|s| s.iter().filter_map(Value::as_str).map(|s| s.to_string()).collect())
+                .unwrap_or_else(Vec::new)),
+            "array" => AvroSchema::Array(Box::new(AvroSchema::parse(attribute("items")?)?)),
+            "map" => AvroSchema::Map(Box::new(AvroSchema::parse(attribute("values")?)?)),
+            "fixed" => AvroSchema::Fixed(attribute("size")?.as_u64()
+                .ok_or_else(|| Error::from("Avro fixed size must be a number"))? as usize),
+            other => bail!("Avro named type references aren't supported: {}", other)
+        })
+    }
+
+    /// Reads a schema file, returning the parsed schema along with its JSON for registration.
+    pub fn load(path: &str) -> Result<(AvroSchema, Value), Error> {
+        let text: Value = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| Error::from(format!("Invalid Avro schema file {}: {}", path, e)))?;
+        Ok( (AvroSchema::parse(&text)?, text) )
+    }
+
+    /// Whether `value` can be written with this schema, to choose the branch of a union.
+    fn accepts(&self, value: &Value) -> bool {
+        match (self, value) {
+            (&AvroSchema::Null, &Value::Null) => true,
+            (&AvroSchema::Boolean, &Value::Bool(_)) => true,
+            (&AvroSchema::Int, &Value::Number(ref n)) => {
+                n.as_i64().map(|n| n >= std::i32::MIN as i64 && n <= std::i32::MAX as i64).unwrap_or(false)
+            }
+            (&AvroSchema::Long, &Value::Number(ref n)) => n.is_i64(),
+            (&AvroSchema::Float, &Value::Number(_)) |
+            (&AvroSchema::Double, &Value::Number(_)) => true,
+            (&AvroSchema::Bytes, &Value::String(_)) |
+            (&AvroSchema::String, &Value::String(_)) => true,
+            (&AvroSchema::Enum(ref symbols), &Value::String(ref s)) => symbols.contains(s),
+            (&AvroSchema::Fixed(size), &Value::String(ref s)) => s.len() == size,
+            (&AvroSchema::Record(_), &Value::Object(_)) |
+            (&AvroSchema::Map(_), &Value::Object(_)) => true,
+            (&AvroSchema::Array(_), &Value::Array(_)) => true,
+            (&AvroSchema::Union(ref branches), v) => branches.iter().any(|b| b.accepts(v)),
+            _ => false
+        }
+    }
+
+    /// Appends the Avro binary encoding of `value`.
+    pub fn write(&self, value: &Value, out: &mut Vec<u8>) -> Result<(), Error> {
+        let mismatch = || Error::from(format!("{} doesn't match Avro schema {:?}", value, self));
+        match *self {
+            AvroSchema::Null => if !value.is_null() { return Err(mismatch()) },
+            AvroSchema::Boolean => out.push(value.as_bool().ok_or_else(mismatch)? as u8),
+            AvroSchema::Int => {
+                let n = value.as_i64().ok_or_else(mismatch)?;
+                if n < std::i32::MIN as i64 || n > std::i32::MAX as i64 {
+                    return Err(mismatch())
+                }
+                write_long(out, n);
+            }
+            AvroSchema::Long => write_long(out, value.as_i64().ok_or_else(mismatch)?),
+            AvroSchema::Float => {
+                let bits = (value.as_f64().ok_or_else(mismatch)? as f32).to_bits();
+                out.extend((0..4).map(|b| (bits >> (8 * b)) as u8));
+            }
+            AvroSchema::Double => {
+                let bits = value.as_f64().ok_or_else(mismatch)?.to_bits();
+                out.extend((0..8).map(|b| (bits >> (8 * b)) as u8));
+            }
+            AvroSchema::Bytes | AvroSchema::String => write_bytes(out, value.as_str().ok_or_else(mismatch)?.as_bytes()),
+            AvroSchema::Record(ref fields) => {
+                let object = value.as_object().ok_or_else(mismatch)?;
+                for field in fields.iter() {
+                    let value = object.get(&field.name)
+                        .or_else(|| field.default.as_ref())
+                        .unwrap_or(&Value::Null);
+                    field.schema.write(value, out)
+                        .map_err(|e| Error::from(format!("Field {}: {}", field.name, e)))?;
+                }
+            }
+            AvroSchema::Enum(ref symbols) => {
+                let symbol = value.as_str().ok_or_else(mismatch)?;
+                let index = symbols.iter().position(|s| s == symbol).ok_or_else(mismatch)?;
+                write_long(out, index as i64);
+            }
+            AvroSchema::Array(ref items) => {
+                let array = value.as_array().ok_or_else(mismatch)?;
+                if !array.is_empty() {
+                    write_long(out, array.len() as i64);
+                    for item in array.iter() {
+                        items.write(item, out)?;
+                    }
+                }
+                write_long(out, 0);
+            }
+            AvroSchema::Map(ref values) => {
+                let map: &Map<String, Value> = value.as_object().ok_or_else(mismatch)?;
+                if !map.is_empty() {
+                    write_long(out, map.len() as i64);
+                    for (k, v) in map.iter() {
+                        write_bytes(out, k.as_bytes());
+                        values.write(v, out)?;
+                    }
+                }
+                write_long(out, 0);
+            }
+            AvroSchema::Union(ref branches) => {
+                let index = branches.iter().position(|b| b.accepts(value)).ok_or_else(mismatch)?;
+                write_long(out, index as i64);
+                branches[index].write(value, out)?;
+            }
+            AvroSchema::Fixed(size) => {
+                let bytes = value.as_str().ok_or_else(mismatch)?.as_bytes();
+                if bytes.len() != size {
+                    return Err(mismatch())
+                }
+                out.extend_from_slice(bytes);
+            }
+        }
+        Ok( () )
+    }
+}
+
+/// Confluent Schema Registry, over plain HTTP.
+#[derive(Debug, Clone)]
+pub struct SchemaRegistry {
+    addr: String,
+    path: String
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> Result<SchemaRegistry, Error> {
+        let (addr, path) = http::parse_url(url, DEFAULT_REGISTRY_PORT)?;
+        Ok(SchemaRegistry {
+            addr: addr,
+            path: path
+        })
+    }
+
+    /// Registers `schema` under `subject`, returning its id. Registering a schema that is
+    /// already registered returns the existing id.
+    pub fn register(&self, subject: &str, schema: &Value) -> Result<u32, Error> {
+        let body = json!({"schema": schema.to_string()}).to_string().into_bytes();
+        let mut stream = TcpStream::connect(self.addr.as_str())?;
+        stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+        // HTTP/1.0, so the response isn't chunked
+        let head = format!(
+            "POST {}/subjects/{}/versions HTTP/1.0\r\nHost: {}\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nContent-Length: {}\r\n\r\n",
+            self.path, subject, self.addr, body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&body)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let (status, body) = http::parse_response(&response)?;
+        if status != 200 {
+            bail!("Schema registry rejected {} with {}: {}", subject, status, String::from_utf8_lossy(body));
+        }
+        serde_json::from_slice::<Value>(body).ok()
+            .and_then(|v| v.get("id").and_then(Value::as_u64))
+            .map(|id| id as u32)
+            .ok_or_else(|| Error::from(format!("Schema registry response without an id: {}", String::from_utf8_lossy(body))))
+    }
+}
+
+/// Converts JSON records to Avro in the Schema Registry wire format: a zero magic byte, the
+/// schema id as a big endian u32, then the Avro binary encoding.
+pub struct Avro {
+    schema: AvroSchema,
+    id: u32
+}
+
+impl Avro {
+    pub fn new(schema: AvroSchema, id: u32) -> Avro {
+        Avro {
+            schema: schema,
+            id: id
+        }
+    }
+}
+
+impl Codec for Avro {
+    fn encode(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let value: Value = serde_json::from_slice(payload)
+            .map_err(|e| Error::from(format!("Record isn't JSON: {}", e)))?;
+        let mut out = vec![0, (self.id >> 24) as u8, (self.id >> 16) as u8, (self.id >> 8) as u8, self.id as u8];
+        self.schema.write(&value, &mut out)?;
+        Ok(Some(out))
+    }
+}
+
+/// Builds the codec of a topic, e.g. registering a schema under the topic's own subject.
+pub type CodecFactory = Box<Fn(&str) -> Result<Box<Codec + Send>, Error> + Send>;
+
+/// Codec of each topic, JSON unless configured otherwise. Records that fail to encode are
+/// logged, counted, and dropped.
+#[derive(Default)]
+pub struct CodecSet {
+    default: Option<Box<Codec + Send>>,
+    factory: Option<CodecFactory>,
+    topics: HashMap<String, Box<Codec + Send>>,
+    failures: Option<Counter>
+}
+
+impl CodecSet {
+    /// Codec of topics without one of their own.
+    pub fn with_default<C>(mut self, codec: C) -> Self
+        where C: Codec + Send + 'static
+    {
+        self.default = Some(Box::new(codec));
+        self
+    }
+
+    /// Builds the codec of each topic without one of its own when its first record is encoded,
+    /// in place of a default codec. Records of a topic whose codec can't be built are dropped
+    /// like those that fail to encode, and building is tried again with its next record.
+    pub fn with_factory<F>(mut self, factory: F) -> Self
+        where F: Fn(&str) -> Result<Box<Codec + Send>, Error> + Send + 'static
+    {
+        self.factory = Some(Box::new(factory));
+        self
+    }
+
+    pub fn with_topic<C>(mut self, topic: &str, codec: C) -> Self
+        where C: Codec + Send + 'static
+    {
+        self.topics.insert(topic.to_string(), Box::new(codec));
+        self
+    }
+
+    pub fn with_failure_counter(mut self, counter: Counter) -> Self {
+        self.failures = Some(counter);
+        self
+    }
+
+    fn failed(&self) {
+        if let Some(ref counter) = self.failures {
+            counter.incr();
+        }
+    }
+
+    /// Encodes `payload` for `topic`, `None` if it can't be encoded.
+    pub fn encode<'a>(&mut self, topic: &str, payload: Cow<'a, Vec<u8>>) -> Option<Cow<'a, Vec<u8>>> {
+        if !self.topics.contains_key(topic) {
+            let built = match self.factory {
+                Some(ref factory) => Some(factory(topic)),
+                None => None
+            };
+            match built {
+                Some(Ok(codec)) => {
+                    self.topics.insert(topic.to_string(), codec);
+                }
+                Some(Err(e)) => {
+                    error!("Dropping record for {}, its codec couldn't be built: {}", topic, e);
+                    self.failed();
+                    return None
+                }
+                None => ()
+            }
+        }
+        let codec = match self.topics.get(topic).or(self.default.as_ref()) {
+            Some(codec) => codec,
+            None => return Some(payload)
+        };
+        match codec.encode(&payload) {
+            Ok(Some(encoded)) => Some(Cow::Owned(encoded)),
+            Ok(None) => Some(payload),
+            Err(e) => {
+                error!("Dropping record that can't be encoded for {}: {}", topic, e);
+                self.failed();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_avro() {
+        let schema = AvroSchema::parse(&json!({
+            "type": "record",
+            "name": "Event",
+            "fields": [
+                {"name": "event_type", "type": "string"},
+                {"name": "src_port", "type": ["null", "int"]},
+                {"name": "vlan", "type": {"type": "array", "items": "long"}, "default": []}
+            ]
+        })).expect("Failed to parse schema");
+        let avro = Avro::new(schema, 7);
+
+        let encoded = avro.encode(br#"{"event_type":"dns","src_port":53}"#).expect("Failed to encode");
+
+        assert_eq!(encoded, Some(vec![0, 0, 0, 0, 7, 6, b'd', b'n', b's', 2, 106, 0]));
+        assert!(avro.encode(br#"{"event_type":1}"#).is_err());
+        assert!(AvroSchema::parse(&json!({"type": "Event"})).is_err());
+    }
+
+    #[test]
+    fn writes_large_numbers_as_long() {
+        let schema = AvroSchema::parse(&json!({
+            "type": "record",
+            "name": "Flow",
+            "fields": [ {"name": "bytes", "type": ["int", "long"]} ]
+        })).expect("Failed to parse schema");
+        let avro = Avro::new(schema, 1);
+
+        let small = avro.encode(br#"{"bytes":1}"#).expect("Failed to encode").expect("Not encoded");
+        let large = avro.encode(br#"{"bytes":5000000000}"#).expect("Failed to encode").expect("Not encoded");
+
+        assert_eq!(small[5], 0);
+        assert_eq!(large[5], 2);
+    }
+
+    #[test]
+    fn encodes_msgpack() {
+        let encoded = MessagePack.encode(br#"{"a":[1,-1,300,true,null]}"#).expect("Failed to encode");
+
+        assert_eq!(encoded, Some(vec![0x81, 0xa1, b'a', 0x95, 0x01, 0xff, 0xd3, 0, 0, 0, 0, 0, 0, 0x01, 0x2c, 0xc3, 0xc0]));
+    }
+
+    #[test]
+    fn selects_codecs_by_topic() {
+        let failures = Counter::new("writer.encode_failed");
+        let mut codecs = CodecSet::default()
+            .with_default(MessagePack)
+            .with_topic("raw", Json)
+            .with_failure_counter(failures.clone());
+        let msg = br#"{"a":1}"#.to_vec();
+
+        assert_eq!(codecs.encode("raw", Cow::Borrowed(&msg)), Some(Cow::Borrowed(&msg)));
+        assert_eq!(codecs.encode("eve", Cow::Borrowed(&msg)), Some(Cow::Owned(vec![0x81, 0xa1, b'a', 0x01])));
+        assert_eq!(codecs.encode("eve", Cow::Owned(b"not json".to_vec())), None);
+        assert_eq!(failures.value(), 1);
+    }
+
+    #[test]
+    fn builds_codecs_per_topic() {
+        use std::sync::{
+            Arc,
+            Mutex
+        };
+
+        let built = Arc::new(Mutex::new(vec![]));
+        let failures = Counter::new("writer.encode_failed");
+        let topics = built.clone();
+        let mut codecs = CodecSet::default()
+            .with_topic("raw", Json)
+            .with_factory(move |topic: &str| -> Result<Box<Codec + Send>, Error> {
+                topics.lock().expect("Lock poisoned").push(topic.to_string());
+                if topic == "unregistered" {
+                    bail!("Schema registry unavailable");
+                }
+                Ok(Box::new(MessagePack))
+            })
+            .with_failure_counter(failures.clone());
+        let msg = br#"{"a":1}"#.to_vec();
+
+        assert_eq!(codecs.encode("alerts", Cow::Borrowed(&msg)), Some(Cow::Owned(vec![0x81, 0xa1, b'a', 0x01])));
+        assert_eq!(codecs.encode("alerts", Cow::Borrowed(&msg)), Some(Cow::Owned(vec![0x81, 0xa1, b'a', 0x01])));
+        assert_eq!(codecs.encode("raw", Cow::Borrowed(&msg)), Some(Cow::Borrowed(&msg)));
+        assert_eq!(codecs.encode("unregistered", Cow::Borrowed(&msg)), None);
+        assert_eq!(codecs.encode("unregistered", Cow::Borrowed(&msg)), None);
+
+        assert_eq!(*built.lock().expect("Lock poisoned"), vec!["alerts", "unregistered", "unregistered"]);
+        assert_eq!(failures.value(), 2);
+    }
+}
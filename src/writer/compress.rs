@@ -0,0 +1,279 @@
+use super::super::{
+    errors::Error,
+    key::KeyGenerator
+};
+#[cfg(feature = "zstd-dict")]
+use super::super::{
+    futures::sync::mpsc::UnboundedSender,
+    metrics::QueueGauge,
+    zstd
+};
+use std;
+
+/// Header naming the compression of a record's payload.
+pub const ENCODING_HEADER: &'static str = "content-encoding";
+/// Header carrying the id of the dictionary a payload was compressed with, as decimal text.
+pub const DICTIONARY_HEADER: &'static str = "zstd.dict_id";
+
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// Id of a zstd dictionary from its header, `None` for raw content dictionaries or anything
+/// else without the dictionary magic number.
+pub fn dictionary_id(dictionary: &[u8]) -> Option<u32> {
+    if dictionary.len() < 8 || dictionary[..4] != DICTIONARY_MAGIC {
+        return None
+    }
+    Some(dictionary[4..8].iter().rev().fold(0, |id, b| id << 8 | *b as u32))
+}
+
+/// Keys dictionary records by their id, so a compacted dictionary topic keeps every id.
+pub struct DictionaryKey;
+
+impl KeyGenerator for DictionaryKey {
+    type Item = Vec<u8>;
+
+    fn generate(&self, msg: &Vec<u8>) -> Self::Item {
+        dictionary_id(msg).map(|id| id.to_string().into_bytes()).unwrap_or_else(Vec::new)
+    }
+}
+
+/// A compressed payload and the headers consumers need to decompress it.
+pub struct Compressed {
+    pub payload: Vec<u8>,
+    pub encoding: &'static str,
+    pub dictionary: Option<u32>
+}
+
+impl Compressed {
+    pub fn headers(&self) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec![ (ENCODING_HEADER.to_string(), self.encoding.as_bytes().to_vec()) ];
+        if let Some(id) = self.dictionary {
+            headers.push( (DICTIONARY_HEADER.to_string(), id.to_string().into_bytes()) );
+        }
+        headers
+    }
+}
+
+/// Compresses payloads after any codec, for compression at the application layer rather than
+/// (or as well as) on produced batches.
+pub trait PayloadCompressor {
+    fn compress(&mut self, payload: &[u8]) -> Result<Compressed, Error>;
+}
+
+impl PayloadCompressor for Box<PayloadCompressor + Send> {
+    fn compress(&mut self, payload: &[u8]) -> Result<Compressed, Error> {
+        (**self).compress(payload)
+    }
+}
+
+/// Keeps every `every`th payload until `target_bytes` have been kept, as samples for training a
+/// dictionary.
+pub struct DictionarySampler {
+    every: usize,
+    seen: usize,
+    target_bytes: usize,
+    sampled_bytes: usize,
+    samples: Vec<Vec<u8>>
+}
+
+impl DictionarySampler {
+    pub fn new(every: usize, target_bytes: usize) -> DictionarySampler {
+        DictionarySampler {
+            every: every.max(1),
+            seen: 0,
+            target_bytes: target_bytes,
+            sampled_bytes: 0,
+            samples: vec![]
+        }
+    }
+
+    /// Offers a payload, returning true once enough have been sampled.
+    pub fn offer(&mut self, payload: &[u8]) -> bool {
+        self.seen += 1;
+        if self.seen % self.every == 0 && self.sampled_bytes < self.target_bytes {
+            self.sampled_bytes += payload.len();
+            self.samples.push(payload.to_vec());
+        }
+        self.is_full()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.sampled_bytes >= self.target_bytes
+    }
+
+    /// Takes the samples, starting over.
+    pub fn take(&mut self) -> Vec<Vec<u8>> {
+        self.sampled_bytes = 0;
+        std::mem::replace(&mut self.samples, vec![])
+    }
+}
+
+/// Compresses payloads with zstd, using a dictionary once one is loaded or trained. Small,
+/// similar JSON records compress poorly on their own; a dictionary trained on sampled traffic
+/// carries their shared structure, so each record only pays for what differs. Dictionaries are
+/// published to a channel, normally produced to a compacted topic keyed by `DictionaryKey`, and
+/// every record names the dictionary it needs in the `zstd.dict_id` header.
+#[cfg(feature = "zstd-dict")]
+pub struct ZstdCompressor {
+    level: i32,
+    dictionary: Option<(u32, zstd::block::Compressor)>,
+    sampler: Option<DictionarySampler>,
+    max_dictionary_size: usize,
+    publisher: Option<(UnboundedSender<Vec<u8>>, QueueGauge)>,
+    unpublished: Option<Vec<u8>>,
+    plain: zstd::block::Compressor
+}
+
+#[cfg(feature = "zstd-dict")]
+impl ZstdCompressor {
+    pub fn new(level: i32) -> ZstdCompressor {
+        ZstdCompressor {
+            level: level,
+            dictionary: None,
+            sampler: None,
+            max_dictionary_size: 112640,
+            publisher: None,
+            unpublished: None,
+            plain: zstd::block::Compressor::new()
+        }
+    }
+
+    /// Compresses with a dictionary trained by `zstd --train` or an earlier run.
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> Result<Self, Error> {
+        let id = dictionary_id(&dictionary)
+            .ok_or_else(|| Error::from("Not a zstd dictionary, raw content dictionaries have no id"))?;
+        self.unpublished = Some(dictionary.clone());
+        self.dictionary = Some( (id, zstd::block::Compressor::with_dict(dictionary)) );
+        Ok(self)
+    }
+
+    /// Trains a dictionary of up to `max_size` bytes from the payloads kept by `sampler`,
+    /// replacing any loaded dictionary once trained. Payloads are compressed without a
+    /// dictionary until then.
+    pub fn with_training(mut self, sampler: DictionarySampler, max_size: usize) -> Self {
+        self.sampler = Some(sampler);
+        self.max_dictionary_size = max_size;
+        self
+    }
+
+    /// Sends every dictionary used on `sender`, counting them in `gauge`. Each is sent as it
+    /// compresses its first record.
+    pub fn with_publisher(mut self, sender: UnboundedSender<Vec<u8>>, gauge: QueueGauge) -> Self {
+        self.publisher = Some( (sender, gauge) );
+        self
+    }
+
+    fn publish(&self, dictionary: Vec<u8>) {
+        if let Some( (ref sender, ref gauge) ) = self.publisher {
+            if sender.unbounded_send(dictionary).is_err() {
+                error!("Dictionary receiver closed, consumers can't fetch this dictionary");
+            } else {
+                gauge.add(1);
+            }
+        }
+    }
+
+    fn train(&mut self) {
+        let samples = match self.sampler {
+            Some(ref mut sampler) => sampler.take(),
+            None => return
+        };
+        self.sampler = None;
+        let dictionary = match zstd::dict::from_samples(&samples, self.max_dictionary_size) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Failed to train zstd dictionary from {} samples: {:?}", samples.len(), e);
+                return
+            }
+        };
+        match dictionary_id(&dictionary) {
+            Some(id) => {
+                info!("Trained zstd dictionary {} of {} bytes from {} samples", id, dictionary.len(), samples.len());
+                self.unpublished = Some(dictionary.clone());
+                self.dictionary = Some( (id, zstd::block::Compressor::with_dict(dictionary)) );
+            }
+            None => warn!("Trained zstd dictionary has no id, not using it")
+        }
+    }
+}
+
+#[cfg(feature = "zstd-dict")]
+impl PayloadCompressor for ZstdCompressor {
+    fn compress(&mut self, payload: &[u8]) -> Result<Compressed, Error> {
+        let full = match self.sampler {
+            Some(ref mut sampler) => sampler.offer(payload),
+            None => false
+        };
+        if full {
+            self.train();
+        }
+        if let Some(dictionary) = self.unpublished.take() {
+            self.publish(dictionary);
+        }
+        let level = self.level;
+        let (payload, dictionary) = match self.dictionary {
+            Some( (ref id, ref mut compressor) ) => (compressor.compress(payload, level)?, Some(*id)),
+            None => (self.plain.compress(payload, level)?, None)
+        };
+        Ok(Compressed {
+            payload: payload,
+            encoding: "zstd",
+            dictionary: dictionary
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_dictionary_ids() {
+        let mut dictionary = DICTIONARY_MAGIC.to_vec();
+        dictionary.extend_from_slice(&[0x39, 0x30, 0x00, 0x00, 0xff]);
+
+        assert_eq!(dictionary_id(&dictionary), Some(12345));
+        assert_eq!(DictionaryKey.generate(&dictionary), b"12345".to_vec());
+        assert_eq!(dictionary_id(b"{\"event_type\":\"flow\"}"), None);
+    }
+
+    #[test]
+    fn samples_until_full() {
+        let mut sampler = DictionarySampler::new(2, 10);
+
+        assert!(!sampler.offer(b"skipped"));
+        assert!(!sampler.offer(b"kept"));
+        assert!(!sampler.offer(b"skipped"));
+        assert!(sampler.offer(b"kept again"));
+
+        assert_eq!(sampler.take(), vec![b"kept".to_vec(), b"kept again".to_vec()]);
+        assert!(!sampler.is_full());
+    }
+
+    #[cfg(feature = "zstd-dict")]
+    #[test]
+    fn compresses_with_trained_dictionary() {
+        let mut compressor = ZstdCompressor::new(3)
+            .with_training(DictionarySampler::new(1, 64 * 1024), 4096);
+        let record = |i: usize| format!(
+            r#"{{"timestamp":"2024-01-01T00:00:{:02}","event_type":"flow","src_ip":"10.0.0.{}","proto":"TCP","flow":{{"pkts_toserver":{}}}}}"#,
+            i % 60, i % 250, i
+        ).into_bytes();
+
+        let first = compressor.compress(&record(0)).expect("Failed to compress");
+        assert_eq!(first.dictionary, None);
+
+        let mut trained = None;
+        for i in 1..2000 {
+            let compressed = compressor.compress(&record(i)).expect("Failed to compress");
+            if compressed.dictionary.is_some() {
+                trained = Some( (compressed, i) );
+                break
+            }
+        }
+        let (compressed, i) = trained.expect("Never trained a dictionary");
+
+        assert!(compressed.payload.len() < record(i).len() / 2);
+        assert!(compressed.headers().iter().any(|&(ref name, _)| name == DICTIONARY_HEADER));
+    }
+}
@@ -0,0 +1,511 @@
+use super::super::{
+    breaker::{
+        BreakerState,
+        CircuitBreaker
+    },
+    chrono::Utc,
+    eve,
+    futures::{
+        Async,
+        Canceled,
+        Future,
+        Poll,
+        Stream,
+        stream::FuturesUnordered
+    },
+    metrics::{
+        LatencyHistogram,
+        QueueGauge
+    },
+    rdkafka::{
+        error::KafkaError,
+        producer::DeliveryFuture
+    },
+    runtime::Delay,
+    source,
+    stats,
+    throttle::{
+        Pacing,
+        ThrottleSignal
+    },
+    trace::SourceLine,
+    verify::{
+        Fingerprint,
+        ProducedDigest,
+        TrailerEmitter
+    }
+};
+use super::retry::Failure;
+use std::{
+    self,
+    time::{
+        Duration,
+        Instant
+    }
+};
+
+/// Longest delay between sends while brokers throttle.
+const MAX_PACING_MS: u64 = 1000;
+
+/// A record just produced, with the delivery of its mirror record when its topic is dual
+/// written, its fingerprint when deliveries are verified, and whether it was sent on the
+/// immediate producer.
+pub struct Sent {
+    pub record: DeliveryFuture,
+    pub mirror: Option<DeliveryFuture>,
+    pub fingerprint: Option<Fingerprint>,
+    pub immediate: bool
+}
+
+struct OutstandingProduce {
+    alert_length: usize,
+    sent_at: Instant,
+    immediate: bool,
+    future_produce: DeliveryFuture,
+    delivered: Option<Result<(i32, i64), KafkaError>>,
+    mirror: Option<DeliveryFuture>,
+    mirror_error: Option<KafkaError>,
+    fingerprint: Option<Fingerprint>,
+    retained: Option<(Vec<u8>, usize)>,
+    event_age: Option<Duration>,
+    source: Option<SourceLine>
+}
+
+struct FinishedProduce {
+    alert_length: usize,
+    sent_at: Instant,
+    immediate: bool,
+    fingerprint: Option<Fingerprint>,
+    retained: Option<(Vec<u8>, usize)>,
+    event_age: Option<Duration>,
+    source: Option<SourceLine>,
+    result: Result<(i32, i64), KafkaError>
+}
+
+impl Future for OutstandingProduce {
+    type Item = FinishedProduce;
+    type Error = Canceled;
+
+    /// Finishes once the record and its mirror, if any, are delivered, failing if either does.
+    fn poll(&mut self) -> Poll<FinishedProduce, Canceled> {
+        if self.delivered.is_none() {
+            if let Async::Ready(result) = self.future_produce.poll()? {
+                self.delivered = Some(result.map_err(|(e, _)| e));
+            }
+        }
+        let mirrored = self.mirror.as_mut().map(|m| m.poll());
+        match mirrored {
+            Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+            Some(Ok(Async::Ready(result))) => {
+                self.mirror = None;
+                if let Err( (e, _) ) = result {
+                    self.mirror_error = Some(e);
+                }
+            }
+            Some(Err(e)) => return Err(e),
+            None => ()
+        }
+        let result = match (self.delivered.take(), self.mirror_error.take()) {
+            (None, _) => return Ok(Async::NotReady),
+            (Some(Ok(_)), Some(e)) => Err(e),
+            (Some(result), _) => result
+        };
+        Ok(Async::Ready(FinishedProduce {
+            alert_length: self.alert_length,
+            sent_at: self.sent_at,
+            immediate: self.immediate,
+            fingerprint: self.fingerprint.take(),
+            retained: self.retained.take(),
+            event_age: self.event_age,
+            source: self.source.take(),
+            result: result
+        }))
+    }
+}
+
+/// Result of a finished delivery.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivered {
+    pub length: usize,
+    pub latency: Duration,
+    pub success: bool,
+    /// The failed record, if it was retained for a `DeliveryErrorHandler`
+    pub failure: Option<Failure>
+}
+
+/// Tracks outstanding deliveries and decides when the next record may be sent, holding back
+/// while `max_in_flight` deliveries are outstanding, while the circuit breaker is open, or while
+/// pacing sends during broker throttling.
+pub struct Deliverer {
+    breaker: Option<CircuitBreaker>,
+    cooldown: Option<Delay>,
+    in_flight_gauge: Option<QueueGauge>,
+    latency: Option<LatencyHistogram>,
+    event_latency: Option<LatencyHistogram>,
+    immediate_latency: Option<(LatencyHistogram, LatencyHistogram)>,
+    throttle: Option<ThrottleSignal>,
+    pacing: Pacing,
+    pacing_delay: Option<Delay>,
+    digest: Option<ProducedDigest>,
+    trailers: Option<TrailerEmitter>,
+    max_in_flight: usize,
+    outstanding: FuturesUnordered<OutstandingProduce>
+}
+
+impl Default for Deliverer {
+    fn default() -> Deliverer {
+        Deliverer {
+            breaker: None,
+            cooldown: None,
+            in_flight_gauge: None,
+            latency: None,
+            event_latency: None,
+            immediate_latency: None,
+            throttle: None,
+            pacing: Pacing::new(Duration::from_millis(MAX_PACING_MS)),
+            pacing_delay: None,
+            digest: None,
+            trailers: None,
+            max_in_flight: 1,
+            outstanding: FuturesUnordered::new()
+        }
+    }
+}
+
+impl Deliverer {
+    /// Stop sending while the breaker is open, leaving events buffered upstream until the
+    /// cooldown expires.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Tracks the number of deliveries awaiting a result from the broker.
+    pub fn with_in_flight_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.in_flight_gauge = Some(gauge);
+        self
+    }
+
+    /// Records the time from send to delivery report of every successful delivery.
+    pub fn with_latency_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.latency = Some(histogram);
+        self
+    }
+
+    /// Records the time from the EVE timestamp of every successfully delivered record to its
+    /// delivery report, the end to end latency including Suricata's own buffering.
+    pub fn with_event_latency_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.event_latency = Some(histogram);
+        self
+    }
+
+    /// Records the send and end to end latencies of records sent on the immediate producer in
+    /// `latency` and `event_latency` rather than with the others, so bulk traffic doesn't hide
+    /// them.
+    pub fn with_immediate_latency_histograms(mut self, latency: LatencyHistogram, event_latency: LatencyHistogram) -> Self {
+        self.immediate_latency = Some( (latency, event_latency) );
+        self
+    }
+
+    /// Age of `msg` by its EVE timestamp, when tracking end to end latency.
+    pub fn event_age(&self, msg: &[u8]) -> Option<Duration> {
+        if self.event_latency.is_none() && self.immediate_latency.is_none() {
+            return None
+        }
+        let timestamp = source::parse_timestamp(eve::timestamp(msg)?)?;
+        Utc::now().signed_duration_since(timestamp).to_std().ok()
+    }
+
+    /// Slow sends while brokers report throttling, and don't count failures during throttling
+    /// towards the circuit breaker since they are usually client side timeouts.
+    pub fn with_throttle(mut self, signal: ThrottleSignal) -> Self {
+        self.throttle = Some(signal);
+        self
+    }
+
+    /// Records the partition, offset, and payload hash of successful deliveries, for
+    /// verification against what the broker returns.
+    pub fn with_digest(mut self, digest: ProducedDigest) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    /// Sends checksum trailers of successful deliveries per partition.
+    pub fn with_trailers(mut self, trailers: TrailerEmitter) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    /// Deliveries that may be awaiting a result at once, 1 by default. librdkafka keeps records
+    /// of a partition in order unless retries reorder them, so limit
+    /// `max.in.flight.requests.per.connection` to 1 when raising this and ordering matters.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_verifying(&self) -> bool {
+        self.digest.is_some() || self.trailers.is_some()
+    }
+
+    /// Starts tracking the delivery of a `sent` record of `length` bytes. `retained` is the
+    /// record and its attempt number, kept to hand the record back if its delivery fails;
+    /// `event_age` is from `event_age`; `source` is the line the record was read from, named if
+    /// it fails. A record dual written to a second topic is only delivered once its mirror is too.
+    pub fn track(
+        &mut self,
+        sent: Sent,
+        length: usize,
+        retained: Option<(Vec<u8>, usize)>,
+        event_age: Option<Duration>,
+        source: Option<SourceLine>
+    ) {
+        self.outstanding.push(OutstandingProduce {
+            alert_length: length,
+            sent_at: Instant::now(),
+            immediate: sent.immediate,
+            future_produce: sent.record,
+            delivered: None,
+            mirror: sent.mirror,
+            mirror_error: None,
+            fingerprint: sent.fingerprint,
+            retained: retained,
+            event_age: event_age,
+            source: source
+        });
+        if let Some(ref gauge) = self.in_flight_gauge {
+            gauge.add(1);
+        }
+        self.schedule_pacing();
+    }
+
+    /// Polls the outstanding deliveries, returning the first to finish, `Ready(None)` if there
+    /// are none.
+    pub fn poll_delivered(&mut self) -> Poll<Option<Delivered>, Canceled> {
+        trace!("Checking {} outstanding futures", self.outstanding.len());
+        let finished = match self.outstanding.poll()? {
+            Async::NotReady => {
+                debug!("Not ready, will poll later");
+                return Ok(Async::NotReady)
+            }
+            Async::Ready(None) => {
+                trace!("No outstanding future, will poll for next future");
+                return Ok(Async::Ready(None))
+            }
+            Async::Ready(Some(f)) => f
+        };
+        let success = match finished.result {
+            Err(ref e) if finished.retained.is_some() => {
+                debug!("Failed to produce: {:?}", e);
+                false
+            }
+            Err(ref e) => {
+                match finished.source {
+                    Some(ref source) => error!("Failed to produce record from {}: {:?}", source, e),
+                    None => error!("Failed to produce: {:?}", e)
+                }
+                false
+            }
+            Ok( (p, o) ) => {
+                debug!("Produced to partition {}, offset {}", p, o);
+                if let (Some(digest), Some(fingerprint)) = (self.digest.as_ref(), finished.fingerprint.as_ref()) {
+                    digest.record(fingerprint, p, o);
+                }
+                if let (Some(trailers), Some(fingerprint)) = (self.trailers.as_mut(), finished.fingerprint.as_ref()) {
+                    trailers.record(fingerprint, p, o);
+                }
+                true
+            }
+        };
+        if let Some(ref gauge) = self.in_flight_gauge {
+            gauge.sub(1);
+        }
+        let latency = Instant::now() - finished.sent_at;
+        if success {
+            let (histogram, event_histogram) = match (finished.immediate, self.immediate_latency.as_ref()) {
+                (true, Some(&(ref latency, ref event_latency))) => (Some(latency), Some(event_latency)),
+                _ => (self.latency.as_ref(), self.event_latency.as_ref())
+            };
+            if let Some(histogram) = histogram {
+                histogram.record(latency);
+            }
+            if let (Some(histogram), Some(age)) = (event_histogram, finished.event_age) {
+                histogram.record(age + latency);
+            }
+        }
+        let failure = match (finished.result, finished.retained) {
+            (Err(e), Some( (msg, attempt) )) => Some(Failure {
+                msg: msg,
+                attempt: attempt,
+                error: e,
+                source: finished.source
+            }),
+            _ => None
+        };
+        Ok(Async::Ready(Some(Delivered {
+            length: finished.alert_length,
+            latency: latency,
+            success: success,
+            failure: failure
+        })))
+    }
+
+    /// Ready when the next record may be sent.
+    pub fn poll_ready(&mut self) -> Async<()> {
+        if self.outstanding.len() >= self.max_in_flight {
+            debug!("{} deliveries in flight, not producing", self.outstanding.len());
+            return Async::NotReady
+        }
+        if let Async::NotReady = self.poll_breaker() {
+            debug!("Circuit breaker open, not producing");
+            return Async::NotReady
+        }
+        // A half open breaker allows a single trial, so it waits for whatever is still in flight
+        let half_open = self.breaker.as_ref().map(|b| b.state() == BreakerState::HalfOpen).unwrap_or(false);
+        if half_open && self.outstanding.len() > 0 {
+            debug!("Circuit breaker half open, waiting on the trial delivery");
+            return Async::NotReady
+        }
+        if let Async::NotReady = self.poll_pacing() {
+            debug!("Pacing sends while throttled");
+            return Async::NotReady
+        }
+        Async::Ready(())
+    }
+
+    /// Adds a finished delivery to `stats` and the circuit breaker.
+    pub fn record(&mut self, delivered: &Delivered, stats: &mut stats::Stats) {
+        if delivered.success {
+            stats.mark(delivered.length, delivered.latency);
+        } else {
+            stats.mark_failure();
+        }
+        let throttled = self.throttle.as_ref().map(|t| t.is_throttled()).unwrap_or(false);
+        if !delivered.success && throttled {
+            debug!("Delivery failed while throttled, not counting towards circuit breaker");
+            return
+        }
+        if let Some(ref mut breaker) = self.breaker {
+            breaker.record(delivered.success, Instant::now());
+            stats.set_breaker(breaker.state(), breaker.transitions());
+        }
+    }
+
+    /// Polls the cooldown timer if the breaker is open, returning `NotReady` until it expires.
+    fn poll_breaker(&mut self) -> Async<()> {
+        let now = Instant::now();
+        let until = match self.breaker.as_mut().and_then(|b| b.blocked_until(now)) {
+            Some(until) => until,
+            None => {
+                self.cooldown = None;
+                return Async::Ready(())
+            }
+        };
+        let mut delay = self.cooldown.take().unwrap_or_else(|| Delay::new(until));
+        match delay.poll() {
+            Ok(Async::NotReady) => {
+                self.cooldown = Some(delay);
+                Async::NotReady
+            }
+            Ok(Async::Ready(())) => Async::Ready(()),
+            Err(e) => {
+                error!("Circuit breaker timer failed: {:?}", e);
+                Async::Ready(())
+            }
+        }
+    }
+
+    fn schedule_pacing(&mut self) {
+        let throttle = match self.throttle {
+            Some(ref signal) => signal.throttle(),
+            None => return
+        };
+        let delay = self.pacing.update(throttle);
+        if delay > Duration::from_millis(0) {
+            self.pacing_delay = Some(Delay::new(Instant::now() + delay));
+        }
+    }
+
+    /// Polls the delay between sends while throttled, returning `NotReady` until it expires.
+    fn poll_pacing(&mut self) -> Async<()> {
+        let mut delay = match self.pacing_delay.take() {
+            Some(d) => d,
+            None => return Async::Ready(())
+        };
+        match delay.poll() {
+            Ok(Async::NotReady) => {
+                self.pacing_delay = Some(delay);
+                Async::NotReady
+            }
+            Ok(Async::Ready(())) => Async::Ready(()),
+            Err(e) => {
+                error!("Pacing timer failed: {:?}", e);
+                Async::Ready(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::super::{
+        breaker::BreakerConfig,
+        metrics::Counter
+    };
+
+    fn failed() -> Delivered {
+        Delivered {
+            length: 10,
+            latency: Duration::from_millis(5),
+            success: false,
+            failure: None
+        }
+    }
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            min_samples: 1,
+            max_error_rate: 0.0,
+            ..BreakerConfig::default()
+        })
+    }
+
+    #[test]
+    fn ready_without_outstanding_deliveries() {
+        let mut deliverer = Deliverer::default();
+
+        assert_eq!(deliverer.poll_delivered(), Ok(Async::Ready(None)));
+        assert_eq!(deliverer.poll_ready(), Async::Ready(()));
+    }
+
+    #[test]
+    fn records_failures_in_breaker() {
+        let mut deliverer = Deliverer::default().with_circuit_breaker(breaker());
+        let mut stats = stats::Stats::default();
+
+        deliverer.record(&failed(), &mut stats);
+
+        assert_eq!(stats.failure_count(), 1);
+        assert_eq!(stats.breaker_state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn ignores_failures_while_throttled() {
+        let throttle = ThrottleSignal::new(Counter::new("writer.throttled"));
+        throttle.set(100);
+        let mut deliverer = Deliverer::default()
+            .with_circuit_breaker(breaker())
+            .with_throttle(throttle);
+        let mut stats = stats::Stats::default();
+
+        deliverer.record(&failed(), &mut stats);
+
+        assert_eq!(stats.failure_count(), 1);
+        assert_eq!(stats.breaker_state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,307 @@
+use super::super::{
+    errors::{
+        Error,
+        ErrorKind
+    },
+    eve,
+    rdkafka::message::OwnedHeaders,
+    serde_json::{
+        self,
+        Value
+    }
+};
+use super::envelope::{
+    CloudEvents,
+    EnvelopeMode
+};
+use std::{
+    self,
+    borrow::Cow
+};
+
+/// Header and field name carrying the generated key for destinations that have no record key.
+pub const KEY_NAME: &'static str = "surikafka.key";
+
+/// Where the generated key is written, in addition to the record key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyPlacement {
+    Record,
+    Header,
+    Field
+}
+
+impl std::str::FromStr for KeyPlacement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<KeyPlacement, Error> {
+        match s {
+            "record" => Ok(KeyPlacement::Record),
+            "header" => Ok(KeyPlacement::Header),
+            "field" => Ok(KeyPlacement::Field),
+            _ => Err(Error::from_kind(ErrorKind::InvalidKeyPlacement(s.to_string())))
+        }
+    }
+}
+
+/// Renders a key for use in a text field, hex encoded unless it is already printable.
+pub fn key_text(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) if s.chars().all(|c| !c.is_control()) => s.to_string(),
+        _ => key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Adds the key to a JSON payload as a top level `surikafka.key` field. Payloads that are not
+/// JSON objects are returned unchanged.
+pub fn embed_key(msg: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut event: Value = match serde_json::from_slice(msg) {
+        Ok(v) => v,
+        Err(_) => return msg.to_vec()
+    };
+    match event.as_object_mut() {
+        Some(object) => {
+            object.insert(KEY_NAME.to_string(), Value::String(key_text(key)));
+        }
+        None => return msg.to_vec()
+    }
+    serde_json::to_vec(&event).unwrap_or_else(|_| msg.to_vec())
+}
+
+/// Produces Kafka record headers for a message, as `(name, value)` pairs.
+pub trait HeaderGenerator {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)>;
+}
+
+/// Adds `sensor`, `event_type`, `timestamp`, and `shipper_version` headers, so consumers can
+/// route records without parsing them. The event type and timestamp are found without parsing
+/// the record and left out when missing.
+#[derive(Debug, Clone)]
+pub struct EveHeaders {
+    sensor: Vec<u8>,
+    version: Vec<u8>
+}
+
+impl EveHeaders {
+    pub fn new(sensor: &str) -> EveHeaders {
+        EveHeaders {
+            sensor: sensor.as_bytes().to_vec(),
+            version: format!("surikafka/{}", env!("CARGO_PKG_VERSION")).into_bytes()
+        }
+    }
+}
+
+impl HeaderGenerator for EveHeaders {
+    fn generate(&self, msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec![ ("sensor".to_string(), self.sensor.clone()) ];
+        if let Some(event_type) = eve::event_type(msg) {
+            headers.push( ("event_type".to_string(), event_type.as_bytes().to_vec()) );
+        }
+        if let Some(timestamp) = eve::timestamp(msg) {
+            headers.push( ("timestamp".to_string(), timestamp.as_bytes().to_vec()) );
+        }
+        headers.push( ("shipper_version".to_string(), self.version.clone()) );
+        headers
+    }
+}
+
+/// Adds the same user defined headers to every record.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StaticHeaders {
+    headers: Vec<(String, Vec<u8>)>
+}
+
+impl StaticHeaders {
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push( (name.to_string(), value.as_bytes().to_vec()) );
+        self
+    }
+
+    /// Parses a header as `name=value`, e.g. `site=ams1`.
+    pub fn with_parsed(self, header: &str) -> Result<Self, Error> {
+        let mut parts = header.splitn(2, '=');
+        match (parts.next().map(str::trim), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => Ok(self.with_header(name, value)),
+            _ => bail!("Invalid header {}, expected name=value", header)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+impl HeaderGenerator for StaticHeaders {
+    fn generate(&self, _msg: &Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        self.headers.clone()
+    }
+}
+
+/// Payload and headers of a record, borrowing the event unless the key is embedded in it.
+pub struct Encoded<'a> {
+    pub payload: Cow<'a, Vec<u8>>,
+    pub headers: Vec<(String, Vec<u8>)>
+}
+
+impl<'a> Encoded<'a> {
+    pub fn owned_headers(&self) -> Option<OwnedHeaders> {
+        owned_headers(&self.headers)
+    }
+}
+
+/// Kafka headers of `headers`, `None` if there are none.
+pub fn owned_headers(headers: &[(String, Vec<u8>)]) -> Option<OwnedHeaders> {
+    if headers.is_empty() {
+        return None
+    }
+    Some(headers.iter().fold(OwnedHeaders::new(), |owned, &(ref name, ref value)| {
+        owned.add(name, value)
+    }))
+}
+
+/// Turns an event and its key into a record payload and headers.
+pub struct Encoder {
+    placement: KeyPlacement,
+    headers: Vec<Box<HeaderGenerator + Send>>,
+    envelope: Option<CloudEvents>
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder {
+            placement: KeyPlacement::Record,
+            headers: vec![],
+            envelope: None
+        }
+    }
+}
+
+impl Encoder {
+    pub fn with_placement(mut self, placement: KeyPlacement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Adds headers from `generator` to every record. May be called several times.
+    pub fn with_headers<H>(mut self, generator: H) -> Self
+        where H: HeaderGenerator + Send + 'static
+    {
+        self.headers.push(Box::new(generator));
+        self
+    }
+
+    /// Wraps every record in a CloudEvents envelope, after embedding the key if placed in a field.
+    pub fn with_envelope(mut self, envelope: CloudEvents) -> Self {
+        self.envelope = match envelope.mode() {
+            EnvelopeMode::None => None,
+            _ => Some(envelope)
+        };
+        self
+    }
+
+    pub fn encode<'a>(&self, msg: &'a Vec<u8>, key: &[u8]) -> Encoded<'a> {
+        self.encode_from(msg, msg, key)
+    }
+
+    /// Encodes `event`, a projection of `msg`, generating headers from the whole of `msg`.
+    pub fn encode_from<'a>(&self, event: &'a Vec<u8>, msg: &Vec<u8>, key: &[u8]) -> Encoded<'a> {
+        let payload = if self.placement == KeyPlacement::Field {
+            Cow::Owned(embed_key(event, key))
+        } else {
+            Cow::Borrowed(event)
+        };
+        let mut headers: Vec<(String, Vec<u8>)> = self.headers.iter()
+            .flat_map(|g| g.generate(msg))
+            .collect();
+        if self.placement == KeyPlacement::Header {
+            headers.push( (KEY_NAME.to_string(), key.to_vec()) );
+        }
+        let payload = match self.envelope {
+            Some(ref envelope) if envelope.mode() == EnvelopeMode::Structured => {
+                headers.push( ("content-type".to_string(), b"application/cloudevents+json".to_vec()) );
+                Cow::Owned(envelope.structured(&payload))
+            }
+            Some(ref envelope) => {
+                headers.extend(envelope.binary_headers(&payload));
+                payload
+            }
+            None => payload
+        };
+        Encoded {
+            payload: payload,
+            headers: headers
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_keys() {
+        let embedded = embed_key(br#"{"event_type":"alert"}"#, b"flow-1");
+        let value: Value = serde_json::from_slice(&embedded).expect("Failed to parse");
+
+        assert_eq!(value[KEY_NAME], "flow-1");
+        assert_eq!(embed_key(b"not json", b"flow-1"), b"not json".to_vec());
+        assert_eq!(key_text(&[0x00, 0xff]), "00ff");
+    }
+
+    #[test]
+    fn borrows_unchanged_payloads() {
+        let msg = br#"{"event_type":"alert"}"#.to_vec();
+        let encoded = Encoder::default().encode(&msg, b"flow-1");
+
+        match encoded.payload {
+            Cow::Borrowed(payload) => assert_eq!(payload, &msg),
+            Cow::Owned(_) => panic!("Payload was copied")
+        }
+        assert!(encoded.owned_headers().is_none());
+    }
+
+    #[test]
+    fn places_keys_in_headers() {
+        let msg = br#"{"event_type":"alert"}"#.to_vec();
+        let encoded = Encoder::default()
+            .with_placement(KeyPlacement::Header)
+            .with_headers(StaticHeaders::default().with_header("sensor", "s1"))
+            .encode(&msg, b"flow-1");
+
+        assert_eq!(*encoded.payload, msg);
+        assert_eq!(encoded.headers, vec![
+            ("sensor".to_string(), b"s1".to_vec()),
+            (KEY_NAME.to_string(), b"flow-1".to_vec())
+        ]);
+    }
+
+    #[test]
+    fn generates_eve_and_static_headers() {
+        let msg = br#"{"timestamp":"2018-06-01T12:00:00.000000+0000","event_type":"dns"}"#.to_vec();
+        let eve = EveHeaders::new("s1").generate(&msg);
+        let user = StaticHeaders::default().with_parsed("site=ams1").expect("Failed to parse");
+
+        assert_eq!(eve[0], ("sensor".to_string(), b"s1".to_vec()));
+        assert_eq!(eve[1], ("event_type".to_string(), b"dns".to_vec()));
+        assert_eq!(eve[2], ("timestamp".to_string(), b"2018-06-01T12:00:00.000000+0000".to_vec()));
+        assert_eq!(eve[3].0, "shipper_version");
+        assert_eq!(EveHeaders::new("s1").generate(&b"not json".to_vec()).len(), 2);
+        assert_eq!(user.generate(&msg), vec![ ("site".to_string(), b"ams1".to_vec()) ]);
+        assert!(StaticHeaders::default().with_parsed("site").is_err());
+        assert!(StaticHeaders::default().with_parsed("=ams1").is_err());
+    }
+
+    #[test]
+    fn wraps_embedded_keys_in_envelopes() {
+        let msg = br#"{"event_type":"alert"}"#.to_vec();
+        let encoded = Encoder::default()
+            .with_placement(KeyPlacement::Field)
+            .with_envelope(CloudEvents::new(EnvelopeMode::Structured, "surikafka/s1"))
+            .encode(&msg, b"flow-1");
+        let value: Value = serde_json::from_slice(&encoded.payload).expect("Failed to parse");
+
+        assert_eq!(value["data"][KEY_NAME], "flow-1");
+        assert_eq!(encoded.headers, vec![
+            ("content-type".to_string(), b"application/cloudevents+json".to_vec())
+        ]);
+    }
+}
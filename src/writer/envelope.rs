@@ -0,0 +1,169 @@
+use super::super::{
+    chrono::Utc,
+    errors::{
+        Error,
+        ErrorKind
+    },
+    eve,
+    serde_json::{
+        self,
+        Value
+    },
+    source
+};
+use std::{
+    self,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering
+    }
+};
+
+/// How records are wrapped in a CloudEvents 1.0 envelope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeMode {
+    /// Records are sent as logged
+    None,
+    /// Payloads are `application/cloudevents+json` documents with the event under `data`
+    Structured,
+    /// Payloads are sent as logged, with the attributes in `ce_*` headers
+    Binary
+}
+
+impl std::str::FromStr for EnvelopeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EnvelopeMode, Error> {
+        match s {
+            "none" => Ok(EnvelopeMode::None),
+            "structured" | "json" => Ok(EnvelopeMode::Structured),
+            "binary" => Ok(EnvelopeMode::Binary),
+            _ => Err(Error::from_kind(ErrorKind::InvalidEnvelopeMode(s.to_string())))
+        }
+    }
+}
+
+/// Attributes of one record's CloudEvent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attributes {
+    pub id: String,
+    pub source: String,
+    pub event_type: String,
+    pub time: Option<String>
+}
+
+/// Wraps records in CloudEvents 1.0 envelopes, for event driven consumers such as Knative or
+/// Argo Events. Events get the type `org.oisf.suricata.eve.<event_type>` and ids unique to this
+/// process, `<start millis>-<sequence>`.
+pub struct CloudEvents {
+    mode: EnvelopeMode,
+    source: String,
+    id_prefix: String,
+    sequence: AtomicUsize
+}
+
+impl CloudEvents {
+    /// `source` identifies the sensor, e.g. `surikafka/<sensor id>`.
+    pub fn new(mode: EnvelopeMode, source: &str) -> CloudEvents {
+        CloudEvents {
+            mode: mode,
+            source: source.to_string(),
+            id_prefix: Utc::now().timestamp_millis().to_string(),
+            sequence: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn mode(&self) -> EnvelopeMode {
+        self.mode
+    }
+
+    pub fn attributes(&self, msg: &[u8], event: Option<&Value>) -> Attributes {
+        let time = event
+            .and_then(|e| e.get("timestamp"))
+            .and_then(Value::as_str)
+            .and_then(source::parse_timestamp)
+            .map(|ts| ts.to_rfc3339());
+        Attributes {
+            id: format!("{}-{}", self.id_prefix, self.sequence.fetch_add(1, Ordering::SeqCst)),
+            source: self.source.clone(),
+            event_type: format!("org.oisf.suricata.eve.{}", eve::event_type(msg).unwrap_or("unknown")),
+            time: time
+        }
+    }
+
+    /// Structured mode payload; records that aren't JSON are carried as a string.
+    pub fn structured(&self, msg: &[u8]) -> Vec<u8> {
+        let event: Value = serde_json::from_slice(msg)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(msg).into_owned()));
+        let attributes = self.attributes(msg, Some(&event));
+        let mut envelope = json!({
+            "specversion": "1.0",
+            "id": attributes.id,
+            "source": attributes.source,
+            "type": attributes.event_type,
+            "datacontenttype": "application/json",
+            "data": event
+        });
+        if let Some(time) = attributes.time {
+            envelope["time"] = Value::String(time);
+        }
+        serde_json::to_vec(&envelope).expect("Envelope is always serializable")
+    }
+
+    /// Binary mode headers, per the CloudEvents Kafka protocol binding.
+    pub fn binary_headers(&self, msg: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let event: Option<Value> = serde_json::from_slice(msg).ok();
+        let attributes = self.attributes(msg, event.as_ref());
+        let mut headers = vec![
+            ("ce_specversion".to_string(), b"1.0".to_vec()),
+            ("ce_id".to_string(), attributes.id.into_bytes()),
+            ("ce_source".to_string(), attributes.source.into_bytes()),
+            ("ce_type".to_string(), attributes.event_type.into_bytes())
+        ];
+        if let Some(time) = attributes.time {
+            headers.push( ("ce_time".to_string(), time.into_bytes()) );
+        }
+        headers.push( ("content-type".to_string(), b"application/json".to_vec()) );
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALERT: &'static [u8] = br#"{"timestamp":"2018-06-01T00:00:00.000000+0000","event_type":"alert"}"#;
+
+    #[test]
+    fn wraps_structured_events() {
+        let envelope = CloudEvents::new(EnvelopeMode::Structured, "surikafka/s1");
+
+        let first: Value = serde_json::from_slice(&envelope.structured(ALERT)).expect("Failed to parse");
+        let second: Value = serde_json::from_slice(&envelope.structured(ALERT)).expect("Failed to parse");
+
+        assert_eq!(first["specversion"], "1.0");
+        assert_eq!(first["type"], "org.oisf.suricata.eve.alert");
+        assert_eq!(first["source"], "surikafka/s1");
+        assert_eq!(first["time"], "2018-06-01T00:00:00+00:00");
+        assert_eq!(first["data"]["event_type"], "alert");
+        assert!(first["id"] != second["id"]);
+    }
+
+    #[test]
+    fn adds_binary_headers() {
+        let envelope = CloudEvents::new(EnvelopeMode::Binary, "surikafka/s1");
+
+        let headers = envelope.binary_headers(br#"{"event_type":"dns"}"#);
+        let names: Vec<&str> = headers.iter().map(|&(ref n, _)| n.as_str()).collect();
+
+        assert_eq!(names, vec!["ce_specversion", "ce_id", "ce_source", "ce_type", "content-type"]);
+        assert_eq!(headers[3].1, b"org.oisf.suricata.eve.dns".to_vec());
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!("json".parse::<EnvelopeMode>().expect("Failed to parse"), EnvelopeMode::Structured);
+        assert_eq!("binary".parse::<EnvelopeMode>().expect("Failed to parse"), EnvelopeMode::Binary);
+        assert!("avro".parse::<EnvelopeMode>().is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use super::super::key::KeyGenerator;
+
+/// Generates the record key of each event.
+pub struct Keyer<K: KeyGenerator> {
+    generator: K
+}
+
+impl<K> Keyer<K>
+    where K: KeyGenerator,
+          K::Item: Sized
+{
+    pub fn new(generator: K) -> Keyer<K> {
+        Keyer {
+            generator: generator
+        }
+    }
+
+    pub fn key(&self, msg: &Vec<u8>) -> K::Item {
+        self.generator.generate(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::super::key::{
+        BytesGenerator,
+        SaltedGenerator
+    };
+
+    #[test]
+    fn keys_by_generator() {
+        let msg = b"event".to_vec();
+
+        assert_eq!(Keyer::new(BytesGenerator).key(&msg), msg);
+
+        let salted = Keyer::new(SaltedGenerator::new(BytesGenerator, b"secret".to_vec()));
+        assert_eq!(salted.key(&msg), salted.key(&msg));
+        assert!(salted.key(&msg) != msg);
+    }
+}
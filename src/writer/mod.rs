@@ -0,0 +1,730 @@
+use super::{
+    breaker::CircuitBreaker,
+    chrono::{
+        DateTime,
+        Utc
+    },
+    errors::Error,
+    eve,
+    futures,
+    futures::{
+        Async,
+        Poll,
+        Stream
+    },
+    inspect::{
+        Filter,
+        Inspection
+    },
+    key::KeyGenerator,
+    metrics::{
+        Counter,
+        LatencyHistogram,
+        QueueGauge,
+        SizeMetrics
+    },
+    partition::PartitionStrategy,
+    rdkafka::{
+        ClientContext,
+        message::ToBytes,
+        producer::{
+            DeliveryFuture,
+            FutureProducer,
+            FutureRecord
+        }
+    },
+    runtime::Delay,
+    stats,
+    throttle::ThrottleSignal,
+    topics::TopicRouter,
+    trace::SourceTrace,
+    verify::{
+        self,
+        Fingerprint,
+        ProducedDigest,
+        TrailerEmitter
+    }
+};
+use std::{
+    self,
+    borrow::Cow,
+    collections::HashMap,
+    time::Instant
+};
+
+mod codec;
+mod compress;
+mod deliver;
+mod encode;
+mod envelope;
+mod key;
+mod project;
+mod retry;
+mod route;
+
+pub use self::codec::{
+    Avro,
+    AvroField,
+    AvroSchema,
+    Codec,
+    CodecFactory,
+    CodecKind,
+    CodecSet,
+    Json,
+    MessagePack,
+    SchemaRegistry
+};
+pub use self::compress::{
+    Compressed,
+    DICTIONARY_HEADER,
+    DictionaryKey,
+    DictionarySampler,
+    ENCODING_HEADER,
+    PayloadCompressor,
+    dictionary_id
+};
+#[cfg(feature = "zstd-dict")]
+pub use self::compress::ZstdCompressor;
+pub use self::deliver::{
+    Deliverer,
+    Delivered,
+    Sent
+};
+pub use self::encode::{
+    Encoded,
+    Encoder,
+    EveHeaders,
+    HeaderGenerator,
+    KEY_NAME,
+    KeyPlacement,
+    StaticHeaders,
+    embed_key,
+    key_text,
+    owned_headers
+};
+pub use self::envelope::{
+    Attributes,
+    CloudEvents,
+    EnvelopeMode
+};
+pub use self::key::Keyer;
+pub use self::project::{
+    Projection,
+    ProjectionSet
+};
+pub use self::retry::{
+    DeadLetterChannel,
+    DeadLetterFile,
+    DeliveryErrorHandler,
+    ExponentialBackoff,
+    FailStream,
+    Failure,
+    FailureAction,
+    LogAndDrop
+};
+pub use self::route::{
+    Route,
+    Router
+};
+
+/// Sends each event of the inner stream to Kafka, with up to `max_in_flight` deliveries
+/// outstanding. The write path is split into stages: `Keyer` generates the record key, `Encoder`
+/// the payload and headers, `Router` the topic and partition, and `Deliverer` tracks deliveries
+/// and holds back sends while the window is full, the circuit breaker is open, or brokers
+/// throttle. A `ProjectionSet` may trim events to the fields their topic keeps before encoding,
+/// and a `CodecSet` then serialize payloads for their topic, and a `PayloadCompressor`
+/// compress them. Failed records are dropped unless a `DeliveryErrorHandler` decides otherwise;
+/// while one waits out a retry, no new records are sent, so with one delivery in flight records
+/// keep their order.
+/// Records of a topic being migrated to a new encoding may also be written to a mirror topic
+/// with the codec of its own, and are only delivered once both are. Records an immediate
+/// `Filter` matches are sent on a producer of their own that doesn't linger.
+pub struct Writer<C, K, S>
+    where C: ClientContext + 'static,
+          K: KeyGenerator,
+          S: Stream,
+          S::Error: std::convert::From<futures::Canceled> + std::convert::From<Error>,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    inner: S,
+    keyer: Keyer<K>,
+    encoder: Encoder,
+    router: Router,
+    projections: Option<ProjectionSet>,
+    codecs: Option<CodecSet>,
+    compressor: Option<Box<PayloadCompressor + Send>>,
+    producer: FutureProducer<C>,
+    sizes: Option<SizeMetrics>,
+    deliverer: Deliverer,
+    error_handler: Option<Box<DeliveryErrorHandler + Send>>,
+    retrying: Vec<(Delay, Vec<u8>, usize)>,
+    retry_gauge: Option<QueueGauge>,
+    trace: Option<SourceTrace>,
+    mirrors: HashMap<String, Mirror>,
+    immediate: Option<Immediate<C>>,
+    inner_done: bool
+}
+
+/// Where records of a dual written topic are also sent, and until when.
+struct Mirror {
+    topic: String,
+    until: Option<DateTime<Utc>>,
+    mirrored: Counter
+}
+
+/// Records sent right away rather than batched with bulk traffic, and the producer sending them.
+struct Immediate<C>
+    where C: ClientContext + 'static
+{
+    filter: Filter,
+    producer: FutureProducer<C>,
+    sent: Counter
+}
+
+impl<C, K, S> Writer<C, K, S>
+    where C: ClientContext + 'static,
+          K: KeyGenerator,
+          K::Item: Sized,
+          S: Stream,
+          S::Error: std::convert::From<futures::Canceled> + std::convert::From<Error>,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    pub fn new(
+        stream: S,
+        topic: String,
+        generator: K,
+        producer: FutureProducer<C>
+    ) -> Writer<C, K, S> {
+        Writer {
+            inner: stream,
+            keyer: Keyer::new(generator),
+            encoder: Encoder::default(),
+            router: Router::new(topic),
+            projections: None,
+            codecs: None,
+            compressor: None,
+            producer: producer,
+            sizes: None,
+            deliverer: Deliverer::default(),
+            error_handler: None,
+            retrying: vec![],
+            retry_gauge: None,
+            trace: None,
+            mirrors: HashMap::new(),
+            immediate: None,
+            inner_done: false
+        }
+    }
+
+    /// Adds headers from `generator` to every record. May be called several times.
+    pub fn with_headers<H>(mut self, generator: H) -> Self
+        where H: HeaderGenerator + Send + 'static
+    {
+        self.encoder = self.encoder.with_headers(generator);
+        self
+    }
+
+    /// Also write the generated key into a header or payload field, for consumers that lose the
+    /// record key (REST proxies, file sinks).
+    pub fn with_key_placement(mut self, placement: KeyPlacement) -> Self {
+        self.encoder = self.encoder.with_placement(placement);
+        self
+    }
+
+    /// Wrap every record in a CloudEvents envelope.
+    pub fn with_envelope(mut self, envelope: CloudEvents) -> Self {
+        self.encoder = self.encoder.with_envelope(envelope);
+        self
+    }
+
+    /// Keep only some fields of the events sent to a topic. Headers are still generated from the
+    /// whole event.
+    pub fn with_projections(mut self, projections: ProjectionSet) -> Self {
+        self.projections = Some(projections);
+        self
+    }
+
+    /// Name the file and line a record was read from when its delivery fails, for records the
+    /// readers recorded in `trace`.
+    pub fn with_trace(mut self, trace: SourceTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Serialize payloads with the codec of their topic, e.g. Avro, rather than sending JSON.
+    /// Records that fail to encode are dropped.
+    pub fn with_codecs(mut self, codecs: CodecSet) -> Self {
+        self.codecs = Some(codecs);
+        self
+    }
+
+    /// Compress payloads after any codec, adding the headers needed to decompress them. Records
+    /// that fail to compress are dropped.
+    pub fn with_compressor<P>(mut self, compressor: P) -> Self
+        where P: PayloadCompressor + Send + 'static
+    {
+        self.compressor = Some(Box::new(compressor));
+        self
+    }
+
+    /// Stop pulling from the inner stream while the breaker is open, leaving events buffered
+    /// upstream until the cooldown expires.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.deliverer = self.deliverer.with_circuit_breaker(breaker);
+        self
+    }
+
+    /// Keep up to `max_in_flight` deliveries awaiting a result rather than waiting for each one
+    /// before sending the next, only holding back the inner stream once the window is full.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.deliverer = self.deliverer.with_max_in_flight(max_in_flight);
+        self
+    }
+
+    /// Tracks the number of deliveries awaiting a result from the broker.
+    pub fn with_in_flight_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.deliverer = self.deliverer.with_in_flight_gauge(gauge);
+        self
+    }
+
+    /// Records the time from send to delivery report of every successful delivery.
+    pub fn with_latency_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.deliverer = self.deliverer.with_latency_histogram(histogram);
+        self
+    }
+
+    /// Records the time from the EVE timestamp of every successfully delivered record to its
+    /// delivery report.
+    pub fn with_event_latency_histogram(mut self, histogram: LatencyHistogram) -> Self {
+        self.deliverer = self.deliverer.with_event_latency_histogram(histogram);
+        self
+    }
+
+    /// Records the size of every payload sent, by `event_type` and by topic.
+    pub fn with_size_metrics(mut self, sizes: SizeMetrics) -> Self {
+        self.sizes = Some(sizes);
+        self
+    }
+
+    /// Slow sends while brokers report throttling, and don't count failures during throttling
+    /// towards the circuit breaker since they are usually client side timeouts.
+    pub fn with_throttle(mut self, signal: ThrottleSignal) -> Self {
+        self.deliverer = self.deliverer.with_throttle(signal);
+        self
+    }
+
+    /// Record the payload hash of every delivered record in `digest`, so a `verify::Verifier`
+    /// can compare them with the records read back.
+    pub fn with_digest(mut self, digest: ProducedDigest) -> Self {
+        self.deliverer = self.deliverer.with_digest(digest);
+        self
+    }
+
+    /// Send a checksum trailer after every so many delivered records of a partition, see
+    /// `verify::TrailerEmitter`.
+    pub fn with_trailers(mut self, trailers: TrailerEmitter) -> Self {
+        self.deliverer = self.deliverer.with_trailers(trailers);
+        self
+    }
+
+    /// Hand records whose delivery failed to `handler`, which may retry them, dead letter them,
+    /// or fail the stream. Records are kept in memory until delivered.
+    pub fn with_error_handler<H>(mut self, handler: H) -> Self
+        where H: DeliveryErrorHandler + Send + 'static
+    {
+        self.error_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Tracks the number of failed records waiting out their backoff before a retry.
+    pub fn with_retry_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.retry_gauge = Some(gauge);
+        self
+    }
+
+    /// Decides what happens to a failed record, returning an error if the stream should fail.
+    fn handle_failure(&mut self, failure: Failure) -> Result<(), Error> {
+        let action = match self.error_handler {
+            Some(ref mut handler) => handler.on_failure(&failure),
+            None => FailureAction::Drop
+        };
+        match action {
+            FailureAction::Retry(backoff) => {
+                self.retrying.push( (Delay::new(Instant::now() + backoff), failure.msg, failure.attempt + 1) );
+                if let Some(ref gauge) = self.retry_gauge {
+                    gauge.add(1);
+                }
+            }
+            FailureAction::Drop => (),
+            FailureAction::Fail => bail!("Failed to deliver record after {} attempts: {:?}", failure.attempt, failure.error)
+        }
+        Ok( () )
+    }
+
+    /// Takes the first retry whose backoff has expired.
+    fn due_retry(&mut self) -> Option<(Vec<u8>, usize)> {
+        let mut due = None;
+        for (i, retry) in self.retrying.iter_mut().enumerate() {
+            match retry.0.poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(())) => (),
+                Err(e) => error!("Retry timer failed: {:?}", e)
+            }
+            due = Some(i);
+            break
+        }
+        due.map(|i| {
+            let (_, msg, attempt) = self.retrying.remove(i);
+            if let Some(ref gauge) = self.retry_gauge {
+                gauge.sub(1);
+            }
+            (msg, attempt)
+        })
+    }
+
+    /// Choose the topic of each event with `router` rather than always using the writer's topic.
+    pub fn with_topic_router(mut self, router: TopicRouter) -> Self {
+        self.router = self.router.with_topic_router(router);
+        self
+    }
+
+    /// Send events to `topic` older than `max_age` when they reach the writer to `archive`
+    /// instead, counting them in `expired`. May be called once per topic.
+    pub fn with_max_age(mut self, topic: &str, max_age: std::time::Duration, archive: &str, expired: Counter) -> Self {
+        self.router = self.router.with_max_age(topic, max_age, archive).with_expired_counter(expired);
+        self
+    }
+
+    /// Also write records sent to `topic` to `mirror`, encoded with the mirror's codec, until
+    /// `until` if given, counting them in `mirrored`. While migrating consumers between
+    /// encodings, both topics then carry every record, and a record only counts as delivered, or
+    /// is retried to both, as one. May be called once per topic.
+    pub fn with_mirror(mut self, topic: &str, mirror: &str, until: Option<DateTime<Utc>>, mirrored: Counter) -> Self {
+        self.mirrors.insert(topic.to_string(), Mirror {
+            topic: mirror.to_string(),
+            until: until,
+            mirrored: mirrored
+        });
+        self
+    }
+
+    /// Send records `filter` matches, by their event, topic, or key, on `producer`, which should
+    /// be configured not to linger, so e.g. severity 1 alerts aren't held back behind bulk
+    /// traffic waiting to fill a batch. They're counted in `sent`, and their latencies recorded
+    /// in the deliverer's immediate histograms when set.
+    pub fn with_immediate(mut self, filter: Filter, producer: FutureProducer<C>, sent: Counter) -> Self {
+        self.immediate = Some(Immediate {
+            filter: filter,
+            producer: producer,
+            sent: sent
+        });
+        self
+    }
+
+    /// Records the send and end to end latencies of immediate records apart from the others.
+    pub fn with_immediate_latency_histograms(mut self, latency: LatencyHistogram, event_latency: LatencyHistogram) -> Self {
+        self.deliverer = self.deliverer.with_immediate_latency_histograms(latency, event_latency);
+        self
+    }
+
+    /// Explicitly assign partitions rather than leaving it to the producer's partitioner.
+    pub fn with_partitioner<P>(mut self, partitioner: P) -> Self
+        where P: PartitionStrategy + Send + 'static
+    {
+        self.router = self.router.with_partitioner(partitioner);
+        self
+    }
+
+    /// Sends `msg`, on the immediate producer if it's an immediate record. Returns `None` if the
+    /// record can't be encoded for its topic.
+    pub fn send(&mut self, msg: &Vec<u8>) -> Option<Sent> {
+        let key = self.keyer.key(msg);
+        let route = self.router.route(msg, key.to_bytes());
+        let immediate = match self.immediate {
+            Some(ref immediate) if immediate.filter.matches_record(msg, &route.topic, key.to_bytes()) => {
+                immediate.sent.incr();
+                true
+            }
+            _ => false
+        };
+        let projected = match self.projections {
+            Some(ref projections) => projections.project(&route.topic, msg),
+            None => None
+        };
+        let Encoded { payload: encoded, headers } = self.encoder.encode_from(projected.as_ref().unwrap_or(msg), msg, key.to_bytes());
+        let mirror = self.mirror_of(&route.topic);
+        let mirror_headers = mirror.as_ref().map(|_| headers.clone());
+        let mut headers = headers;
+        let payload = self.payload(&route.topic, &*encoded, &mut headers)?;
+        if let Some(ref mut sizes) = self.sizes {
+            sizes.record(&route.topic, eve::event_type(msg), payload.len());
+        }
+        let fingerprint = if self.deliverer.is_verifying() {
+            Some(Fingerprint {
+                topic: route.topic.clone(),
+                hash: verify::payload_hash(&*payload)
+            })
+        } else {
+            None
+        };
+        let sent = self.produce(&route.topic, route.partition, &key, &*payload, &headers, immediate);
+        let mirrored = match (mirror, mirror_headers) {
+            (Some(mirror), Some(mut headers)) => match self.payload(&mirror, &*encoded, &mut headers) {
+                Some(payload) => Some(self.produce(&mirror, None, &key, &*payload, &headers, immediate)),
+                None => None
+            },
+            _ => None
+        };
+        Some(Sent {
+            record: sent,
+            mirror: mirrored,
+            fingerprint: fingerprint,
+            immediate: immediate
+        })
+    }
+
+    /// What `msg` would be sent as: its topic, partition, key, and headers, and the event after
+    /// projection. Nothing is produced, and payloads aren't serialized or compressed.
+    pub fn inspect(&mut self, msg: &Vec<u8>) -> Inspection {
+        let key = self.keyer.key(msg);
+        let route = self.router.route(msg, key.to_bytes());
+        let projected = match self.projections {
+            Some(ref projections) => projections.project(&route.topic, msg),
+            None => None
+        };
+        let event = projected.as_ref().unwrap_or(msg);
+        let encoded = self.encoder.encode_from(event, msg, key.to_bytes());
+        Inspection {
+            topic: route.topic,
+            partition: route.partition,
+            key: key.to_bytes().to_vec(),
+            headers: encoded.headers,
+            event: encoded.payload.into_owned()
+        }
+    }
+
+    /// Inspects every event of the inner stream rather than sending it, for checking keys and
+    /// routes on a sensor without touching the topics.
+    pub fn into_inspections(mut self) -> impl Stream<Item=Inspection, Error=S::Error> {
+        futures::stream::poll_fn(move || {
+            match try_ready!(self.inner.poll()) {
+                Some(msg) => Ok(Async::Ready(Some(self.inspect(msg.as_ref())))),
+                None => Ok(Async::Ready(None))
+            }
+        })
+    }
+
+    /// The mirror topic of `topic`, if it's still dual written, counting the record as mirrored.
+    fn mirror_of(&mut self, topic: &str) -> Option<String> {
+        let ended = match self.mirrors.get(topic) {
+            Some(mirror) => match mirror.until {
+                Some(until) if Utc::now() >= until => true,
+                _ => {
+                    mirror.mirrored.incr();
+                    return Some(mirror.topic.clone())
+                }
+            },
+            None => return None
+        };
+        if ended {
+            if let Some(mirror) = self.mirrors.remove(topic) {
+                info!("Stopped dual writing {} to {} at {}", topic, mirror.topic, mirror.until.map(|u| u.to_rfc3339()).unwrap_or_default());
+            }
+        }
+        None
+    }
+
+    /// Serializes `payload` with the codec of `topic` and compresses it, adding the compression
+    /// headers to `headers`.
+    fn payload<'a>(&mut self, topic: &str, payload: &'a Vec<u8>, headers: &mut Vec<(String, Vec<u8>)>) -> Option<Cow<'a, Vec<u8>>> {
+        let payload = match self.codecs {
+            Some(ref mut codecs) => codecs.encode(topic, Cow::Borrowed(payload))?,
+            None => Cow::Borrowed(payload)
+        };
+        match self.compressor {
+            Some(ref mut compressor) => match compressor.compress(&payload) {
+                Ok(compressed) => {
+                    headers.extend(compressed.headers());
+                    Some(Cow::Owned(compressed.payload))
+                }
+                Err(e) => {
+                    warn!("Failed to compress record for {}: {}", topic, e);
+                    None
+                }
+            },
+            None => Some(payload)
+        }
+    }
+
+    fn produce(&self, topic: &str, partition: Option<i32>, key: &K::Item, payload: &Vec<u8>, headers: &[(String, Vec<u8>)], immediate: bool) -> DeliveryFuture {
+        let record: FutureRecord<K::Item, Vec<u8>> = FutureRecord::to(topic)
+            .key(key)
+            .payload(payload);
+        let record = match partition {
+            Some(p) => record.partition(p),
+            None => record
+        };
+        let record = match owned_headers(headers) {
+            Some(headers) => record.headers(headers),
+            None => record
+        };
+        match self.immediate {
+            Some(ref i) if immediate => i.producer.send(record, 1000),
+            _ => self.producer.send(record, 1000)
+        }
+    }
+}
+
+impl<C, K, S> Stream for Writer<C, K, S>
+    where C: ClientContext + 'static,
+          K: KeyGenerator,
+          K::Item: Sized,
+          S: Stream,
+          S::Error: std::convert::From<futures::Canceled> + std::convert::From<Error>,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    type Item = stats::Stats;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut current_stats = stats::Stats::default();
+        loop {
+            if let Async::Ready(Some(mut delivered)) = self.deliverer.poll_delivered()? {
+                self.deliverer.record(&delivered, &mut current_stats);
+                if let Some(failure) = delivered.failure.take() {
+                    self.handle_failure(failure)?;
+                }
+                continue
+            }
+            if !self.retrying.is_empty() && self.deliverer.poll_ready().is_ready() {
+                if let Some( (msg, attempt) ) = self.due_retry() {
+                    let sent = match self.send(&msg) {
+                        Some(sent) => sent,
+                        None => continue
+                    };
+                    let length = msg.len();
+                    let event_age = self.deliverer.event_age(&msg);
+                    let source = self.trace.as_ref().and_then(|t| t.lookup(&msg));
+                    self.deliverer.track(sent, length, Some( (msg, attempt) ), event_age, source);
+                    continue
+                }
+            }
+            let finished = self.inner_done && self.deliverer.in_flight() == 0 && self.retrying.is_empty();
+            // Records read after a failed one wait for its retry rather than overtaking it
+            if self.inner_done || !self.retrying.is_empty() || self.deliverer.poll_ready().is_not_ready() {
+                if !current_stats.is_empty() {
+                    return Ok(Async::Ready(Some(current_stats)));
+                } else if finished {
+                    return Ok(Async::Ready(None))
+                } else {
+                    return Ok(Async::NotReady)
+                }
+            }
+            match self.inner.poll()? {
+                Async::Ready(Some(msg)) => {
+                    let sent = match self.send(msg.as_ref()) {
+                        Some(sent) => sent,
+                        None => continue
+                    };
+                    let retained = self.error_handler.as_ref().map(|_| (msg.as_ref().clone(), 1));
+                    let event_age = self.deliverer.event_age(msg.as_ref());
+                    let source = self.trace.as_ref().and_then(|t| t.lookup(msg.as_ref()));
+                    self.deliverer.track(sent, msg.as_ref().len(), retained, event_age, source);
+                }
+                Async::NotReady => {
+                    debug!("No messages ready to send");
+                    if !current_stats.is_empty() {
+                        return Ok(Async::Ready(Some(current_stats)));
+                    } else {
+                        return Ok(Async::NotReady)
+                    }
+                }
+                Async::Ready(None) => {
+                    debug!("No more messages available");
+                    self.inner_done = true;
+                }
+            }
+        }
+    }
+}
+
+pub trait WithProduce<S>
+    where S: Stream + Sized,
+          S::Error: std::convert::From<futures::Canceled> + std::convert::From<Error>,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    fn produce<C, K>(
+        self,
+        topic: String,
+        generator: K,
+        producer: FutureProducer<C>
+    ) -> Writer<C, K, S>
+        where C: ClientContext + 'static,
+              K: KeyGenerator,
+              K::Item: Sized;
+}
+
+impl<S> WithProduce<S> for S
+    where S: Stream,
+          S::Error: std::convert::From<futures::Canceled> + std::convert::From<Error>,
+          S::Item: std::convert::AsRef<Vec<u8>>
+{
+    fn produce<C, K>(
+        self,
+        topic: String,
+        generator: K,
+        producer: FutureProducer<C>
+    ) -> Writer<C, K, S>
+        where C: ClientContext + 'static,
+              K: KeyGenerator,
+              K::Item: Sized
+    {
+        Writer::new(self, topic, generator, producer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::{
+        key::BytesGenerator,
+        env_logger,
+        errors::{
+            Error
+        },
+        rdkafka::{
+            ClientConfig
+        },
+        tokio
+    };
+
+    #[test]
+    fn produces_messages() {
+        let _ = env_logger::try_init();
+
+        let mut rt = tokio::runtime::Runtime::new().expect("Failed to build runtime");
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", "localhost:9092")
+            .set("produce.offset.report", "true")
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Producer creation error");
+
+        let send_stream = futures::stream::iter_ok::<Vec<Vec<u8>>, Error>(
+            vec![
+                "string1".to_string().into_bytes(),
+                "string2".to_string().into_bytes(),
+                "string3".to_string().into_bytes()
+            ]
+        );
+
+        let fut_result = send_stream
+            .produce("test_topic".to_string(), BytesGenerator, producer)
+            .collect();
+
+        let sent = rt.block_on(fut_result).expect("Failed to send");
+
+        assert_eq!(sent.len(), 3);
+    }
+}
\ No newline at end of file
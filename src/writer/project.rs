@@ -0,0 +1,196 @@
+use super::super::{
+    errors::Error,
+    serde::de::{
+        Deserializer,
+        DeserializeSeed,
+        IgnoredAny,
+        MapAccess,
+        Visitor
+    },
+    serde_json::{
+        self,
+        Map,
+        Value
+    },
+    transform
+};
+use std::{
+    self,
+    collections::HashMap
+};
+
+/// What to keep of a field: all of it, or some of its own fields.
+#[derive(Debug, Clone, PartialEq)]
+enum Keep {
+    All,
+    Fields(Projection)
+}
+
+/// Fields kept of an event, by dotted path, e.g. `alert,flow_id,src_ip,flow.start`. Kept fields
+/// are parsed while the rest of the record is only scanned past, so large dropped fields such
+/// as `payload` or `http.http_response_body` cost little more than reading them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Projection {
+    fields: HashMap<String, Keep>
+}
+
+impl Projection {
+    /// Parses a comma separated list of dotted paths.
+    pub fn parse(s: &str) -> Result<Projection, Error> {
+        let mut projection = Projection::default();
+        for path in transform::split_list(s) {
+            projection.keep(&transform::dotted_path(&path)?);
+        }
+        if projection.fields.is_empty() {
+            bail!("Projection {} keeps no fields", s);
+        }
+        Ok(projection)
+    }
+
+    fn keep(&mut self, path: &[String]) {
+        let (field, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return
+        };
+        if rest.is_empty() {
+            self.fields.insert(field.clone(), Keep::All);
+            return
+        }
+        let keep = self.fields.entry(field.clone()).or_insert_with(|| Keep::Fields(Projection::default()));
+        if let Keep::Fields(ref mut nested) = *keep {
+            nested.keep(rest);
+        }
+    }
+
+    /// The kept fields of a JSON object, `None` if `msg` isn't one.
+    pub fn project(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        let mut deserializer = serde_json::Deserializer::from_slice(msg);
+        let projected = self.deserialize(&mut deserializer).ok()?;
+        serde_json::to_vec(&projected).ok()
+    }
+
+    /// Projects an already parsed value, keeping values that aren't objects as they are.
+    fn project_value(&self, value: Value) -> Value {
+        match value {
+            Value::Object(object) => {
+                let mut projected = Map::new();
+                for (name, value) in object {
+                    match self.fields.get(&name) {
+                        Some(&Keep::All) => { projected.insert(name, value); }
+                        Some(&Keep::Fields(ref nested)) => { projected.insert(name, nested.project_value(value)); }
+                        None => ()
+                    }
+                }
+                Value::Object(projected)
+            }
+            other => other
+        }
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for &'a Projection {
+    type Value = Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for &'a Projection {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a JSON object")
+    }
+
+    fn visit_map<M>(self, mut map: M) -> Result<Value, M::Error>
+        where M: MapAccess<'de>
+    {
+        let mut projected = Map::new();
+        while let Some(name) = map.next_key::<String>()? {
+            match self.fields.get(&name) {
+                Some(&Keep::All) => {
+                    projected.insert(name, map.next_value::<Value>()?);
+                }
+                Some(&Keep::Fields(ref nested)) => {
+                    // Nested objects are small next to what's skipped at the top level
+                    let value = map.next_value::<Value>()?;
+                    projected.insert(name, nested.project_value(value));
+                }
+                None => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(Value::Object(projected))
+    }
+}
+
+/// Projections by topic, for topics that only need part of each event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProjectionSet {
+    topics: HashMap<String, Projection>
+}
+
+impl ProjectionSet {
+    pub fn with_topic(mut self, topic: &str, projection: Projection) -> Self {
+        self.topics.insert(topic.to_string(), projection);
+        self
+    }
+
+    /// Parses a projection as `topic=field,field`, e.g. `eve-alerts=alert,flow_id,src_ip`.
+    pub fn with_parsed(self, setting: &str) -> Result<Self, Error> {
+        let mut parts = setting.splitn(2, '=');
+        match (parts.next().map(str::trim), parts.next()) {
+            (Some(topic), Some(fields)) if !topic.is_empty() => Ok(self.with_topic(topic, Projection::parse(fields)?)),
+            _ => bail!("Invalid projection {}, expected topic=field,field", setting)
+        }
+    }
+
+    /// The projected event for `topic`, `None` if the topic keeps whole events or the event
+    /// isn't a JSON object.
+    pub fn project(&self, topic: &str, msg: &[u8]) -> Option<Vec<u8>> {
+        self.topics.get(topic).and_then(|p| p.project(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_listed_fields() {
+        let projection = Projection::parse("timestamp, flow_id, src_ip, alert.signature_id, alert.severity").expect("Failed to parse");
+        let event = br#"{"timestamp":"t","flow_id":1,"event_type":"alert","src_ip":"10.0.0.1","payload":"AAAA","alert":{"signature_id":2000001,"signature":"ET SCAN","severity":2},"packet":[1,2]}"#;
+
+        let projected: Value = serde_json::from_slice(&projection.project(event).expect("Failed to project")).expect("Failed to parse");
+
+        assert_eq!(projected, json!({
+            "timestamp": "t", "flow_id": 1, "src_ip": "10.0.0.1",
+            "alert": {"signature_id": 2000001, "severity": 2}
+        }));
+    }
+
+    #[test]
+    fn whole_field_wins_over_nested() {
+        let projection = Projection::parse("alert.severity,alert").expect("Failed to parse");
+
+        assert_eq!(projection.project(br#"{"alert":{"severity":1,"signature":"x"}}"#), Some(br#"{"alert":{"severity":1,"signature":"x"}}"#.to_vec()));
+        assert_eq!(projection.project(b"not json"), None);
+        assert_eq!(projection.project(b"[1,2]"), None);
+        assert!(Projection::parse(" , ").is_err());
+        assert!(Projection::parse("alert..severity").is_err());
+    }
+
+    #[test]
+    fn projects_by_topic() {
+        let projections = ProjectionSet::default()
+            .with_parsed("eve-alerts=alert").expect("Failed to parse");
+
+        assert_eq!(projections.project("eve-alerts", br#"{"alert":1,"payload":"x"}"#), Some(br#"{"alert":1}"#.to_vec()));
+        assert_eq!(projections.project("eve-flow", br#"{"flow":1}"#), None);
+        assert!(ProjectionSet::default().with_parsed("alert,flow_id").is_err());
+    }
+}
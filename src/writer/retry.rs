@@ -0,0 +1,250 @@
+use super::super::{
+    futures::sync::mpsc::UnboundedSender,
+    metrics::{
+        Counter,
+        QueueGauge
+    },
+    rdkafka::error::KafkaError,
+    trace::SourceLine
+};
+use std::{
+    self,
+    fs::{
+        File,
+        OpenOptions
+    },
+    io::Write,
+    path::Path,
+    time::Duration
+};
+
+/// What to do with a record whose delivery failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FailureAction {
+    /// Send it again after the delay
+    Retry(Duration),
+    /// Give up on it, having handed it off or logged it
+    Drop,
+    /// Fail the writer's stream, leaving the decision to its caller
+    Fail
+}
+
+/// A record whose delivery failed, after `attempt` attempts. `source` is the line it was read
+/// from, when sources are traced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub msg: Vec<u8>,
+    pub attempt: usize,
+    pub error: KafkaError,
+    pub source: Option<SourceLine>
+}
+
+impl Failure {
+    /// ` from <path>:<line>` for log messages, empty if the source isn't known.
+    pub fn origin(&self) -> String {
+        self.source.as_ref().map(|s| format!(" from {}", s)).unwrap_or_default()
+    }
+}
+
+/// Decides what happens to records whose delivery failed. Without a handler, the writer logs
+/// failures and drops the records.
+pub trait DeliveryErrorHandler {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction;
+}
+
+impl<H: DeliveryErrorHandler + ?Sized> DeliveryErrorHandler for Box<H> {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        (**self).on_failure(failure)
+    }
+}
+
+/// Logs and drops failed records.
+pub struct LogAndDrop;
+
+impl DeliveryErrorHandler for LogAndDrop {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        error!("Dropping record{} after {} failed attempts: {:?}", failure.origin(), failure.attempt, failure.error);
+        FailureAction::Drop
+    }
+}
+
+/// Fails the stream on the first record that can't be delivered.
+pub struct FailStream;
+
+impl DeliveryErrorHandler for FailStream {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        error!("Failed to deliver record{} after {} attempts: {:?}", failure.origin(), failure.attempt, failure.error);
+        FailureAction::Fail
+    }
+}
+
+/// Retries failed records up to `max_attempts` attempts in total, doubling the delay after every
+/// attempt up to `max_backoff`, then hands them to the fallback handler.
+pub struct ExponentialBackoff {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    fallback: Box<DeliveryErrorHandler + Send>,
+    retries: Option<Counter>
+}
+
+impl ExponentialBackoff {
+    pub fn new(max_attempts: usize, initial_backoff: Duration, max_backoff: Duration) -> ExponentialBackoff {
+        ExponentialBackoff {
+            max_attempts: max_attempts,
+            initial_backoff: initial_backoff,
+            max_backoff: max_backoff,
+            fallback: Box::new(LogAndDrop),
+            retries: None
+        }
+    }
+
+    /// Handles records that failed every attempt, logging and dropping them by default.
+    pub fn with_fallback<H>(mut self, fallback: H) -> Self
+        where H: DeliveryErrorHandler + Send + 'static
+    {
+        self.fallback = Box::new(fallback);
+        self
+    }
+
+    pub fn with_retry_counter(mut self, counter: Counter) -> Self {
+        self.retries = Some(counter);
+        self
+    }
+
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31) as u32;
+        self.initial_backoff.checked_mul(1 << exponent)
+            .map(|b| b.min(self.max_backoff))
+            .unwrap_or(self.max_backoff)
+    }
+}
+
+impl DeliveryErrorHandler for ExponentialBackoff {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        if failure.attempt >= self.max_attempts {
+            return self.fallback.on_failure(failure)
+        }
+        let backoff = self.backoff(failure.attempt);
+        warn!("Delivery attempt {} of record{} failed, retrying in {:?}: {:?}", failure.attempt, failure.origin(), backoff, failure.error);
+        if let Some(ref counter) = self.retries {
+            counter.incr();
+        }
+        FailureAction::Retry(backoff)
+    }
+}
+
+/// Appends failed records to a local file, one per line, for later replay.
+pub struct DeadLetterFile {
+    file: File,
+    written: Option<Counter>
+}
+
+impl DeadLetterFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DeadLetterFile, std::io::Error> {
+        Ok(DeadLetterFile {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            written: None
+        })
+    }
+
+    pub fn with_counter(mut self, counter: Counter) -> Self {
+        self.written = Some(counter);
+        self
+    }
+}
+
+impl DeliveryErrorHandler for DeadLetterFile {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        let mut line = failure.msg.clone();
+        line.push(b'\n');
+        match self.file.write_all(&line) {
+            Ok(()) => {
+                if let Some(ref counter) = self.written {
+                    counter.incr();
+                }
+                FailureAction::Drop
+            }
+            Err(e) => {
+                error!("Failed to write dead letter: {}", e);
+                FailureAction::Fail
+            }
+        }
+    }
+}
+
+/// Sends failed records to a channel, e.g. one produced to a secondary topic.
+pub struct DeadLetterChannel {
+    sender: UnboundedSender<Vec<u8>>,
+    gauge: Option<QueueGauge>
+}
+
+impl DeadLetterChannel {
+    pub fn new(sender: UnboundedSender<Vec<u8>>) -> DeadLetterChannel {
+        DeadLetterChannel {
+            sender: sender,
+            gauge: None
+        }
+    }
+
+    /// Counts records sent; the receiving side is expected to subtract as it consumes them.
+    pub fn with_queue_gauge(mut self, gauge: QueueGauge) -> Self {
+        self.gauge = Some(gauge);
+        self
+    }
+}
+
+impl DeliveryErrorHandler for DeadLetterChannel {
+    fn on_failure(&mut self, failure: &Failure) -> FailureAction {
+        if self.sender.unbounded_send(failure.msg.clone()).is_err() {
+            error!("Dead letter receiver closed");
+            return FailureAction::Fail
+        }
+        if let Some(ref gauge) = self.gauge {
+            gauge.add(1);
+        }
+        FailureAction::Drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::rdkafka::types::RDKafkaError;
+
+    fn failure(attempt: usize) -> Failure {
+        Failure {
+            msg: br#"{"event_type":"alert"}"#.to_vec(),
+            attempt: attempt,
+            error: KafkaError::MessageProduction(RDKafkaError::MessageTimedOut),
+            source: None
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let retries = Counter::new("writer.retries");
+        let mut policy = ExponentialBackoff::new(3, Duration::from_millis(100), Duration::from_millis(150))
+            .with_fallback(FailStream)
+            .with_retry_counter(retries.clone());
+
+        assert_eq!(policy.on_failure(&failure(1)), FailureAction::Retry(Duration::from_millis(100)));
+        assert_eq!(policy.on_failure(&failure(2)), FailureAction::Retry(Duration::from_millis(150)));
+        assert_eq!(policy.on_failure(&failure(3)), FailureAction::Fail);
+        assert_eq!(retries.value(), 2);
+    }
+
+    #[test]
+    fn writes_dead_letters() {
+        let path = std::env::temp_dir().join(format!("surikafka-dead-letters-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut dead_letters = DeadLetterFile::open(&path).expect("Failed to open");
+
+        assert_eq!(dead_letters.on_failure(&failure(3)), FailureAction::Drop);
+        assert_eq!(dead_letters.on_failure(&failure(3)), FailureAction::Drop);
+
+        let written = std::fs::read_to_string(&path).expect("Failed to read");
+        assert_eq!(written.lines().count(), 2);
+        std::fs::remove_file(&path).expect("Failed to clean up");
+    }
+}
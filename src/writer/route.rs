@@ -0,0 +1,154 @@
+use super::super::{
+    chrono::Utc,
+    eve,
+    metrics::Counter,
+    partition::PartitionStrategy,
+    source,
+    topics::TopicRouter
+};
+use std::{
+    collections::HashMap,
+    time::Duration
+};
+
+/// Destination of a record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub topic: String,
+    pub partition: Option<i32>
+}
+
+/// Longest an event may take to reach the producer and still go to its topic, and the topic it
+/// goes to instead once older.
+#[derive(Debug, Clone, PartialEq)]
+struct AgeLimit {
+    max_age: Duration,
+    archive: String
+}
+
+/// Chooses the topic and, optionally, the partition of each record.
+pub struct Router {
+    topic: String,
+    topics: Option<TopicRouter>,
+    partitioner: Option<Box<PartitionStrategy + Send>>,
+    age_limits: HashMap<String, AgeLimit>,
+    expired: Option<Counter>
+}
+
+impl Router {
+    pub fn new(topic: String) -> Router {
+        Router {
+            topic: topic,
+            topics: None,
+            partitioner: None,
+            age_limits: HashMap::new(),
+            expired: None
+        }
+    }
+
+    /// Choose the topic of each event with `topics` rather than always using the default topic.
+    pub fn with_topic_router(mut self, topics: TopicRouter) -> Self {
+        self.topics = Some(topics);
+        self
+    }
+
+    /// Explicitly assign partitions rather than leaving it to the producer's partitioner.
+    pub fn with_partitioner<P>(mut self, partitioner: P) -> Self
+        where P: PartitionStrategy + Send + 'static
+    {
+        self.partitioner = Some(Box::new(partitioner));
+        self
+    }
+
+    /// Sends events older than `max_age` by their EVE timestamp, e.g. the backlog after an
+    /// outage, to `archive` rather than `topic`, so consumers alerting from `topic` can trust
+    /// what they read is recent. Events without a timestamp are never expired.
+    pub fn with_max_age(mut self, topic: &str, max_age: Duration, archive: &str) -> Self {
+        self.age_limits.insert(topic.to_string(), AgeLimit { max_age: max_age, archive: archive.to_string() });
+        self
+    }
+
+    /// Counts events sent to an archive topic for being too old.
+    pub fn with_expired_counter(mut self, counter: Counter) -> Self {
+        self.expired = Some(counter);
+        self
+    }
+
+    fn expire(&self, topic: String, msg: &[u8]) -> String {
+        let limit = match self.age_limits.get(&topic) {
+            Some(limit) => limit,
+            None => return topic
+        };
+        let age = eve::timestamp(msg)
+            .and_then(source::parse_timestamp)
+            .and_then(|t| Utc::now().signed_duration_since(t).to_std().ok());
+        match age {
+            Some(age) if age > limit.max_age => {
+                debug!("Event {:?} old, sending it to {} rather than {}", age, limit.archive, topic);
+                if let Some(ref counter) = self.expired {
+                    counter.incr();
+                }
+                limit.archive.clone()
+            }
+            _ => topic
+        }
+    }
+
+    pub fn route(&mut self, msg: &[u8], key: &[u8]) -> Route {
+        let topic = match self.topics {
+            Some(ref mut topics) => topics.route(msg),
+            None => self.topic.clone()
+        };
+        let topic = if self.age_limits.is_empty() { topic } else { self.expire(topic, msg) };
+        Route {
+            topic: topic,
+            partition: self.partitioner.as_ref().and_then(|p| p.partition(key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::super::super::super::partition::SensorPinned;
+
+    #[test]
+    fn routes_to_default_topic() {
+        let mut router = Router::new("eve".to_string());
+
+        assert_eq!(router.route(br#"{"event_type":"alert"}"#, b"key"), Route {
+            topic: "eve".to_string(),
+            partition: None
+        });
+    }
+
+    #[test]
+    fn routes_by_event_type_and_key() {
+        let pinned = SensorPinned::new("sensor-1", 12, 3);
+        let assigned = pinned.assigned();
+        let mut router = Router::new("eve".to_string())
+            .with_topic_router(TopicRouter::new("eve", "eve-{event_type}"))
+            .with_partitioner(pinned);
+
+        let route = router.route(br#"{"event_type":"alert"}"#, b"key");
+
+        assert_eq!(route.topic, "eve-alert");
+        assert!(assigned.contains(&route.partition.expect("No partition")));
+    }
+
+    #[test]
+    fn archives_old_events() {
+        let expired = Counter::new("writer.expired");
+        let mut router = Router::new("eve".to_string())
+            .with_topic_router(TopicRouter::new("eve", "eve-{event_type}"))
+            .with_max_age("eve-alert", Duration::from_secs(300), "eve-alert.expired")
+            .with_expired_counter(expired.clone());
+        let recent = format!(r#"{{"timestamp":"{}","event_type":"alert"}}"#, Utc::now().format("%Y-%m-%dT%H:%M:%S%.6f%z"));
+
+        assert_eq!(router.route(br#"{"timestamp":"2020-01-01T00:00:00.000000+0000","event_type":"alert"}"#, b"").topic, "eve-alert.expired");
+        assert_eq!(router.route(recent.as_bytes(), b"").topic, "eve-alert");
+        assert_eq!(router.route(br#"{"event_type":"alert"}"#, b"").topic, "eve-alert");
+        assert_eq!(router.route(br#"{"timestamp":"2020-01-01T00:00:00.000000+0000","event_type":"flow"}"#, b"").topic, "eve-flow");
+        assert_eq!(expired.value(), 1);
+    }
+}